@@ -1,5 +1,5 @@
 use std::collections::HashMap;
-use std::{fs, path::PathBuf, time::Duration};
+use std::{fs, net::SocketAddr, path::PathBuf, time::Duration};
 
 use cratetorrent::{
     alert::AlertReceiver,
@@ -23,9 +23,15 @@ pub struct App {
 }
 
 impl App {
-    pub fn new(download_dir: PathBuf) -> Result<Self> {
+    pub fn new(
+        download_dir: PathBuf,
+        listen_addr: Option<SocketAddr>,
+    ) -> Result<Self> {
         // start engine
-        let conf = Conf::new(download_dir.clone());
+        let mut conf = Conf::new(download_dir.clone());
+        if let Some(listen_addr) = listen_addr {
+            conf.engine.listen_addr = listen_addr;
+        }
         let (engine, alert_rx) = cratetorrent::engine::spawn(conf)?;
         let alert_rx = alert_rx.fuse();
 
@@ -73,7 +79,6 @@ impl App {
         // create torrent
         let torrent_id = self.engine.create_torrent(TorrentParams {
             metainfo: metainfo.clone(),
-            listen_addr: args.listen,
             mode: args.mode,
             conf: Some(TorrentConf {
                 alerts: TorrentAlertConf {
@@ -82,6 +87,9 @@ impl App {
                 },
                 ..Default::default()
             }),
+            resume_data: None,
+            file_priorities: None,
+            extensions: Vec::new(),
         })?;
 
         let torrent = Torrent {
@@ -78,7 +78,7 @@ async fn main() -> Result<()> {
     let mut terminal = Terminal::new(backend)?;
 
     // set up app state and input events
-    let mut app = App::new(args.download_dir.clone())?;
+    let mut app = App::new(args.download_dir.clone(), args.listen)?;
     let mut keys = Keys::new(key::EXIT_KEY);
 
     // for now we only support creation of a single torrent, but technically
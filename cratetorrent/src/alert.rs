@@ -21,7 +21,10 @@
 
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 
-use crate::{error::Error, torrent::stats::TorrentStats, TorrentId};
+use crate::{
+    dht::DhtState, error::Error, metainfo::Metainfo, resume::ResumeData,
+    torrent::stats::TorrentStats, Bitfield, PieceIndex, TorrentId,
+};
 
 pub(crate) type AlertSender = UnboundedSender<Alert>;
 /// The channel on which alerts from the engine can be received. See [`Alert`]
@@ -32,14 +35,52 @@ pub type AlertReceiver = UnboundedReceiver<Alert>;
 #[derive(Debug)]
 #[non_exhaustive]
 pub enum Alert {
+    /// Posted when the metadata of a torrent created from a magnet link has
+    /// been downloaded, right before the torrent is started.
+    MetadataReceived {
+        id: TorrentId,
+        metainfo: Box<Metainfo>,
+    },
     /// Posted when the torrent has finished downloading.
     TorrentComplete(TorrentId),
+    /// Posted when a piece with a deadline, set with
+    /// [`EngineHandle::set_piece_deadline`](crate::engine::EngineHandle::set_piece_deadline)
+    /// or by a [file stream](crate::stream::FileStream), was downloaded, or
+    /// right away if we already have it.
+    DeadlinePieceComplete {
+        id: TorrentId,
+        index: PieceIndex,
+        /// Whether the piece was downloaded after its deadline.
+        is_late: bool,
+    },
+    /// Posted when the torrent has been removed from the engine, and its files
+    /// deleted, if this was requested.
+    TorrentRemoved(TorrentId),
     /// Each running torrent sends an update of its latest statistics every
     /// second via this alert.
     TorrentStats {
         id: TorrentId,
         stats: Box<TorrentStats>,
     },
+    /// The resume data of a torrent, posted in response to
+    /// [`EngineHandle::save_resume_data`](crate::engine::EngineHandle::save_resume_data).
+    ResumeData {
+        id: TorrentId,
+        data: Box<ResumeData>,
+    },
+    /// Posted while a torrent is checking its pieces on disk, each time
+    /// another percent of its pieces is verified.
+    TorrentCheckProgress {
+        id: TorrentId,
+        checked_count: usize,
+        piece_count: usize,
+    },
+    /// Posted when a torrent has finished verifying its pieces on disk, with
+    /// the pieces that were found to be valid.
+    TorrentChecked { id: TorrentId, pieces: Bitfield },
+    /// The state of the engine's DHT node, posted in response to
+    /// [`EngineHandle::save_dht_state`](crate::engine::EngineHandle::save_dht_state).
+    DhtState(Box<DhtState>),
     /// An error from somewhere inside the engine.
     Error(Error),
 }
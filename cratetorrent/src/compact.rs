@@ -0,0 +1,73 @@
+//! The compact representation of peer addresses, as used by peer exchange.
+//!
+//! A compact IPv4 address is the 4 byte IP address followed by the 2 byte
+//! port, while a compact IPv6 address is the 16 byte IP address followed by
+//! the port, all in network byte order.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// The length of a compact IPv4 address.
+pub(crate) const COMPACT_V4_LEN: usize = 6;
+
+/// The length of a compact IPv6 address.
+pub(crate) const COMPACT_V6_LEN: usize = 18;
+
+/// Appends the compact representation of the address to the buffer.
+pub(crate) fn encode_addr(addr: &SocketAddr, buf: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => buf.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => buf.extend_from_slice(&ip.octets()),
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Decodes a string of compact IPv4 addresses.
+///
+/// A trailing partial address is ignored.
+pub(crate) fn decode_v4_addrs(buf: &[u8]) -> Vec<SocketAddr> {
+    buf.chunks_exact(COMPACT_V4_LEN)
+        .map(|b| {
+            let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+            let port = u16::from_be_bytes([b[4], b[5]]);
+            SocketAddr::new(ip.into(), port)
+        })
+        .collect()
+}
+
+/// Decodes a string of compact IPv6 addresses.
+///
+/// A trailing partial address is ignored.
+pub(crate) fn decode_v6_addrs(buf: &[u8]) -> Vec<SocketAddr> {
+    buf.chunks_exact(COMPACT_V6_LEN)
+        .map(|b| {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&b[..16]);
+            let port = u16::from_be_bytes([b[16], b[17]]);
+            SocketAddr::new(Ipv6Addr::from(ip).into(), port)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that addresses are encoded in the compact format and decoded
+    /// back.
+    #[test]
+    fn should_encode_and_decode_compact_addrs() {
+        let v4: SocketAddr = "192.168.0.10:49123".parse().unwrap();
+        let mut buf = Vec::new();
+        encode_addr(&v4, &mut buf);
+        assert_eq!(buf, [192, 168, 0, 10, 0xbf, 0xe3]);
+        // the partial address at the end is ignored
+        buf.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(decode_v4_addrs(&buf), vec![v4]);
+
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let mut buf = Vec::new();
+        encode_addr(&v6, &mut buf);
+        assert_eq!(buf.len(), COMPACT_V6_LEN);
+        assert_eq!(decode_v6_addrs(&buf), vec![v6]);
+    }
+}
@@ -1,8 +1,18 @@
 //! This module defines types used to configure the engine and its parts.
 
-use std::{path::PathBuf, time::Duration};
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use crate::PeerId;
+use crate::{
+    dht::DhtState,
+    error::{Error, Result},
+    peer::Connector,
+    PeerId,
+};
 
 /// The default cratetorrent client id.
 pub const CRATETORRENT_CLIENT_ID: &PeerId = b"cbt-0000000000000000";
@@ -24,6 +34,17 @@ impl Conf {
             engine: EngineConf {
                 client_id: *CRATETORRENT_CLIENT_ID,
                 download_dir: download_dir.into(),
+                // the port 0 tells the kernel to assign a free port from the
+                // dynamic range, and on dual-stack hosts the unspecified IPv6
+                // address accepts IPv4 connections too
+                listen_addr: SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+                utp: true,
+                connector: None,
+                dht: None,
+                // needs testing
+                scrape_interval: Some(Duration::from_secs(30 * 60)),
+                // this gives UDP trackers time for one retransmission
+                scrape_timeout: Duration::from_secs(30),
             },
             torrent: TorrentConf::default(),
         }
@@ -38,6 +59,100 @@ pub struct EngineConf {
     /// The directory in which a torrent's files are placed upon download and
     /// from which they are seeded.
     pub download_dir: PathBuf,
+    /// The address on which the engine listens for the connections of the
+    /// peers of all torrents. Incoming connections are routed to torrents by
+    /// the info hash in the peer's handshake.
+    ///
+    /// By default the engine listens on all IPv6 addresses, which includes
+    /// IPv4 on dual-stack hosts, and on a random port. A fixed port should be
+    /// set if it needs to be forwarded, and the actual address is returned by
+    /// [`EngineHandle::listen_addr`](crate::engine::EngineHandle::listen_addr).
+    pub listen_addr: SocketAddr,
+    /// Whether peer connections are also made and accepted over uTP, the
+    /// uTorrent transport protocol (BEP 29). uTP connections are accepted on
+    /// the UDP port of the same number as the listener's TCP port, which
+    /// therefore can't be the port of the DHT node.
+    ///
+    /// Since uTP backs off as soon as it detects queuing delay on the network,
+    /// it yields to the user's other traffic. Outbound connections are tried
+    /// over uTP first, and over TCP if the peer doesn't answer.
+    ///
+    /// uTP is enabled by default.
+    pub utp: bool,
+    /// If set, the engine makes all outbound peer connections with this
+    /// connector instead of over uTP and TCP, which allows peer sessions to
+    /// run over any transport, such as a proxy or a Unix socket. Connections
+    /// made over such a transport by other peers are handed to the engine with
+    /// [`EngineHandle::add_incoming_peer`](crate::engine::EngineHandle::add_incoming_peer).
+    ///
+    /// Not set by default.
+    pub connector: Option<Arc<dyn Connector>>,
+    /// If set, the engine runs a DHT node with this configuration, which
+    /// torrents use to find peers in addition to their trackers. Private
+    /// torrents never use the DHT.
+    ///
+    /// The DHT is disabled by default.
+    pub dht: Option<DhtConf>,
+    /// If set, the engine scrapes the trackers of all torrents at this
+    /// interval, which keeps the swarm sizes in the torrents'
+    /// [stats](crate::torrent::stats::TorrentStats::swarm) up to date between
+    /// announces.
+    ///
+    /// The torrents that share a tracker are scraped with a single request.
+    pub scrape_interval: Option<Duration>,
+    /// The time after which a scrape of a tracker is abandoned.
+    ///
+    /// The statistics are only informational and are refreshed with the next
+    /// scrape, so this is shorter than the announce timeout.
+    pub scrape_timeout: Duration,
+}
+
+impl EngineConf {
+    /// Returns an error if the configuration can't work.
+    pub(crate) fn validate(&self) -> Result<()> {
+        // uTP and the DHT each need their own UDP socket, and a port of 0 is
+        // assigned a different free port for each
+        if let Some(dht) = &self.dht {
+            let port = self.listen_addr.port();
+            if self.utp && port != 0 && dht.listen_addr.port() == port {
+                return Err(Error::InvalidConf(
+                    "uTP and the DHT can't listen on the same UDP port",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration of the engine's DHT node.
+#[derive(Clone, Debug)]
+pub struct DhtConf {
+    /// The UDP address on which the node listens.
+    ///
+    /// If uTP is enabled, the port must differ from the port of
+    /// [`EngineConf::listen_addr`].
+    pub listen_addr: SocketAddr,
+    /// The nodes via which the node joins the DHT, as `host:port` strings.
+    ///
+    /// These are only used while the node knows few other nodes.
+    pub bootstrap_nodes: Vec<String>,
+    /// The state of the node saved in a previous run, from which its id and
+    /// routing table are restored.
+    pub state: Option<DhtState>,
+}
+
+impl Default for DhtConf {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 6881),
+            bootstrap_nodes: vec![
+                "router.bittorrent.com:6881".into(),
+                "router.utorrent.com:6881".into(),
+                "dht.transmissionbt.com:6881".into(),
+            ],
+            state: None,
+        }
+    }
 }
 
 /// Configuration for a torrent.
@@ -57,14 +172,82 @@ pub struct TorrentConf {
     /// to announcing every 30 seconds.
     pub announce_interval: Duration,
 
-    /// After this many attempts, the torrent stops announcing to a tracker.
-    pub tracker_error_threshold: usize,
+    /// The time after which an announce to a tracker is abandoned, which then
+    /// counts as a failed attempt.
+    pub announce_timeout: Duration,
+
+    /// The time after which the announce telling a tracker that the torrent
+    /// stopped is abandoned.
+    ///
+    /// This is shorter than [`Self::announce_timeout`], as the torrent waits
+    /// for these announces when it's shut down.
+    pub stop_announce_timeout: Duration,
+
+    /// After a failed announce, the torrent waits this long before retrying
+    /// the tracker. The delay is doubled with each failure in a row, up to
+    /// [`Self::max_tracker_retry_interval`], and it's reset once the tracker
+    /// responds.
+    pub tracker_retry_interval: Duration,
+
+    /// The longest the torrent waits before retrying a failing tracker.
+    pub max_tracker_retry_interval: Duration,
+
+    /// Whether to announce to all trackers in all tiers.
+    ///
+    /// By default, trackers are used as described in BEP 12: the torrent only
+    /// announces to the first tracker that responds, trying the trackers of
+    /// a tier in order, and moving on to the next tier only if all trackers
+    /// in the tier failed. Some private trackers, however, require that the
+    /// torrent is announced to all of them.
+    pub announce_to_all_trackers: bool,
+
+    /// The number of peers we upload to at a time, besides the one that is
+    /// unchoked optimistically.
+    ///
+    /// These slots go to the peers that upload the fastest to us while
+    /// downloading, and to the peers we can upload the fastest to while
+    /// seeding.
+    pub upload_slot_count: usize,
+
+    /// Whether to download the pieces in order, rather than rarest first,
+    /// which is useful when the torrent's files are played while they are
+    /// downloaded.
+    ///
+    /// This should be used sparingly, as it makes the download slower for the
+    /// whole swarm, since the pieces we have are the same ones other peers in
+    /// sequential mode have. Pieces with a higher priority are still
+    /// downloaded first.
+    pub sequential_download: bool,
+
+    /// Whether the torrent's peer connections are encrypted, see
+    /// [`EncryptionPolicy`].
+    pub encryption: EncryptionPolicy,
 
     /// Specifies which optional alerts to send, besides the default periodic
     /// stats update.
     pub alerts: TorrentAlertConf,
 }
 
+/// Whether peer connections are encrypted with message stream encryption
+/// (MSE), which hides BitTorrent traffic from ISPs that throttle it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EncryptionPolicy {
+    /// Only plaintext connections are made and accepted.
+    Disabled,
+    /// Outgoing connections are encrypted, but if the peer doesn't support
+    /// encryption, the connection is retried in plaintext. Both encrypted and
+    /// plaintext connections are accepted.
+    Enabled,
+    /// Only fully encrypted connections are made and accepted.
+    Forced,
+}
+
+impl Default for EncryptionPolicy {
+    fn default() -> Self {
+        Self::Enabled
+    }
+}
+
 /// Configuration of a torrent's optional alerts.
 ///
 /// By default, all optional alerts are turned off. This is because some of
@@ -98,8 +281,18 @@ impl Default for TorrentConf {
             max_connected_peer_count: 50,
             // needs teting
             announce_interval: Duration::from_secs(60 * 60),
+            // this gives UDP trackers time for two retransmissions
+            announce_timeout: Duration::from_secs(60),
+            // the torrent is shutting down, so don't hold it up for long
+            stop_announce_timeout: Duration::from_secs(5),
+            // needs testing
+            tracker_retry_interval: Duration::from_secs(60),
+            max_tracker_retry_interval: Duration::from_secs(60 * 60),
+            announce_to_all_trackers: false,
             // needs testing
-            tracker_error_threshold: 15,
+            upload_slot_count: 4,
+            sequential_download: false,
+            encryption: EncryptionPolicy::default(),
             alerts: Default::default(),
         }
     }
@@ -90,6 +90,16 @@ impl Counter {
     // TODO: turn this into a const generic parameter once that's supported
     const WEIGHT: u64 = 5;
 
+    /// Creates a counter that starts from the given total, without it counting
+    /// towards the current round (e.g. when restoring the totals of a previous
+    /// run).
+    pub fn with_total(total: u64) -> Self {
+        Self {
+            total,
+            ..Default::default()
+        }
+    }
+
     /// Records some bytes that were transferred.
     pub fn add(&mut self, bytes: u64) {
         self.total += bytes;
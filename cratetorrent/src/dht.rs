@@ -0,0 +1,1137 @@
+//! This module contains the engine's node in the mainline DHT (BEP 5), which
+//! is used to find the peers of torrents without trackers.
+//!
+//! The DHT is a Kademlia based distributed hash table in which each node has
+//! a random 160 bit id, and the peers of a torrent are stored on the nodes
+//! whose ids are closest to the torrent's info hash, where the distance is the
+//! XOR of the two. Each node keeps a routing table of other nodes, with more
+//! nodes close to itself than far away, so that any id can be found in
+//! a logarithmic number of steps.
+//!
+//! There is a single DHT node per engine, which runs on its own task and
+//! communicates over UDP. Torrents ask it for peers, upon which it performs an
+//! iterative lookup of the nodes closest to the info hash, returning the peers
+//! they know of and then announcing the torrent's port to them. The node also
+//! answers the queries of other nodes and stores the peers announced to it.
+//!
+//! The state of the node (its id and the nodes in its routing table) may be
+//! saved via
+//! [`EngineHandle::save_dht_state`](crate::engine::EngineHandle::save_dht_state)
+//! and restored in the next run via [`DhtConf::state`], so that the node
+//! doesn't have to join the DHT from scratch.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket as StdUdpSocket},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future, select,
+    stream::{self, Fuse, StreamExt},
+};
+use serde::de::Error as _;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task, time,
+};
+
+use crate::{
+    alert::{Alert, AlertSender},
+    compact::{decode_v4_addrs, encode_addr},
+    conf::DhtConf,
+    error::Result,
+    metainfo::BencodeError,
+    Sha1Hash,
+};
+use msg::{Body, Msg, NodeInfo, Query, Response};
+use routing::{RoutingTable, K};
+
+mod msg;
+mod routing;
+
+/// The id of a DHT node, which is in the same space as info hashes.
+pub type NodeId = Sha1Hash;
+
+/// Torrents short of peers don't query the DHT more often than this.
+pub(crate) const MIN_QUERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Torrents announce themselves to the DHT this often, which also gets them
+/// new peers.
+pub(crate) const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The number of queries a lookup has in flight at the same time.
+const ALPHA: usize = 3;
+
+/// The maximum number of nodes a lookup keeps track of.
+const MAX_LOOKUP_NODE_COUNT: usize = 4 * K;
+
+/// If a node doesn't respond to our query within this time, the query is
+/// considered failed.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The secret with which tokens are generated is changed this often, and
+/// tokens generated with the previous secret are still accepted.
+const TOKEN_ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Announced peers are forgotten after this long, unless they announce again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// The maximum number of peers stored per torrent.
+const MAX_STORED_PEER_COUNT: usize = 1000;
+
+/// The maximum number of torrents whose peers are stored, so that other nodes
+/// can't make us store an unbounded number of them.
+const MAX_STORED_INFO_HASH_COUNT: usize = 10_000;
+
+/// The maximum number of peers returned in a response, so that it fits in
+/// a single UDP packet.
+const MAX_RETURNED_PEER_COUNT: usize = 50;
+
+/// While the routing table has fewer than [`K`] nodes, we try to join the DHT
+/// via the bootstrap nodes this often.
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The routing table is refreshed by looking up our own id this often.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The size of the receive buffer, which is larger than any valid message.
+const MAX_MSG_LEN: usize = 4096;
+
+/// Creates the DHT node with the given configuration and spawns it on a new
+/// task, returning the task's join handle and the node's handle.
+///
+/// The socket is bound before the task is spawned, so that the port of the
+/// node is known right away.
+pub(crate) fn spawn(
+    conf: DhtConf,
+    alert_tx: AlertSender,
+) -> Result<(JoinHandle, DhtHandle)> {
+    log::info!("Spawning DHT task");
+    let socket = StdUdpSocket::bind(conf.listen_addr)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    let port = socket.local_addr()?.port();
+    let (mut dht, tx) = Dht::new(socket, conf, alert_tx);
+    let join_handle = task::spawn(async move { dht.run().await });
+    log::info!("Spawned DHT task on port {}", port);
+
+    Ok((join_handle, DhtHandle { tx, port }))
+}
+
+pub(crate) type JoinHandle = task::JoinHandle<Result<()>>;
+
+/// The channel for sending commands to the DHT task.
+pub(crate) type Sender = UnboundedSender<Command>;
+/// The channel the DHT task uses to listen for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The channel on which the DHT returns the peers of a torrent.
+pub(crate) type PeersSender = UnboundedSender<Vec<SocketAddr>>;
+
+/// A handle to the running DHT node, shared by the torrents in the engine.
+#[derive(Clone, Debug)]
+pub(crate) struct DhtHandle {
+    pub tx: Sender,
+    /// The UDP port on which the node listens, which is advertised to peers.
+    pub port: u16,
+}
+
+/// The commands the DHT task can receive.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Look up the peers of the torrent and send them on the given channel as
+    /// they are found. If a port is given, the torrent is then announced to
+    /// the nodes closest to it as being downloaded on that port.
+    GetPeers {
+        info_hash: Sha1Hash,
+        announce_port: Option<u16>,
+        peers_tx: PeersSender,
+    },
+    /// Ping the node at the address, which is added to the routing table if it
+    /// responds. Sent when a peer tells us the port of its DHT node.
+    AddNode(SocketAddr),
+    /// Post the state of the node to the user.
+    SaveState,
+    /// Eventually shut down the DHT task.
+    Shutdown,
+}
+
+/// The state of a DHT node, with which the node can be restarted without
+/// having to join the DHT from scratch.
+///
+/// It is posted in an [`Alert::DhtState`] in response to
+/// [`EngineHandle::save_dht_state`](crate::engine::EngineHandle::save_dht_state)
+/// and may be passed to the engine in [`DhtConf::state`] on the next start.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DhtState {
+    /// The id of the node.
+    pub id: NodeId,
+    /// The addresses of the nodes in the routing table.
+    pub nodes: Vec<SocketAddr>,
+}
+
+/// The bencoded form of the DHT state, in which the nodes are in the compact
+/// format.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawDhtState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+impl DhtState {
+    /// Parses the state from its bencoded form.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, BencodeError> {
+        let raw: RawDhtState = serde_bencode::from_bytes(buf)?;
+        let mut id = [0; 20];
+        if raw.id.len() != id.len() {
+            return Err(BencodeError::invalid_length(
+                raw.id.len(),
+                &"a 20 byte node id",
+            ));
+        }
+        id.copy_from_slice(&raw.id);
+        Ok(Self {
+            id,
+            nodes: decode_v4_addrs(&raw.nodes),
+        })
+    }
+
+    /// Encodes the state so that it can be persisted by the application.
+    ///
+    /// Only IPv4 nodes are included.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BencodeError> {
+        let mut nodes = Vec::new();
+        for addr in self.nodes.iter().filter(|addr| addr.is_ipv4()) {
+            encode_addr(addr, &mut nodes);
+        }
+        serde_bencode::to_bytes(&RawDhtState {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: ByteBuf::from(nodes),
+        })
+    }
+}
+
+/// Returns the XOR distance of the two ids, which may be compared as a big
+/// endian number.
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut dist = [0; 20];
+    for (d, (a, b)) in dist.iter_mut().zip(a.iter().zip(b.iter())) {
+        *d = a ^ b;
+    }
+    dist
+}
+
+/// Our node in the DHT.
+struct Dht {
+    id: NodeId,
+    /// The socket is shared with the stream of incoming messages.
+    socket: Arc<UdpSocket>,
+    routing: RoutingTable,
+    /// Our queries that are waiting for a response, by transaction id.
+    transactions: HashMap<u16, Transaction>,
+    next_tid: u16,
+    /// The lookups in progress.
+    lookups: HashMap<usize, Lookup>,
+    next_lookup_id: usize,
+    /// The peers announced to us, by info hash, along with the time of their
+    /// last announce.
+    peers: HashMap<Sha1Hash, Vec<(SocketAddr, Instant)>>,
+    /// The current and the previous secret with which tokens are generated.
+    token_secrets: [[u8; 8]; 2],
+    last_token_rotation_time: Instant,
+    /// The host names of the nodes via which we join the DHT, resolved when
+    /// the task starts.
+    bootstrap_hosts: Vec<String>,
+    /// The resolved bootstrap nodes and the nodes of the restored routing
+    /// table.
+    bootstrap_addrs: Vec<SocketAddr>,
+    last_bootstrap_time: Option<Instant>,
+    last_refresh_time: Instant,
+    cmd_rx: Fuse<Receiver>,
+    alert_tx: AlertSender,
+}
+
+/// A query we sent and are waiting for a response to.
+struct Transaction {
+    addr: SocketAddr,
+    sent_time: Instant,
+    kind: TransactionKind,
+}
+
+/// What a query was sent for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TransactionKind {
+    Ping,
+    /// The query is a step of the lookup with the given id.
+    Lookup(usize),
+    Announce,
+}
+
+impl Dht {
+    fn new(
+        socket: UdpSocket,
+        conf: DhtConf,
+        alert_tx: AlertSender,
+    ) -> (Self, Sender) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (id, bootstrap_addrs) = match conf.state {
+            Some(state) => (state.id, state.nodes),
+            None => (rand::random(), Vec::new()),
+        };
+        let now = Instant::now();
+        (
+            Self {
+                id,
+                socket: Arc::new(socket),
+                routing: RoutingTable::new(id),
+                transactions: HashMap::new(),
+                next_tid: 0,
+                lookups: HashMap::new(),
+                next_lookup_id: 0,
+                peers: HashMap::new(),
+                token_secrets: [rand::random(), rand::random()],
+                last_token_rotation_time: now,
+                bootstrap_hosts: conf.bootstrap_nodes,
+                bootstrap_addrs,
+                last_bootstrap_time: None,
+                last_refresh_time: now,
+                cmd_rx: cmd_rx.fuse(),
+                alert_tx,
+            },
+            cmd_tx,
+        )
+    }
+
+    /// Runs the node until it's shut down.
+    async fn run(&mut self) -> Result<()> {
+        log::info!("Starting DHT node {}", hex::encode(self.id));
+
+        let hosts = std::mem::take(&mut self.bootstrap_hosts);
+        self.bootstrap_addrs.extend(resolve_hosts(hosts).await);
+        self.bootstrap(Instant::now());
+
+        let socket = Arc::clone(&self.socket);
+        let mut incoming = stream::unfold(socket, |socket| async move {
+            let mut buf = [0; MAX_MSG_LEN];
+            let result =
+                future::poll_fn(|cx| socket.poll_recv_from(cx, &mut buf))
+                    .await
+                    .map(|(len, addr)| (buf[..len].to_vec(), addr));
+            Some((result, socket))
+        })
+        .boxed()
+        .fuse();
+        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+
+        loop {
+            select! {
+                now = tick_timer.select_next_some() => {
+                    self.tick(now.into_std());
+                }
+                result = incoming.select_next_some() => match result {
+                    Ok((buf, addr)) => {
+                        self.handle_msg(&buf, addr, Instant::now());
+                    }
+                    // e.g. an ICMP port unreachable error of a previous send
+                    Err(e) => log::debug!("DHT socket error: {}", e),
+                },
+                cmd = self.cmd_rx.select_next_some() => match cmd {
+                    Command::GetPeers { info_hash, announce_port, peers_tx } => {
+                        log::debug!(
+                            "Looking up peers of {} in DHT",
+                            hex::encode(info_hash)
+                        );
+                        let kind = LookupKind::GetPeers {
+                            announce_port,
+                            peers_tx,
+                        };
+                        self.start_lookup(info_hash, kind, Instant::now());
+                    }
+                    Command::AddNode(addr) => {
+                        self.send_query(
+                            addr,
+                            Query::Ping,
+                            TransactionKind::Ping,
+                            Instant::now(),
+                        );
+                    }
+                    Command::SaveState => {
+                        let state = DhtState {
+                            id: self.id,
+                            nodes: self
+                                .routing
+                                .good_nodes()
+                                .map(|node| node.addr)
+                                .collect(),
+                        };
+                        self.alert_tx.send(Alert::DhtState(Box::new(state)))?;
+                    }
+                    Command::Shutdown => {
+                        log::info!("Shutting down DHT node");
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Times out unanswered queries and performs periodic maintenance.
+    fn tick(&mut self, now: Instant) {
+        let expired_tids: Vec<_> = self
+            .transactions
+            .iter()
+            .filter(|(_, t)| {
+                now.saturating_duration_since(t.sent_time) >= QUERY_TIMEOUT
+            })
+            .map(|(tid, _)| *tid)
+            .collect();
+        for tid in expired_tids {
+            if let Some(t) = self.transactions.remove(&tid) {
+                log::trace!("DHT query to {} timed out", t.addr);
+                self.routing.mark_failed(t.addr);
+                if let TransactionKind::Lookup(lookup_id) = t.kind {
+                    if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                        lookup.handle_failure(t.addr);
+                    }
+                    self.step_lookup(lookup_id, now);
+                }
+            }
+        }
+
+        if now.saturating_duration_since(self.last_token_rotation_time)
+            >= TOKEN_ROTATION_INTERVAL
+        {
+            self.last_token_rotation_time = now;
+            self.token_secrets = [rand::random(), self.token_secrets[0]];
+            // also a good time to drop the peers that haven't announced in
+            // a while
+            for peers in self.peers.values_mut() {
+                peers.retain(|(_, time)| {
+                    now.saturating_duration_since(*time) < PEER_TTL
+                });
+            }
+            self.peers.retain(|_, peers| !peers.is_empty());
+        }
+
+        if self.routing.len() < K {
+            let should_bootstrap = match self.last_bootstrap_time {
+                Some(t) => {
+                    now.saturating_duration_since(t) >= BOOTSTRAP_INTERVAL
+                }
+                None => true,
+            };
+            if should_bootstrap {
+                self.bootstrap(now);
+            }
+        } else if now.saturating_duration_since(self.last_refresh_time)
+            >= REFRESH_INTERVAL
+        {
+            log::debug!("Refreshing DHT routing table");
+            self.last_refresh_time = now;
+            self.start_lookup(self.id, LookupKind::FindNode, now);
+        }
+    }
+
+    /// Joins the DHT by looking up our own id, starting from the bootstrap
+    /// nodes, which fills our routing table with our neighbours.
+    fn bootstrap(&mut self, now: Instant) {
+        log::debug!(
+            "Bootstrapping DHT from {} node(s)",
+            self.bootstrap_addrs.len()
+        );
+        self.last_bootstrap_time = Some(now);
+        self.start_lookup(self.id, LookupKind::FindNode, now);
+    }
+
+    /// Starts a lookup of the target from the closest nodes in our routing
+    /// table.
+    ///
+    /// If we know few nodes, e.g. because we're still joining the DHT, the
+    /// bootstrap nodes are queried as well. Their ids are not known, so
+    /// they're not candidates of the lookup, but the nodes they return are.
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind, now: Instant) {
+        let mut lookup = Lookup::new(target, kind);
+        lookup.add_nodes(self.routing.closest(&target, K));
+        let query = lookup.query();
+        let lookup_id = self.next_lookup_id;
+        self.next_lookup_id = self.next_lookup_id.wrapping_add(1);
+
+        if self.routing.len() < K {
+            for addr in self.bootstrap_addrs.clone() {
+                lookup.pending_count += 1;
+                self.send_query(
+                    addr,
+                    query.clone(),
+                    TransactionKind::Lookup(lookup_id),
+                    now,
+                );
+            }
+        }
+        self.lookups.insert(lookup_id, lookup);
+        self.step_lookup(lookup_id, now);
+    }
+
+    /// Sends the next queries of the lookup, or if it's complete, removes it
+    /// and announces the torrent, if requested.
+    fn step_lookup(&mut self, lookup_id: usize, now: Instant) {
+        let (query, nodes) = match self.lookups.get_mut(&lookup_id) {
+            Some(lookup) => (lookup.query(), lookup.next_queries()),
+            None => return,
+        };
+        for node in nodes {
+            self.send_query(
+                node.addr,
+                query.clone(),
+                TransactionKind::Lookup(lookup_id),
+                now,
+            );
+        }
+
+        if !self.lookups[&lookup_id].is_done() {
+            return;
+        }
+        let lookup = match self.lookups.remove(&lookup_id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        log::debug!(
+            "DHT lookup of {} complete, found {} peer(s)",
+            hex::encode(lookup.target),
+            lookup.found_peers.len()
+        );
+        if let LookupKind::GetPeers {
+            announce_port: Some(port),
+            ..
+        } = lookup.kind
+        {
+            for (node, token) in lookup.announce_targets() {
+                self.send_query(
+                    node.addr,
+                    Query::AnnouncePeer {
+                        info_hash: lookup.target,
+                        port,
+                        token,
+                        implied_port: false,
+                    },
+                    TransactionKind::Announce,
+                    now,
+                );
+            }
+        }
+    }
+
+    /// Handles a message from another node.
+    fn handle_msg(&mut self, buf: &[u8], addr: SocketAddr, now: Instant) {
+        let msg = match Msg::from_bytes(buf) {
+            Some(msg) => msg,
+            None => {
+                log::trace!("Invalid DHT message from {}", addr);
+                return;
+            }
+        };
+        match msg.body {
+            Body::Query { id, query } => {
+                self.handle_query(msg.tid, id, query, addr, now);
+            }
+            Body::Response(resp) => {
+                if let Some(t) = self.take_transaction(&msg.tid, addr) {
+                    self.routing.insert(resp.id, addr, now);
+                    if let TransactionKind::Lookup(lookup_id) = t.kind {
+                        self.handle_lookup_response(lookup_id, addr, resp);
+                        self.step_lookup(lookup_id, now);
+                    }
+                }
+            }
+            Body::Error { code, message } => {
+                if let Some(t) = self.take_transaction(&msg.tid, addr) {
+                    log::debug!(
+                        "DHT node {} sent error {}: {}",
+                        addr,
+                        code,
+                        message
+                    );
+                    if let TransactionKind::Lookup(lookup_id) = t.kind {
+                        if let Some(lookup) = self.lookups.get_mut(&lookup_id) {
+                            lookup.handle_failure(addr);
+                        }
+                        self.step_lookup(lookup_id, now);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers a query of another node, which is also added to our routing
+    /// table.
+    fn handle_query(
+        &mut self,
+        tid: Vec<u8>,
+        id: NodeId,
+        query: Query,
+        addr: SocketAddr,
+        now: Instant,
+    ) {
+        log::trace!("DHT node {} sent query {:?}", addr, query);
+        self.routing.insert(id, addr, now);
+
+        let mut resp = Response {
+            id: self.id,
+            ..Default::default()
+        };
+        match query {
+            Query::Ping => {}
+            Query::FindNode { target } => {
+                resp.nodes = self.routing.closest(&target, K);
+            }
+            Query::GetPeers { info_hash } => {
+                resp.token = Some(self.token(addr, 0));
+                match self.peers.get(&info_hash) {
+                    Some(peers) => {
+                        resp.values = peers
+                            .iter()
+                            .rev()
+                            .take(MAX_RETURNED_PEER_COUNT)
+                            .map(|(addr, _)| *addr)
+                            .collect();
+                    }
+                    None => resp.nodes = self.routing.closest(&info_hash, K),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                token,
+                implied_port,
+            } => {
+                if token != self.token(addr, 0) && token != self.token(addr, 1)
+                {
+                    log::debug!("DHT node {} sent invalid token", addr);
+                    self.send(
+                        addr,
+                        &Msg {
+                            tid,
+                            body: Body::Error {
+                                code: 203,
+                                message: "Invalid token".into(),
+                            },
+                        },
+                    );
+                    return;
+                }
+                let port = if implied_port { addr.port() } else { port };
+                let peer_addr = SocketAddr::new(addr.ip(), port);
+                self.store_peer(info_hash, peer_addr, now);
+            }
+        }
+        self.send(
+            addr,
+            &Msg {
+                tid,
+                body: Body::Response(resp),
+            },
+        );
+    }
+
+    /// Stores the peer announced to us, or updates its announce time if we
+    /// already know it.
+    ///
+    /// Once we store the peers of too many torrents, the peers of new
+    /// torrents are rejected until the stored peers expire.
+    fn store_peer(
+        &mut self,
+        info_hash: Sha1Hash,
+        peer_addr: SocketAddr,
+        now: Instant,
+    ) {
+        if !self.peers.contains_key(&info_hash)
+            && self.peers.len() >= MAX_STORED_INFO_HASH_COUNT
+        {
+            log::debug!(
+                "Not storing DHT peer {} of {}, too many torrents",
+                peer_addr,
+                hex::encode(info_hash)
+            );
+            return;
+        }
+        let peers = self.peers.entry(info_hash).or_default();
+        if let Some(peer) =
+            peers.iter_mut().find(|(addr, _)| *addr == peer_addr)
+        {
+            peer.1 = now;
+        } else if peers.len() < MAX_STORED_PEER_COUNT {
+            peers.push((peer_addr, now));
+        }
+    }
+
+    /// Updates the lookup with the nodes and peers in the response.
+    fn handle_lookup_response(
+        &mut self,
+        lookup_id: usize,
+        addr: SocketAddr,
+        resp: Response,
+    ) {
+        let lookup = match self.lookups.get_mut(&lookup_id) {
+            Some(lookup) => lookup,
+            None => return,
+        };
+        lookup.handle_response(addr, resp.token);
+        // our own node may be among the closest nodes of others
+        let own_id = self.id;
+        lookup.add_nodes(resp.nodes.into_iter().filter(|n| n.id != own_id));
+
+        let Lookup {
+            kind, found_peers, ..
+        } = lookup;
+        if let LookupKind::GetPeers { peers_tx, .. } = kind {
+            let new_peers: Vec<_> = resp
+                .values
+                .into_iter()
+                .filter(|addr| found_peers.insert(*addr))
+                .collect();
+            if !new_peers.is_empty() {
+                // the torrent may have been removed in the meantime
+                peers_tx.send(new_peers).ok();
+            }
+        }
+    }
+
+    /// Returns the transaction of the response or error, if it's a response
+    /// to a query we sent to its sender.
+    fn take_transaction(
+        &mut self,
+        tid: &[u8],
+        addr: SocketAddr,
+    ) -> Option<Transaction> {
+        if tid.len() != 2 {
+            return None;
+        }
+        let tid = u16::from_be_bytes([tid[0], tid[1]]);
+        match self.transactions.get(&tid) {
+            Some(t) if t.addr == addr => self.transactions.remove(&tid),
+            _ => {
+                log::trace!("Unexpected DHT response from {}", addr);
+                None
+            }
+        }
+    }
+
+    /// Sends the query to the node and records it as a transaction.
+    fn send_query(
+        &mut self,
+        addr: SocketAddr,
+        query: Query,
+        kind: TransactionKind,
+        now: Instant,
+    ) {
+        // skip over the ids of old queries that are still pending
+        while self.transactions.contains_key(&self.next_tid) {
+            self.next_tid = self.next_tid.wrapping_add(1);
+        }
+        let tid = self.next_tid;
+        self.next_tid = self.next_tid.wrapping_add(1);
+
+        self.send(
+            addr,
+            &Msg {
+                tid: tid.to_be_bytes().to_vec(),
+                body: Body::Query { id: self.id, query },
+            },
+        );
+        self.transactions.insert(
+            tid,
+            Transaction {
+                addr,
+                sent_time: now,
+                kind,
+            },
+        );
+    }
+
+    /// Sends the message to the address.
+    ///
+    /// If the socket's send buffer is full the message is dropped, which is
+    /// no different from it being lost on the way.
+    fn send(&self, addr: SocketAddr, msg: &Msg) {
+        if let Err(e) = self.socket.try_send_to(&msg.to_bytes(), addr) {
+            log::debug!("Error sending DHT message to {}: {}", addr, e);
+        }
+    }
+
+    /// Returns the token for the address, generated with the current secret if
+    /// `secret_index` is 0, or the previous one if it's 1.
+    ///
+    /// The token is only valid for the IP address it was given to, so that
+    /// a node can't announce other hosts as peers.
+    fn token(&self, addr: SocketAddr, secret_index: usize) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(self.token_secrets[secret_index]);
+        match addr.ip() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize().to_vec()
+    }
+}
+
+/// Resolves the host names of the bootstrap nodes, skipping the ones that
+/// can't be resolved.
+///
+/// Only IPv4 addresses are kept, as the node only supports IPv4.
+async fn resolve_hosts(hosts: Vec<String>) -> Vec<SocketAddr> {
+    let resolve = move || -> Vec<SocketAddr> {
+        hosts
+            .iter()
+            .filter_map(|host| match host.to_socket_addrs() {
+                Ok(addrs) => Some(addrs),
+                Err(e) => {
+                    log::warn!("Cannot resolve DHT node {}: {}", host, e);
+                    None
+                }
+            })
+            .flatten()
+            .filter(SocketAddr::is_ipv4)
+            .collect()
+    };
+    task::spawn_blocking(resolve).await.unwrap_or_else(|e| {
+        log::warn!("Error resolving DHT nodes: {}", e);
+        Vec::new()
+    })
+}
+
+/// An iterative lookup of the nodes closest to a target id.
+///
+/// The lookup queries the [`ALPHA`] closest nodes it knows of that it hasn't
+/// queried yet, adding the nodes they return to its candidates, until the
+/// [`K`] closest nodes have all responded or failed.
+struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    /// The candidate nodes, ordered by their distance to the target.
+    nodes: Vec<LookupNode>,
+    /// The number of queries in flight.
+    pending_count: usize,
+    /// The peers already sent to the torrent.
+    found_peers: HashSet<SocketAddr>,
+}
+
+/// What the lookup is for.
+enum LookupKind {
+    /// Finding the closest nodes, which fills the routing table.
+    FindNode,
+    /// Finding the peers of a torrent.
+    GetPeers {
+        announce_port: Option<u16>,
+        peers_tx: PeersSender,
+    },
+}
+
+/// A candidate node of a lookup.
+struct LookupNode {
+    info: NodeInfo,
+    state: LookupNodeState,
+    /// The token the node sent in its response to `get_peers`, needed to
+    /// announce to it.
+    token: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LookupNodeState {
+    New,
+    Pending,
+    Responded,
+    Failed,
+}
+
+impl Lookup {
+    fn new(target: NodeId, kind: LookupKind) -> Self {
+        Self {
+            target,
+            kind,
+            nodes: Vec::new(),
+            pending_count: 0,
+            found_peers: HashSet::new(),
+        }
+    }
+
+    /// Returns the query sent to the candidates.
+    fn query(&self) -> Query {
+        match self.kind {
+            LookupKind::FindNode => Query::FindNode {
+                target: self.target,
+            },
+            LookupKind::GetPeers { .. } => Query::GetPeers {
+                info_hash: self.target,
+            },
+        }
+    }
+
+    /// Adds the nodes that are not yet candidates, keeping only the closest
+    /// ones.
+    fn add_nodes(&mut self, nodes: impl IntoIterator<Item = NodeInfo>) {
+        for info in nodes {
+            if self
+                .nodes
+                .iter()
+                .any(|n| n.info.id == info.id || n.info.addr == info.addr)
+            {
+                continue;
+            }
+            let dist = distance(&info.id, &self.target);
+            let pos = self
+                .nodes
+                .iter()
+                .position(|n| distance(&n.info.id, &self.target) > dist)
+                .unwrap_or(self.nodes.len());
+            self.nodes.insert(
+                pos,
+                LookupNode {
+                    info,
+                    state: LookupNodeState::New,
+                    token: None,
+                },
+            );
+        }
+        self.nodes.truncate(MAX_LOOKUP_NODE_COUNT);
+    }
+
+    /// Returns the closest nodes not yet queried that should be queried now,
+    /// marking them as pending.
+    fn next_queries(&mut self) -> Vec<NodeInfo> {
+        let mut queries = Vec::new();
+        for node in self
+            .nodes
+            .iter_mut()
+            .filter(|n| n.state != LookupNodeState::Failed)
+            .take(K)
+        {
+            if self.pending_count >= ALPHA {
+                break;
+            }
+            if node.state == LookupNodeState::New {
+                node.state = LookupNodeState::Pending;
+                self.pending_count += 1;
+                queries.push(node.info);
+            }
+        }
+        queries
+    }
+
+    /// Returns whether no queries are in flight and all of the closest nodes
+    /// have been queried.
+    fn is_done(&self) -> bool {
+        self.pending_count == 0
+            && !self
+                .nodes
+                .iter()
+                .filter(|n| n.state != LookupNodeState::Failed)
+                .take(K)
+                .any(|n| n.state == LookupNodeState::New)
+    }
+
+    /// Records the response of the node at the address.
+    ///
+    /// The address may not be a candidate, e.g. if it's a bootstrap node.
+    fn handle_response(&mut self, addr: SocketAddr, token: Option<Vec<u8>>) {
+        self.pending_count = self.pending_count.saturating_sub(1);
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.info.addr == addr)
+        {
+            node.state = LookupNodeState::Responded;
+            node.token = token;
+        }
+    }
+
+    /// Records that the node at the address failed to respond.
+    fn handle_failure(&mut self, addr: SocketAddr) {
+        self.pending_count = self.pending_count.saturating_sub(1);
+        if let Some(node) = self.nodes.iter_mut().find(|n| n.info.addr == addr)
+        {
+            node.state = LookupNodeState::Failed;
+        }
+    }
+
+    /// Returns the closest nodes that responded with a token, to which the
+    /// torrent is announced.
+    fn announce_targets(&self) -> Vec<(NodeInfo, Vec<u8>)> {
+        self.nodes
+            .iter()
+            .filter(|n| n.state == LookupNodeState::Responded)
+            .filter_map(|n| Some((n.info, n.token.clone()?)))
+            .take(K)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::DhtConf;
+
+    /// Spawns a DHT node on loopback that bootstraps from the given nodes.
+    fn spawn_node(bootstrap_nodes: Vec<String>) -> (JoinHandle, DhtHandle) {
+        let (alert_tx, _) = mpsc::unbounded_channel();
+        spawn(
+            DhtConf {
+                listen_addr: "127.0.0.1:0".parse().unwrap(),
+                bootstrap_nodes,
+                state: None,
+            },
+            alert_tx,
+        )
+        .unwrap()
+    }
+
+    /// Tests that the state of the node is encoded and decoded.
+    #[test]
+    fn should_encode_and_decode_dht_state() {
+        let state = DhtState {
+            id: [7; 20],
+            nodes: vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap(),
+            ],
+        };
+        let encoded = state.to_bytes().unwrap();
+        assert_eq!(DhtState::from_bytes(&encoded).unwrap(), state);
+        assert!(DhtState::from_bytes(b"d2:id3:abc5:nodes0:e").is_err());
+    }
+
+    /// Tests that the lookup queries at most `ALPHA` of the closest nodes at
+    /// a time and is done once the closest nodes have all responded.
+    #[test]
+    fn should_query_closest_nodes_in_lookup() {
+        let mut lookup = Lookup::new([0; 20], LookupKind::FindNode);
+        let nodes: Vec<_> = (1..=K as u8 + 2)
+            .map(|i| NodeInfo {
+                id: [i; 20],
+                addr: SocketAddr::new([127, 0, 0, 1].into(), i as u16),
+            })
+            .collect();
+        lookup.add_nodes(nodes.iter().rev().copied());
+        assert_eq!(lookup.nodes[0].info, nodes[0]);
+
+        let queries = lookup.next_queries();
+        assert_eq!(queries, nodes[..ALPHA].to_vec());
+        assert!(lookup.next_queries().is_empty());
+        assert!(!lookup.is_done());
+
+        // a failed node is replaced by the next closest one, beyond the
+        // initial K
+        lookup.handle_failure(nodes[0].addr);
+        for node in nodes[1..ALPHA].iter() {
+            lookup.handle_response(node.addr, Some(vec![1]));
+        }
+        let mut queried = ALPHA;
+        loop {
+            let queries = lookup.next_queries();
+            if queries.is_empty() {
+                break;
+            }
+            for node in queries {
+                queried += 1;
+                lookup.handle_response(node.addr, None);
+            }
+        }
+        assert!(lookup.is_done());
+        assert_eq!(queried, K + 1);
+        // only the nodes that sent a token are announced to
+        assert_eq!(lookup.announce_targets().len(), ALPHA - 1);
+    }
+
+    /// Tests that the peers of new torrents are not stored once the peers of
+    /// too many torrents are, while the known torrents still get new peers.
+    #[tokio::test]
+    async fn should_limit_stored_info_hash_count() {
+        let listen_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let socket = UdpSocket::bind(listen_addr).await.unwrap();
+        let (alert_tx, _) = mpsc::unbounded_channel();
+        let (mut dht, _) = Dht::new(
+            socket,
+            DhtConf {
+                listen_addr,
+                bootstrap_nodes: Vec::new(),
+                state: None,
+            },
+            alert_tx,
+        );
+        let now = Instant::now();
+        let peer_addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let info_hash = |i: usize| {
+            let mut info_hash = [0; 20];
+            info_hash[..8].copy_from_slice(&i.to_be_bytes());
+            info_hash
+        };
+        for i in 0..MAX_STORED_INFO_HASH_COUNT {
+            dht.store_peer(info_hash(i), peer_addr, now);
+        }
+        assert_eq!(dht.peers.len(), MAX_STORED_INFO_HASH_COUNT);
+
+        dht.store_peer(info_hash(MAX_STORED_INFO_HASH_COUNT), peer_addr, now);
+        assert_eq!(dht.peers.len(), MAX_STORED_INFO_HASH_COUNT);
+        assert!(!dht
+            .peers
+            .contains_key(&info_hash(MAX_STORED_INFO_HASH_COUNT)));
+
+        let other_peer_addr: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        dht.store_peer(info_hash(0), other_peer_addr, now);
+        assert_eq!(dht.peers[&info_hash(0)].len(), 2);
+    }
+
+    /// Tests that a peer announced by one node is found by another, on a small
+    /// DHT on loopback.
+    #[tokio::test]
+    async fn should_find_announced_peer() {
+        let (_, bootstrap) = spawn_node(Vec::new());
+        let bootstrap_addr = format!("127.0.0.1:{}", bootstrap.port);
+        let mut nodes = Vec::new();
+        for _ in 0..4 {
+            nodes.push(spawn_node(vec![bootstrap_addr.clone()]).1);
+        }
+
+        let info_hash = [0xab; 20];
+        let peer_addr: SocketAddr = "127.0.0.1:51413".parse().unwrap();
+        let (announce_tx, _announce_rx) = mpsc::unbounded_channel();
+        let (peers_tx, mut peers_rx) = mpsc::unbounded_channel();
+
+        // the nodes join the DHT in the background, so retry until the peer
+        // is found
+        let found = time::timeout(Duration::from_secs(10), async {
+            loop {
+                nodes[0]
+                    .tx
+                    .send(Command::GetPeers {
+                        info_hash,
+                        announce_port: Some(peer_addr.port()),
+                        peers_tx: announce_tx.clone(),
+                    })
+                    .unwrap();
+                time::delay_for(Duration::from_millis(200)).await;
+                nodes[3]
+                    .tx
+                    .send(Command::GetPeers {
+                        info_hash,
+                        announce_port: None,
+                        peers_tx: peers_tx.clone(),
+                    })
+                    .unwrap();
+                if let Ok(Some(peers)) =
+                    time::timeout(Duration::from_millis(300), peers_rx.recv())
+                        .await
+                {
+                    return peers;
+                }
+            }
+        })
+        .await
+        .expect("peer not found in DHT");
+        assert_eq!(found, vec![peer_addr]);
+
+        for node in nodes.iter().chain(std::iter::once(&bootstrap)) {
+            node.tx.send(Command::Shutdown).unwrap();
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! The KRPC protocol messages exchanged by DHT nodes.
+//!
+//! Each message is a bencoded dictionary sent in a single UDP datagram. It is
+//! either a query, a response to a query, or an error, and it contains the
+//! transaction id of the query so that responses can be matched to queries.
+//! See [BEP 5](http://bittorrent.org/beps/bep_0005.html#krpc-protocol) for the
+//! details.
+
+use std::net::SocketAddr;
+
+use serde_bytes::ByteBuf;
+
+use super::NodeId;
+use crate::{
+    compact::{
+        decode_v4_addrs, decode_v6_addrs, encode_addr, COMPACT_V4_LEN,
+        COMPACT_V6_LEN,
+    },
+    Sha1Hash,
+};
+
+/// The length of a node's compact info: its id followed by its compact IPv4
+/// address.
+const COMPACT_NODE_LEN: usize = 20 + COMPACT_V4_LEN;
+
+/// The id and address of a DHT node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// A KRPC message.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Msg {
+    /// The transaction id, chosen by the querying node and echoed back in the
+    /// response.
+    pub tid: Vec<u8>,
+    pub body: Body,
+}
+
+/// The kinds of KRPC messages.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Body {
+    /// A query from the node with the given id.
+    Query {
+        id: NodeId,
+        query: Query,
+    },
+    Response(Response),
+    Error {
+        code: i64,
+        message: String,
+    },
+}
+
+/// The queries a node may send.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Query {
+    /// Checks whether the node is reachable.
+    Ping,
+    /// Asks for the nodes closest to the target.
+    FindNode { target: NodeId },
+    /// Asks for the peers of the torrent, or the nodes closest to its info
+    /// hash if the node doesn't know any.
+    GetPeers { info_hash: Sha1Hash },
+    /// Tells the node that we are downloading the torrent on the given port.
+    ///
+    /// The token is the one the node sent us in its response to our
+    /// `get_peers` query. If `implied_port` is set, the port is ignored and
+    /// the source port of the UDP packet is used instead.
+    AnnouncePeer {
+        info_hash: Sha1Hash,
+        port: u16,
+        token: Vec<u8>,
+        implied_port: bool,
+    },
+}
+
+/// A response to any of the queries.
+///
+/// Which fields are set depends on the query: all responses contain the
+/// responding node's id, `find_node` responses contain nodes, and `get_peers`
+/// responses contain a token and peers or nodes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Response {
+    pub id: NodeId,
+    pub nodes: Vec<NodeInfo>,
+    pub values: Vec<SocketAddr>,
+    pub token: Option<Vec<u8>>,
+}
+
+/// The bencoded form of a message.
+///
+/// The fields are ordered by key, as bencoded dictionaries must be sorted.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawMsg {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<RawArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<RawArgs>,
+    t: ByteBuf,
+    y: String,
+}
+
+/// The bencoded form of query arguments and of responses, which share the
+/// same keys.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawArgs {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl Msg {
+    /// Parses a message, returning `None` if it's not a valid KRPC message
+    /// or if it's a query we don't support.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let raw: RawMsg = serde_bencode::from_bytes(buf).ok()?;
+        let body = match raw.y.as_str() {
+            "q" => {
+                let args = raw.a?;
+                let id = to_hash(&args.id)?;
+                let query = match raw.q?.as_str() {
+                    "ping" => Query::Ping,
+                    "find_node" => Query::FindNode {
+                        target: to_hash(args.target.as_ref()?)?,
+                    },
+                    "get_peers" => Query::GetPeers {
+                        info_hash: to_hash(args.info_hash.as_ref()?)?,
+                    },
+                    "announce_peer" => Query::AnnouncePeer {
+                        info_hash: to_hash(args.info_hash.as_ref()?)?,
+                        port: args.port.unwrap_or_default(),
+                        token: args.token?.into_vec(),
+                        implied_port: args.implied_port.unwrap_or_default()
+                            != 0,
+                    },
+                    _ => return None,
+                };
+                Body::Query { id, query }
+            }
+            "r" => {
+                let args = raw.r?;
+                Body::Response(Response {
+                    id: to_hash(&args.id)?,
+                    nodes: args
+                        .nodes
+                        .map(|nodes| decode_nodes(&nodes))
+                        .unwrap_or_default(),
+                    values: args
+                        .values
+                        .unwrap_or_default()
+                        .iter()
+                        .flat_map(|value| decode_peer(value))
+                        .collect(),
+                    token: args.token.map(ByteBuf::into_vec),
+                })
+            }
+            "e" => {
+                let (code, message) = raw.e?;
+                Body::Error { code, message }
+            }
+            _ => return None,
+        };
+        Some(Self {
+            tid: raw.t.into_vec(),
+            body,
+        })
+    }
+
+    /// Encodes the message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = RawMsg {
+            t: ByteBuf::from(self.tid.clone()),
+            ..Default::default()
+        };
+        match &self.body {
+            Body::Query { id, query } => {
+                raw.y = "q".into();
+                let mut args = RawArgs {
+                    id: ByteBuf::from(id.to_vec()),
+                    ..Default::default()
+                };
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target } => {
+                        args.target = Some(ByteBuf::from(target.to_vec()));
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash } => {
+                        args.info_hash =
+                            Some(ByteBuf::from(info_hash.to_vec()));
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        token,
+                        implied_port,
+                    } => {
+                        args.info_hash =
+                            Some(ByteBuf::from(info_hash.to_vec()));
+                        args.port = Some(*port);
+                        args.token = Some(ByteBuf::from(token.clone()));
+                        args.implied_port = Some(*implied_port as u8);
+                        "announce_peer"
+                    }
+                };
+                raw.q = Some(name.into());
+                raw.a = Some(args);
+            }
+            Body::Response(resp) => {
+                raw.y = "r".into();
+                let mut args = RawArgs {
+                    id: ByteBuf::from(resp.id.to_vec()),
+                    token: resp.token.clone().map(ByteBuf::from),
+                    ..Default::default()
+                };
+                if !resp.nodes.is_empty() {
+                    let mut nodes = Vec::new();
+                    for node in resp.nodes.iter().filter(|n| n.addr.is_ipv4()) {
+                        nodes.extend_from_slice(&node.id);
+                        encode_addr(&node.addr, &mut nodes);
+                    }
+                    args.nodes = Some(ByteBuf::from(nodes));
+                }
+                if !resp.values.is_empty() {
+                    args.values = Some(
+                        resp.values
+                            .iter()
+                            .map(|addr| {
+                                let mut buf = Vec::new();
+                                encode_addr(addr, &mut buf);
+                                ByteBuf::from(buf)
+                            })
+                            .collect(),
+                    );
+                }
+                raw.r = Some(args);
+            }
+            Body::Error { code, message } => {
+                raw.y = "e".into();
+                raw.e = Some((*code, message.clone()));
+            }
+        }
+        // serializing a struct of strings and integers can't fail
+        serde_bencode::to_bytes(&raw).expect("cannot encode KRPC message")
+    }
+}
+
+/// Decodes a string of compact node infos.
+///
+/// A trailing partial node is ignored.
+fn decode_nodes(buf: &[u8]) -> Vec<NodeInfo> {
+    buf.chunks_exact(COMPACT_NODE_LEN)
+        .filter_map(|b| {
+            Some(NodeInfo {
+                id: to_hash(&b[..20])?,
+                addr: decode_v4_addrs(&b[20..]).pop()?,
+            })
+        })
+        .collect()
+}
+
+/// Decodes a peer in the values of a `get_peers` response, which may be an
+/// IPv4 or, as per BEP 32, an IPv6 compact address.
+fn decode_peer(buf: &[u8]) -> Option<SocketAddr> {
+    match buf.len() {
+        COMPACT_V4_LEN => decode_v4_addrs(buf).pop(),
+        COMPACT_V6_LEN => decode_v6_addrs(buf).pop(),
+        _ => None,
+    }
+}
+
+/// Converts the buffer to a node id or info hash, if it has the right length.
+fn to_hash(buf: &[u8]) -> Option<Sha1Hash> {
+    let mut hash = [0; 20];
+    if buf.len() != hash.len() {
+        return None;
+    }
+    hash.copy_from_slice(buf);
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests parsing and encoding the ping query example from BEP 5.
+    #[test]
+    fn should_parse_and_encode_ping_query() {
+        let buf = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let msg = Msg::from_bytes(buf).unwrap();
+        assert_eq!(
+            msg,
+            Msg {
+                tid: b"aa".to_vec(),
+                body: Body::Query {
+                    id: *b"abcdefghij0123456789",
+                    query: Query::Ping,
+                },
+            }
+        );
+        assert_eq!(msg.to_bytes(), buf.to_vec());
+    }
+
+    /// Tests that all queries, responses and errors survive an encoding round
+    /// trip.
+    #[test]
+    fn should_encode_and_decode_msgs() {
+        let id = [1; 20];
+        let bodies = vec![
+            Body::Query {
+                id,
+                query: Query::FindNode { target: [2; 20] },
+            },
+            Body::Query {
+                id,
+                query: Query::GetPeers { info_hash: [3; 20] },
+            },
+            Body::Query {
+                id,
+                query: Query::AnnouncePeer {
+                    info_hash: [3; 20],
+                    port: 6881,
+                    token: b"token".to_vec(),
+                    implied_port: true,
+                },
+            },
+            Body::Response(Response {
+                id,
+                nodes: vec![NodeInfo {
+                    id: [4; 20],
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                }],
+                values: vec![
+                    "10.0.0.2:6882".parse().unwrap(),
+                    "10.0.0.3:6883".parse().unwrap(),
+                    "[2001:db8::1]:6884".parse().unwrap(),
+                ],
+                token: Some(b"token".to_vec()),
+            }),
+            Body::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".into(),
+            },
+        ];
+        for body in bodies {
+            let msg = Msg {
+                tid: vec![0, 1],
+                body,
+            };
+            assert_eq!(Msg::from_bytes(&msg.to_bytes()).unwrap(), msg);
+        }
+    }
+
+    /// Tests that messages with missing or malformed fields are rejected.
+    #[test]
+    fn should_reject_invalid_msgs() {
+        // the id is too short
+        assert!(Msg::from_bytes(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe")
+            .is_none());
+        // unknown query
+        assert!(Msg::from_bytes(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q3:foo1:t2:aa1:y1:qe"
+        )
+        .is_none());
+        // missing target
+        assert!(Msg::from_bytes(
+            b"d1:ad2:id20:abcdefghij0123456789e1:q9:find_node1:t2:aa1:y1:qe"
+        )
+        .is_none());
+        assert!(Msg::from_bytes(b"not bencode").is_none());
+    }
+}
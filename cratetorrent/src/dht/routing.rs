@@ -0,0 +1,201 @@
+//! The routing table of the DHT node, which stores the nodes we know of.
+//!
+//! The table has a bucket for each possible length of the prefix shared by
+//! our id and a node's id, so nodes close to us are stored in the deeper and
+//! smaller buckets, and we know more about our own neighbourhood than about
+//! the rest of the DHT. Each bucket holds at most [`K`] nodes.
+
+use std::{net::SocketAddr, time::Instant};
+
+use super::{distance, msg::NodeInfo, NodeId};
+
+/// The maximum number of nodes in a bucket, and the number of nodes returned
+/// when asked for the nodes closest to an id.
+pub(crate) const K: usize = 8;
+
+/// After this many queries that the node failed to respond to in a row, it's
+/// considered bad and may be replaced by another node.
+const MAX_FAILED_QUERY_COUNT: usize = 2;
+
+/// A node in the routing table.
+#[derive(Clone, Debug)]
+pub(crate) struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    /// The last time the node responded to us or sent us a query.
+    pub last_seen: Instant,
+    /// The number of our queries in a row that the node didn't respond to.
+    pub failed_query_count: usize,
+}
+
+impl Node {
+    /// Returns whether the node stopped responding to our queries.
+    fn is_bad(&self) -> bool {
+        self.failed_query_count >= MAX_FAILED_QUERY_COUNT
+    }
+}
+
+/// The routing table of our DHT node.
+pub(crate) struct RoutingTable {
+    own_id: NodeId,
+    /// The bucket at index `i` contains the nodes whose ids share the first
+    /// `i` bits with our own id.
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![Vec::new(); own_id.len() * 8],
+        }
+    }
+
+    /// Returns the number of nodes in the table.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    /// Returns all nodes in the table that are not known to be bad.
+    pub fn good_nodes(&self) -> impl Iterator<Item = &Node> {
+        self.buckets.iter().flatten().filter(|node| !node.is_bad())
+    }
+
+    /// Adds the node to the table, or if it's already in it, marks it as
+    /// seen.
+    ///
+    /// If the node's bucket is full, it replaces a bad node in the bucket. If
+    /// there are none, the node is dropped, as long-lived nodes are preferred
+    /// over new ones.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr, now: Instant) {
+        let bucket = match self.bucket_index(&id) {
+            Some(index) => &mut self.buckets[index],
+            None => return,
+        };
+        if let Some(node) = bucket.iter_mut().find(|node| node.id == id) {
+            // don't let another host take over the node's id
+            if node.addr == addr {
+                node.last_seen = now;
+                node.failed_query_count = 0;
+            }
+            return;
+        }
+
+        let node = Node {
+            id,
+            addr,
+            last_seen: now,
+            failed_query_count: 0,
+        };
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(bad_node) = bucket.iter_mut().find(|n| n.is_bad()) {
+            log::debug!("Replacing bad DHT node {}", bad_node.addr);
+            *bad_node = node;
+        }
+    }
+
+    /// Records that the node at the address didn't respond to our query.
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        if let Some(node) = self
+            .buckets
+            .iter_mut()
+            .flatten()
+            .find(|node| node.addr == addr)
+        {
+            node.failed_query_count += 1;
+        }
+    }
+
+    /// Returns at most `count` good nodes closest to the target, ordered by
+    /// their distance to it.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<_> = self
+            .good_nodes()
+            .map(|node| NodeInfo {
+                id: node.id,
+                addr: node.addr,
+            })
+            .collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    /// Returns the index of the bucket in which the node belongs, or `None` if
+    /// the id is our own.
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let dist = distance(&self.own_id, id);
+        let first_diff = dist.iter().position(|b| *b != 0)?;
+        Some(first_diff * 8 + dist[first_diff].leading_zeros() as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::new([127, 0, 0, 1].into(), port)
+    }
+
+    /// Returns an id that differs from the zero id in the given bit.
+    fn id_with_bit(bit: usize, last_byte: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[bit / 8] |= 0x80 >> (bit % 8);
+        id[19] |= last_byte;
+        id
+    }
+
+    /// Tests that nodes are put into the bucket of their shared prefix length
+    /// and that full buckets don't take new nodes unless they have bad ones.
+    #[test]
+    fn should_limit_bucket_size() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        assert_eq!(table.bucket_index(&[0; 20]), None);
+        assert_eq!(table.bucket_index(&id_with_bit(0, 0)), Some(0));
+        assert_eq!(table.bucket_index(&id_with_bit(13, 0)), Some(13));
+
+        // our own id is never added
+        table.insert([0; 20], addr(1), now);
+        assert_eq!(table.len(), 0);
+
+        for i in 0..K as u8 + 1 {
+            table.insert(id_with_bit(0, i), addr(i as u16), now);
+        }
+        assert_eq!(table.len(), K);
+        // re-inserting a node doesn't duplicate it
+        table.insert(id_with_bit(0, 0), addr(0), now);
+        assert_eq!(table.len(), K);
+
+        // the new node replaces the first node once it goes bad
+        let new_id = id_with_bit(0, 100);
+        for _ in 0..MAX_FAILED_QUERY_COUNT {
+            table.mark_failed(addr(0));
+        }
+        assert_eq!(table.good_nodes().count(), K - 1);
+        table.insert(new_id, addr(100), now);
+        assert_eq!(table.len(), K);
+        assert!(table.good_nodes().any(|node| node.id == new_id));
+        assert!(!table.good_nodes().any(|node| node.addr == addr(0)));
+    }
+
+    /// Tests that the closest nodes are returned in order of their distance.
+    #[test]
+    fn should_return_closest_nodes() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new([0; 20]);
+        for bit in 0..20 {
+            table.insert(id_with_bit(bit, 0), addr(bit as u16), now);
+        }
+        let target = id_with_bit(3, 0);
+        let closest = table.closest(&target, 3);
+        assert_eq!(closest.len(), 3);
+        assert_eq!(closest[0].id, target);
+        // the rest differ from the target in the bit that's set in the target
+        // and one more, the lowest one being the closest
+        assert_eq!(closest[1].id, id_with_bit(19, 0));
+        assert_eq!(closest[2].id, id_with_bit(18, 0));
+    }
+}
@@ -12,8 +12,8 @@ use tokio::{
 };
 
 use crate::{
-    engine, error::Error, peer, storage_info::StorageInfo, torrent, BlockInfo,
-    TorrentId,
+    engine, peer, resume::ResumeData, storage_info::StorageInfo, torrent,
+    BlockInfo, Priority, TorrentId,
 };
 use error::*;
 use io::torrent::Torrent;
@@ -47,6 +47,8 @@ pub(crate) enum Command {
     NewTorrent {
         id: TorrentId,
         storage_info: StorageInfo,
+        /// The priority of each file, files with priority 0 are not created.
+        file_priorities: Vec<Priority>,
         piece_hashes: Vec<u8>,
         torrent_tx: torrent::Sender,
     },
@@ -63,6 +65,18 @@ pub(crate) enum Command {
         block_info: BlockInfo,
         result_tx: peer::Sender,
     },
+    /// Verify all of the torrent's pieces on disk and report the result to
+    /// torrent.
+    CheckTorrent(TorrentId),
+    /// Complete the torrent's resume data with the state of its files and its
+    /// in-progress pieces, and return it to the torrent.
+    SaveResumeData {
+        id: TorrentId,
+        resume_data: Box<ResumeData>,
+    },
+    /// Remove the torrent's entry, closing its files, and optionally delete
+    /// the files from disk.
+    RemoveTorrent { id: TorrentId, delete_files: bool },
     /// Eventually shut down the disk task.
     Shutdown,
 }
@@ -104,6 +118,7 @@ impl Disk {
                 Command::NewTorrent {
                     id,
                     storage_info,
+                    file_priorities,
                     piece_hashes,
                     torrent_tx,
                 } => {
@@ -126,8 +141,12 @@ impl Disk {
                     // NOTE: Do _NOT_ return on failure, we don't want to kill
                     // the disk task due to potential disk IO errors: we just
                     // want to log it and notify engine of it.
-                    let torrent_res =
-                        Torrent::new(storage_info, piece_hashes, torrent_tx);
+                    let torrent_res = Torrent::new(
+                        storage_info,
+                        &file_priorities,
+                        piece_hashes,
+                        torrent_tx,
+                    );
                     match torrent_res {
                         Ok(torrent) => {
                             log::info!("Torrent {} successfully allocated", id);
@@ -170,6 +189,22 @@ impl Disk {
                 } => {
                     self.read_block(id, block_info, result_tx).await?;
                 }
+                Command::CheckTorrent(id) => match self.torrents.get(&id) {
+                    Some(torrent) => torrent.write().await.check_pieces(),
+                    None => log::warn!("Torrent {} not found", id),
+                },
+                Command::SaveResumeData { id, resume_data } => {
+                    match self.torrents.get(&id) {
+                        Some(torrent) => torrent
+                            .read()
+                            .await
+                            .save_resume_data(resume_data)?,
+                        None => log::warn!("Torrent {} not found", id),
+                    }
+                }
+                Command::RemoveTorrent { id, delete_files } => {
+                    self.remove_torrent(id, delete_files)?;
+                }
                 Command::Shutdown => {
                     log::info!("Shutting down disk event loop");
                     break;
@@ -195,13 +230,15 @@ impl Disk {
 
         // check torrent id
         //
-        // TODO: maybe we don't want to crash the disk task due to an invalid
-        // torrent id: could it be that disk requests for a torrent arrive after
-        // a torrent has been removed?
-        let torrent = self.torrents.get(&id).ok_or_else(|| {
-            log::error!("Torrent {} not found", id);
-            Error::InvalidTorrentId
-        })?;
+        // Don't crash the disk task due to an invalid torrent id, as this may
+        // be a request that arrived after the torrent has been removed.
+        let torrent = match self.torrents.get(&id) {
+            Some(torrent) => torrent,
+            None => {
+                log::warn!("Torrent {} not found", id);
+                return Ok(());
+            }
+        };
         torrent.write().await.write_block(block_info, data)
     }
 
@@ -222,15 +259,48 @@ impl Disk {
 
         // check torrent id
         //
-        // TODO: maybe we don't want to crash the disk task due to an invalid
-        // torrent id: could it be that disk requests for a torrent arrive after
-        // a torrent has been removed?
-        let torrent = self.torrents.get(&id).ok_or_else(|| {
-            log::error!("Torrent {} not found", id);
-            Error::InvalidTorrentId
-        })?;
+        // Don't crash the disk task due to an invalid torrent id, as this may
+        // be a request that arrived after the torrent has been removed.
+        let torrent = match self.torrents.get(&id) {
+            Some(torrent) => torrent,
+            None => {
+                log::warn!("Torrent {} not found", id);
+                return Ok(());
+            }
+        };
         torrent.read().await.read_block(block_info, tx)
     }
+
+    /// Removes the torrent's entry, which closes its files and drops its write
+    /// buffer and read cache, and if requested, deletes its files from disk.
+    ///
+    /// The engine is notified of the result.
+    fn remove_torrent(
+        &mut self,
+        id: TorrentId,
+        delete_files: bool,
+    ) -> Result<()> {
+        log::info!("Removing torrent {} (delete files: {})", id, delete_files);
+        let result = match self.torrents.remove(&id) {
+            Some(torrent) => {
+                let torrent = torrent.into_inner();
+                if delete_files {
+                    // NOTE: as with allocation, do _NOT_ return on failure,
+                    // just notify engine of it
+                    torrent.delete_files()
+                } else {
+                    Ok(())
+                }
+            }
+            None => {
+                log::warn!("Torrent {} not found", id);
+                Ok(())
+            }
+        };
+        self.engine_tx
+            .send(engine::Command::TorrentRemoval { id, result })?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -244,7 +314,7 @@ mod tests {
     use tokio::sync::mpsc;
 
     use super::*;
-    use crate::{block_count, FileInfo, BLOCK_LEN};
+    use crate::{block_count, FileInfo, BLOCK_LEN, DEFAULT_PRIORITY};
 
     /// Tests the allocation of a torrent, and then the allocation of the same
     /// torrent returning an error.
@@ -266,6 +336,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -289,6 +360,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info,
+                file_priorities: vec![DEFAULT_PRIORITY],
                 piece_hashes,
                 torrent_tx: torrent_tx.clone(),
             })
@@ -305,6 +377,193 @@ mod tests {
         ));
     }
 
+    /// Tests that the files of a torrent that are not downloaded are not
+    /// created.
+    #[tokio::test]
+    async fn should_not_create_skipped_files() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("not_create_skipped_files");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![0],
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        let alert = rx.recv().await.unwrap();
+        assert!(matches!(
+            alert,
+            engine::Command::TorrentAllocation { result: Ok(()), .. }
+        ));
+
+        let file = info.files.first().unwrap();
+        assert!(!info.download_dir.join(&file.path).exists());
+    }
+
+    /// Tests removing a torrent along with its files from disk, and then
+    /// verifying that disk requests for the removed torrent don't kill the disk
+    /// task.
+    #[tokio::test]
+    async fn should_remove_torrent_and_delete_files() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            piece_hashes,
+            info,
+            torrent_tx,
+            ..
+        } = Env::new("remove_torrent");
+
+        // allocate torrent via channel
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+        let file = info.files.first().unwrap();
+        let path = info.download_dir.join(&file.path);
+        assert!(path.is_file());
+
+        // remove torrent and its files
+        disk_tx
+            .send(Command::RemoveTorrent {
+                id,
+                delete_files: true,
+            })
+            .unwrap();
+        let alert = rx.recv().await.unwrap();
+        assert!(matches!(
+            alert,
+            engine::Command::TorrentRemoval { result: Ok(()), .. }
+        ));
+        assert!(!path.exists());
+        // the download directory is not the torrent's own and must be kept
+        assert!(info.download_dir.is_dir());
+
+        // a late request for the removed torrent should be ignored, and
+        // removing it again should succeed
+        disk_tx
+            .send(Command::WriteBlock {
+                id,
+                block_info: BlockInfo {
+                    piece_index: 0,
+                    offset: 0,
+                    len: BLOCK_LEN,
+                },
+                data: vec![0; BLOCK_LEN as usize],
+            })
+            .unwrap();
+        disk_tx
+            .send(Command::RemoveTorrent {
+                id,
+                delete_files: false,
+            })
+            .unwrap();
+        let alert = rx.recv().await.unwrap();
+        assert!(matches!(
+            alert,
+            engine::Command::TorrentRemoval { result: Ok(()), .. }
+        ));
+    }
+
+    /// Tests that the disk task completes resume data with the blocks of
+    /// in-progress pieces and the state of the torrent's files.
+    #[tokio::test]
+    async fn should_save_resume_data() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            pieces,
+            piece_hashes,
+            info,
+            torrent_tx,
+            mut torrent_rx,
+        } = Env::new("save_resume_data");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+
+        // write the first two blocks of a piece, so that it's not complete
+        let index = 2;
+        let blocks: Vec<_> = (0..2)
+            .map(|i| BlockInfo {
+                piece_index: index,
+                offset: i * BLOCK_LEN,
+                len: BLOCK_LEN,
+            })
+            .collect();
+        for block in blocks.iter() {
+            let data = &pieces[index]
+                [block.offset as usize..(block.offset + block.len) as usize];
+            disk_tx
+                .send(Command::WriteBlock {
+                    id,
+                    block_info: *block,
+                    data: data.to_vec(),
+                })
+                .unwrap();
+        }
+
+        disk_tx
+            .send(Command::SaveResumeData {
+                id,
+                resume_data: Box::new(ResumeData::default()),
+            })
+            .unwrap();
+        let resume_data = match torrent_rx.recv().await {
+            Some(torrent::Command::ResumeData(resume_data)) => resume_data,
+            _ => panic!("resume data not returned"),
+        };
+
+        assert_eq!(resume_data.files.len(), 1);
+        assert_eq!(resume_data.files[0].len, 0);
+        assert_eq!(resume_data.partial_pieces.len(), 1);
+        let piece = &resume_data.partial_pieces[0];
+        assert_eq!(piece.index, index);
+        assert_eq!(piece.blocks.len(), blocks.len());
+        for (block, info) in piece.blocks.iter().zip(blocks.iter()) {
+            assert_eq!(block.offset, info.offset);
+            assert_eq!(
+                block.data,
+                &pieces[index]
+                    [info.offset as usize..(info.offset + info.len) as usize]
+            );
+        }
+
+        // clean up test env
+        let file = info.files.first().unwrap();
+        fs::remove_file(info.download_dir.join(&file.path))
+            .expect("cannot clean up disk test torrent file");
+    }
+
     /// Tests writing of a complete valid torrent's pieces and verifying that an
     /// alert of each disk write is returned by the disk task.
     #[tokio::test]
@@ -326,6 +585,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -368,6 +628,80 @@ mod tests {
             .expect("cannot clean up disk test torrent file");
     }
 
+    /// Tests that checking a torrent reports each checked piece and returns
+    /// only the pieces that were written to disk.
+    #[tokio::test]
+    async fn should_check_torrent_pieces() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (_, disk_tx) = spawn(tx).unwrap();
+
+        let Env {
+            id,
+            pieces,
+            piece_hashes,
+            info,
+            torrent_tx,
+            mut torrent_rx,
+        } = Env::new("check_torrent_pieces");
+
+        disk_tx
+            .send(Command::NewTorrent {
+                id,
+                storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
+                piece_hashes,
+                torrent_tx,
+            })
+            .unwrap();
+        rx.recv().await.expect("cannot allocate torrent");
+
+        // write every other piece to disk
+        let written: Vec<_> = (0..pieces.len()).step_by(2).collect();
+        for &index in written.iter() {
+            let piece = &pieces[index];
+            for_each_block(index, piece.len() as u32, |block| {
+                let block_end = block.offset + block.len;
+                let data = &piece[block.offset as usize..block_end as usize];
+                disk_tx
+                    .send(Command::WriteBlock {
+                        id,
+                        block_info: block,
+                        data: data.to_vec(),
+                    })
+                    .unwrap();
+            });
+            match torrent_rx.recv().await {
+                Some(torrent::Command::PieceCompletion(Ok(piece))) => {
+                    assert!(piece.is_valid)
+                }
+                _ => panic!("Piece could not be written to disk"),
+            }
+        }
+
+        disk_tx.send(Command::CheckTorrent(id)).unwrap();
+        for i in 0..pieces.len() {
+            match torrent_rx.recv().await {
+                Some(torrent::Command::CheckProgress { checked_count }) => {
+                    assert_eq!(checked_count, i + 1)
+                }
+                _ => panic!("check progress not reported"),
+            }
+        }
+        let own_pieces = match torrent_rx.recv().await {
+            Some(torrent::Command::CheckComplete(own_pieces)) => own_pieces,
+            _ => panic!("check result not returned"),
+        };
+        assert_eq!(own_pieces.len(), pieces.len());
+        for index in 0..pieces.len() {
+            assert_eq!(own_pieces[index], written.contains(&index));
+        }
+
+        // clean up test env
+        let file = info.files.first().unwrap();
+        fs::remove_file(info.download_dir.join(&file.path))
+            .expect("cannot clean up disk test torrent file");
+    }
+
     /// Tests writing of an invalid piece and verifying that an alert of it
     /// is returned by the disk task.
     #[tokio::test]
@@ -389,6 +723,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -447,6 +782,7 @@ mod tests {
             .send(Command::NewTorrent {
                 id,
                 storage_info: info.clone(),
+                file_priorities: vec![DEFAULT_PRIORITY],
                 piece_hashes: piece_hashes.clone(),
                 torrent_tx: torrent_tx.clone(),
             })
@@ -589,7 +925,7 @@ mod tests {
             let mut piece_hashes = Vec::with_capacity(pieces.len() * 20);
             for piece in pieces.iter() {
                 let hash = Sha1::digest(&piece);
-                piece_hashes.extend(hash.as_slice());
+                piece_hashes.extend_from_slice(&hash);
             }
             assert_eq!(piece_hashes.len(), pieces.len() * 20);
 
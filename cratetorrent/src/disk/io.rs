@@ -62,6 +62,8 @@ mod tests {
         // read and compare
         let mut file_content = Vec::new();
         file.handle
+            .as_mut()
+            .unwrap()
             .read_to_end(&mut file_content)
             .expect("cannot read test file");
         assert_eq!(
@@ -102,6 +104,8 @@ mod tests {
         let mut file = files[0].write().unwrap();
         let mut file_content = Vec::new();
         file.handle
+            .as_mut()
+            .unwrap()
             .read_to_end(&mut file_content)
             .expect("cannot read test file");
         assert_eq!(
@@ -237,6 +241,8 @@ mod tests {
             let mut file = file.write().unwrap();
             let mut file_content = Vec::new();
             file.handle
+                .as_mut()
+                .unwrap()
                 .read_to_end(&mut file_content)
                 .expect("cannot read test file");
             // compare the content of file to the portion that corresponds to
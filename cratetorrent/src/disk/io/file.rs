@@ -1,7 +1,8 @@
 use std::{
-    fs::{File, OpenOptions},
+    fs::{self, File, OpenOptions},
+    io,
     os::unix::io::AsRawFd,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use nix::sys::uio::{preadv, pwritev};
@@ -16,7 +17,11 @@ use crate::{
 
 pub(crate) struct TorrentFile {
     pub info: FileInfo,
-    pub handle: File,
+    /// The file's absolute path.
+    path: PathBuf,
+    /// The file handle, or None if the file doesn't exist, in which case it's
+    /// created when it's first written to.
+    pub handle: Option<File>,
 }
 
 impl TorrentFile {
@@ -42,12 +47,59 @@ impl TorrentFile {
                 NewTorrentError::Io(e)
             })?;
         debug_assert!(path.exists());
-        Ok(Self { info, handle })
+        Ok(Self {
+            info,
+            path,
+            handle: Some(handle),
+        })
+    }
+
+    /// Opens the file at the path of combining the download directory and the
+    /// path defined in the file info, if it exists. Unlike [`Self::new`], the
+    /// file is not created until it's first written to.
+    ///
+    /// This is used for the files that are not downloaded, which are only
+    /// written to if a downloaded piece overlaps with them.
+    pub fn open_existing(
+        download_dir: &Path,
+        info: FileInfo,
+    ) -> Result<Self, NewTorrentError> {
+        log::trace!("Opening file {:?} in dir {:?}", info, download_dir);
+        let path = download_dir.join(&info.path);
+        let handle = match OpenOptions::new().write(true).read(true).open(&path)
+        {
+            Ok(handle) => Some(handle),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                log::warn!("Failed to open file {:?}", path);
+                return Err(NewTorrentError::Io(e));
+            }
+        };
+        Ok(Self { info, path, handle })
+    }
+
+    /// Returns the file handle, creating the file and its directory first if
+    /// the file doesn't exist.
+    fn handle_or_create(&mut self) -> io::Result<&File> {
+        if self.handle.is_none() {
+            if let Some(dir) = self.path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            log::info!("Creating file {:?}", self.path);
+            let handle = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .read(true)
+                .open(&self.path)?;
+            self.handle = Some(handle);
+        }
+        Ok(self.handle.as_ref().expect("file not open"))
     }
 
     /// Writes to file at most the slice length number of bytes of blocks at the
     /// file slice's offset, using pwritev, called repeteadly until all blocks are
-    /// written to disk.
+    /// written to disk. If the file doesn't exist, it's created first.
     ///
     /// It returns the slice of blocks that weren't written to disk. That is, it
     /// returns the second half of `blocks` as though they were split at the
@@ -59,10 +111,11 @@ impl TorrentFile {
     /// Since the syscall may be invoked repeatedly to perform disk IO, this
     /// means that this operation is not guaranteed to be atomic.
     pub fn write<'a>(
-        &self,
+        &mut self,
         file_slice: FileSlice,
         blocks: &'a mut [IoVec<&'a [u8]>],
     ) -> Result<&'a mut [IoVec<&'a [u8]>], WriteError> {
+        let fd = self.handle_or_create().map_err(WriteError::Io)?.as_raw_fd();
         let mut iovecs = IoVecs::bounded(blocks, file_slice.len as usize);
         // the write buffer cannot be larger than the file slice we want to
         // write to
@@ -80,16 +133,17 @@ impl TorrentFile {
         // transferred to disk (or an error occurs)
         let mut total_write_count = 0;
         while !iovecs.as_slice().is_empty() {
-            let write_count = pwritev(
-                self.handle.as_raw_fd(),
-                iovecs.as_slice(),
-                file_slice.offset as i64,
-            )
-            .map_err(|e| {
-                log::warn!("File {:?} write error: {}", self.info.path, e);
-                // FIXME: convert actual error here
-                WriteError::Io(std::io::Error::last_os_error())
-            })?;
+            let write_count =
+                pwritev(fd, iovecs.as_slice(), file_slice.offset as i64)
+                    .map_err(|e| {
+                        log::warn!(
+                            "File {:?} write error: {}",
+                            self.info.path,
+                            e
+                        );
+                        // FIXME: convert actual error here
+                        WriteError::Io(std::io::Error::last_os_error())
+                    })?;
 
             // tally up the total write count
             total_write_count += write_count;
@@ -133,21 +187,24 @@ impl TorrentFile {
         // from other files after this one, in which case the cursor should
         // be on the next byte to read to.
 
+        // a file that doesn't exist has no data
+        let fd = self
+            .handle
+            .as_ref()
+            .ok_or(ReadError::MissingData)?
+            .as_raw_fd();
+
         // IO syscalls are not guaranteed to transfer the whole input buffer in one
         // go, so we need to repeat until all bytes have been confirmed to be
         // transferred to disk (or an error occurs)
         let mut total_read_count = 0;
         while !iovecs.is_empty() && (total_read_count as u64) < file_slice.len {
-            let read_count = preadv(
-                self.handle.as_raw_fd(),
-                iovecs,
-                file_slice.offset as i64,
-            )
-            .map_err(|e| {
-                log::warn!("File {:?} read error: {}", self.info.path, e);
-                // FIXME: convert actual error here
-                ReadError::Io(std::io::Error::last_os_error())
-            })?;
+            let read_count = preadv(fd, iovecs, file_slice.offset as i64)
+                .map_err(|e| {
+                    log::warn!("File {:?} read error: {}", self.info.path, e);
+                    // FIXME: convert actual error here
+                    ReadError::Io(std::io::Error::last_os_error())
+                })?;
 
             // if there was nothing to read from file it means we tried to
             // read a piece from a portion of a file not yet downloaded or
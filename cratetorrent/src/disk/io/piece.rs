@@ -108,7 +108,7 @@ impl Piece {
         let mut total_write_count = 0;
 
         for file in files.iter() {
-            let file = file.write().unwrap();
+            let mut file = file.write().unwrap();
 
             // determine which part of the file we need to write to
             debug_assert!(self.len as u64 > total_write_count);
@@ -144,6 +144,25 @@ impl Piece {
     }
 }
 
+/// Returns whether the hash of the blocks, in the given order, matches the
+/// expected hash.
+///
+/// # Important
+///
+/// This is potentially a computationally expensive function and should be
+/// executed on a thread pool and not the executor.
+pub(super) fn matches_hash(
+    blocks: &[CachedBlock],
+    expected_hash: &Sha1Hash,
+) -> bool {
+    let mut hasher = Sha1::new();
+    for block in blocks.iter() {
+        hasher.update(block.as_slice());
+    }
+    let hash = hasher.finalize();
+    hash[..] == expected_hash[..]
+}
+
 /// Reads a piece's blocks from the specified portion of the file from disk.
 ///
 /// # Arguments
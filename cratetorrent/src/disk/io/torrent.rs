@@ -1,9 +1,10 @@
 use std::{
     collections::{BTreeMap, HashMap},
     fs,
+    path::Path,
     sync::{
         self,
-        atomic::{AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
 };
@@ -20,9 +21,10 @@ use crate::{
         },
     },
     peer,
+    resume::{FileResumeData, PartialBlock, PartialPiece, ResumeData},
     storage_info::StorageInfo,
     torrent::{self, PieceCompletion},
-    Block, BlockInfo, CachedBlock, PieceIndex,
+    Bitfield, Block, BlockInfo, CachedBlock, PieceIndex, Priority,
 };
 
 /// Torrent information related to disk IO.
@@ -96,7 +98,8 @@ struct ThreadContext {
     read_cache: sync::Mutex<LruCache<PieceIndex, Vec<CachedBlock>>>,
 
     /// Handles of all files in torrent, opened in advance during torrent
-    /// creation.
+    /// creation. The files that are not downloaded are only created once
+    /// a downloaded piece that overlaps with them is written.
     ///
     /// Each writer thread will get exclusive access to the file handle it
     /// needs, referring to it directly in the vector (hence the arc).
@@ -117,6 +120,14 @@ struct ThreadContext {
     ///
     /// Stas are atomically updated by the IO worker threads themselves.
     stats: Stats,
+
+    /// Whether the torrent's pieces are being checked, during which blocks
+    /// are not written.
+    ///
+    /// The check thread resets it once it's done. Pieces whose writes were
+    /// already in flight when the check started may or may not be found by
+    /// the check, but torrent discards their write results either way.
+    is_checking: AtomicBool,
 }
 
 #[derive(Default)]
@@ -138,8 +149,12 @@ impl Torrent {
     /// For a single file, there is a path validity check and then the file is
     /// opened. For multi-file torrents, if there are any subdirectories in the
     /// torrent archive, they are created and all files are opened.
+    ///
+    /// Files with priority 0 are not created, only opened if they already
+    /// exist.
     pub fn new(
         info: StorageInfo,
+        file_priorities: &[Priority],
         piece_hashes: Vec<u8>,
         torrent_tx: torrent::Sender,
     ) -> Result<Self, NewTorrentError> {
@@ -163,21 +178,33 @@ impl Torrent {
                 file.len,
                 file.path
             );
-            vec![sync::RwLock::new(TorrentFile::new(
-                &info.download_dir,
-                file.clone(),
-            )?)]
+            let file = if file_priorities[0] > 0 {
+                TorrentFile::new(&info.download_dir, file.clone())?
+            } else {
+                log::debug!("Not creating skipped file {:?}", file.path);
+                TorrentFile::open_existing(&info.download_dir, file.clone())?
+            };
+            vec![sync::RwLock::new(file)]
         } else {
             debug_assert!(!info.files.is_empty());
             log::debug!("Torrent is multi file: {:?}", info.files);
             log::debug!("Setting up directory structure");
 
             let mut torrent_files = Vec::with_capacity(info.files.len());
-            for file in info.files.iter() {
+            for (file, priority) in info.files.iter().zip(file_priorities) {
+                if *priority == 0 {
+                    log::debug!("Not creating skipped file {:?}", file.path);
+                    torrent_files.push(sync::RwLock::new(
+                        TorrentFile::open_existing(
+                            &info.download_dir,
+                            file.clone(),
+                        )?,
+                    ));
+                    continue;
+                }
+
                 let path = info.download_dir.join(&file.path);
-                // file or subdirectory in download root must not exist if
-                // download root does not exists
-                debug_assert!(!path.exists());
+                // the file may already exist, e.g. when resuming the torrent
                 debug_assert!(path.is_absolute());
 
                 // get the parent of the file path: if there is one (i.e.
@@ -215,6 +242,7 @@ impl Torrent {
                 )),
                 files,
                 stats: Stats::default(),
+                is_checking: AtomicBool::new(false),
             }),
             piece_hashes,
         })
@@ -227,6 +255,13 @@ impl Torrent {
     ) -> Result<()> {
         log::trace!("Saving block {} to disk", info);
 
+        // the check resets the download state, so any blocks that come in
+        // during it are stale
+        if self.thread_ctx.is_checking.load(Ordering::Acquire) {
+            log::debug!("Discarding block {} written during check", info);
+            return Ok(());
+        }
+
         let piece_index = info.piece_index;
         if !self.write_buf.contains_key(&piece_index) {
             self.start_new_piece(info.piece_index);
@@ -494,6 +529,181 @@ impl Torrent {
 
         Ok(())
     }
+
+    /// Verifies all pieces of the torrent on disk against their expected hashes
+    /// and reports the pieces we have to torrent.
+    ///
+    /// Since a check resets the torrent's download state, the write buffer and
+    /// the read cache are cleared first. The pieces are then read and hashed on
+    /// a blocking thread, and torrent is sent a progress update after each
+    /// percent of the pieces, followed by the resulting bitfield once all
+    /// pieces have been checked. Pieces that can't be read (e.g. because the
+    /// file is missing or is too short) are treated as missing.
+    ///
+    /// No blocks are written until the check completes.
+    pub fn check_pieces(&mut self) {
+        log::info!("Checking {} pieces on disk", self.info.piece_count);
+
+        self.thread_ctx.is_checking.store(true, Ordering::Release);
+        self.write_buf.clear();
+        self.thread_ctx.read_cache.lock().unwrap().clear();
+
+        let info = self.info.clone();
+        let piece_hashes = self.piece_hashes.clone();
+        let ctx = Arc::clone(&self.thread_ctx);
+        task::spawn_blocking(move || {
+            let mut own_pieces = Bitfield::repeat(false, info.piece_count);
+            for index in 0..info.piece_count {
+                let hash_pos = index * 20;
+                let mut expected_hash = [0; 20];
+                expected_hash
+                    .copy_from_slice(&piece_hashes[hash_pos..hash_pos + 20]);
+
+                let piece_len = info.piece_len(index);
+                match piece::read(
+                    info.torrent_piece_offset(index),
+                    info.files_intersecting_piece(index),
+                    &ctx.files[..],
+                    piece_len,
+                ) {
+                    Ok(blocks) => {
+                        ctx.stats
+                            .read_count
+                            .fetch_add(piece_len as u64, Ordering::Relaxed);
+                        if piece::matches_hash(&blocks, &expected_hash) {
+                            own_pieces.set(index, true);
+                        } else {
+                            log::debug!("Piece {} hash mismatch", index);
+                        }
+                    }
+                    Err(e) => {
+                        log::debug!("Piece {} cannot be read: {}", index, e);
+                    }
+                }
+
+                // a torrent may have many pieces, so progress is only
+                // reported when the checked percentage changes
+                let checked_count = index + 1;
+                if checked_count * 100 / info.piece_count
+                    > index * 100 / info.piece_count
+                {
+                    ctx.tx
+                        .send(torrent::Command::CheckProgress { checked_count })
+                        .ok();
+                }
+            }
+            ctx.is_checking.store(false, Ordering::Release);
+
+            log::info!(
+                "Checked pieces on disk, have {}/{}",
+                own_pieces.count_ones(),
+                info.piece_count
+            );
+            ctx.tx
+                .send(torrent::Command::CheckComplete(own_pieces))
+                .map_err(|e| {
+                    log::error!("Error sending check result: {}", e);
+                    e
+                })
+                .ok();
+        });
+    }
+
+    /// Fills in the state of the torrent's files and the blocks of the pieces
+    /// in the write buffer, and returns the resume data to torrent.
+    ///
+    /// Note that blocks that are being written to disk at the time of this
+    /// call may not be reflected in the resume data.
+    pub fn save_resume_data(
+        &self,
+        mut resume_data: Box<ResumeData>,
+    ) -> Result<()> {
+        resume_data.files = self
+            .info
+            .files
+            .iter()
+            .map(|file| {
+                FileResumeData::new(&self.info.download_dir.join(&file.path))
+            })
+            .collect();
+        resume_data.partial_pieces = self
+            .write_buf
+            .iter()
+            .map(|(index, piece)| PartialPiece {
+                index: *index,
+                blocks: piece
+                    .blocks
+                    .iter()
+                    .map(|(offset, data)| PartialBlock {
+                        offset: *offset,
+                        data: data.clone(),
+                    })
+                    .collect(),
+            })
+            .collect();
+        self.thread_ctx
+            .tx
+            .send(torrent::Command::ResumeData(resume_data))?;
+        Ok(())
+    }
+
+    /// Closes the torrent's files and deletes them from disk, along with the
+    /// subdirectories that were created for them, if they are left empty.
+    ///
+    /// If the torrent is an archive, its own directory in the download
+    /// directory is also removed if empty. Files that no longer exist are
+    /// skipped.
+    pub fn delete_files(self) -> std::io::Result<()> {
+        let Self {
+            info, thread_ctx, ..
+        } = self;
+        // close our file handles (though blocking IO threads that are still
+        // in progress may hold onto them for a bit longer, which is fine)
+        drop(thread_ctx);
+
+        let mut dirs = Vec::new();
+        for file in info.files.iter() {
+            let path = info.download_dir.join(&file.path);
+            log::info!("Deleting torrent file {:?}", path);
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    log::warn!("Torrent file {:?} does not exist", path);
+                }
+                Err(e) => return Err(e),
+            }
+
+            // collect the file's subdirectories within the download directory
+            dirs.extend(
+                path.ancestors()
+                    .skip(1)
+                    .take_while(|dir| *dir != info.download_dir)
+                    .map(Path::to_path_buf),
+            );
+        }
+        // an archive is downloaded into its own directory
+        if info.is_archive() {
+            dirs.push(info.download_dir.clone());
+        }
+
+        // remove the deepest directories first so that their parents may be
+        // empty by the time we get to them
+        dirs.sort_unstable_by(|a, b| {
+            b.components()
+                .count()
+                .cmp(&a.components().count())
+                .then_with(|| a.cmp(b))
+        });
+        dirs.dedup();
+        for dir in dirs.iter() {
+            if dir.is_dir() && fs::read_dir(dir)?.next().is_none() {
+                log::info!("Deleting torrent directory {:?}", dir);
+                fs::remove_dir(dir)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 // TODO(https://github.com/mandreyel/cratetorrent/issues/22):
@@ -25,6 +25,10 @@ pub(crate) struct PieceDownload {
     /// The blocks in this piece, tracking which are downloaded, pending, or
     /// received. The vec is preallocated to the number of blocks in piece.
     blocks: Vec<BlockStatus>,
+    /// Whether the piece is at risk of missing its deadline, in which case the
+    /// fastest peers request its blocks even if they are already requested
+    /// from other peers.
+    is_urgent: bool,
 }
 
 impl PieceDownload {
@@ -33,7 +37,12 @@ impl PieceDownload {
         let block_count = block_count(len);
         let mut blocks = Vec::new();
         blocks.resize_with(block_count, Default::default);
-        Self { index, len, blocks }
+        Self {
+            index,
+            len,
+            blocks,
+            is_urgent: false,
+        }
     }
 
     /// Returns the index of the piece that is downloaded.
@@ -41,6 +50,16 @@ impl PieceDownload {
         self.index
     }
 
+    /// Returns whether the piece is at risk of missing its deadline.
+    pub fn is_urgent(&self) -> bool {
+        self.is_urgent
+    }
+
+    /// Sets whether the piece is at risk of missing its deadline.
+    pub fn set_urgent(&mut self, is_urgent: bool) {
+        self.is_urgent = is_urgent;
+    }
+
     /// Picks the requested number of blocks or fewer, if fewer are remaining.
     /// If we're in end game mode, we ignore blocks requested by other peers.
     pub fn pick_blocks(
@@ -138,6 +157,15 @@ impl PieceDownload {
         prev_status
     }
 
+    /// Marks the given block as received without it having been requested.
+    ///
+    /// This is used for blocks that were restored from resume data.
+    pub fn restore_block(&mut self, block: &BlockInfo) {
+        debug_assert_eq!(block.piece_index, self.index);
+        debug_assert!(block.offset < self.len);
+        self.blocks[block.index_in_piece()] = BlockStatus::Received;
+    }
+
     /// Marks all blocks free to be requested again.
     pub fn free_all_blocks(&mut self) {
         log::trace!("Canceling all blocks in piece {}", self.index);
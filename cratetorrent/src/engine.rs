@@ -16,25 +16,43 @@
 
 use std::{
     collections::HashMap,
-    net::{Ipv4Addr, SocketAddr},
+    fs,
+    net::{Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Instant,
 };
 
 use futures::stream::StreamExt;
+use reqwest::Url;
 use tokio::{
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
-    task,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task, time,
 };
 
 use crate::{
-    alert::{AlertReceiver, AlertSender},
+    alert::{Alert, AlertReceiver, AlertSender},
     conf::{Conf, TorrentConf},
+    dht,
     disk::{self, error::NewTorrentError},
     error::*,
-    metainfo::Metainfo,
+    listener::{self, IncomingPeer, ListenerHandle},
+    magnet::MagnetLink,
+    metadata::{self, Metadata, MetadataDownload},
+    metainfo::{Metainfo, MetainfoError},
+    peer::{
+        mse,
+        transport::{BoxTransport, Connector, DefaultConnector, Transport},
+        Extension,
+    },
+    resume::ResumeData,
     storage_info::StorageInfo,
+    stream::FileStream,
     torrent::{self, Torrent},
-    tracker::Tracker,
-    Bitfield, TorrentId,
+    tracker::{self, Tracker},
+    Bitfield, PieceIndex, Priority, Sha1Hash, TorrentId, DEFAULT_PRIORITY,
 };
 
 /// Spawns the engine as a tokio task.
@@ -52,6 +70,7 @@ pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
     let (alert_tx, alert_rx) = mpsc::unbounded_channel();
     let (mut engine, tx) = Engine::new(conf, alert_tx)?;
 
+    let listen_addr = engine.listener.addr;
     let join_handle = task::spawn(async move { engine.run().await });
     log::info!("Spawned engine task");
 
@@ -59,6 +78,7 @@ pub fn spawn(conf: Conf) -> Result<(EngineHandle, AlertReceiver)> {
         EngineHandle {
             tx,
             join_handle: Some(join_handle),
+            listen_addr,
         },
         alert_rx,
     ))
@@ -70,9 +90,19 @@ type JoinHandle = task::JoinHandle<Result<()>>;
 pub struct EngineHandle {
     tx: Sender,
     join_handle: Option<JoinHandle>,
+    listen_addr: SocketAddr,
 }
 
 impl EngineHandle {
+    /// Returns the address on which the engine listens for the connections of
+    /// the peers of all torrents.
+    ///
+    /// If the port in [`EngineConf::listen_addr`](crate::conf::EngineConf::listen_addr)
+    /// was 0, this contains the port that was actually assigned.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
     /// Creates and starts a torrent, if its metainfo is valid.
     ///
     /// If successful, it returns the id of the torrent. This id can be used to
@@ -84,6 +114,242 @@ impl EngineHandle {
         Ok(id)
     }
 
+    /// Creates and starts a torrent from a magnet link.
+    ///
+    /// The torrent's metadata is first downloaded from peers that support the
+    /// metadata exchange extension. Until then the torrent can only be
+    /// removed, and other commands result in an
+    /// [`Error::InvalidTorrentId`] alert. Once the metadata is downloaded, an
+    /// [`Alert::MetadataReceived`] is posted and the torrent is started as if
+    /// it had been created with [`Self::create_torrent`].
+    pub fn create_magnet_torrent(
+        &self,
+        params: MagnetParams,
+    ) -> Result<TorrentId> {
+        log::trace!("Creating torrent from magnet link");
+        let id = TorrentId::new();
+        self.tx.send(Command::CreateMagnetTorrent { id, params })?;
+        Ok(id)
+    }
+
+    /// Pauses the torrent with the given id.
+    ///
+    /// All of the torrent's peer sessions are gracefully disconnected and its
+    /// trackers are notified that the torrent has stopped, but its download
+    /// progress and its files on disk are kept. A paused torrent does not
+    /// connect to new peers or accept incoming connections until it is resumed
+    /// with [`Self::resume_torrent`].
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn pause_torrent(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Pausing torrent {}", id);
+        self.tx.send(Command::PauseTorrent(id))?;
+        Ok(())
+    }
+
+    /// Resumes a torrent previously paused with [`Self::pause_torrent`].
+    ///
+    /// The torrent announces to its trackers again and starts connecting to
+    /// peers from where it left off. Resuming a torrent that is not paused has
+    /// no effect.
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn resume_torrent(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Resuming torrent {}", id);
+        self.tx.send(Command::ResumeTorrent(id))?;
+        Ok(())
+    }
+
+    /// Verifies all pieces of the torrent with the given id against the data
+    /// on disk.
+    ///
+    /// The torrent disconnects its peers and drops its in-progress downloads
+    /// for the duration of the check. Its progress is reported in
+    /// [`Alert::TorrentCheckProgress`] alerts and an [`Alert::TorrentChecked`]
+    /// is posted once it's done, after which the torrent returns to its
+    /// previous state.
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn force_recheck(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Rechecking torrent {}", id);
+        self.tx.send(Command::ForceRecheck(id))?;
+        Ok(())
+    }
+
+    /// Sets the download priority of each file of the torrent with the given
+    /// id, in the same order as in the metainfo, see [`Priority`].
+    ///
+    /// Pieces of files with a higher priority are downloaded first, and files
+    /// with priority 0 are not downloaded at all. Pieces that overlap with
+    /// a downloaded file are still downloaded in full, so the parts of the
+    /// neighboring files they contain are written to disk as well. The
+    /// download is complete, and an [`Alert::TorrentComplete`] is posted, once
+    /// all pieces of the downloaded files are.
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted,
+    /// and if the number of priorities doesn't match the number of files, an
+    /// error alert is posted.
+    pub fn set_file_priorities(
+        &self,
+        id: TorrentId,
+        priorities: Vec<Priority>,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} file priorities", id);
+        self.tx
+            .send(Command::SetFilePriorities { id, priorities })?;
+        Ok(())
+    }
+
+    /// Sets whether the pieces of the torrent with the given id are downloaded
+    /// in order, rather than rarest first, see
+    /// [`TorrentConf::sequential_download`].
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn set_sequential_download(
+        &self,
+        id: TorrentId,
+        sequential: bool,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} sequential download", id);
+        self.tx
+            .send(Command::SetSequentialDownload { id, sequential })?;
+        Ok(())
+    }
+
+    /// Sets the time by which the piece of the torrent with the given id
+    /// should be downloaded, such as when it's about to be played, replacing
+    /// its previous deadline, if any.
+    ///
+    /// Pieces with a deadline are downloaded before all other pieces, in order
+    /// of their deadline, from the fastest peers. If a piece is at risk of
+    /// missing its deadline, its blocks are also requested from the other fast
+    /// peers in parallel. Once the piece is downloaded, or right away if we
+    /// already have it, an [`Alert::DeadlinePieceComplete`] is posted. Pieces
+    /// with priority 0 are not downloaded even if they have a deadline.
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted,
+    /// and if the piece index is not valid, an error alert is posted.
+    pub fn set_piece_deadline(
+        &self,
+        id: TorrentId,
+        index: PieceIndex,
+        deadline: Instant,
+    ) -> Result<()> {
+        log::trace!("Setting torrent {} piece {} deadline", id, index);
+        self.tx.send(Command::SetPieceDeadline {
+            id,
+            index,
+            deadline,
+        })?;
+        Ok(())
+    }
+
+    /// Removes the deadline of the piece of the torrent with the given id, so
+    /// that it's downloaded like any other piece, e.g. after the user skipped
+    /// past it.
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn reset_piece_deadline(
+        &self,
+        id: TorrentId,
+        index: PieceIndex,
+    ) -> Result<()> {
+        log::trace!("Resetting torrent {} piece {} deadline", id, index);
+        self.tx.send(Command::ResetPieceDeadline { id, index })?;
+        Ok(())
+    }
+
+    /// Opens a stream of the file with the given index, in the same order as
+    /// in the metainfo, of the torrent with the given id, which reads the file
+    /// while it's downloaded, see [`FileStream`].
+    ///
+    /// If the file was not being downloaded, i.e. its priority was 0, it's
+    /// downloaded with the default priority from now on.
+    ///
+    /// Returns an error if the torrent id or the file index is not valid. The
+    /// stream can't be opened until the metadata of a torrent created from
+    /// a magnet link is downloaded.
+    pub async fn open_file(
+        &self,
+        id: TorrentId,
+        file_index: usize,
+    ) -> Result<FileStream> {
+        log::trace!("Opening torrent {} file {}", id, file_index);
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx.send(Command::OpenFile {
+            id,
+            file_index,
+            result_tx,
+        })?;
+        // the sender is dropped if the torrent stopped
+        result_rx.await.map_err(|_| Error::Channel)?
+    }
+
+    /// Requests the resume data of the torrent with the given id.
+    ///
+    /// The resume data is collected asynchronously and is posted in an
+    /// [`Alert::ResumeData`]. It is most accurate if the torrent is paused
+    /// first, as otherwise its state may change by the time it's saved.
+    ///
+    /// If the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn save_resume_data(&self, id: TorrentId) -> Result<()> {
+        log::trace!("Saving torrent {} resume data", id);
+        self.tx.send(Command::SaveResumeData(id))?;
+        Ok(())
+    }
+
+    /// Requests the state of the engine's DHT node, which is posted in an
+    /// [`Alert::DhtState`].
+    ///
+    /// The state may be passed to the engine in
+    /// [`DhtConf::state`](crate::conf::DhtConf::state) on its next start. If
+    /// the DHT is not enabled, this does nothing.
+    pub fn save_dht_state(&self) -> Result<()> {
+        log::trace!("Saving DHT state");
+        self.tx.send(Command::SaveDhtState)?;
+        Ok(())
+    }
+
+    /// Hands the engine a connection that a peer made to us over a transport
+    /// of the library user's choice, such as a Unix socket.
+    ///
+    /// The connection is treated as if the engine's listener had accepted it:
+    /// the peer's handshake is received and the connection is routed to the
+    /// torrent whose info hash is in it, or dropped if there is no such
+    /// torrent.
+    pub fn add_incoming_peer<T: Transport + 'static>(
+        &self,
+        addr: SocketAddr,
+        socket: T,
+    ) -> Result<()> {
+        log::trace!("Adding incoming peer {}", addr);
+        self.tx.send(Command::AcceptPeer {
+            addr,
+            socket: Box::new(socket),
+        })?;
+        Ok(())
+    }
+
+    /// Removes the torrent with the given id from the engine.
+    ///
+    /// The torrent is gracefully shut down (its peers are disconnected and its
+    /// trackers are notified) and its disk entry is dropped, which closes its
+    /// files and frees its read cache. If `delete_files` is set, the torrent's
+    /// downloaded files are also deleted, along with any of its directories
+    /// that are left empty.
+    ///
+    /// An [`Alert::TorrentRemoved`] is posted once the removal is complete. If
+    /// the id is not valid, an [`Error::InvalidTorrentId`] alert is posted.
+    pub fn remove_torrent(
+        &self,
+        id: TorrentId,
+        delete_files: bool,
+    ) -> Result<()> {
+        log::trace!("Removing torrent {}", id);
+        self.tx.send(Command::RemoveTorrent { id, delete_files })?;
+        Ok(())
+    }
+
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     ///
@@ -114,22 +380,46 @@ pub struct TorrentParams {
     pub conf: Option<TorrentConf>,
     /// Whether to download or seed the torrent.
     ///
-    /// This is expected to be removed as this will become automatic once
-    /// torrent resume data is supported.
+    /// Which pieces we have is detected automatically: if valid resume data is
+    /// given, the pieces are restored from it, otherwise any existing files of
+    /// the torrent are verified before the torrent starts. This is thus only
+    /// used for the seeds to connect to.
     pub mode: Mode,
-    /// The address on which the torrent should listen for new peers.
+    /// The resume data of the torrent from a previous run, if any.
     ///
-    /// This has to be unique for each torrent. If not set, or if already in
-    /// use, a random port is assigned.
-    // TODO: probably use an engine wide address, but requires some
-    // rearchitecting
-    pub listen_addr: Option<SocketAddr>,
+    /// If the resume data doesn't belong to this torrent or the torrent's files
+    /// were modified since it was saved, it is not used and an error alert is
+    /// posted.
+    pub resume_data: Option<ResumeData>,
+    /// The download priority of each file in the torrent, in the same order as
+    /// in the metainfo, see [`Priority`]. Files with priority 0 are not
+    /// downloaded.
+    ///
+    /// If not set, the priorities are restored from the resume data, or
+    /// without it, all files are downloaded with the default priority. If the
+    /// number of priorities doesn't match the number of files, they are
+    /// ignored and an error alert is posted.
+    pub file_priorities: Option<Vec<Priority>>,
+    /// Handlers of custom extension protocol (BEP 10) messages, which are
+    /// advertised to the torrent's peers.
+    pub extensions: Vec<Arc<dyn Extension>>,
+}
+
+/// Information for creating a new torrent from a magnet link.
+pub struct MagnetParams {
+    /// The parsed magnet link.
+    pub magnet: MagnetLink,
+    /// If set, overrides the default global config.
+    pub conf: Option<TorrentConf>,
+    /// Handlers of custom extension protocol (BEP 10) messages, which are
+    /// advertised to the torrent's peers once its metadata is downloaded.
+    pub extensions: Vec<Arc<dyn Extension>>,
 }
 
 /// The download mode.
-// TODO: remove in favor of automatic detection
-// TODO: when seeding is specified, we need to verify that the files to be
-// seeded exist and are complete
+///
+/// Seeding a torrent requires its files to be present in the download
+/// directory, as they are verified before the torrent starts.
 #[derive(Debug)]
 pub enum Mode {
     Download { seeds: Vec<SocketAddr> },
@@ -148,6 +438,15 @@ pub(crate) enum Command {
         id: TorrentId,
         params: TorrentParams,
     },
+    /// Contains the magnet link from which to create a new torrent, once its
+    /// metadata is downloaded.
+    CreateMagnetTorrent { id: TorrentId, params: MagnetParams },
+    /// The result of downloading the metadata of a torrent created from
+    /// a magnet link.
+    MetadataDownload {
+        id: TorrentId,
+        result: Result<Box<Metadata>, MetainfoError>,
+    },
     /// Torrent allocation result. If successful, the id of the allocated
     /// torrent is returned for identification, if not, the reason of the error
     /// is included.
@@ -155,6 +454,64 @@ pub(crate) enum Command {
         id: TorrentId,
         result: Result<(), NewTorrentError>,
     },
+    /// Pauses the torrent, keeping its state so that it can be resumed later.
+    PauseTorrent(TorrentId),
+    /// Resumes a paused torrent.
+    ResumeTorrent(TorrentId),
+    /// Verifies the torrent's pieces on disk.
+    ForceRecheck(TorrentId),
+    /// Sets the download priority of each of the torrent's files.
+    SetFilePriorities {
+        id: TorrentId,
+        priorities: Vec<Priority>,
+    },
+    /// Sets whether the torrent's pieces are downloaded in order.
+    SetSequentialDownload { id: TorrentId, sequential: bool },
+    /// Sets the time by which the piece should be downloaded.
+    SetPieceDeadline {
+        id: TorrentId,
+        index: PieceIndex,
+        deadline: Instant,
+    },
+    /// Removes the deadline of the piece.
+    ResetPieceDeadline { id: TorrentId, index: PieceIndex },
+    /// Opens a stream of the torrent's file and returns it via the sender.
+    OpenFile {
+        id: TorrentId,
+        file_index: usize,
+        result_tx: oneshot::Sender<Result<FileStream>>,
+    },
+    /// Collects the torrent's resume data and posts it as an alert.
+    SaveResumeData(TorrentId),
+    /// Posts the state of the DHT node as an alert.
+    SaveDhtState,
+    /// Shuts down and removes the torrent, optionally deleting its files.
+    RemoveTorrent { id: TorrentId, delete_files: bool },
+    /// Torrent removal result, sent by the disk task once the torrent's disk
+    /// entry was dropped and, if requested, its files were deleted, or by the
+    /// removal task of a torrent that had no metadata yet.
+    TorrentRemoval {
+        id: TorrentId,
+        result: std::io::Result<()>,
+    },
+    /// Scrapes the trackers of all torrents, sent periodically by the scrape
+    /// timer task.
+    ScrapeTrackers,
+    /// A connection accepted by the listener, which is handed to the torrent
+    /// whose info hash is in the peer's handshake.
+    IncomingPeer(Box<IncomingPeer>),
+    /// A connection the user made over their own transport, which is accepted
+    /// like the connections of the listener.
+    AcceptPeer {
+        addr: SocketAddr,
+        socket: BoxTransport,
+    },
+    /// Looks up the info hash of the torrent that the peer of an encrypted
+    /// connection wants, by the hash of the info hash it sent.
+    FindEncryptedTorrent {
+        hash: Sha1Hash,
+        result_tx: oneshot::Sender<Option<Sha1Hash>>,
+    },
     /// Gracefully shuts down the engine and waits for all its torrents to do
     /// the same.
     Shutdown,
@@ -163,6 +520,15 @@ pub(crate) enum Command {
 struct Engine {
     /// All currently running torrents in engine.
     torrents: HashMap<TorrentId, TorrentEntry>,
+    /// The torrents created from magnet links whose metadata is still being
+    /// downloaded.
+    metadata_downloads: HashMap<TorrentId, MetadataEntry>,
+    /// The tasks that stop the torrents being removed, which are joined on
+    /// shutdown so that the removals reach the disk task before it stops.
+    removals: HashMap<TorrentId, task::JoinHandle<()>>,
+    /// A copy of the engine's own command channel, passed to metadata
+    /// downloads for returning their results.
+    cmd_tx: Sender,
 
     /// The port on which other entities in the engine, or the API consumer
     /// sends the engine commands.
@@ -172,35 +538,121 @@ struct Engine {
     disk_tx: disk::Sender,
     disk_join_handle: Option<disk::JoinHandle>,
 
+    /// The listener that accepts the connections of all torrents' peers, which
+    /// stops when the engine is dropped.
+    listener: ListenerHandle,
+    /// Our IPv6 address, if we accept IPv6 connections.
+    ipv6_addr: Option<Ipv6Addr>,
+    /// Makes the outbound connections of all torrents and metadata downloads.
+    connector: Arc<dyn Connector>,
+
+    /// The DHT node, if enabled.
+    dht: Option<dht::DhtHandle>,
+    dht_join_handle: Option<dht::JoinHandle>,
+
     /// The channel on which tasks in the engine post alerts to user.
     alert_tx: AlertSender,
 
+    /// The tracker clients used for scraping, shared by all torrents that use
+    /// the same tracker so that their scrapes can be batched.
+    scrape_trackers: HashMap<Url, Arc<Tracker>>,
+
     /// The global engine configuration that includes defaults for torrents
     /// whose config is not overridden.
     conf: Conf,
 }
 
+/// A torrent's entry in the engine while its metadata is being downloaded.
+struct MetadataEntry {
+    /// The metadata download's command channel.
+    tx: metadata::Sender,
+    /// The metadata download task's join handle.
+    join_handle: Option<task::JoinHandle<Result<()>>>,
+    /// The parameters with which to create the torrent once the metadata is
+    /// downloaded.
+    conf: Option<TorrentConf>,
+    extensions: Vec<Arc<dyn Extension>>,
+}
+
 /// A running torrent's entry in the engine.
 struct TorrentEntry {
     /// The torrent's command channel on which engine sends commands to torrent.
     tx: torrent::Sender,
     /// The torrent task's join handle, used during shutdown.
     join_handle: Option<task::JoinHandle<torrent::error::Result<()>>>,
+    /// The info hash of the torrent, with which it is scraped.
+    info_hash: Sha1Hash,
+    /// The URLs of the torrent's trackers, in all tiers.
+    tracker_urls: Vec<Url>,
+    /// Whether the user paused the torrent, in which case the connections of
+    /// its peers are refused before they are handed to it.
+    is_paused: bool,
 }
 
 impl Engine {
-    /// Creates a new engine, spawning the disk task.
+    /// Creates a new engine, spawning the disk task and the DHT task, if the
+    /// DHT is enabled.
     fn new(conf: Conf, alert_tx: AlertSender) -> Result<(Self, Sender)> {
+        conf.engine.validate()?;
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
         let (disk_join_handle, disk_tx) = disk::spawn(cmd_tx.clone())?;
+        let listener = listener::spawn(
+            conf.engine.listen_addr,
+            conf.engine.utp,
+            cmd_tx.clone(),
+        )?;
+        let ipv6_addr = if listener.addr.is_ipv6() {
+            listener::local_ipv6_addr()
+        } else {
+            None
+        };
+        log::info!("IPv6 address: {:?}", ipv6_addr);
+        let connector = conf.engine.connector.clone().unwrap_or_else(|| {
+            Arc::new(DefaultConnector::new(listener.utp.clone()))
+        });
+        let (dht_join_handle, dht) = match &conf.engine.dht {
+            Some(dht_conf) => {
+                let (join_handle, dht) =
+                    dht::spawn(dht_conf.clone(), alert_tx.clone())?;
+                (Some(join_handle), Some(dht))
+            }
+            None => (None, None),
+        };
+
+        // periodically tell the engine to scrape its torrents' trackers, until
+        // the engine stops
+        if let Some(scrape_interval) = conf.engine.scrape_interval {
+            let scrape_tx = cmd_tx.clone();
+            task::spawn(async move {
+                let mut timer = time::interval_at(
+                    time::Instant::now() + scrape_interval,
+                    scrape_interval,
+                );
+                loop {
+                    timer.tick().await;
+                    if scrape_tx.send(Command::ScrapeTrackers).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
 
         Ok((
             Self {
                 torrents: HashMap::new(),
+                metadata_downloads: HashMap::new(),
+                removals: HashMap::new(),
+                cmd_tx: cmd_tx.clone(),
                 cmd_rx,
                 disk_tx,
                 disk_join_handle: Some(disk_join_handle),
+                listener,
+                ipv6_addr,
+                connector,
+                dht,
+                dht_join_handle,
                 alert_tx,
+                scrape_trackers: HashMap::new(),
                 conf,
             },
             cmd_tx,
@@ -217,6 +669,12 @@ impl Engine {
                 Command::CreateTorrent { id, params } => {
                     self.create_torrent(id, params).await?;
                 }
+                Command::CreateMagnetTorrent { id, params } => {
+                    self.create_magnet_torrent(id, params)?;
+                }
+                Command::MetadataDownload { id, result } => {
+                    self.handle_metadata_download(id, result).await?;
+                }
                 Command::TorrentAllocation { id, result } => match result {
                     Ok(_) => {
                         log::info!("Torrent {} allocated on disk", id);
@@ -229,6 +687,127 @@ impl Engine {
                         );
                     }
                 },
+                Command::PauseTorrent(id) => {
+                    if let Some(torrent) = self.torrents.get_mut(&id) {
+                        torrent.is_paused = true;
+                    }
+                    self.send_torrent_cmd(id, torrent::Command::Pause)?;
+                }
+                Command::ResumeTorrent(id) => {
+                    if let Some(torrent) = self.torrents.get_mut(&id) {
+                        torrent.is_paused = false;
+                    }
+                    self.send_torrent_cmd(id, torrent::Command::Resume)?;
+                }
+                Command::ForceRecheck(id) => {
+                    self.send_torrent_cmd(id, torrent::Command::ForceRecheck)?;
+                }
+                Command::SetFilePriorities { id, priorities } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::SetFilePriorities(priorities),
+                    )?;
+                }
+                Command::SetSequentialDownload { id, sequential } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::SetSequentialDownload(sequential),
+                    )?;
+                }
+                Command::SetPieceDeadline {
+                    id,
+                    index,
+                    deadline,
+                } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::SetPieceDeadline { index, deadline },
+                    )?;
+                }
+                Command::ResetPieceDeadline { id, index } => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::ResetPieceDeadline(index),
+                    )?;
+                }
+                Command::OpenFile {
+                    id,
+                    file_index,
+                    result_tx,
+                } => match self.torrents.get(&id) {
+                    Some(torrent) => {
+                        // if the torrent task stopped, the sender is dropped
+                        // and the user gets an error
+                        torrent
+                            .tx
+                            .send(torrent::Command::OpenFile {
+                                file_index,
+                                result_tx,
+                            })
+                            .ok();
+                    }
+                    None => {
+                        log::warn!("Torrent {} not found", id);
+                        result_tx.send(Err(Error::InvalidTorrentId)).ok();
+                    }
+                },
+                Command::SaveResumeData(id) => {
+                    self.send_torrent_cmd(
+                        id,
+                        torrent::Command::SaveResumeData,
+                    )?;
+                }
+                Command::SaveDhtState => match &self.dht {
+                    Some(dht) => {
+                        if dht.tx.send(dht::Command::SaveState).is_err() {
+                            log::warn!("DHT task is not running");
+                        }
+                    }
+                    None => log::warn!("DHT not enabled, cannot save state"),
+                },
+                Command::RemoveTorrent { id, delete_files } => {
+                    self.remove_torrent(id, delete_files)?;
+                }
+                Command::TorrentRemoval { id, result } => {
+                    self.removals.remove(&id);
+                    match result {
+                        Ok(_) => {
+                            log::info!("Torrent {} removed", id);
+                            self.alert_tx.send(Alert::TorrentRemoved(id))?;
+                        }
+                        Err(e) => {
+                            log::error!(
+                                "Error deleting torrent {} files: {}",
+                                id,
+                                e
+                            );
+                            self.alert_tx.send(Alert::Error(
+                                Error::Torrent {
+                                    id,
+                                    error: TorrentError::Io(e),
+                                },
+                            ))?;
+                        }
+                    }
+                }
+                Command::ScrapeTrackers => self.scrape_trackers(),
+                Command::IncomingPeer(peer) => self.route_incoming_peer(peer),
+                Command::AcceptPeer { addr, socket } => {
+                    listener::accept(addr, socket, self.cmd_tx.clone())
+                }
+                Command::FindEncryptedTorrent { hash, result_tx } => {
+                    // paused torrents don't accept peers, so the encrypted
+                    // handshake fails without a match
+                    let info_hash = self
+                        .torrents
+                        .values()
+                        .filter(|t| !t.is_paused)
+                        .map(|t| t.info_hash)
+                        .find(|info_hash| {
+                            mse::info_hash_hash(info_hash) == hash
+                        });
+                    result_tx.send(info_hash).ok();
+                }
                 Command::Shutdown => {
                     self.shutdown().await?;
                     break;
@@ -252,34 +831,95 @@ impl Engine {
         );
         // TODO: don't duplicate trackers if multiple torrents use the same
         // ones (common in practice)
+        let tracker_urls =
+            params.metainfo.trackers.iter().flatten().cloned().collect();
         let trackers = params
             .metainfo
             .trackers
             .into_iter()
-            .map(Tracker::new)
+            .map(|tier| tier.into_iter().map(Tracker::new).collect())
             .collect();
-        let own_pieces = params.mode.own_pieces(storage_info.piece_count);
+        // only use resume data if it is still valid
+        let resume_data = match params.resume_data {
+            Some(resume_data)
+                if resume_data
+                    .is_valid(&params.metainfo.info_hash, &storage_info) =>
+            {
+                log::info!("Restoring torrent {} from resume data", id);
+                Some(resume_data)
+            }
+            Some(_) => {
+                log::warn!("Torrent {} resume data invalid, ignoring", id);
+                self.alert_tx.send(Alert::Error(Error::Torrent {
+                    id,
+                    error: TorrentError::InvalidResumeData,
+                }))?;
+                None
+            }
+            None => None,
+        };
+        // the priorities given by the user take precedence over the ones in
+        // the resume data
+        let file_count = storage_info.files.len();
+        let file_priorities = match params.file_priorities {
+            Some(priorities) if priorities.len() == file_count => priorities,
+            Some(_) => {
+                log::warn!("Torrent {} file priorities invalid, ignoring", id);
+                self.alert_tx.send(Alert::Error(Error::Torrent {
+                    id,
+                    error: TorrentError::InvalidFilePriorities,
+                }))?;
+                vec![DEFAULT_PRIORITY; file_count]
+            }
+            None => resume_data
+                .as_ref()
+                .map(|resume_data| resume_data.file_priorities.clone())
+                .filter(|priorities| priorities.len() == file_count)
+                .unwrap_or_else(|| vec![DEFAULT_PRIORITY; file_count]),
+        };
+        // without resume data we don't know which pieces we have, so if any of
+        // the torrent's files exist, they need to be checked first
+        let (own_pieces, needs_check) =
+            match resume_data.as_ref().and_then(ResumeData::own_pieces) {
+                Some(own_pieces) => (own_pieces, false),
+                None => (
+                    Bitfield::repeat(false, storage_info.piece_count),
+                    storage_info.files.iter().any(|file| {
+                        fs::metadata(storage_info.download_dir.join(&file.path))
+                            .map(|metadata| metadata.len() > 0)
+                            .unwrap_or(false)
+                    }),
+                ),
+            };
 
         // create and spawn torrent
-        // TODO: For now we spawn automatically, but later when we add torrent
-        // pause/restart APIs, this will be a separate step. There should be
-        // a `start` flag in `params` that says whether to immediately spawn
-        // a new torrent (or maybe in `TorrentConf`).
+        // TODO: For now we spawn automatically. There should be a `start` flag
+        // in `params` that says whether to immediately start a new torrent or
+        // to create it in the paused state (or maybe in `TorrentConf`).
         let (mut torrent, torrent_tx) = Torrent::new(torrent::Params {
             id,
             disk_tx: self.disk_tx.clone(),
             info_hash: params.metainfo.info_hash,
             storage_info: storage_info.clone(),
             own_pieces,
+            file_priorities: file_priorities.clone(),
             trackers,
             client_id: self.conf.engine.client_id,
-            listen_addr: params.listen_addr.unwrap_or_else(|| {
-                // the port 0 tells the kernel to assign a free port from the
-                // dynamic range
-                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
-            }),
+            listen_addr: self.listener.addr,
+            ipv6_addr: self.ipv6_addr,
             conf,
             alert_tx: self.alert_tx.clone(),
+            resume_data,
+            needs_check,
+            extensions: params.extensions,
+            is_private: params.metainfo.is_private,
+            // private torrents may only get peers from their trackers
+            dht: if params.metainfo.is_private {
+                None
+            } else {
+                self.dht.clone()
+            },
+            connector: Arc::clone(&self.connector),
         });
 
         // Allocate torrent on disk. This is an asynchronous process and we can
@@ -300,6 +940,7 @@ impl Engine {
         self.disk_tx.send(disk::Command::NewTorrent {
             id,
             storage_info,
+            file_priorities,
             piece_hashes: params.metainfo.pieces,
             torrent_tx: torrent_tx.clone(),
         })?;
@@ -313,16 +954,298 @@ impl Engine {
             TorrentEntry {
                 tx: torrent_tx,
                 join_handle: Some(join_handle),
+                info_hash: params.metainfo.info_hash,
+                tracker_urls,
+                is_paused: false,
             },
         );
 
         Ok(())
     }
 
+    /// Hands the connection accepted by the listener to the torrent whose info
+    /// hash the peer sent in its handshake, or drops the connection without
+    /// answering the handshake if there is no such torrent or it's paused.
+    fn route_incoming_peer(&self, peer: Box<IncomingPeer>) {
+        let info_hash = peer.handshake.info_hash;
+        match self.torrents.values().find(|t| t.info_hash == info_hash) {
+            Some(torrent) if torrent.is_paused => log::info!(
+                "Rejecting peer {} of paused torrent {}",
+                peer.addr,
+                hex::encode(info_hash)
+            ),
+            Some(torrent) => {
+                // the torrent may be shutting down
+                torrent.tx.send(torrent::Command::IncomingPeer(peer)).ok();
+            }
+            None => log::info!(
+                "Rejecting peer {} of unknown torrent {}",
+                peer.addr,
+                hex::encode(info_hash)
+            ),
+        }
+    }
+
+    /// Scrapes the trackers of all torrents, and sends each torrent its swarm
+    /// statistics.
+    ///
+    /// The info hashes of the torrents that share a tracker are scraped
+    /// together, in as few requests as possible.
+    fn scrape_trackers(&mut self) {
+        let batches_by_tracker =
+            batch_scrapes(self.torrents.values().map(|torrent| {
+                (
+                    &torrent.tracker_urls[..],
+                    (torrent.info_hash, torrent.tx.clone()),
+                )
+            }));
+
+        // drop the clients of trackers that are no longer used
+        self.scrape_trackers
+            .retain(|url, _| batches_by_tracker.contains_key(url));
+
+        let timeout = self.conf.engine.scrape_timeout;
+        for (url, batches) in batches_by_tracker {
+            let tracker = self
+                .scrape_trackers
+                .entry(url.clone())
+                .or_insert_with(|| Arc::new(Tracker::new(url.clone())));
+            if !tracker.supports_scrape() {
+                continue;
+            }
+            log::debug!(
+                "Scraping torrents from tracker {} in {} request(s)",
+                tracker,
+                batches.len()
+            );
+            for batch in batches {
+                let tracker = Arc::clone(tracker);
+                task::spawn(async move {
+                    let info_hashes: Vec<_> =
+                        batch.iter().map(|(info_hash, _)| *info_hash).collect();
+                    let result =
+                        time::timeout(timeout, tracker.scrape(&info_hashes))
+                            .await
+                            .unwrap_or(Err(tracker::TrackerError::Timeout));
+                    match result {
+                        Ok(stats) => {
+                            send_scrape_results(tracker.url(), &batch, stats)
+                        }
+                        Err(e) => {
+                            log::warn!(
+                                "Error scraping tracker {}: {}",
+                                tracker,
+                                e
+                            );
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    /// Spawns the download of the metadata of a torrent created from
+    /// a magnet link.
+    fn create_magnet_torrent(
+        &mut self,
+        id: TorrentId,
+        params: MagnetParams,
+    ) -> Result<()> {
+        let conf = params
+            .conf
+            .clone()
+            .unwrap_or_else(|| self.conf.torrent.clone());
+        let (mut download, tx) = MetadataDownload::new(metadata::Params {
+            id,
+            info_hash: params.magnet.info_hash,
+            client_id: self.conf.engine.client_id,
+            trackers: params.magnet.trackers,
+            peers: params.magnet.peers,
+            port: self.listener.addr.port(),
+            conf,
+            dht: self.dht.clone(),
+            connector: Arc::clone(&self.connector),
+            engine_tx: self.cmd_tx.clone(),
+            alert_tx: self.alert_tx.clone(),
+        });
+        let join_handle = task::spawn(async move { download.run().await });
+
+        self.metadata_downloads.insert(
+            id,
+            MetadataEntry {
+                tx,
+                join_handle: Some(join_handle),
+                conf: params.conf,
+                extensions: params.extensions,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Creates the torrent whose metadata was downloaded, or notifies the user
+    /// if the metadata is invalid.
+    async fn handle_metadata_download(
+        &mut self,
+        id: TorrentId,
+        result: Result<Box<Metadata>, MetainfoError>,
+    ) -> Result<()> {
+        // the torrent may have been removed in the meantime
+        let mut entry = match self.metadata_downloads.remove(&id) {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        if let Some(join_handle) = entry.join_handle.take() {
+            if let Err(e) = join_handle.await.expect("task error") {
+                log::error!("Torrent {} metadata download error: {}", id, e);
+            }
+        }
+
+        match result {
+            Ok(metadata) => {
+                let Metadata { metainfo, peers } = *metadata;
+                log::info!("Torrent {} metadata: {:?}", id, metainfo);
+                self.alert_tx.send(Alert::MetadataReceived {
+                    id,
+                    metainfo: Box::new(metainfo.clone()),
+                })?;
+                self.create_torrent(
+                    id,
+                    TorrentParams {
+                        metainfo,
+                        conf: entry.conf,
+                        mode: Mode::Download { seeds: peers },
+                        resume_data: None,
+                        file_priorities: None,
+                        extensions: entry.extensions,
+                    },
+                )
+                .await
+            }
+            Err(e) => {
+                log::warn!("Torrent {} metadata invalid: {}", id, e);
+                self.alert_tx.send(Alert::Error(Error::Torrent {
+                    id,
+                    error: TorrentError::InvalidMetadata(e),
+                }))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Shuts down the torrent task and then tells disk to remove the torrent's
+    /// entry, and optionally its files.
+    ///
+    /// The torrent is stopped on a separate task, as it announces its exit to
+    /// its trackers, which may take a while, and the engine would otherwise
+    /// stall all other torrents in the meantime. The removal is reported with
+    /// [`Command::TorrentRemoval`] once it's complete.
+    fn remove_torrent(
+        &mut self,
+        id: TorrentId,
+        delete_files: bool,
+    ) -> Result<()> {
+        // a torrent without metadata has no files or disk entry yet
+        if let Some(mut entry) = self.metadata_downloads.remove(&id) {
+            log::info!("Stopping torrent {} metadata download for removal", id);
+            entry.tx.send(metadata::Command::Shutdown).ok();
+            let join_handle = entry.join_handle.take();
+            let engine_tx = self.cmd_tx.clone();
+            let removal = task::spawn(async move {
+                if let Some(join_handle) = join_handle {
+                    if let Err(e) = join_handle.await.expect("task error") {
+                        log::error!(
+                            "Torrent {} metadata download error: {}",
+                            id,
+                            e
+                        );
+                    }
+                }
+                // the engine may have stopped in the meantime
+                engine_tx
+                    .send(Command::TorrentRemoval { id, result: Ok(()) })
+                    .ok();
+            });
+            self.removals.insert(id, removal);
+            return Ok(());
+        }
+
+        let mut torrent = match self.torrents.remove(&id) {
+            Some(torrent) => torrent,
+            None => {
+                log::warn!("Torrent {} not found", id);
+                self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+                return Ok(());
+            }
+        };
+
+        log::info!("Shutting down torrent {} for removal", id);
+        // the torrent task may no longer be running, so don't panic here
+        torrent.tx.send(torrent::Command::Shutdown).ok();
+        let join_handle = torrent.join_handle.take();
+        let disk_tx = self.disk_tx.clone();
+        let removal = task::spawn(async move {
+            if let Some(join_handle) = join_handle {
+                if let Err(e) = join_handle.await.expect("task error") {
+                    log::error!("Torrent error: {}", e);
+                }
+            }
+
+            // The torrent's peer sessions have all been stopped by now, so
+            // all their disk commands are queued before this one, and it's
+            // safe to remove the torrent from disk. The disk task replies with
+            // the result.
+            disk_tx
+                .send(disk::Command::RemoveTorrent { id, delete_files })
+                .ok();
+        });
+        self.removals.insert(id, removal);
+
+        Ok(())
+    }
+
+    /// Forwards the command to the torrent with the given id.
+    ///
+    /// If no such torrent exists, the user is notified via an alert but this
+    /// is not an engine error.
+    fn send_torrent_cmd(
+        &self,
+        id: TorrentId,
+        cmd: torrent::Command,
+    ) -> Result<()> {
+        match self.torrents.get(&id) {
+            Some(torrent) => {
+                // the torrent task may have stopped due to an error, which has
+                // already been reported to user
+                if torrent.tx.send(cmd).is_err() {
+                    log::warn!("Torrent {} task is not running", id);
+                }
+            }
+            None => {
+                log::warn!("Torrent {} not found", id);
+                self.alert_tx.send(Alert::Error(Error::InvalidTorrentId))?;
+            }
+        }
+        Ok(())
+    }
+
     /// Gracefully shuts down the engine and all its components.
     async fn shutdown(&mut self) -> Result<()> {
         log::info!("Shutting down engine");
 
+        // stop downloading metadata, these torrents don't have anything to
+        // clean up
+        for entry in self.metadata_downloads.values_mut() {
+            entry.tx.send(metadata::Command::Shutdown).ok();
+        }
+        for entry in self.metadata_downloads.values_mut() {
+            if let Some(join_handle) = entry.join_handle.take() {
+                if let Err(e) = join_handle.await.expect("task error") {
+                    log::error!("Metadata download error: {}", e);
+                }
+            }
+        }
+
         // tell all torrents to shut down and join their tasks
         for torrent in self.torrents.values_mut() {
             // the torrent task may no longer be running, so don't panic here
@@ -343,6 +1266,20 @@ impl Engine {
                 log::error!("Torrent error: {}", e);
             }
         }
+        for (_, removal) in self.removals.drain() {
+            removal.await.expect("task error");
+        }
+
+        // the torrents no longer use the DHT, so it can be shut down too
+        if let Some(dht) = &self.dht {
+            // the DHT task may no longer be running, so don't panic here
+            dht.tx.send(dht::Command::Shutdown).ok();
+        }
+        if let Some(join_handle) = self.dht_join_handle.take() {
+            if let Err(e) = join_handle.await.expect("DHT task has panicked") {
+                log::error!("DHT error: {}", e);
+            }
+        }
 
         // send a shutdown command to disk
         self.disk_tx.send(disk::Command::Shutdown)?;
@@ -359,13 +1296,6 @@ impl Engine {
 }
 
 impl Mode {
-    fn own_pieces(&self, piece_count: usize) -> Bitfield {
-        match self {
-            Self::Download { .. } => Bitfield::repeat(false, piece_count),
-            Self::Seed => Bitfield::repeat(true, piece_count),
-        }
-    }
-
     fn seeds(self) -> Vec<SocketAddr> {
         match self {
             Self::Download { seeds } => seeds,
@@ -373,3 +1303,162 @@ impl Mode {
         }
     }
 }
+
+/// A torrent to scrape: its info hash and the channel on which it's sent its
+/// swarm statistics.
+type ScrapeTarget = (Sha1Hash, torrent::Sender);
+
+/// Groups the torrents by their trackers, given the URLs of the trackers of
+/// each torrent, and splits the torrents of each tracker into batches that
+/// are small enough to be scraped with a single request.
+fn batch_scrapes<'a>(
+    torrents: impl Iterator<Item = (&'a [Url], ScrapeTarget)>,
+) -> HashMap<&'a Url, Vec<Vec<ScrapeTarget>>> {
+    let mut batches_by_tracker: HashMap<&Url, Vec<Vec<_>>> = HashMap::new();
+    for (urls, target) in torrents {
+        for url in urls {
+            let batches = batches_by_tracker.entry(url).or_default();
+            match batches.last_mut() {
+                Some(batch)
+                    if batch.len() < tracker::MAX_SCRAPE_INFO_HASH_COUNT =>
+                {
+                    batch.push(target.clone())
+                }
+                _ => batches.push(vec![target.clone()]),
+            }
+        }
+    }
+    batches_by_tracker
+}
+
+/// Sends the torrents of the batch their swarm statistics from the result of
+/// scraping the tracker with the URL.
+///
+/// The tracker may leave out torrents it doesn't know, and the torrents may
+/// have been removed in the meantime, neither of which is an error.
+fn send_scrape_results(
+    url: &Url,
+    batch: &[ScrapeTarget],
+    stats: Vec<(Sha1Hash, tracker::ScrapeStats)>,
+) {
+    for (info_hash, stats) in stats {
+        for (_, tx) in batch.iter().filter(|(hash, _)| *hash == info_hash) {
+            tx.send(torrent::Command::ScrapeResult {
+                url: url.clone(),
+                stats,
+            })
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        conf::DhtConf,
+        tracker::{ScrapeStats, MAX_SCRAPE_INFO_HASH_COUNT},
+    };
+
+    /// Tests that the engine doesn't start if uTP would listen on the DHT's
+    /// UDP port, but does if either is on another port or uTP is disabled.
+    #[tokio::test]
+    async fn should_reject_utp_on_dht_port() {
+        let mut conf = Conf::new("/tmp");
+        conf.engine.listen_addr = "127.0.0.1:6881".parse().unwrap();
+        conf.engine.dht = Some(DhtConf::default());
+        assert!(matches!(conf.engine.validate(), Err(Error::InvalidConf(_))));
+        assert!(matches!(spawn(conf.clone()), Err(Error::InvalidConf(_))));
+
+        conf.engine.utp = false;
+        assert!(conf.engine.validate().is_ok());
+        conf.engine.utp = true;
+        conf.engine.listen_addr = "127.0.0.1:6882".parse().unwrap();
+        assert!(conf.engine.validate().is_ok());
+        conf.engine.listen_addr = "127.0.0.1:0".parse().unwrap();
+        assert!(conf.engine.validate().is_ok());
+    }
+
+    #[test]
+    fn should_batch_scrapes_by_tracker() {
+        let url_a: Url = "http://a.example.com/announce".parse().unwrap();
+        let url_b: Url = "udp://b.example.com:6969".parse().unwrap();
+        let only_a = [url_a.clone()];
+        let both = [url_a.clone(), url_b.clone()];
+        let (tx, _rx) = mpsc::unbounded_channel();
+
+        // more torrents use the first tracker than fit in a single request
+        let torrent_count = MAX_SCRAPE_INFO_HASH_COUNT + 10;
+        let torrents = (0..torrent_count).map(|i| {
+            let urls = if i % 10 == 0 { &both[..] } else { &only_a[..] };
+            ([i as u8; 20], urls)
+        });
+        let batches = batch_scrapes(
+            torrents.map(|(info_hash, urls)| (urls, (info_hash, tx.clone()))),
+        );
+        assert_eq!(batches.len(), 2);
+
+        let batches_a = &batches[&url_a];
+        assert_eq!(batches_a.len(), 2);
+        assert!(batches_a
+            .iter()
+            .all(|batch| batch.len() <= MAX_SCRAPE_INFO_HASH_COUNT));
+        let info_hashes_a: Vec<_> = batches_a
+            .iter()
+            .flatten()
+            .map(|(hash, _)| hash[0])
+            .collect();
+        assert_eq!(info_hashes_a, (0..torrent_count as u8).collect::<Vec<_>>());
+
+        let batches_b = &batches[&url_b];
+        assert_eq!(batches_b.len(), 1);
+        let info_hashes_b: Vec<_> =
+            batches_b[0].iter().map(|(hash, _)| hash[0]).collect();
+        assert_eq!(
+            info_hashes_b,
+            (0..torrent_count as u8).step_by(10).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn should_send_scrape_results_to_their_torrents() {
+        let url: Url = "http://example.com/announce".parse().unwrap();
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        let (tx3, mut rx3) = mpsc::unbounded_channel();
+        let batch = [([1; 20], tx1), ([2; 20], tx2), ([3; 20], tx3)];
+        let stats = |seeder_count| ScrapeStats {
+            seeder_count,
+            leecher_count: 1,
+            download_count: 2,
+        };
+
+        // the tracker doesn't know the third torrent, and returns the results
+        // in a different order, along with one for a torrent we didn't ask
+        // for
+        send_scrape_results(
+            &url,
+            &batch,
+            vec![
+                ([2; 20], stats(20)),
+                ([4; 20], stats(40)),
+                ([1; 20], stats(10)),
+            ],
+        );
+
+        for (rx, seeder_count) in [(&mut rx1, 10), (&mut rx2, 20)].iter_mut() {
+            match rx.try_recv() {
+                Ok(torrent::Command::ScrapeResult {
+                    url: result_url,
+                    stats: result_stats,
+                }) => {
+                    assert_eq!(result_url, url);
+                    assert_eq!(result_stats, stats(*seeder_count));
+                }
+                _ => panic!("torrent didn't receive its scrape result"),
+            }
+            assert!(rx.try_recv().is_err());
+        }
+        assert!(rx3.try_recv().is_err());
+    }
+}
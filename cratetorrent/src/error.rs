@@ -23,6 +23,8 @@ pub enum Error {
     // TODO: consider adding more variations (path exists, doesn't exist,
     // permission issues)
     InvalidDownloadPath,
+    /// The engine configuration is invalid, for the given reason.
+    InvalidConf(&'static str),
     /// The torrent ID did not correspond to any entry. This is returned when
     /// the user specified a torrent that does not exist.
     InvalidTorrentId,
@@ -46,6 +48,7 @@ impl fmt::Display for Error {
         match self {
             Channel => write!(fmt, "channel error"),
             InvalidDownloadPath => write!(fmt, "invalid download path"),
+            InvalidConf(reason) => write!(fmt, "invalid conf: {}", reason),
             InvalidTorrentId => write!(fmt, "invalid torrent id"),
             Io(e) => e.fmt(fmt),
             Torrent { id, error } => {
@@ -10,9 +10,8 @@
 //! future, however.
 //!
 //! It also lacks most features present in battle-hardened torrent engines, such
-//! as [libtorrent](https://github.com/arvidn/libtorrent). These include: DHT
-//! for peer exchange, magnet links, stream encryption, UDP trackers, and many
-//! more.
+//! as [libtorrent](https://github.com/arvidn/libtorrent). These include: stream
+//! encryption, and many more.
 //!
 //! Therefore in the current state of the project, this should only be viewed as
 //! a toy program.
@@ -45,9 +44,9 @@
 //! [constructor](crate::metainfo::Metainfo::from_bytes). This will fail if the
 //! metainfo is semantically or syntactically invalid.
 //!
-//! Note that in order to download a torrent the metainfo has to contain HTTP
-//! trackers, or some seeds have to be manually specified. As mentioned above,
-//! DHT or even UDP trackers are not currently supported.
+//! Note that in order to download a torrent the metainfo has to contain HTTP or
+//! UDP trackers, some seeds have to be manually specified, or the
+//! [DHT](crate::conf::EngineConf::dht) has to be enabled.
 //!
 //! Once this is done, a command to the engine has to be sent to create the
 //! torrent. This is done using
@@ -61,10 +60,24 @@
 //! configuration is used for all new torrents, but this way it is possible to
 //! configure a torrent on a case-by-case basis.
 //!
-//! For now, the torrent's download mode has to be specified via `Mode`:
-//! whether to download or seed (upload) the torrent. If the latter is chosen,
-//! the torrent's contents _have_ to exist in the directory specified as the
-//! torrent's download directory in `TorrentConf`.
+//! The torrent's download mode is specified via `Mode`: whether to download or
+//! seed (upload) the torrent. The pieces we have are detected automatically: if
+//! any of the torrent's files already exist in the download directory, they are
+//! verified before the torrent is started. Thus, when seeding, the torrent's
+//! contents _have_ to exist in the download directory.
+//!
+//! A torrent that was started in a previous run of the engine can be continued
+//! from where it left off without verifying its files by passing its [resume
+//! data](crate::resume) in `TorrentParams`. A torrent's files may also be
+//! verified again at any time with
+//! [`EngineHandle::force_recheck`](crate::engine::EngineHandle::force_recheck).
+//!
+//! Alternatively, a torrent may be started from a [magnet link](crate::magnet)
+//! via
+//! [`EngineHandle::create_magnet_torrent`](crate::engine::EngineHandle::create_magnet_torrent).
+//! In this case the torrent's metadata is first downloaded from peers that
+//! support the metadata exchange extension, after which the torrent is started
+//! as usual.
 //!
 //! ## Full example of a download
 //!
@@ -85,10 +98,11 @@
 //!     let metainfo = Metainfo::from_bytes(&metainfo)?;
 //!     let torrent_id = engine.create_torrent(TorrentParams {
 //!         metainfo,
-//!         // tell the engine to assign a randomly chosen free port
-//!         listen_addr: None,
 //!         mode: Mode::Download { seeds: Vec::new() },
 //!         conf: None,
+//!         resume_data: None,
+//!         file_priorities: None,
+//!         extensions: Vec::new(),
 //!     })?;
 //!
 //!     // listen to alerts from the engine
@@ -130,6 +144,12 @@
 //!
 //! Therefore the application must make sure to provide its own way of stopping
 //! the download.
+//!
+//! # Testing
+//!
+//! With the `test-support` feature, the `testing` module provides a harness
+//! that runs a swarm of engines and a mock tracker in one process, for testing
+//! applications built on cratetorrent without external torrent clients.
 
 // needed by the `select!` macro reaching the default recursion limit
 #![recursion_limit = "256"]
@@ -150,20 +170,30 @@ pub use storage_info::FileInfo;
 
 pub mod alert;
 mod avg;
+mod compact;
 pub mod conf;
 mod counter;
+pub mod dht;
 mod disk;
 mod download;
 pub mod engine;
 pub mod error;
 pub mod iovecs;
+mod listener;
+pub mod magnet;
+mod metadata;
 pub mod metainfo;
 pub mod peer;
 mod piece_picker;
 pub mod prelude;
+pub mod resume;
 pub mod storage_info;
+pub mod stream;
+#[cfg(any(test, feature = "test-support"))]
+pub mod testing;
 pub mod torrent;
 mod tracker;
+mod utp;
 
 /// Each torrent gets a randomly assigned ID that is globally unique.
 /// This id is used in engine APIs to interact with torrents.
@@ -197,6 +227,16 @@ pub(crate) type FileIndex = usize;
 /// source code we use `usize` to be consistent with other index types in Rust.
 pub(crate) type PieceIndex = usize;
 
+/// The download priority of a file or piece.
+///
+/// Pieces with a higher priority are downloaded before pieces with a lower
+/// one, and pieces with priority 0 are not downloaded at all. A piece's
+/// priority is the highest priority of the files it overlaps with.
+pub type Priority = u8;
+
+/// The priority of files whose priority was not set.
+pub const DEFAULT_PRIORITY: Priority = 4;
+
 /// The peer ID is an arbitrary 20 byte string.
 ///
 /// Guidelines for choosing a peer ID: http://bittorrent.org/beps/bep_0020.html.
@@ -0,0 +1,497 @@
+//! The engine's listener, which accepts the connections of the peers of all
+//! torrents on a single port, over TCP and, if enabled, over uTP on the UDP
+//! port of the same number.
+//!
+//! A peer's first message is its handshake, which contains the info hash of
+//! the torrent the peer wants. The listener reads the handshake of each new
+//! connection and passes the connection to the engine, which then hands it to
+//! the torrent with that info hash, so that all torrents can share one port.
+//!
+//! If the connection is encrypted, the encrypted handshake, which identifies
+//! the torrent by a hash of its info hash, precedes the BitTorrent handshake.
+//! The listener performs it too, asking the engine for the torrent.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use futures::{
+    future::FutureExt,
+    select,
+    stream::{self, StreamExt},
+};
+use nix::{ifaddrs, net::if_::InterfaceFlags, sys::socket::SockAddr};
+use socket2::{Domain, Socket, Type};
+use tokio::{io::AsyncReadExt, net::TcpListener, sync::oneshot, task, time};
+use tokio_util::codec::Framed;
+
+use crate::{
+    engine,
+    peer::{
+        codec::{Handshake, HandshakeCodec, PROTOCOL_STRING},
+        mse::{self, CryptoMethod, PeerStream},
+        BoxTransport,
+    },
+    utp::{self, UtpSocket},
+    Sha1Hash,
+};
+
+/// A new connection may take this long to send its handshake before it's
+/// dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection accepted by the listener, whose handshake was received.
+#[derive(Debug)]
+pub(crate) struct IncomingPeer {
+    /// The address of the peer.
+    pub addr: SocketAddr,
+    /// The connection, which needs to be kept in the handshake codec as its
+    /// buffer may already hold messages the peer sent after its handshake.
+    pub socket: Framed<PeerStream<BoxTransport>, HandshakeCodec>,
+    /// The peer's handshake.
+    pub handshake: Handshake,
+    /// The crypto method chosen in the encrypted handshake, or `None` if the
+    /// connection is not encrypted.
+    pub crypto: Option<CryptoMethod>,
+}
+
+/// The handle to the listener task, which stops the task when dropped.
+pub(crate) struct ListenerHandle {
+    /// The address on which the listener is bound.
+    pub addr: SocketAddr,
+    /// The uTP socket, bound on the same port, if uTP is enabled.
+    pub utp: Option<UtpSocket>,
+    /// Dropping this sender tells the task to stop.
+    _shutdown_tx: oneshot::Sender<()>,
+}
+
+/// Binds the listener to the address and spawns its task, which sends each
+/// incoming connection whose handshake was received to the engine.
+///
+/// An IPv6 address also accepts IPv4 connections, and if it's the unspecified
+/// IPv6 address and the host doesn't support IPv6, the listener falls back to
+/// listening only on IPv4.
+///
+/// If `utp` is set, uTP connections are accepted on the UDP port of the same
+/// number, unless it can't be bound, in which case uTP is disabled.
+///
+/// The socket is bound before the task is spawned, so that the actual port is
+/// known right away.
+pub(crate) fn spawn(
+    addr: SocketAddr,
+    utp: bool,
+    engine_tx: engine::Sender,
+) -> io::Result<ListenerHandle> {
+    log::info!("Spawning listener task");
+    let mut listener = match bind_tcp(addr) {
+        Ok(listener) => listener,
+        Err(e) if addr.ip() == IpAddr::from(Ipv6Addr::UNSPECIFIED) => {
+            log::warn!("Cannot listen on IPv6, using IPv4 only: {}", e);
+            let addr =
+                SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port());
+            bind_tcp(addr)?
+        }
+        Err(e) => return Err(e),
+    };
+    let addr = listener.local_addr()?;
+
+    let (utp, utp_incoming) = if utp {
+        match bind_udp(addr).and_then(utp::spawn) {
+            Ok((utp, incoming)) => {
+                log::info!("Listening for uTP connections on {}", utp.addr);
+                (Some(utp), Some(incoming))
+            }
+            Err(e) => {
+                log::warn!("Cannot listen for uTP connections: {}", e);
+                (None, None)
+            }
+        }
+    } else {
+        (None, None)
+    };
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    task::spawn(async move {
+        let mut incoming = listener.incoming().fuse();
+        let mut utp_incoming = match utp_incoming {
+            Some(incoming) => incoming.boxed().fuse(),
+            None => stream::pending().boxed().fuse(),
+        };
+        let mut shutdown_rx = shutdown_rx.fuse();
+        loop {
+            select! {
+                socket = incoming.select_next_some() => match socket {
+                    Ok(socket) => match socket.peer_addr() {
+                        Ok(addr) => {
+                            accept(addr, Box::new(socket), engine_tx.clone())
+                        }
+                        Err(e) => {
+                            log::info!("Error getting socket address of peer: {}", e);
+                        }
+                    },
+                    Err(e) => {
+                        log::info!("Error accepting peer connection: {}", e);
+                    }
+                },
+                socket = utp_incoming.select_next_some() => {
+                    let addr = socket.peer_addr();
+                    accept(addr, Box::new(socket), engine_tx.clone());
+                }
+                // the engine stopped
+                _ = shutdown_rx => break,
+            }
+        }
+        log::info!("Listener stopped");
+    });
+    log::info!("Spawned listener task on {}", addr);
+
+    Ok(ListenerHandle {
+        addr,
+        utp,
+        _shutdown_tx: shutdown_tx,
+    })
+}
+
+/// Binds a TCP listener to the address.
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::stream())?;
+    // like the standard library, so that the port can be bound again right
+    // after the engine restarts
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    // the same backlog as the standard library's
+    socket.listen(128)?;
+    let listener = socket.into_tcp_listener();
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Binds a UDP socket to the address.
+fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::dgram())?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket())
+}
+
+/// Creates a socket of the type for the address's IP version.
+///
+/// Whether an IPv6 socket also accepts IPv4 connections depends on the OS by
+/// default, so it is set explicitly.
+fn new_socket(addr: SocketAddr, ty: Type) -> io::Result<Socket> {
+    let domain = if addr.is_ipv6() {
+        Domain::ipv6()
+    } else {
+        Domain::ipv4()
+    };
+    let socket = Socket::new(domain, ty, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(false)?;
+    }
+    Ok(socket)
+}
+
+/// Spawns a task that waits for the handshake of the new connection and then
+/// passes the connection to the engine.
+///
+/// This is also used for the connections the library user hands to the
+/// engine, which may be over any transport.
+pub(crate) fn accept(
+    addr: SocketAddr,
+    socket: BoxTransport,
+    engine_tx: engine::Sender,
+) {
+    let addr = unmap_addr(addr);
+    log::info!("New connection {}", addr);
+
+    task::spawn(async move {
+        let handshake = receive_handshake(addr, socket, &engine_tx);
+        match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(peer)) => {
+                // the engine may have stopped in the meantime
+                engine_tx
+                    .send(engine::Command::IncomingPeer(Box::new(peer)))
+                    .ok();
+            }
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                log::info!("Peer {} disconnected before handshake", addr);
+            }
+            Ok(Err(e)) => {
+                log::info!("Invalid handshake from peer {}: {}", addr, e);
+            }
+            Err(_) => log::info!("Peer {} handshake timed out", addr),
+        }
+    });
+}
+
+/// Receives the handshake of the new connection, preceded by the encrypted
+/// handshake if the connection is encrypted.
+async fn receive_handshake(
+    addr: SocketAddr,
+    mut socket: BoxTransport,
+    engine_tx: &engine::Sender,
+) -> io::Result<IncomingPeer> {
+    // a plaintext connection starts with the protocol string of the
+    // BitTorrent handshake, while an encrypted one starts with a public key,
+    // which is longer
+    let mut prefix = vec![0; 1 + PROTOCOL_STRING.len()];
+    socket.read_exact(&mut prefix).await?;
+    let is_plaintext = prefix[0] as usize == PROTOCOL_STRING.len()
+        && &prefix[1..] == PROTOCOL_STRING.as_bytes();
+    let (socket, crypto, info_hash) = if is_plaintext {
+        (PeerStream::new(socket, prefix), None, None)
+    } else {
+        let (socket, crypto, info_hash) =
+            mse::handshake_inbound(socket, prefix, |hash| {
+                find_info_hash(engine_tx, hash)
+            })
+            .await?;
+        log::info!("Encrypted connection {} ({:?})", addr, crypto);
+        (socket, Some(crypto), Some(info_hash))
+    };
+
+    let mut socket = Framed::new(socket, HandshakeCodec);
+    let handshake = match socket.next().await {
+        Some(handshake) => handshake?,
+        None => return Err(io::ErrorKind::UnexpectedEof.into()),
+    };
+    // the BitTorrent handshake must be for the torrent of the encrypted
+    // handshake
+    if info_hash.map(|h| h != handshake.info_hash).unwrap_or(false) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "info hash differs from that of encrypted handshake",
+        ));
+    }
+
+    Ok(IncomingPeer {
+        addr,
+        socket,
+        handshake,
+        crypto,
+    })
+}
+
+/// Asks the engine for the info hash of the torrent with the hash sent in an
+/// encrypted handshake.
+async fn find_info_hash(
+    engine_tx: &engine::Sender,
+    hash: Sha1Hash,
+) -> Option<Sha1Hash> {
+    let (result_tx, result_rx) = oneshot::channel();
+    engine_tx
+        .send(engine::Command::FindEncryptedTorrent { hash, result_tx })
+        .ok()?;
+    result_rx.await.ok().flatten()
+}
+
+/// Returns the first global IPv6 address of the host's network interfaces
+/// that are up, if it has one.
+pub(crate) fn local_ipv6_addr() -> Option<Ipv6Addr> {
+    ifaddrs::getifaddrs()
+        .ok()?
+        .filter(|iface| {
+            iface.flags.contains(InterfaceFlags::IFF_UP)
+                && !iface.flags.contains(InterfaceFlags::IFF_LOOPBACK)
+        })
+        .find_map(|iface| match iface.address? {
+            SockAddr::Inet(addr) => match addr.ip().to_std() {
+                IpAddr::V6(ip) if is_global_ipv6(&ip) => Some(ip),
+                _ => None,
+            },
+            _ => None,
+        })
+}
+
+/// Returns whether other peers may reach us on the IPv6 address.
+fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    let first_segment = ip.segments()[0];
+    !ip.is_loopback()
+        && !ip.is_unspecified()
+        // link-local addresses are only valid on the local link
+        && first_segment & 0xffc0 != 0xfe80
+        // unique local addresses are not routed on the internet
+        && first_segment & 0xfe00 != 0xfc00
+        // nor are IPv4 addresses in IPv6 form
+        && ip.to_ipv4().is_none()
+}
+
+/// Converts an IPv4-mapped IPv6 address, which is how a dual-stack listener
+/// reports the address of an IPv4 peer, to the plain IPv4 address, so that
+/// a peer is known by the same address whether it connected to us or we to
+/// it.
+fn unmap_addr(addr: SocketAddr) -> SocketAddr {
+    if let IpAddr::V6(ip) = addr.ip() {
+        if let [0, 0, 0, 0, 0, 0xffff, hi, lo] = ip.segments() {
+            let ip = Ipv4Addr::from((hi as u32) << 16 | lo as u32);
+            return SocketAddr::new(ip.into(), addr.port());
+        }
+    }
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::SinkExt;
+    use tokio::net::TcpStream;
+
+    use super::*;
+    use crate::conf::EncryptionPolicy;
+
+    #[test]
+    fn should_unmap_ipv4_mapped_addrs() {
+        let mapped: SocketAddr = "[::ffff:192.168.0.10]:6881".parse().unwrap();
+        assert_eq!(unmap_addr(mapped), "192.168.0.10:6881".parse().unwrap());
+        let v6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        assert_eq!(unmap_addr(v6), v6);
+    }
+
+    #[test]
+    fn should_only_advertise_global_ipv6_addrs() {
+        for ip in &["2001:db8::1", "2a00:1450:4001::200e"] {
+            assert!(is_global_ipv6(&ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["::", "::1", "fe80::1", "fd00::1", "::ffff:192.168.0.10"] {
+            assert!(!is_global_ipv6(&ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    /// Tests that a listener on the unspecified IPv6 address accepts IPv4
+    /// connections too, regardless of the OS default.
+    #[tokio::test]
+    async fn should_accept_ipv4_connection_on_ipv6_listener() {
+        let (engine_tx, mut engine_rx) = tokio::sync::mpsc::unbounded_channel();
+        let listener =
+            spawn("[::]:0".parse().unwrap(), false, engine_tx).unwrap();
+
+        let addr =
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), listener.addr.port());
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        socket.send(handshake).await.unwrap();
+
+        match engine_rx.next().await {
+            Some(engine::Command::IncomingPeer(peer)) => {
+                assert_eq!(peer.addr, local_addr);
+            }
+            _ => panic!("expected incoming peer"),
+        }
+    }
+
+    /// Tests that the connection is passed to the engine once the peer sent
+    /// its handshake.
+    #[tokio::test]
+    async fn should_pass_connection_to_engine_after_handshake() {
+        let (engine_tx, mut engine_rx) = tokio::sync::mpsc::unbounded_channel();
+        let listener =
+            spawn("127.0.0.1:0".parse().unwrap(), false, engine_tx).unwrap();
+
+        let socket = TcpStream::connect(listener.addr).await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        socket.send(handshake).await.unwrap();
+
+        match engine_rx.next().await {
+            Some(engine::Command::IncomingPeer(peer)) => {
+                assert_eq!(peer.addr, local_addr);
+                assert_eq!(peer.handshake, handshake);
+            }
+            _ => panic!("expected incoming peer"),
+        }
+    }
+
+    /// Tests that a connection made over a transport other than TCP or uTP,
+    /// which the user hands to the engine, is accepted like the listener's.
+    #[tokio::test]
+    async fn should_accept_connection_over_any_transport() {
+        let (engine_tx, mut engine_rx) = tokio::sync::mpsc::unbounded_channel();
+        let (socket, peer_socket) = tokio::io::duplex(1024);
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        accept(addr, Box::new(socket), engine_tx);
+
+        let mut peer_socket = Framed::new(peer_socket, HandshakeCodec);
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        peer_socket.send(handshake).await.unwrap();
+
+        match engine_rx.next().await {
+            Some(engine::Command::IncomingPeer(peer)) => {
+                assert_eq!(peer.addr, addr);
+                assert_eq!(peer.handshake, handshake);
+                assert!(peer.crypto.is_none());
+            }
+            _ => panic!("expected incoming peer"),
+        }
+    }
+
+    /// Tests that the listener performs the encrypted handshake, looking up
+    /// the torrent with the engine, before receiving the BitTorrent handshake.
+    #[tokio::test]
+    async fn should_accept_encrypted_connection() {
+        let (engine_tx, mut engine_rx) = tokio::sync::mpsc::unbounded_channel();
+        let listener =
+            spawn("127.0.0.1:0".parse().unwrap(), false, engine_tx).unwrap();
+        let info_hash = [1; 20];
+
+        let socket = TcpStream::connect(listener.addr).await.unwrap();
+        let handshake = task::spawn(async move {
+            mse::handshake_outbound(
+                socket,
+                &info_hash,
+                EncryptionPolicy::Enabled,
+            )
+            .await
+        });
+
+        match engine_rx.next().await {
+            Some(engine::Command::FindEncryptedTorrent { hash, result_tx }) => {
+                assert_eq!(hash, mse::info_hash_hash(&info_hash));
+                result_tx.send(Some(info_hash)).unwrap();
+            }
+            _ => panic!("expected torrent lookup"),
+        }
+
+        let (socket, crypto) = handshake.await.unwrap().unwrap();
+        assert_eq!(crypto, CryptoMethod::Rc4);
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = Handshake::new(info_hash, [2; 20]);
+        socket.send(handshake).await.unwrap();
+
+        match engine_rx.next().await {
+            Some(engine::Command::IncomingPeer(peer)) => {
+                assert_eq!(peer.handshake, handshake);
+                assert_eq!(peer.crypto, Some(CryptoMethod::Rc4));
+            }
+            _ => panic!("expected incoming peer"),
+        }
+    }
+
+    /// Tests that uTP connections are accepted on the UDP port of the same
+    /// number as the TCP port.
+    #[tokio::test]
+    async fn should_accept_utp_connection() {
+        let (engine_tx, mut engine_rx) = tokio::sync::mpsc::unbounded_channel();
+        let listener =
+            spawn("127.0.0.1:0".parse().unwrap(), true, engine_tx).unwrap();
+        let utp_addr = listener.utp.as_ref().unwrap().addr;
+        assert_eq!(utp_addr, listener.addr);
+
+        let (client, _) =
+            utp::spawn(UdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+        let socket = client.connect(utp_addr).await.unwrap();
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = Handshake::new([1; 20], [2; 20]);
+        socket.send(handshake).await.unwrap();
+
+        match engine_rx.next().await {
+            Some(engine::Command::IncomingPeer(peer)) => {
+                assert_eq!(peer.addr, client.addr);
+                assert_eq!(peer.handshake, handshake);
+            }
+            _ => panic!("expected incoming peer"),
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! This module contains the parsing of magnet links, with which a torrent may be
+//! started knowing only its info hash.
+//!
+//! The rest of the torrent's metadata is then downloaded from peers, which are
+//! found via the trackers and peer addresses included in the magnet link.
+
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use reqwest::Url;
+
+use crate::{tracker::Tracker, Sha1Hash};
+
+/// The prefix of the `xt` (exact topic) parameter for BitTorrent info hashes.
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// The errors that may occur when parsing a magnet link.
+#[derive(Debug, PartialEq)]
+pub enum MagnetError {
+    /// The link is not a valid magnet URI.
+    InvalidUri,
+    /// The link doesn't contain a BitTorrent info hash.
+    MissingInfoHash,
+    /// The info hash is neither a 40 character hex nor a 32 character base32
+    /// encoded string.
+    InvalidInfoHash,
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MagnetError::*;
+        match self {
+            InvalidUri => write!(f, "invalid magnet URI"),
+            MissingInfoHash => write!(f, "missing info hash"),
+            InvalidInfoHash => write!(f, "invalid info hash"),
+        }
+    }
+}
+
+impl std::error::Error for MagnetError {}
+
+/// A parsed magnet link.
+///
+/// See [BEP 9](http://bittorrent.org/beps/bep_0009.html#magnet-uri-format) for
+/// the format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MagnetLink {
+    /// The info hash of the torrent.
+    pub info_hash: Sha1Hash,
+    /// The display name of the torrent, if included in the link. This is only
+    /// meant to be shown to the user until the torrent's metadata is
+    /// downloaded.
+    pub name: Option<String>,
+    /// The trackers that we can announce to.
+    ///
+    /// As with the metainfo, trackers with unsupported protocols are
+    /// ignored.
+    pub trackers: Vec<Url>,
+    /// The addresses of peers that we can connect to.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl MagnetLink {
+    /// Parses a magnet link of the form
+    /// `magnet:?xt=urn:btih:<info-hash>&dn=<name>&tr=<tracker>&x.pe=<peer>`.
+    ///
+    /// Only the info hash is mandatory, and unknown parameters are ignored, as
+    /// are trackers and peers that can't be parsed.
+    pub fn parse(uri: &str) -> Result<Self, MagnetError> {
+        let url = Url::parse(uri).map_err(|_| MagnetError::InvalidUri)?;
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::InvalidUri);
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "xt" => {
+                    // there may be several exact topics for different
+                    // protocols, of which we only need the BitTorrent one
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                // trackers may also be numbered, e.g. `tr.1`
+                key if key == "tr" || key.starts_with("tr.") => {
+                    match Url::parse(&value) {
+                        Ok(url) if Tracker::is_supported(&url) => {
+                            trackers.push(url)
+                        }
+                        Ok(_) => {
+                            log::debug!(
+                                "Skipping unsupported tracker {}",
+                                value
+                            )
+                        }
+                        Err(_) => log::warn!("Invalid tracker URL {}", value),
+                    }
+                }
+                "x.pe" => match value.parse() {
+                    Ok(addr) => peers.push(addr),
+                    Err(_) => log::warn!("Invalid peer address {}", value),
+                },
+                _ => log::debug!("Ignoring magnet parameter {}", key),
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+impl FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// Parses the info hash, which may be hex or base32 encoded.
+fn parse_info_hash(s: &str) -> Result<Sha1Hash, MagnetError> {
+    let mut info_hash = [0; 20];
+    match s.len() {
+        40 => {
+            hex::decode_to_slice(s, &mut info_hash)
+                .map_err(|_| MagnetError::InvalidInfoHash)?;
+        }
+        32 => {
+            // every 8 base32 characters encode 5 bytes
+            for (chunk, out) in
+                s.as_bytes().chunks(8).zip(info_hash.chunks_mut(5))
+            {
+                let mut bits = 0u64;
+                for c in chunk {
+                    let value = match c.to_ascii_uppercase() {
+                        c @ b'A'..=b'Z' => c - b'A',
+                        c @ b'2'..=b'7' => c - b'2' + 26,
+                        _ => return Err(MagnetError::InvalidInfoHash),
+                    };
+                    bits = (bits << 5) | value as u64;
+                }
+                out.copy_from_slice(&bits.to_be_bytes()[3..]);
+            }
+        }
+        _ => return Err(MagnetError::InvalidInfoHash),
+    }
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: Sha1Hash = [
+        0xc9, 0xe1, 0x57, 0x63, 0xf7, 0x22, 0xf2, 0x3e, 0x98, 0xa2, 0x9d, 0xec,
+        0xdf, 0xae, 0x34, 0x1b, 0x98, 0xd5, 0x30, 0x56,
+    ];
+
+    /// Tests parsing a magnet link with all supported parameters.
+    #[test]
+    fn should_parse_magnet_link() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c9e15763f722f23e98a29decdfae341b98d53056\
+            &dn=Cosmos+Laundromat\
+            &tr=http%3A%2F%2Ftracker.example.com%2Fannounce\
+            &tr.1=udp%3A%2F%2Ftracker.example.com%3A80\
+            &tr.2=wss%3A%2F%2Ftracker.example.com\
+            &x.pe=127.0.0.1:6881&x.pe=not-an-address\
+            &ws=http%3A%2F%2Fexample.com%2Ffile",
+        )
+        .unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        assert_eq!(magnet.name.as_deref(), Some("Cosmos Laundromat"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                Url::parse("http://tracker.example.com/announce").unwrap(),
+                Url::parse("udp://tracker.example.com:80").unwrap(),
+            ]
+        );
+        assert_eq!(magnet.peers, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    /// Tests that a base32 encoded info hash is decoded to the same bytes as
+    /// its hex equivalent.
+    #[test]
+    fn should_parse_base32_info_hash() {
+        let magnet: MagnetLink =
+            "magnet:?xt=urn:btih:ZHQVOY7XELZD5GFCTXWN7LRUDOMNKMCW"
+                .parse()
+                .unwrap();
+        assert_eq!(magnet.info_hash, INFO_HASH);
+        assert!(magnet.name.is_none());
+        assert!(magnet.trackers.is_empty());
+        assert!(magnet.peers.is_empty());
+    }
+
+    /// Tests that invalid magnet links are rejected.
+    #[test]
+    fn should_reject_invalid_magnet_links() {
+        assert_eq!(
+            MagnetLink::parse("http://example.com/?xt=urn:btih:abc"),
+            Err(MagnetError::InvalidUri)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?dn=foo"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:btih:c9e15763f722f23e98a2"),
+            Err(MagnetError::InvalidInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse(
+                "magnet:?xt=urn:btih:x9e15763f722f23e98a29decdfae341b98d53056"
+            ),
+            Err(MagnetError::InvalidInfoHash)
+        );
+    }
+}
@@ -0,0 +1,462 @@
+//! This module contains the download of a torrent's metadata, for torrents
+//! started from a magnet link.
+//!
+//! A magnet link only contains the torrent's info hash, and optionally some
+//! trackers and peers. Before the torrent can be created, its info dictionary
+//! needs to be downloaded from peers that support the metadata exchange
+//! extension (BEP 9). This is done by the [`MetadataDownload`] task, which
+//! gets peers from the trackers, the DHT and the magnet link, and downloads the
+//! metadata from a few of them at a time until one of them succeeds. The
+//! result is sent to the engine, which then creates the torrent as usual.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{select, stream::Fuse, StreamExt};
+use reqwest::Url;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task, time,
+};
+
+use crate::{
+    alert::{Alert, AlertSender},
+    conf::TorrentConf,
+    dht::{self, DhtHandle},
+    engine,
+    error::{Error, Result},
+    metainfo::Metainfo,
+    peer::{self, error::PeerError, Connector},
+    tracker::{self, Announce, Response, Tracker, TrackerError},
+    PeerId, Sha1Hash, TorrentId,
+};
+
+/// The maximum number of peers from which the metadata is downloaded at the
+/// same time.
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
+/// If a peer doesn't send us the metadata within this time, we try another
+/// peer.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// If the tracker doesn't provide a minimum announce interval, we don't
+/// announce more often than this when we run out of peers.
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The channel on which the engine can send commands to the metadata download.
+pub(crate) type Sender = UnboundedSender<Command>;
+type Receiver = UnboundedReceiver<Command>;
+
+/// The commands the metadata download can receive.
+pub(crate) enum Command {
+    /// Stop the download, e.g. because the torrent was removed.
+    Shutdown,
+}
+
+/// Parameters for the metadata download constructor.
+pub(crate) struct Params {
+    pub id: TorrentId,
+    pub info_hash: Sha1Hash,
+    pub client_id: PeerId,
+    pub trackers: Vec<Url>,
+    pub peers: Vec<SocketAddr>,
+    /// The port we announce to trackers.
+    pub port: u16,
+    pub conf: TorrentConf,
+    pub dht: Option<DhtHandle>,
+    /// Makes the connections to peers.
+    pub connector: Arc<dyn Connector>,
+    pub engine_tx: engine::Sender,
+    pub alert_tx: AlertSender,
+}
+
+/// The successfully downloaded metadata, sent to the engine.
+pub(crate) struct Metadata {
+    /// The torrent's metainfo, constructed from the downloaded info dictionary
+    /// and the trackers in the magnet link.
+    pub metainfo: Metainfo,
+    /// All peers we know of, so that the torrent can connect to them.
+    pub peers: Vec<SocketAddr>,
+}
+
+/// The result of downloading the metadata from a single peer.
+type FetchResult = (SocketAddr, peer::error::Result<Vec<u8>>);
+
+/// The result of an announce to the tracker with the index.
+type AnnounceResult = (usize, tracker::Result<Response>);
+
+/// Downloads a torrent's metadata from peers.
+pub(crate) struct MetadataDownload {
+    id: TorrentId,
+    info_hash: Sha1Hash,
+    client_id: PeerId,
+    /// The trackers from which we get peers.
+    trackers: Vec<TrackerEntry>,
+    port: u16,
+    conf: TorrentConf,
+    /// The peers we haven't tried downloading the metadata from yet.
+    available_peers: Vec<SocketAddr>,
+    /// All peers we know of, including the ones we already tried.
+    known_peers: HashSet<SocketAddr>,
+    /// The number of peers we're currently downloading the metadata from.
+    pending_fetch_count: usize,
+    /// The fetches run on their own tasks, which send their results here.
+    fetch_tx: UnboundedSender<FetchResult>,
+    fetch_rx: Fuse<UnboundedReceiver<FetchResult>>,
+    /// The announces run on their own tasks too, so that a slow tracker
+    /// doesn't hold up the download, and send their results here.
+    announce_tx: UnboundedSender<AnnounceResult>,
+    announce_rx: Fuse<UnboundedReceiver<AnnounceResult>>,
+    /// The engine's DHT node, if enabled, which sends the peers it finds on
+    /// the peers channel.
+    dht: Option<DhtHandle>,
+    dht_peers_tx: dht::PeersSender,
+    dht_peers_rx: Fuse<UnboundedReceiver<Vec<SocketAddr>>>,
+    last_dht_query_time: Option<Instant>,
+    connector: Arc<dyn Connector>,
+    cmd_rx: Fuse<Receiver>,
+    engine_tx: engine::Sender,
+    alert_tx: AlertSender,
+}
+
+/// A tracker and the time we last announced to it.
+struct TrackerEntry {
+    /// The tracker client, shared with the task of the in-progress announce.
+    client: Arc<Tracker>,
+    /// Whether an announce to the tracker is in progress.
+    is_announcing: bool,
+    last_announce_time: Option<Instant>,
+    min_interval: Option<Duration>,
+    error_count: usize,
+    retry_time: Option<Instant>,
+}
+
+impl MetadataDownload {
+    /// Creates a new metadata download, returning it and its command channel.
+    pub fn new(params: Params) -> (Self, Sender) {
+        let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+        let (fetch_tx, fetch_rx) = mpsc::unbounded_channel();
+        let (announce_tx, announce_rx) = mpsc::unbounded_channel();
+        let (dht_peers_tx, dht_peers_rx) = mpsc::unbounded_channel();
+        let trackers = params
+            .trackers
+            .into_iter()
+            .map(|url| TrackerEntry {
+                client: Arc::new(Tracker::new(url)),
+                is_announcing: false,
+                last_announce_time: None,
+                min_interval: None,
+                error_count: 0,
+                retry_time: None,
+            })
+            .collect();
+        (
+            Self {
+                id: params.id,
+                info_hash: params.info_hash,
+                client_id: params.client_id,
+                trackers,
+                port: params.port,
+                conf: params.conf,
+                known_peers: params.peers.iter().copied().collect(),
+                available_peers: params.peers,
+                pending_fetch_count: 0,
+                fetch_tx,
+                fetch_rx: fetch_rx.fuse(),
+                announce_tx,
+                announce_rx: announce_rx.fuse(),
+                dht: params.dht,
+                dht_peers_tx,
+                dht_peers_rx: dht_peers_rx.fuse(),
+                last_dht_query_time: None,
+                connector: params.connector,
+                cmd_rx: cmd_rx.fuse(),
+                engine_tx: params.engine_tx,
+                alert_tx: params.alert_tx,
+            },
+            cmd_tx,
+        )
+    }
+
+    /// Runs the download until the metadata is downloaded or until it's shut
+    /// down.
+    pub async fn run(&mut self) -> Result<()> {
+        log::info!("Starting torrent {} metadata download", self.id);
+        let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
+
+        loop {
+            select! {
+                now = tick_timer.select_next_some() => {
+                    // only ask trackers for peers once we've run out of them
+                    if self.available_peers.is_empty() {
+                        self.announce(now.into_std());
+                        self.query_dht(now.into_std());
+                    }
+                    self.fetch_from_peers();
+                }
+                (index, result) = self.announce_rx.select_next_some() => {
+                    self.handle_announce_result(
+                        index,
+                        result,
+                        Instant::now(),
+                    )?;
+                    self.fetch_from_peers();
+                }
+                peers = self.dht_peers_rx.select_next_some() => {
+                    for addr in peers {
+                        if self.known_peers.insert(addr) {
+                            self.available_peers.push(addr);
+                        }
+                    }
+                    self.fetch_from_peers();
+                }
+                (addr, result) = self.fetch_rx.select_next_some() => {
+                    self.pending_fetch_count -= 1;
+                    match result {
+                        Ok(info) => {
+                            self.handle_metadata(info)?;
+                            return Ok(());
+                        }
+                        Err(e) => {
+                            log::info!(
+                                "Torrent {} metadata download from peer {} \
+                                failed: {}",
+                                self.id,
+                                addr,
+                                e
+                            );
+                            self.alert_tx.send(Alert::Error(Error::Peer {
+                                id: self.id,
+                                addr,
+                                error: e,
+                            }))?;
+                            self.fetch_from_peers();
+                        }
+                    }
+                }
+                cmd = self.cmd_rx.select_next_some() => {
+                    match cmd {
+                        Command::Shutdown => {
+                            log::info!(
+                                "Stopping torrent {} metadata download",
+                                self.id
+                            );
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parses the downloaded info dictionary and sends the result to the
+    /// engine.
+    fn handle_metadata(&mut self, info: Vec<u8>) -> Result<()> {
+        log::info!("Downloaded torrent {} metadata", self.id);
+        // magnet links have no tracker tiers, so all trackers are put in a
+        // single tier, which the torrent shuffles and fails over within, as
+        // per BEP 12
+        let tier: Vec<_> = self
+            .trackers
+            .iter()
+            .map(|t| t.client.url().clone())
+            .collect();
+        let trackers = if tier.is_empty() {
+            Vec::new()
+        } else {
+            vec![tier]
+        };
+        let result = Metainfo::from_info_bytes(&info, self.info_hash, trackers)
+            .map(|metainfo| {
+                Box::new(Metadata {
+                    metainfo,
+                    peers: self.known_peers.drain().collect(),
+                })
+            });
+        self.engine_tx.send(engine::Command::MetadataDownload {
+            id: self.id,
+            result,
+        })?;
+        Ok(())
+    }
+
+    /// Starts downloading the metadata from available peers, on separate
+    /// tasks.
+    fn fetch_from_peers(&mut self) {
+        while self.pending_fetch_count < MAX_CONCURRENT_FETCHES {
+            let addr = match self.available_peers.pop() {
+                Some(addr) => addr,
+                None => break,
+            };
+            log::info!(
+                "Downloading torrent {} metadata from peer {}",
+                self.id,
+                addr
+            );
+            let info_hash = self.info_hash;
+            let client_id = self.client_id;
+            let connector = Arc::clone(&self.connector);
+            let fetch_tx = self.fetch_tx.clone();
+            task::spawn(async move {
+                let result = time::timeout(
+                    FETCH_TIMEOUT,
+                    peer::metadata::fetch_metadata(
+                        &*connector,
+                        addr,
+                        info_hash,
+                        client_id,
+                    ),
+                )
+                .await
+                .unwrap_or_else(|_| {
+                    Err(PeerError::Io(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "metadata download timed out",
+                    )))
+                });
+                // the download may have finished in the meantime
+                fetch_tx.send((addr, result)).ok();
+            });
+            self.pending_fetch_count += 1;
+        }
+    }
+
+    /// Asks the DHT for peers, if enabled.
+    ///
+    /// We don't announce ourselves, as we can't serve the torrent until we
+    /// have its metadata.
+    fn query_dht(&mut self, now: Instant) {
+        let dht = match &self.dht {
+            Some(dht) => dht,
+            None => return,
+        };
+        if let Some(last_dht_query_time) = self.last_dht_query_time {
+            if now.saturating_duration_since(last_dht_query_time)
+                < dht::MIN_QUERY_INTERVAL
+            {
+                return;
+            }
+        }
+        self.last_dht_query_time = Some(now);
+        let cmd = dht::Command::GetPeers {
+            info_hash: self.info_hash,
+            announce_port: None,
+            peers_tx: self.dht_peers_tx.clone(),
+        };
+        if dht.tx.send(cmd).is_err() {
+            log::warn!("DHT task is not running");
+        }
+    }
+
+    /// Requests peers from all trackers that we're allowed to announce to.
+    ///
+    /// The announces run on separate tasks, which send their results to the
+    /// download.
+    fn announce(&mut self, now: Instant) {
+        for (index, tracker) in self.trackers.iter_mut().enumerate() {
+            if tracker.is_announcing {
+                continue;
+            }
+            // wait before retrying failing trackers
+            if matches!(tracker.retry_time, Some(t) if now < t) {
+                continue;
+            }
+            if let Some(last_announce_time) = tracker.last_announce_time {
+                let min_interval =
+                    tracker.min_interval.unwrap_or(MIN_ANNOUNCE_INTERVAL);
+                if now < last_announce_time + min_interval {
+                    continue;
+                }
+            }
+            tracker.last_announce_time = Some(now);
+            tracker.is_announcing = true;
+
+            let params = Announce {
+                info_hash: self.info_hash,
+                peer_id: self.client_id,
+                port: self.port,
+                ip: None,
+                ipv6: None,
+                downloaded: 0,
+                uploaded: 0,
+                // we don't know the torrent's size yet, but we mustn't report
+                // 0 as then trackers may think we're a seed and not return
+                // other seeds
+                left: 1,
+                peer_count: Some(self.conf.min_requested_peer_count),
+                tracker_id: None,
+                event: None,
+            };
+            let client = Arc::clone(&tracker.client);
+            let announce_tx = self.announce_tx.clone();
+            let timeout = self.conf.announce_timeout;
+            task::spawn(async move {
+                let result =
+                    match time::timeout(timeout, client.announce(params)).await
+                    {
+                        Ok(result) => result,
+                        Err(_) => Err(TrackerError::Timeout),
+                    };
+                // the download may have finished in the meantime
+                announce_tx.send((index, result)).ok();
+            });
+        }
+    }
+
+    /// Adds the peers returned by the tracker with the index, or schedules a
+    /// retry of the tracker if the announce failed.
+    fn handle_announce_result(
+        &mut self,
+        index: usize,
+        result: tracker::Result<Response>,
+        now: Instant,
+    ) -> Result<()> {
+        let tracker = &mut self.trackers[index];
+        tracker.is_announcing = false;
+        match result {
+            Ok(resp) => {
+                log::info!(
+                    "Announced torrent {} to tracker {}, got {} peer(s)",
+                    self.id,
+                    tracker.client,
+                    resp.peers.len()
+                );
+                tracker.error_count = 0;
+                tracker.retry_time = None;
+                if let Some(min_interval) = resp.min_interval {
+                    tracker.min_interval = Some(min_interval);
+                }
+                for addr in resp.peers {
+                    if self.known_peers.insert(addr) {
+                        self.available_peers.push(addr);
+                    }
+                }
+            }
+            Err(e) => {
+                log::warn!(
+                    "Error announcing to tracker {}: {}",
+                    tracker.client,
+                    e
+                );
+                tracker.error_count += 1;
+                tracker.retry_time = Some(
+                    now + tracker::retry_delay(
+                        tracker.error_count,
+                        self.conf.tracker_retry_interval,
+                        self.conf.max_tracker_retry_interval,
+                    ),
+                );
+                self.alert_tx.send(Alert::Error(Error::Tracker {
+                    id: self.id,
+                    error: e,
+                }))?;
+            }
+        }
+        Ok(())
+    }
+}
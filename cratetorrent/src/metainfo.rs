@@ -8,7 +8,7 @@ use std::{
 
 use reqwest::Url;
 
-use crate::{FileInfo, Sha1Hash};
+use crate::{tracker::Tracker, FileInfo, Sha1Hash};
 
 pub use serde_bencode::Error as BencodeError;
 
@@ -78,10 +78,16 @@ pub struct Metainfo {
     pub piece_len: u32,
     /// The paths and lenths of the files in torrent.
     pub files: Vec<FileInfo>,
-    /// The trackers that we can announce to.
-    /// The tier information is not currently present in this field as
-    /// cratetorrent doesn't use it. In the future it may be added.
-    pub trackers: Vec<Url>,
+    /// The trackers that we can announce to, grouped into tiers (BEP 12).
+    ///
+    /// The tiers are in the order of preference given in the metainfo, and
+    /// trackers with unsupported protocols are left out, as are tiers that
+    /// have no other trackers. If the metainfo only has a single announce URL,
+    /// it's the only tier.
+    pub trackers: Vec<Vec<Url>>,
+    /// Whether the torrent is private (BEP 27), in which case peers may only be
+    /// obtained from its trackers, so peer exchange is disabled.
+    pub is_private: bool,
 }
 
 impl Metainfo {
@@ -96,16 +102,73 @@ impl Metainfo {
         // verify it afterwards
         let metainfo: raw::Metainfo = serde_bencode::from_bytes(buf)?;
 
+        // the announce list supersedes the single announce URL, see BEP 12
+        let mut trackers = Vec::new();
+        if !metainfo.announce_list.is_empty() {
+            trackers.reserve(metainfo.announce_list.len());
+            for tier in metainfo.announce_list.iter() {
+                let mut urls = Vec::with_capacity(tier.len());
+                for tracker in tier.iter() {
+                    let url = Url::parse(tracker)?;
+                    // the tracker may use a protocol we don't support
+                    if Tracker::is_supported(&url) {
+                        urls.push(url);
+                    }
+                }
+                if !urls.is_empty() {
+                    trackers.push(urls);
+                }
+            }
+        } else if let Some(tracker) = &metainfo.announce {
+            let url = Url::parse(tracker)?;
+            if Tracker::is_supported(&url) {
+                trackers.push(vec![url]);
+            }
+        }
+
+        if trackers.is_empty() {
+            log::warn!("No supported trackers in metainfo");
+        }
+
+        // create info hash as a last step
+        let info_hash = metainfo.create_info_hash()?;
+
+        Self::from_info(metainfo.info, info_hash, trackers)
+    }
+
+    /// Parses a torrent's info dictionary, downloaded from peers, into a new
+    /// [`Metainfo`] instance.
+    ///
+    /// This is used when only the info hash of a torrent is known, e.g. from
+    /// a magnet link. The info dictionary must already have been verified
+    /// against the info hash, as the info hash is not recomputed here. The
+    /// trackers are not part of the info dictionary and have to be passed in
+    /// separately.
+    pub fn from_info_bytes(
+        buf: &[u8],
+        info_hash: Sha1Hash,
+        trackers: Vec<Vec<Url>>,
+    ) -> Result<Self> {
+        let info: raw::Info = serde_bencode::from_bytes(buf)?;
+        Self::from_info(info, info_hash, trackers)
+    }
+
+    /// Verifies the parsed info dictionary and creates the metainfo from it.
+    fn from_info(
+        info: raw::Info,
+        info_hash: Sha1Hash,
+        trackers: Vec<Vec<Url>>,
+    ) -> Result<Self> {
         // the pieces field is a concatenation of 20 byte SHA-1 hashes, so it
         // must be a multiple of 20
-        if metainfo.info.pieces.len() % 20 != 0 {
+        if info.pieces.len() % 20 != 0 {
             return Err(MetainfoError::InvalidPieces);
         }
 
         // verify download structure and build up files metadata
         let mut files = Vec::new();
-        if let Some(len) = metainfo.info.len {
-            if metainfo.info.files.is_some() {
+        if let Some(len) = info.len {
+            if info.files.is_some() {
                 log::warn!("Metainfo cannot contain both `length` and `files`");
                 return Err(MetainfoError::InvalidMetainfo);
             }
@@ -116,11 +179,11 @@ impl Metainfo {
 
             // the path of this file is just the torrent name
             files.push(FileInfo {
-                path: metainfo.info.name.clone().into(),
+                path: info.name.clone().into(),
                 len,
                 torrent_offset: 0,
             });
-        } else if let Some(raw_files) = &metainfo.info.files {
+        } else if let Some(raw_files) = &info.files {
             if raw_files.is_empty() {
                 log::warn!("Metainfo files must not be empty");
                 return Err(MetainfoError::InvalidMetainfo);
@@ -171,46 +234,14 @@ impl Metainfo {
             return Err(MetainfoError::InvalidMetainfo);
         }
 
-        let mut trackers = Vec::new();
-        if !metainfo.announce_list.is_empty() {
-            let tracker_count = metainfo
-                .announce_list
-                .iter()
-                .map(|t| t.len())
-                .sum::<usize>()
-                + metainfo.announce.as_ref().map(|_| 1).unwrap_or_default();
-            trackers.reserve(tracker_count);
-
-            for tier in metainfo.announce_list.iter() {
-                for tracker in tier.iter() {
-                    let url = Url::parse(&tracker)?;
-                    // the tracker may be over UDP, which we don't support (yet)
-                    if url.scheme() == "http" || url.scheme() == "https" {
-                        trackers.push(url);
-                    }
-                }
-            }
-        } else if let Some(tracker) = &metainfo.announce {
-            let url = Url::parse(&tracker)?;
-            if url.scheme() == "http" || url.scheme() == "https" {
-                trackers.push(url);
-            }
-        }
-
-        if trackers.is_empty() {
-            log::warn!("No HTTP trackers in metainfo");
-        }
-
-        // create info hash as a last step
-        let info_hash = metainfo.create_info_hash()?;
-
         Ok(Self {
-            name: metainfo.info.name,
+            name: info.name,
             info_hash,
-            pieces: metainfo.info.pieces,
-            piece_len: metainfo.info.piece_len,
+            pieces: info.pieces,
+            piece_len: info.piece_len,
             files,
             trackers,
+            is_private: info.private == Some(1),
         })
     }
 
@@ -287,8 +318,8 @@ mod raw {
         #[serde(rename = "length")]
         pub len: Option<u64>,
         pub files: Option<Vec<File>>,
-        /// This is not currently used but needs to be kept in here so that we
-        /// can encode back a valid info hash for hashing.
+        /// This needs to be kept in here as is so that we can encode back
+        /// a valid info hash for hashing.
         pub private: Option<u8>,
     }
 
@@ -302,3 +333,69 @@ mod raw {
 
 // TODO(https://github.com/mandreyel/cratetorrent/issues/8): add metainfo
 // parsing tests
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+
+    /// Tests that the info dictionary downloaded for a magnet link results in
+    /// the same metainfo as the full metainfo file.
+    #[test]
+    fn should_parse_info_bytes() {
+        let info = b"d6:lengthi40000e4:name8:file.bin\
+            12:piece lengthi32768e6:pieces40:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe";
+        let mut buf =
+            b"d8:announce30:http://tracker.example.com/ann4:info".to_vec();
+        buf.extend_from_slice(info);
+        buf.push(b'e');
+        let metainfo = Metainfo::from_bytes(&buf).unwrap();
+
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&Sha1::digest(info));
+        assert_eq!(metainfo.info_hash, info_hash);
+
+        let from_info = Metainfo::from_info_bytes(
+            info,
+            info_hash,
+            metainfo.trackers.clone(),
+        )
+        .unwrap();
+        assert_eq!(from_info.name, "file.bin");
+        assert_eq!(from_info.info_hash, metainfo.info_hash);
+        assert_eq!(from_info.pieces, metainfo.pieces);
+        assert_eq!(from_info.piece_len, 32768);
+        assert_eq!(from_info.piece_count(), 2);
+        assert_eq!(from_info.download_len(), 40000);
+        assert_eq!(from_info.trackers, metainfo.trackers);
+
+        // an info dictionary without files is invalid
+        let invalid = b"d4:name8:file.bin12:piece lengthi32768e6:pieces0:e";
+        assert!(
+            Metainfo::from_info_bytes(invalid, info_hash, Vec::new()).is_err()
+        );
+    }
+
+    /// Tests that the tiers of the announce list are kept, without the
+    /// trackers we don't support, and that the announce list takes precedence
+    /// over the single announce URL.
+    #[test]
+    fn should_parse_tracker_tiers() {
+        let buf = b"d8:announce29:http://d.example.com/announce\
+            13:announce-listl\
+            l29:http://a.example.com/announce24:udp://b.example.com:6969e\
+            l19:wss://c.example.come\
+            e4:infod6:lengthi40000e4:name8:file.bin\
+            12:piece lengthi32768e6:pieces40:\
+            aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbee";
+        let metainfo = Metainfo::from_bytes(buf).unwrap();
+        assert_eq!(
+            metainfo.trackers,
+            vec![vec![
+                Url::parse("http://a.example.com/announce").unwrap(),
+                Url::parse("udp://b.example.com:6969").unwrap(),
+            ]]
+        );
+    }
+}
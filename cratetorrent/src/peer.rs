@@ -9,7 +9,8 @@
 //! one, due to making use of shared data in torrent.
 
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
+    io,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
@@ -21,7 +22,6 @@ use futures::{
     SinkExt, StreamExt,
 };
 use tokio::{
-    net::TcpStream,
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
         RwLock,
@@ -32,8 +32,9 @@ use tokio_util::codec::{Framed, FramedParts};
 
 use crate::{
     alert::Alert,
+    conf::EncryptionPolicy,
     counter::ThruputCounters,
-    disk,
+    dht, disk,
     download::{BlockStatus, PieceDownload},
     error::Error,
     torrent::{self, TorrentContext},
@@ -41,13 +42,25 @@ use crate::{
 };
 use codec::*;
 use error::*;
+use extension::{ExtendedHandshake, HANDSHAKE_ID};
+use fast::{allowed_fast_set, ALLOWED_FAST_COUNT};
+use mse::PeerStream;
+use pex::{PexFlags, PexMsg, MAX_PEX_PEERS, UT_PEX, UT_PEX_ID};
 use state::*;
 
+pub use extension::Extension;
 pub use state::{ConnectionState, SessionState};
+pub use transport::{BoxTransport, Connector, Transport};
 
-mod codec;
+pub(crate) mod codec;
 pub mod error;
+mod extension;
+mod fast;
+pub(crate) mod metadata;
+pub(crate) mod mse;
+pub(crate) mod pex;
 mod state;
+pub(crate) mod transport;
 
 /// The most essential information of a peer session that is sent to torrent
 /// with each session tick.
@@ -76,13 +89,31 @@ pub(crate) enum Command {
         /// Tell the session to enter endgame mode.
         in_endgame: bool,
     },
+    /// The torrent's current peers that may be advertised to the peer via peer
+    /// exchange. The session sends the peer the changes since the last time it
+    /// advertised peers.
+    PexPeers(Arc<Vec<(SocketAddr, PexFlags)>>),
+    /// Stop uploading to the peer, as decided by the torrent's choker.
+    Choke,
+    /// Allow the peer to download from us, as decided by the torrent's
+    /// choker.
+    Unchoke,
+    /// The priorities of the torrent's pieces changed, so we may no longer
+    /// want, or may now want, the peer's pieces.
+    PrioritiesChanged {
+        /// Tell the session to enter or leave endgame mode.
+        in_endgame: bool,
+    },
+    /// Whether the peer is one of the torrent's fastest peers, which download
+    /// the pieces with a deadline.
+    SetFast(bool),
     /// Eventually shut down the peer session.
     Shutdown,
 }
 
 /// Determines who initiated the connection.
-#[derive(Clone, Copy, PartialEq)]
-enum Direction {
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
     Outbound,
     Inbound,
 }
@@ -104,8 +135,9 @@ enum Direction {
 ///
 /// # Important
 ///
-/// For now only the BitTorrent v1 specification is implemented, without any
-/// extensions.
+/// For now only the BitTorrent v1 specification is implemented, with the Fast
+/// extension (BEP 6) and the extension protocol (BEP 10). Messages of the
+/// extension protocol are handled by the torrent's [`Extension`]s.
 pub(crate) struct PeerSession {
     /// Shared information of the torrent.
     torrent: Arc<TorrentContext>,
@@ -152,9 +184,10 @@ pub(crate) struct PeerSession {
     /// will be wasted. Thus this method avoids bandwidth waste and cuts down
     /// overall download times.
     ///
-    /// Since the Fast extension is not supported (yet), this is emptied when
+    /// If the peer doesn't support the Fast extension, this is emptied when
     /// we're choked, as in that case we don't expect outstanding requests to be
-    /// served.
+    /// served. Otherwise the requests are kept, as the peer rejects the ones it
+    /// won't serve, at which point they are removed.
     ///
     /// Note that if a reuest for a piece's block is in this queue, there _must_
     /// be a corresponding entry for the piece download in `downloads`.
@@ -169,6 +202,13 @@ pub(crate) struct PeerSession {
     /// or when the peer cancels it. If a peer sends a request and cancels it
     /// before the disk read is done, the read block is dropped.
     incoming_requests: HashSet<BlockInfo>,
+    /// The pieces the peer may request from us even while choked, i.e. its
+    /// allowed fast set. Empty if the peer doesn't support the Fast extension.
+    allowed_fast: HashSet<PieceIndex>,
+
+    /// The peers we told the peer about via peer exchange, which are the ones
+    /// we're connected to as far as the peer knows.
+    pex_peers: HashSet<SocketAddr>,
 }
 
 /// Information about the peer we're connected to.
@@ -188,6 +228,21 @@ pub(super) struct PeerInfo {
     /// This is equivalent to `self.pieces.count_ones()` and is updated every
     /// time the peer sends us an announcement of a new piece.
     pub piece_count: usize,
+    /// Whether the peer advertised support for the extension protocol in its
+    /// handshake.
+    pub supports_extensions: bool,
+    /// Whether the peer advertised in its handshake that it runs a DHT node.
+    pub supports_dht: bool,
+    /// Whether the peer advertised support for the Fast extension in its
+    /// handshake. Since we always advertise it, this means the extension is
+    /// enabled for the session.
+    pub supports_fast: bool,
+    /// The pieces the peer allows us to request even while we're choked.
+    pub allowed_fast: HashSet<PieceIndex>,
+    /// The extensions the peer supports, mapped to the ids with which the peer
+    /// wants to receive their messages. This is the `m` dictionary of the
+    /// peer's extended handshake, and is empty until it is received.
+    pub extensions: BTreeMap<String, u8>,
 }
 
 impl PeerSession {
@@ -215,6 +270,11 @@ impl PeerSession {
                     pieces: Bitfield::repeat(false, piece_count),
                     piece_count: 0,
                     id: Default::default(),
+                    supports_extensions: false,
+                    supports_dht: false,
+                    supports_fast: false,
+                    allowed_fast: HashSet::new(),
+                    extensions: BTreeMap::new(),
                 },
                 ctx: SessionContext {
                     log_target,
@@ -222,6 +282,8 @@ impl PeerSession {
                 },
                 outgoing_requests: HashSet::new(),
                 incoming_requests: HashSet::new(),
+                allowed_fast: HashSet::new(),
+                pex_peers: HashSet::new(),
             },
             cmd_tx,
         )
@@ -232,52 +294,158 @@ impl PeerSession {
     /// This method tries to connect to the peer at the address given in the
     /// constructor, send a handshake, and start the session.
     /// It returns if the connection is closed or an error occurs.
+    ///
+    /// The connection is made by the engine's [`Connector`], which by default
+    /// connects over uTP if enabled, falling back to TCP if the peer doesn't
+    /// support it.
+    ///
+    /// Unless disabled, the connection is encrypted first. If that fails and
+    /// encryption is not forced, the peer is reconnected in plaintext.
     pub async fn start_outbound(&mut self) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Starting outbound session");
+        let result = self.connect_and_start().await;
+        self.report_failed_start(&result);
+        result
+    }
 
-        // establish the TCP connection
+    /// Connects to the peer and starts the session over the new connection.
+    async fn connect_and_start(&mut self) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Connecting to peer");
         self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = TcpStream::connect(self.peer.addr).await?;
-        log::info!(target: &self.ctx.log_target, "Connected to peer");
+        let socket = self.connect().await?;
+
+        let socket = if self.torrent.encryption == EncryptionPolicy::Disabled {
+            PeerStream::new(socket, Vec::new())
+        } else {
+            self.encrypt_outbound(socket).await?
+        };
 
         let socket = Framed::new(socket, HandshakeCodec);
-        self.start(socket, Direction::Outbound).await
+        self.start(socket, Direction::Outbound, None).await
     }
 
-    /// Starts an inbound peer session from an existing TCP connection.
+    /// Performs the encrypted handshake on the new outbound connection.
     ///
-    /// The method waits for the peer to send its handshake, responds
-    /// with a handshake, and starts the session.
+    /// If it fails and encryption is not forced, the peer may not support
+    /// encryption, so it's reconnected in plaintext.
+    async fn encrypt_outbound(
+        &mut self,
+        socket: BoxTransport,
+    ) -> Result<PeerStream<BoxTransport>> {
+        let policy = self.torrent.encryption;
+        let handshake =
+            mse::handshake_outbound(socket, &self.torrent.info_hash, policy);
+        let error = match time::timeout(MSE_HANDSHAKE_TIMEOUT, handshake).await
+        {
+            Ok(Ok((socket, crypto))) => {
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Encrypted connection ({:?})",
+                    crypto
+                );
+                return Ok(socket);
+            }
+            Ok(Err(e)) => e,
+            Err(_) => io::Error::new(
+                io::ErrorKind::TimedOut,
+                "encrypted handshake timed out",
+            ),
+        };
+
+        if policy == EncryptionPolicy::Forced {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Encrypted handshake failed: {}",
+                error
+            );
+            return Err(error.into());
+        }
+        log::info!(
+            target: &self.ctx.log_target,
+            "Encrypted handshake failed, reconnecting in plaintext: {}",
+            error
+        );
+        // the default connector remembers whether the peer answered over uTP,
+        // so it reconnects over the transport that worked
+        let socket = self.connect().await?;
+        Ok(PeerStream::new(socket, Vec::new()))
+    }
+
+    /// Connects to the peer with the engine's connector.
+    async fn connect(&self) -> Result<BoxTransport> {
+        let socket = self.torrent.connector.connect(self.peer.addr).await?;
+        log::info!(target: &self.ctx.log_target, "Connected to peer");
+        Ok(socket)
+    }
+
+    /// Starts an inbound peer session from a connection accepted by the
+    /// engine, whose handshake the engine already received.
+    ///
+    /// The method responds with a handshake and starts the session.
     /// It returns if the connection is closed or an error occurs.
-    pub async fn start_inbound(&mut self, socket: TcpStream) -> Result<()> {
+    pub async fn start_inbound<S: Transport>(
+        &mut self,
+        socket: Framed<S, HandshakeCodec>,
+        peer_handshake: Handshake,
+    ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Starting inbound session");
         self.ctx.set_connection_state(ConnectionState::Connecting);
-        let socket = Framed::new(socket, HandshakeCodec);
-        self.start(socket, Direction::Inbound).await
+        let result = self
+            .start(socket, Direction::Inbound, Some(peer_handshake))
+            .await;
+        self.report_failed_start(&result);
+        result
+    }
+
+    /// Tells the torrent that the session is over if it failed before it
+    /// could do so itself, e.g. because the connection or the handshake
+    /// failed, so that the torrent doesn't keep the peer around and may
+    /// connect to it again later.
+    fn report_failed_start(&mut self, result: &Result<()>) {
+        if result.is_ok()
+            || self.ctx.state.connection == ConnectionState::Disconnected
+        {
+            return;
+        }
+        self.ctx.set_connection_state(ConnectionState::Disconnected);
+        self.torrent
+            .cmd_tx
+            .send(torrent::Command::PeerState {
+                addr: self.peer.addr,
+                info: self.session_info(),
+            })
+            .ok();
     }
 
     /// Helper method for the common steps of setting up a session.
-    async fn start(
+    ///
+    /// The peer's handshake is given if it was already received.
+    async fn start<S: Transport>(
         &mut self,
-        mut socket: Framed<TcpStream, HandshakeCodec>,
+        mut socket: Framed<S, HandshakeCodec>,
         direction: Direction,
+        peer_handshake: Option<Handshake>,
     ) -> Result<()> {
         self.ctx.set_connection_state(ConnectionState::Handshaking);
 
         // if this is an outbound connection, we have to send the first
         // handshake
         if direction == Direction::Outbound {
-            let handshake =
-                Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+            let handshake = self.own_handshake();
             log::info!(target: &self.ctx.log_target, "Sending handshake");
             self.ctx.counters.protocol.up += handshake.len();
             socket.send(handshake).await?;
         }
 
-        // receive peer's handshake
-        log::info!(target: &self.ctx.log_target, "Waiting for peer handshake");
-        if let Some(peer_handshake) = socket.next().await {
+        // receive peer's handshake, unless we already have it
+        let peer_handshake = match peer_handshake {
+            Some(peer_handshake) => Some(Ok(peer_handshake)),
+            None => {
+                log::info!(target: &self.ctx.log_target, "Waiting for peer handshake");
+                socket.next().await
+            }
+        };
+        if let Some(peer_handshake) = peer_handshake {
             let peer_handshake = peer_handshake?;
             log::info!(target: &self.ctx.log_target, "Peer sent handshake");
             log::trace!(target: &self.ctx.log_target, "Peer handshake: {:?}", peer_handshake);
@@ -296,13 +464,14 @@ impl PeerSession {
 
             // set the peer's id
             self.peer.id = Some(peer_handshake.peer_id);
+            self.peer.supports_extensions =
+                peer_handshake.supports_extension_protocol();
+            self.peer.supports_dht = peer_handshake.supports_dht();
+            self.peer.supports_fast = peer_handshake.supports_fast();
 
             // if this is an inbound connection, we reply with the handshake
             if direction == Direction::Inbound {
-                let handshake = Handshake::new(
-                    self.torrent.info_hash,
-                    self.torrent.client_id,
-                );
+                let handshake = self.own_handshake();
                 log::info!(target: &self.ctx.log_target, "Sending handshake");
                 self.ctx.counters.protocol.up += handshake.len();
                 socket.send(handshake).await?;
@@ -372,6 +541,15 @@ impl PeerSession {
             self.free_pending_blocks().await;
         }
 
+        // the peer's pieces are no longer available to us
+        if self.peer.piece_count > 0 {
+            self.torrent
+                .piece_picker
+                .write()
+                .await
+                .unregister_peer_pieces(&self.peer.pieces);
+        }
+
         // send a state update message to torrent to actualize possible download
         // stats changes
         self.ctx.set_connection_state(ConnectionState::Disconnected);
@@ -387,9 +565,9 @@ impl PeerSession {
     ///
     /// This is the main session "loop" and performs the core of the session
     /// logic: exchange of messages, timeout logic, etc.
-    async fn run(
+    async fn run<S: Transport>(
         &mut self,
-        socket: Framed<TcpStream, PeerCodec>,
+        socket: Framed<S, PeerCodec>,
     ) -> Result<()> {
         self.ctx.connected_time = Some(Instant::now());
 
@@ -400,17 +578,48 @@ impl PeerSession {
 
         // This is the beginning of the session, which is the only time
         // a peer is allowed to advertise their pieces. If we have pieces
-        // available, send a bitfield message.
+        // available, send a bitfield message. With the Fast extension,
+        // availability must always be sent, and having all or none of the
+        // pieces has its own, shorter, message.
         {
             let piece_picker_guard = self.torrent.piece_picker.read().await;
             let own_pieces = piece_picker_guard.own_pieces();
-            if own_pieces.any() {
+            let msg = if self.peer.supports_fast && own_pieces.all() {
+                Some(Message::HaveAll)
+            } else if self.peer.supports_fast && own_pieces.not_any() {
+                Some(Message::HaveNone)
+            } else if own_pieces.any() {
+                Some(Message::Bitfield(own_pieces.clone()))
+            } else {
+                None
+            };
+            if let Some(msg) = msg {
                 log::info!(target: &self.ctx.log_target, "Sending piece availability");
-                sink.send(Message::Bitfield(own_pieces.clone())).await?;
+                sink.send(msg).await?;
                 log::info!(target: &self.ctx.log_target, "Sent piece availability");
             }
         }
 
+        // the extended handshake is sent after the bitfield, if the peer
+        // supports the extension protocol
+        if self.peer.supports_extensions {
+            self.send_extended_handshake(&mut sink).await?;
+        }
+
+        // tell the peer where our DHT node listens so that it can add it to
+        // its routing table
+        if self.peer.supports_dht {
+            if let Some(dht) = &self.torrent.dht {
+                sink.send(Message::Port(dht.port)).await?;
+                self.ctx.counters.protocol.up += MessageId::Port.header_len();
+            }
+        }
+
+        // tell the peer which of our pieces it may download while choked
+        if self.peer.supports_fast {
+            self.send_allowed_fast_set(&mut sink).await?;
+        }
+
         // used for collecting session stats every second
         let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
 
@@ -425,16 +634,33 @@ impl PeerSession {
                     let msg = msg?;
 
                     // handle bitfield message separately as it may only be
-                    // received directly after the handshake (later once we
-                    // implement the FAST extension, there will be other piece
-                    // availability related messages to handle)
-                    if self.ctx.state.connection == ConnectionState::AvailabilityExchange {
-                        if let Message::Bitfield(bitfield) = msg {
-                            self.handle_bitfield_msg(&mut sink, bitfield).await?;
-                        } else {
+                    // received directly after the handshake, as may the have
+                    // all and have none messages of the Fast extension
+                    //
+                    // Peers may send their extended handshake or DHT port
+                    // before their bitfield, so these messages don't end the
+                    // exchange.
+                    if self.ctx.state.connection == ConnectionState::AvailabilityExchange
+                        && !matches!(msg, Message::Extended { .. } | Message::Port(_))
+                    {
+                        let piece_count = self.torrent.storage.piece_count;
+                        let availability = match msg {
+                            Message::Bitfield(bitfield) => Ok(bitfield),
+                            Message::HaveAll if self.peer.supports_fast => {
+                                Ok(Bitfield::repeat(true, piece_count))
+                            }
+                            Message::HaveNone if self.peer.supports_fast => {
+                                Ok(Bitfield::repeat(false, piece_count))
+                            }
+                            msg => Err(msg),
+                        };
+                        match availability {
+                            Ok(bitfield) => {
+                                self.handle_bitfield_msg(&mut sink, bitfield).await?;
+                            }
                             // it's not mandatory to send a bitfield message
                             // right after the handshake
-                            self.handle_msg(&mut sink, msg).await?;
+                            Err(msg) => self.handle_msg(&mut sink, msg).await?,
                         }
 
                         // if neither of us have any pieces, disconnect, there
@@ -474,6 +700,24 @@ impl PeerSession {
                             self.ctx.in_endgame = in_endgame;
                             self.handle_piece_completion(&mut sink, index).await?;
                         }
+                        Command::PexPeers(peers) => {
+                            self.send_pex_msg(&mut sink, &peers).await?;
+                        }
+                        Command::Choke => self.choke_peer(&mut sink).await?,
+                        Command::Unchoke => self.unchoke_peer(&mut sink).await?,
+                        Command::PrioritiesChanged { in_endgame } => {
+                            self.ctx.in_endgame = in_endgame;
+                            let is_interested = self
+                                .torrent
+                                .piece_picker
+                                .read()
+                                .await
+                                .is_interested(&self.peer.pieces);
+                            self.update_interest(&mut sink, is_interested).await?;
+                        }
+                        Command::SetFast(is_fast) => {
+                            self.ctx.is_fast = is_fast;
+                        }
                         Command::Shutdown => {
                             log::info!(
                                 target: &self.ctx.log_target,
@@ -496,9 +740,9 @@ impl PeerSession {
     /// perhaps to the user directly, if requested), when the session leaves
     /// slow-start, when it checks various timeouts, and when it updates the
     /// target request queue size.
-    async fn tick(
+    async fn tick<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         now: Instant,
     ) -> Result<()> {
         // if we haven't become interested in each other for too long,
@@ -576,9 +820,9 @@ impl PeerSession {
     }
 
     /// Times out the peer if it hasn't sent a request in too long.
-    async fn check_request_timeout(
+    async fn check_request_timeout<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
     ) -> Result<()> {
         if let Some(last_outgoing_request_time) =
             self.ctx.last_outgoing_request_time
@@ -651,9 +895,9 @@ impl PeerSession {
 
     /// Handles a message expected in the `AvailabilityExchange` state
     /// (currently only the bitfield message).
-    async fn handle_bitfield_msg(
+    async fn handle_bitfield_msg<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         mut bitfield: Bitfield,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Handling peer Bitfield message");
@@ -700,15 +944,35 @@ impl PeerSession {
     }
 
     /// Handles messages from peer that are expected in the `Connected` state.
-    async fn handle_msg(
+    async fn handle_msg<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         msg: Message,
     ) -> Result<()> {
         // record protocol message size
         self.ctx.counters.protocol.down += msg.protocol_len();
+
+        // the messages of the Fast extension may only be sent if both sides
+        // support it
+        if !self.peer.supports_fast
+            && matches!(
+                msg,
+                Message::SuggestPiece { .. }
+                    | Message::HaveAll
+                    | Message::HaveNone
+                    | Message::RejectRequest(_)
+                    | Message::AllowedFast { .. }
+            )
+        {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer sent Fast extension message without advertising it"
+            );
+            return Err(PeerError::FastNotSupported);
+        }
+
         match msg {
-            Message::Bitfield(_) => {
+            Message::Bitfield(_) | Message::HaveAll | Message::HaveNone => {
                 log::info!(
                     target: &self.ctx.log_target,
                     "Peer sent bitfield message not after handshake"
@@ -721,10 +985,13 @@ impl PeerSession {
             Message::Choke => {
                 if !self.ctx.state.is_choked {
                     log::info!(target: &self.ctx.log_target, "Peer choked us");
-                    // since we're choked we don't expect to receive blocks
-                    // for our pending requests and free them for other peers to
-                    // download
-                    self.free_pending_blocks().await;
+                    // Without the Fast extension we don't expect to receive
+                    // blocks for our pending requests once choked and free
+                    // them for other peers to download. With it, the peer
+                    // rejects the requests it won't serve.
+                    if !self.peer.supports_fast {
+                        self.free_pending_blocks().await;
+                    }
                     self.ctx.update_state(|state| state.is_choked = true);
                 }
             }
@@ -743,17 +1010,14 @@ impl PeerSession {
                 }
             }
             Message::Interested => {
+                // whether the peer is unchoked is decided by the torrent's
+                // choker, which learns of the peer's interest with the next
+                // state update
                 if !self.ctx.state.is_peer_interested {
-                    // TODO(https://github.com/mandreyel/cratetorrent/issues/60):
-                    // we currently unchkoe peer unconditionally, but we should
-                    // implement the proper unchoke algorithm in `Torrent`
                     log::info!(target: &self.ctx.log_target, "Peer became interested");
-                    log::info!(target: &self.ctx.log_target, "Unchoking peer");
                     self.ctx.update_state(|state| {
                         state.is_peer_interested = true;
-                        state.is_peer_choked = false;
                     });
-                    sink.send(Message::Unchoke).await?;
                 }
             }
             Message::NotInterested => {
@@ -781,7 +1045,7 @@ impl PeerSession {
                 self.make_requests(sink).await?;
             }
             Message::Request(block_info) => {
-                self.handle_request_msg(block_info).await?;
+                self.handle_request_msg(sink, block_info).await?;
             }
             Message::Have { piece_index } => {
                 self.handle_have_msg(sink, piece_index).await?;
@@ -790,10 +1054,268 @@ impl PeerSession {
                 // before processing request validate block info
                 self.validate_block_info(&block_info)?;
                 log::info!(target: &self.ctx.log_target, "Peer cancelled block {}", block_info);
-                self.incoming_requests.remove(&block_info);
+                // with the Fast extension, every request must be answered,
+                // either with the block or with a reject
+                if self.incoming_requests.remove(&block_info)
+                    && self.peer.supports_fast
+                {
+                    self.reject_request(sink, block_info).await?;
+                }
+            }
+            Message::Port(port) => {
+                log::info!(target: &self.ctx.log_target, "Peer sent DHT port {}", port);
+                if let Some(dht) = &self.torrent.dht {
+                    let addr = SocketAddr::new(self.peer.addr.ip(), port);
+                    dht.tx.send(dht::Command::AddNode(addr)).ok();
+                }
+            }
+            Message::Extended { id, payload } => {
+                self.handle_extended_msg(sink, id, payload).await?;
+            }
+            Message::SuggestPiece { piece_index } => {
+                self.validate_piece_index(piece_index)?;
+                // suggestions are only hints, and we pick pieces ourselves
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Peer suggested piece {}",
+                    piece_index
+                );
+            }
+            Message::RejectRequest(block_info) => {
+                self.validate_block_info(&block_info)?;
+                self.handle_reject_request_msg(block_info).await;
             }
+            Message::AllowedFast { piece_index } => {
+                self.validate_piece_index(piece_index)?;
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Peer allows fast download of piece {}",
+                    piece_index
+                );
+                self.peer.allowed_fast.insert(piece_index);
+                // we may be able to download the piece even though we're
+                // choked
+                if self.ctx.state.is_choked {
+                    self.make_requests(sink).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the handshake we send the peer, which advertises the Fast
+    /// extension, and our DHT node if we run one.
+    fn own_handshake(&self) -> Handshake {
+        let mut handshake =
+            Handshake::new(self.torrent.info_hash, self.torrent.client_id);
+        handshake.set_supports_fast();
+        if self.torrent.dht.is_some() {
+            handshake.set_supports_dht();
+        }
+        handshake
+    }
+
+    /// Sends our extended handshake, advertising the torrent's extensions.
+    async fn send_extended_handshake<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+    ) -> Result<()> {
+        let handshake = extension::extended_handshake(
+            &self.torrent.extensions,
+            !self.torrent.is_private,
+        );
+        log::info!(
+            target: &self.ctx.log_target,
+            "Sending extended handshake: {:?}",
+            handshake
+        );
+        let payload = handshake
+            .to_bytes()
+            .map_err(|_| PeerError::InvalidExtendedHandshake)?;
+        self.send_extended_msg(sink, HANDSHAKE_ID, payload).await
+    }
+
+    /// Handles a message of the extension protocol: the peer's extended
+    /// handshake or a message of one of the torrent's extensions.
+    async fn handle_extended_msg<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+        id: u8,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        if !self.peer.supports_extensions {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer sent extended message without advertising extensions"
+            );
+            return Ok(());
+        }
+
+        if id == HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::from_bytes(&payload)
+                .map_err(|_| PeerError::InvalidExtendedHandshake)?;
+            log::info!(
+                target: &self.ctx.log_target,
+                "Peer sent extended handshake: {:?}",
+                handshake
+            );
+            self.peer.extensions = handshake.m;
+
+            // let the extensions the peer also supports greet it
+            let torrent = Arc::clone(&self.torrent);
+            for ext in torrent.extensions.iter() {
+                if let Some(peer_id) = self.peer_extension_id(ext.name()) {
+                    if let Some(reply) = ext.on_handshake(self.peer.addr) {
+                        self.send_extended_msg(sink, peer_id, reply).await?;
+                    }
+                }
+            }
+            return Ok(());
+        }
+
+        if id == UT_PEX_ID && !self.torrent.is_private {
+            return self.handle_pex_msg(&payload);
         }
 
+        let torrent = Arc::clone(&self.torrent);
+        let ext = match extension::user_extension(&torrent.extensions, id) {
+            Some(ext) => ext,
+            None => {
+                log::info!(
+                    target: &self.ctx.log_target,
+                    "Peer sent unknown extended message {}, ignoring",
+                    id
+                );
+                return Ok(());
+            }
+        };
+        log::debug!(
+            target: &self.ctx.log_target,
+            "Peer sent {} message",
+            ext.name()
+        );
+        if let Some(reply) = ext.on_message(self.peer.addr, &payload) {
+            match self.peer_extension_id(ext.name()) {
+                Some(peer_id) => {
+                    self.send_extended_msg(sink, peer_id, reply).await?
+                }
+                None => log::warn!(
+                    target: &self.ctx.log_target,
+                    "Peer doesn't support {}, not replying",
+                    ext.name()
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Passes on the peers the peer connected to, to the torrent.
+    ///
+    /// The peers the peer disconnected from may still be reachable, so they
+    /// are not removed from the torrent.
+    fn handle_pex_msg(&mut self, payload: &[u8]) -> Result<()> {
+        let mut msg = match PexMsg::from_bytes(payload) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!(
+                    target: &self.ctx.log_target,
+                    "Peer sent invalid peer exchange message: {}",
+                    e
+                );
+                return Ok(());
+            }
+        };
+        log::info!(
+            target: &self.ctx.log_target,
+            "Peer exchange: {} added, {} dropped peer(s)",
+            msg.added.len(),
+            msg.dropped.len()
+        );
+        // don't let a peer flood us with addresses
+        msg.added.truncate(MAX_PEX_PEERS);
+        if !msg.added.is_empty() {
+            self.torrent.cmd_tx.send(torrent::Command::PexPeers {
+                addr: self.peer.addr,
+                peers: msg.added,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Tells the peer which of the torrent's peers we connected to and
+    /// disconnected from since we last told it, if it supports peer exchange.
+    async fn send_pex_msg<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+        peers: &[(SocketAddr, PexFlags)],
+    ) -> Result<()> {
+        if self.torrent.is_private {
+            return Ok(());
+        }
+        let id = match self.peer_extension_id(UT_PEX) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        let added: Vec<_> = peers
+            .iter()
+            .filter(|(addr, _)| {
+                *addr != self.peer.addr && !self.pex_peers.contains(addr)
+            })
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        let dropped: Vec<_> = self
+            .pex_peers
+            .iter()
+            .filter(|addr| !peers.iter().any(|(a, _)| a == *addr))
+            .take(MAX_PEX_PEERS)
+            .copied()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return Ok(());
+        }
+
+        for (addr, _) in added.iter() {
+            self.pex_peers.insert(*addr);
+        }
+        for addr in dropped.iter() {
+            self.pex_peers.remove(addr);
+        }
+        log::info!(
+            target: &self.ctx.log_target,
+            "Sending peer exchange: {} added, {} dropped peer(s)",
+            added.len(),
+            dropped.len()
+        );
+        let payload = PexMsg { added, dropped }
+            .to_bytes()
+            .expect("cannot encode peer exchange message");
+        self.send_extended_msg(sink, id, payload).await
+    }
+
+    /// Returns the id with which the peer wants to receive messages of the
+    /// given extension, if it supports it.
+    fn peer_extension_id(&self, name: &str) -> Option<u8> {
+        self.peer
+            .extensions
+            .get(name)
+            .copied()
+            .filter(|id| *id != HANDSHAKE_ID)
+    }
+
+    /// Sends a message of the extension protocol with the given id.
+    async fn send_extended_msg<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+        id: u8,
+        payload: Vec<u8>,
+    ) -> Result<()> {
+        let msg = Message::Extended { id, payload };
+        self.ctx.counters.protocol.up += msg.protocol_len();
+        sink.send(msg).await?;
         Ok(())
     }
 
@@ -802,42 +1324,87 @@ impl PeerSession {
     ///
     /// To see what this means, please refer to the
     /// `Status::best_request_queue_len` or the relevant section in DESIGN.md.
-    async fn make_requests(
+    async fn make_requests<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
     ) -> Result<()> {
         log::trace!(target: &self.ctx.log_target, "Making requests");
 
-        if self.ctx.state.is_choked {
-            log::debug!(target: &self.ctx.log_target, "Cannot make requests while choked");
-            return Ok(());
-        }
-
         if !self.ctx.state.is_interested {
             log::debug!(target: &self.ctx.log_target, "Cannot make requests if not interested");
             return Ok(());
         }
 
+        // while choked, only the pieces in the peer's allowed fast set may be
+        // requested
+        let allowed_pieces = if self.ctx.state.is_choked {
+            let mut allowed_pieces =
+                Bitfield::repeat(false, self.torrent.storage.piece_count);
+            for &index in self.peer.allowed_fast.iter() {
+                if self.peer.pieces[index] {
+                    allowed_pieces.set(index, true);
+                }
+            }
+            if allowed_pieces.not_any() {
+                log::debug!(target: &self.ctx.log_target, "Cannot make requests while choked");
+                return Ok(());
+            }
+            self.ctx.prepare_for_allowed_fast_download();
+            Some(allowed_pieces)
+        } else {
+            None
+        };
+
         // TODO: optimize this by using the preallocated hashset in self
         let mut requests = Vec::new();
-        let target_request_queue_len =
-            self.ctx.target_request_queue_len.unwrap_or_default();
+
+        // The fastest peers download the pieces with a deadline first. The
+        // blocks of the pieces at risk of missing their deadline are requested
+        // even if they were already requested from other, slower, peers.
+        if self.ctx.is_fast && allowed_pieces.is_none() {
+            for download in self.torrent.downloads.read().await.values() {
+                let to_request_count = self.to_request_count(&requests);
+                if to_request_count == 0 {
+                    break;
+                }
+                let mut download_write_guard = download.write().await;
+                if download_write_guard.is_urgent() {
+                    log::debug!(
+                        target: &self.ctx.log_target,
+                        "Requesting urgent piece {}",
+                        download_write_guard.piece_index()
+                    );
+                    download_write_guard.pick_blocks(
+                        to_request_count,
+                        &mut requests,
+                        true,
+                        &self.outgoing_requests,
+                    );
+                }
+            }
+            self.start_downloads(&mut requests, true, None).await;
+        }
 
         // If we have active downloads, prefer to continue those. This will
         // result in less in-progress pieces.
         for download in self.torrent.downloads.write().await.values_mut() {
-            // check and calculate the number of requests we can make now
-            let outgoing_request_count =
-                requests.len() + self.outgoing_requests.len();
             // our outgoing request queue shouldn't exceed the allowed request
             // queue size
-            if outgoing_request_count >= target_request_queue_len {
+            let to_request_count = self.to_request_count(&requests);
+            if to_request_count == 0 {
                 break;
             }
-            let to_request_count =
-                target_request_queue_len - outgoing_request_count;
 
             let mut download_write_guard = download.write().await;
+            // urgent downloads were already continued above
+            if self.ctx.is_fast && download_write_guard.is_urgent() {
+                continue;
+            }
+            if let Some(allowed_pieces) = &allowed_pieces {
+                if !allowed_pieces[download_write_guard.piece_index()] {
+                    continue;
+                }
+            }
             log::trace!(
                 target: &self.ctx.log_target,
                 "Trying to continue download {}",
@@ -852,22 +1419,72 @@ impl PeerSession {
         }
 
         // while we can make more requests we start new download(s)
+        self.start_downloads(&mut requests, false, allowed_pieces.as_ref())
+            .await;
+
+        if !requests.is_empty() {
+            log::info!(
+                target: &self.ctx.log_target,
+                "Requesting {} block(s) ({} pending)",
+                requests.len(),
+                self.outgoing_requests.len()
+            );
+            self.ctx.last_outgoing_request_time = Some(Instant::now());
+            // make the actual requests
+            for req in requests.into_iter() {
+                log::debug!(target: &self.ctx.log_target, "Requesting block {}", req);
+                self.outgoing_requests.insert(req);
+                // TODO: batch these in a single syscall, or is this already
+                // being done by the tokio codec type?
+                sink.send(Message::Request(req)).await?;
+                self.ctx.counters.protocol.up +=
+                    MessageId::Request.header_len();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of requests we can make in addition to the ones we
+    /// already have, so that our outgoing request queue doesn't exceed the
+    /// target request queue size.
+    fn to_request_count(&self, requests: &[BlockInfo]) -> usize {
+        self.ctx
+            .target_request_queue_len
+            .unwrap_or_default()
+            .saturating_sub(requests.len() + self.outgoing_requests.len())
+    }
+
+    /// Picks new pieces to download and their blocks to request while we can
+    /// make more requests, or only pieces with a deadline, if `deadline_only`
+    /// is set.
+    ///
+    /// Pieces are picked among `allowed_pieces` if given, and among all the
+    /// peer's pieces otherwise.
+    async fn start_downloads(
+        &mut self,
+        requests: &mut Vec<BlockInfo>,
+        deadline_only: bool,
+        allowed_pieces: Option<&Bitfield>,
+    ) {
+        let pieces = allowed_pieces.unwrap_or(&self.peer.pieces);
         loop {
-            let outgoing_request_count =
-                requests.len() + self.outgoing_requests.len();
-            // our outgoing request queue shouldn't exceed the allowed request
-            // queue size
-            if outgoing_request_count >= target_request_queue_len {
+            let to_request_count = self.to_request_count(requests);
+            if to_request_count == 0 {
                 break;
             }
-            let to_request_count =
-                target_request_queue_len - outgoing_request_count;
 
             log::debug!(target: &self.ctx.log_target, "Trying to pick new piece");
 
-            if let Some(index) =
-                self.torrent.piece_picker.write().await.pick_piece()
-            {
+            let mut piece_picker = self.torrent.piece_picker.write().await;
+            let pick = if deadline_only {
+                piece_picker.pick_deadline_piece(pieces)
+            } else {
+                piece_picker.pick_piece(pieces)
+            };
+            drop(piece_picker);
+
+            if let Some(index) = pick {
                 log::info!(target: &self.ctx.log_target, "Picked piece {}", index);
 
                 let mut download = PieceDownload::new(
@@ -877,7 +1494,7 @@ impl PeerSession {
 
                 download.pick_blocks(
                     to_request_count,
-                    &mut requests,
+                    requests,
                     self.ctx.in_endgame,
                     &self.outgoing_requests,
                 );
@@ -899,28 +1516,6 @@ impl PeerSession {
                 break;
             }
         }
-
-        if !requests.is_empty() {
-            log::info!(
-                target: &self.ctx.log_target,
-                "Requesting {} block(s) ({} pending)",
-                requests.len(),
-                self.outgoing_requests.len()
-            );
-            self.ctx.last_outgoing_request_time = Some(Instant::now());
-            // make the actual requests
-            for req in requests.into_iter() {
-                log::debug!(target: &self.ctx.log_target, "Requesting block {}", req);
-                self.outgoing_requests.insert(req);
-                // TODO: batch these in a single syscall, or is this already
-                // being done by the tokio codec type?
-                sink.send(Message::Request(req)).await?;
-                self.ctx.counters.protocol.up +=
-                    MessageId::Request.header_len();
-            }
-        }
-
-        Ok(())
     }
 
     /// Verifies block validity, registers the download, and records statistics.
@@ -1006,6 +1601,29 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Handles the peer's rejection of our request by freeing the block for
+    /// other peer sessions to download.
+    async fn handle_reject_request_msg(&mut self, block_info: BlockInfo) {
+        if !self.outgoing_requests.remove(&block_info) {
+            log::warn!(
+                target: &self.ctx.log_target,
+                "Peer rejected block {} we didn't request",
+                block_info
+            );
+            return;
+        }
+        log::info!(target: &self.ctx.log_target, "Peer rejected block {}", block_info);
+        if let Some(download) = self
+            .torrent
+            .downloads
+            .read()
+            .await
+            .get(&block_info.piece_index)
+        {
+            download.write().await.free_block(&block_info);
+        }
+    }
+
     /// Handles the peer request message.
     ///
     /// If the request is valid and that peer may make requests, we instruct the
@@ -1013,8 +1631,9 @@ impl PeerSession {
     /// we receive a message on the peer session's command port in
     /// [`Self::run`]. This is when the block is actually sent to peer, if by
     /// the request is not cancelled by then.
-    async fn handle_request_msg(
+    async fn handle_request_msg<S: Transport>(
         &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         block_info: BlockInfo,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Got request: {:?}", block_info);
@@ -1022,10 +1641,20 @@ impl PeerSession {
         // before processing request validate block info
         self.validate_block_info(&block_info)?;
 
-        // check if peer is not choked: if they are, they can't request blocks
-        if self.ctx.state.is_peer_choked {
-            log::warn!(target: &self.ctx.log_target, "Choked peer sent request");
-            return Err(PeerError::RequestWhileChoked);
+        // check if peer is not choked: if they are, they can't request blocks,
+        // but the request may have been sent before our choke message arrived,
+        // so it's not an error. With the Fast extension, the pieces of the
+        // peer's allowed fast set that we have may be requested while choked,
+        // and other requests are rejected rather than ignored.
+        if self.ctx.state.is_peer_choked
+            && !self.is_allowed_fast(block_info.piece_index).await
+        {
+            if self.peer.supports_fast {
+                log::info!(target: &self.ctx.log_target, "Choked peer sent request, rejecting");
+                return self.reject_request(sink, block_info).await;
+            }
+            log::info!(target: &self.ctx.log_target, "Choked peer sent request, ignoring");
+            return Ok(());
         }
 
         // check if peer is not already requesting this block
@@ -1049,11 +1678,106 @@ impl PeerSession {
         Ok(())
     }
 
+    /// Returns whether the peer may download the piece while choked, which is
+    /// the case if the piece is in its allowed fast set and we have it.
+    async fn is_allowed_fast(&self, index: PieceIndex) -> bool {
+        self.allowed_fast.contains(&index)
+            && self.torrent.piece_picker.read().await.own_pieces()[index]
+    }
+
+    /// Tells the peer that we won't serve its request.
+    async fn reject_request<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+        block_info: BlockInfo,
+    ) -> Result<()> {
+        log::debug!(target: &self.ctx.log_target, "Rejecting request {}", block_info);
+        self.ctx.counters.protocol.up += MessageId::RejectRequest.header_len();
+        sink.send(Message::RejectRequest(block_info)).await?;
+        Ok(())
+    }
+
+    /// Sends the peer the pieces of its allowed fast set that we have, which
+    /// it may download from us even while choked.
+    ///
+    /// The rest of the set is sent as we complete the pieces.
+    async fn send_allowed_fast_set<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+    ) -> Result<()> {
+        let set = allowed_fast_set(
+            self.peer.addr.ip(),
+            &self.torrent.info_hash,
+            self.torrent.storage.piece_count,
+            ALLOWED_FAST_COUNT,
+        );
+        self.allowed_fast = set.iter().copied().collect();
+        let own_pieces =
+            self.torrent.piece_picker.read().await.own_pieces().clone();
+        for piece_index in set {
+            if own_pieces[piece_index] {
+                log::debug!(
+                    target: &self.ctx.log_target,
+                    "Allowing fast download of piece {}",
+                    piece_index
+                );
+                self.ctx.counters.protocol.up +=
+                    MessageId::AllowedFast.header_len();
+                sink.send(Message::AllowedFast { piece_index }).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Chokes the peer, which means we no longer serve its requests, including
+    /// the ones it already sent, except for the pieces in its allowed fast
+    /// set.
+    async fn choke_peer<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+    ) -> Result<()> {
+        if self.ctx.state.is_peer_choked {
+            return Ok(());
+        }
+        log::info!(target: &self.ctx.log_target, "Choking peer");
+        self.ctx.update_state(|state| state.is_peer_choked = true);
+        sink.send(Message::Choke).await?;
+        // blocks that are still being read from disk are dropped when they
+        // arrive, and with the Fast extension, the peer is told so
+        let mut rejected = Vec::new();
+        for block_info in self.incoming_requests.iter() {
+            if !self.is_allowed_fast(block_info.piece_index).await {
+                rejected.push(*block_info);
+            }
+        }
+        for block_info in rejected {
+            self.incoming_requests.remove(&block_info);
+            if self.peer.supports_fast {
+                self.reject_request(sink, block_info).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Unchokes the peer, allowing it to request blocks from us.
+    async fn unchoke_peer<S: Transport>(
+        &mut self,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
+    ) -> Result<()> {
+        if !self.ctx.state.is_peer_choked {
+            return Ok(());
+        }
+        log::info!(target: &self.ctx.log_target, "Unchoking peer");
+        self.ctx.update_state(|state| state.is_peer_choked = false);
+        sink.send(Message::Unchoke).await?;
+        Ok(())
+    }
+
     /// Sends the block to peer if the peer still wants it (hasn't canceled the
     /// request).
-    async fn send_block(
+    async fn send_block<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         block: Block,
     ) -> Result<()> {
         let info = block.info();
@@ -1086,9 +1810,9 @@ impl PeerSession {
 
     /// Handles the announcement of a new piece that peer has. This may cause us
     /// to become interested in peer and start making requests.
-    async fn handle_have_msg(
+    async fn handle_have_msg<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         piece_index: PieceIndex,
     ) -> Result<()> {
         log::info!(target: &self.ctx.log_target, "Peer has piece {}", piece_index);
@@ -1119,9 +1843,9 @@ impl PeerSession {
     }
 
     /// Checks whether we have become or stopped being interested in the peer.
-    async fn update_interest(
+    async fn update_interest<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         is_interested: bool,
     ) -> Result<()> {
         // we may have become interested in peer
@@ -1177,9 +1901,9 @@ impl PeerSession {
     ///
     /// If peer has the piece, we check if we had any requests for blocks in it
     /// that we need to cancel. If peer doesn't have the piece, we announce it.
-    async fn handle_piece_completion(
+    async fn handle_piece_completion<S: Transport>(
         &mut self,
-        sink: &mut SplitSink<Framed<TcpStream, PeerCodec>, Message>,
+        sink: &mut SplitSink<Framed<S, PeerCodec>, Message>,
         piece_index: PieceIndex,
     ) -> Result<()> {
         // if peer doesn't have the piece, announce it
@@ -1190,6 +1914,13 @@ impl PeerSession {
                 piece_index
             );
             sink.send(Message::Have { piece_index }).await?;
+            // the peer may now download the piece while choked, if it's in its
+            // allowed fast set
+            if self.allowed_fast.contains(&piece_index) {
+                self.ctx.counters.protocol.up +=
+                    MessageId::AllowedFast.header_len();
+                sink.send(Message::AllowedFast { piece_index }).await?;
+            }
         } else {
             // Otherwise peer has it and we may have requested it. Check if
             // there are any pending requests for blocks in this piece, and if
@@ -1219,3 +1950,7 @@ impl PeerSession {
 /// After this timeout if the peers haven't become intereseted in each other,
 /// the connection is severed.
 const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The encrypted handshake of an outbound connection is abandoned after this
+/// long.
+const MSE_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
@@ -15,8 +15,9 @@ pub(crate) struct Handshake {
     /// The protocol string, which must equal "BitTorrent protocol", as
     /// otherwise the connetion is aborted.
     pub prot: [u8; 19],
-    /// A reserved field, currently all zeros. This is where the client's
-    /// supported extensions are announced.
+    /// A reserved field, where the client's supported extensions are
+    /// announced. Currently the extension protocol, the DHT, and the Fast
+    /// extension are advertised.
     pub reserved: [u8; 8],
     /// The torrent's SHA1 info hash, used to identify the torrent in the
     /// handshake and to verify the peer.
@@ -27,13 +28,15 @@ pub(crate) struct Handshake {
 
 impl Handshake {
     /// Creates a new protocol version 1 handshake with the given info hash and
-    /// peer id.
+    /// peer id, advertising support for the extension protocol (BEP 10).
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         let mut prot = [0; 19];
         prot.copy_from_slice(PROTOCOL_STRING.as_bytes());
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_FLAG;
         Self {
             prot,
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
@@ -43,8 +46,50 @@ impl Handshake {
     pub const fn len(&self) -> u64 {
         19 + 8 + 20 + 20
     }
+
+    /// Returns whether the sender of the handshake supports the extension
+    /// protocol (BEP 10).
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_FLAG != 0
+    }
+
+    /// Advertises that we run a DHT node (BEP 5), in which case the other side
+    /// may send us our node's port via the port message.
+    pub fn set_supports_dht(&mut self) {
+        self.reserved[DHT_BYTE] |= DHT_FLAG;
+    }
+
+    /// Returns whether the sender of the handshake runs a DHT node.
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[DHT_BYTE] & DHT_FLAG != 0
+    }
+
+    /// Advertises support for the Fast extension (BEP 6).
+    pub fn set_supports_fast(&mut self) {
+        self.reserved[FAST_BYTE] |= FAST_FLAG;
+    }
+
+    /// Returns whether the sender of the handshake supports the Fast
+    /// extension. The extension is only used if both sides support it.
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[FAST_BYTE] & FAST_FLAG != 0
+    }
 }
 
+/// The extension protocol is signaled by the 20th bit from the right of the
+/// reserved field, i.e. the fifth bit of the sixth byte.
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_FLAG: u8 = 0x10;
+
+/// DHT support is signaled by the last bit of the reserved field.
+const DHT_BYTE: usize = 7;
+const DHT_FLAG: u8 = 0x01;
+
+/// Fast extension support is signaled by the third bit from the right of the
+/// reserved field.
+const FAST_BYTE: usize = 7;
+const FAST_FLAG: u8 = 0x04;
+
 /// The protocol version 1 string included in the handshake.
 pub(crate) const PROTOCOL_STRING: &str = "BitTorrent protocol";
 
@@ -56,6 +101,7 @@ pub(crate) const PROTOCOL_STRING: &str = "BitTorrent protocol";
 /// receiving and sending a handshake the codec should be switched to
 /// [`PeerCodec`], but care should be taken not to discard the underlying
 /// receive and send buffers.
+#[derive(Debug)]
 pub(crate) struct HandshakeCodec;
 
 impl Encoder<Handshake> for HandshakeCodec {
@@ -172,6 +218,32 @@ pub(crate) enum Message {
         data: BlockData,
     },
     Cancel(BlockInfo),
+    /// The port on which the sender's DHT node listens.
+    Port(u16),
+    /// A message of the extension protocol (BEP 10).
+    ///
+    /// The id is 0 for the extended handshake, and for other messages it is
+    /// the id that the receiving side assigned to the extension in its
+    /// extended handshake. The payload is opaque to the codec.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// The sender suggests that the piece be downloaded from it (BEP 6).
+    SuggestPiece {
+        piece_index: usize,
+    },
+    /// The sender has all pieces, sent in place of the bitfield (BEP 6).
+    HaveAll,
+    /// The sender has no pieces, sent in place of the bitfield (BEP 6).
+    HaveNone,
+    /// The sender won't serve the request (BEP 6).
+    RejectRequest(BlockInfo),
+    /// The piece may be requested from the sender even while the receiver is
+    /// choked (BEP 6).
+    AllowedFast {
+        piece_index: usize,
+    },
 }
 
 impl Message {
@@ -188,6 +260,13 @@ impl Message {
             Self::Request(_) => Some(MessageId::Request),
             Self::Block { .. } => Some(MessageId::Block),
             Self::Cancel(_) => Some(MessageId::Cancel),
+            Self::Port(_) => Some(MessageId::Port),
+            Self::Extended { .. } => Some(MessageId::Extended),
+            Self::SuggestPiece { .. } => Some(MessageId::SuggestPiece),
+            Self::HaveAll => Some(MessageId::HaveAll),
+            Self::HaveNone => Some(MessageId::HaveNone),
+            Self::RejectRequest(_) => Some(MessageId::RejectRequest),
+            Self::AllowedFast { .. } => Some(MessageId::AllowedFast),
         }
     }
 
@@ -195,7 +274,9 @@ impl Message {
     /// message header. For all but the block message this is simply the size of
     /// the message. For the block message this is the message header.
     pub fn protocol_len(&self) -> u64 {
-        if let Some(id) = self.id() {
+        if let Self::Extended { payload, .. } = self {
+            MessageId::Extended.header_len() + payload.len() as u64
+        } else if let Some(id) = self.id() {
             id.header_len()
         } else {
             assert_eq!(*self, Self::KeepAlive);
@@ -219,6 +300,13 @@ pub(crate) enum MessageId {
     Request = 6,
     Block = 7,
     Cancel = 8,
+    Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
 impl MessageId {
@@ -237,6 +325,13 @@ impl MessageId {
             Self::Request => 4 + 1 + 3 * 4,
             Self::Block => 4 + 1 + 2 * 4,
             Self::Cancel => 4 + 1 + 3 * 4,
+            Self::Port => 4 + 1 + 2,
+            Self::SuggestPiece => 4 + 1 + 4,
+            Self::HaveAll => 4 + 1,
+            Self::HaveNone => 4 + 1,
+            Self::RejectRequest => 4 + 1 + 3 * 4,
+            Self::AllowedFast => 4 + 1 + 4,
+            Self::Extended => 4 + 1 + 1,
         }
     }
 }
@@ -256,6 +351,13 @@ impl TryFrom<u8> for MessageId {
             k if k == Request as u8 => Ok(Request),
             k if k == Block as u8 => Ok(Block),
             k if k == Cancel as u8 => Ok(Cancel),
+            k if k == Port as u8 => Ok(Port),
+            k if k == SuggestPiece as u8 => Ok(SuggestPiece),
+            k if k == HaveAll as u8 => Ok(HaveAll),
+            k if k == HaveNone as u8 => Ok(HaveNone),
+            k if k == RejectRequest as u8 => Ok(RejectRequest),
+            k if k == AllowedFast as u8 => Ok(AllowedFast),
+            k if k == Extended as u8 => Ok(Extended),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
                 "Unknown message id",
@@ -398,6 +500,80 @@ impl Encoder<Message> for PeerCodec {
                 // payload
                 block.encode(buf)?;
             }
+            Port(port) => {
+                // message length prefix: 1 byte message id and 2 byte port
+                let msg_len = 1 + 2;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::Port as u8);
+                // payload
+                buf.put_u16(port);
+            }
+            Extended { id, payload } => {
+                // message length prefix:
+                // 1 byte message id, 1 byte extended message id, and n byte
+                // payload
+                let msg_len = 1 + 1 + payload.len() as u32;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::Extended as u8);
+                // payload
+                buf.put_u8(id);
+                buf.extend_from_slice(&payload);
+            }
+            SuggestPiece { piece_index } => {
+                // message length prefix:
+                // 1 byte message id and 4 byte piece index
+                let msg_len = 1 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::SuggestPiece as u8);
+                // payload
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                buf.put_u32(piece_index);
+            }
+            HaveAll => {
+                // message length prefix: 1 byte message id
+                let msg_len = 1;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HaveAll as u8);
+                // no payload
+            }
+            HaveNone => {
+                // message length prefix: 1 byte message id
+                let msg_len = 1;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::HaveNone as u8);
+                // no payload
+            }
+            RejectRequest(block) => {
+                // message length prefix:
+                // 1 byte message id, 4 byte piece index, 4 byte offset, 4 byte
+                // length
+                let msg_len = 1 + 4 + 4 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::RejectRequest as u8);
+                // payload
+                block.encode(buf)?;
+            }
+            AllowedFast { piece_index } => {
+                // message length prefix:
+                // 1 byte message id and 4 byte piece index
+                let msg_len = 1 + 4;
+                buf.put_u32(msg_len);
+                // message id
+                buf.put_u8(MessageId::AllowedFast as u8);
+                // payload
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                buf.put_u32(piece_index);
+            }
         }
 
         Ok(())
@@ -524,6 +700,90 @@ impl Decoder for PeerCodec {
                     len,
                 })
             }
+            MessageId::Port => {
+                if msg_len != 3 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Port message must have a 2 byte port",
+                    ));
+                }
+                Message::Port(buf.get_u16())
+            }
+            MessageId::Extended => {
+                if msg_len < 2 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Extended message must have an id",
+                    ));
+                }
+                let id = buf.get_u8();
+                let mut payload = vec![0; msg_len - 2];
+                buf.copy_to_slice(&mut payload);
+                Message::Extended { id, payload }
+            }
+            MessageId::SuggestPiece => {
+                if msg_len != 5 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "SuggestPiece message must have a 4 byte piece index",
+                    ));
+                }
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                Message::SuggestPiece { piece_index }
+            }
+            MessageId::HaveAll => {
+                if msg_len != 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "HaveAll message must not have a payload",
+                    ));
+                }
+                Message::HaveAll
+            }
+            MessageId::HaveNone => {
+                if msg_len != 1 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "HaveNone message must not have a payload",
+                    ));
+                }
+                Message::HaveNone
+            }
+            MessageId::RejectRequest => {
+                if msg_len != 13 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "RejectRequest message must have a 12 byte block info",
+                    ));
+                }
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                let offset = buf.get_u32();
+                let len = buf.get_u32();
+                Message::RejectRequest(BlockInfo {
+                    piece_index,
+                    offset,
+                    len,
+                })
+            }
+            MessageId::AllowedFast => {
+                if msg_len != 5 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "AllowedFast message must have a 4 byte piece index",
+                    ));
+                }
+                let piece_index = buf.get_u32();
+                let piece_index = piece_index.try_into().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, e)
+                })?;
+                Message::AllowedFast { piece_index }
+            }
         };
 
         Ok(Some(msg))
@@ -558,6 +818,10 @@ mod tests {
             make_keep_alive(),
             make_interested(),
             make_cancel(),
+            make_extended(),
+            make_have_all(),
+            make_reject_request(),
+            make_allowed_fast(),
             make_block(),
             make_not_interested(),
             make_choke(),
@@ -621,6 +885,10 @@ mod tests {
             make_block(),
             make_interested(),
             make_cancel(),
+            make_extended(),
+            make_have_all(),
+            make_reject_request(),
+            make_allowed_fast(),
             make_block(),
             make_not_interested(),
             make_choke(),
@@ -809,6 +1077,108 @@ mod tests {
         assert_message_codec(msg, expected_encoded);
     }
 
+    #[test]
+    fn test_port_codec() {
+        assert_message_codec(
+            Message::Port(6881),
+            Bytes::from_static(&[0, 0, 0, 3, 9, 0x1a, 0xe1]),
+        );
+    }
+
+    #[test]
+    fn test_extended_codec() {
+        let (msg, expected_encoded) = make_extended();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    #[test]
+    fn test_suggest_piece_codec() {
+        assert_message_codec(
+            Message::SuggestPiece { piece_index: 42 },
+            Bytes::from_static(&[0, 0, 0, 5, 13, 0, 0, 0, 42]),
+        );
+    }
+
+    #[test]
+    fn test_have_all_codec() {
+        let (msg, expected_encoded) = make_have_all();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    #[test]
+    fn test_have_none_codec() {
+        assert_message_codec(
+            Message::HaveNone,
+            make_empty_msg_encoded_payload(MessageId::HaveNone),
+        );
+    }
+
+    #[test]
+    fn test_reject_request_codec() {
+        let (msg, expected_encoded) = make_reject_request();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    #[test]
+    fn test_allowed_fast_codec() {
+        let (msg, expected_encoded) = make_allowed_fast();
+        assert_message_codec(msg, expected_encoded);
+    }
+
+    /// Tests that the Fast extension messages with an invalid length are
+    /// rejected rather than read past the end of the message.
+    #[test]
+    fn test_invalid_fast_msg_len_decoding() {
+        let invalid_msgs: &[&[u8]] = &[
+            // suggest piece and allowed fast without the piece index
+            &[0, 0, 0, 1, MessageId::SuggestPiece as u8],
+            &[0, 0, 0, 1, MessageId::AllowedFast as u8],
+            // reject request with only a piece index
+            &[0, 0, 0, 5, MessageId::RejectRequest as u8, 0, 0, 0, 1],
+            // have all and have none with a payload
+            &[0, 0, 0, 2, MessageId::HaveAll as u8, 0],
+            &[0, 0, 0, 2, MessageId::HaveNone as u8, 0],
+        ];
+        for msg in invalid_msgs {
+            let mut buf = BytesMut::from(*msg);
+            let result = PeerCodec.decode(&mut buf);
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    /// Tests that the extension protocol bit is set in and read from the
+    /// correct position in the handshake's reserved field.
+    #[test]
+    fn test_handshake_extension_protocol_bit() {
+        let mut handshake = Handshake::new([0xab; 20], [0xcd; 20]);
+        assert!(handshake.supports_extension_protocol());
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0]);
+        handshake.reserved = [0; 8];
+        assert!(!handshake.supports_extension_protocol());
+    }
+
+    /// Tests that the DHT bit is set in and read from the last bit of the
+    /// handshake's reserved field.
+    #[test]
+    fn test_handshake_dht_bit() {
+        let mut handshake = Handshake::new([0xab; 20], [0xcd; 20]);
+        assert!(!handshake.supports_dht());
+        handshake.set_supports_dht();
+        assert!(handshake.supports_dht());
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+    }
+
+    /// Tests that the Fast extension bit is set in and read from the correct
+    /// position in the handshake's reserved field.
+    #[test]
+    fn test_handshake_fast_bit() {
+        let mut handshake = Handshake::new([0xab; 20], [0xcd; 20]);
+        assert!(!handshake.supports_fast());
+        handshake.set_supports_fast();
+        assert!(handshake.supports_fast());
+        assert_eq!(handshake.reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+    }
+
     /// Helper function that asserts that a message is encoded and subsequently
     /// decoded correctly.
     fn assert_message_codec(msg: Message, expected_encoded: Bytes) {
@@ -863,8 +1233,17 @@ mod tests {
         )
     }
 
-    /// Helper used to create 'choke', 'unchoke', 'interested', and 'not
-    /// interested' encoded messages that all have the same format.
+    /// Returns `HaveAll` and its expected encoded variant.
+    fn make_have_all() -> (Message, Bytes) {
+        (
+            Message::HaveAll,
+            make_empty_msg_encoded_payload(MessageId::HaveAll),
+        )
+    }
+
+    /// Helper used to create 'choke', 'unchoke', 'interested', 'not
+    /// interested', 'have all', and 'have none' encoded messages that all have
+    /// the same format.
     fn make_empty_msg_encoded_payload(id: MessageId) -> Bytes {
         // 1 byte message id
         let msg_len = 1;
@@ -984,8 +1363,66 @@ mod tests {
         (msg, encoded)
     }
 
-    /// Helper used to create 'request' and 'cancel' encoded messages that have
-    /// the same format.
+    /// Returns `RejectRequest` and its expected encoded variant.
+    fn make_reject_request() -> (Message, Bytes) {
+        let piece_index = 42;
+        let offset = 0x4000;
+        let len = BLOCK_LEN;
+        let msg = Message::RejectRequest(BlockInfo {
+            piece_index,
+            offset,
+            len,
+        });
+        let encoded = make_block_info_encoded_msg_payload(
+            MessageId::RejectRequest,
+            piece_index,
+            offset,
+            len,
+        );
+        (msg, encoded)
+    }
+
+    /// Returns `AllowedFast` and its expected encoded variant.
+    fn make_allowed_fast() -> (Message, Bytes) {
+        let piece_index = 42;
+        let msg = Message::AllowedFast { piece_index };
+        let encoded = {
+            // 1 byte message id and 4 byte piece index
+            let msg_len = 1 + 4;
+            // 4 byte message length prefix and message length
+            let buf_len = 4 + msg_len;
+            let mut buf = BytesMut::with_capacity(buf_len);
+            buf.put_u32(msg_len as u32);
+            buf.put_u8(MessageId::AllowedFast as u8);
+            // ok to unwrap, only used in tests
+            buf.put_u32(piece_index.try_into().unwrap());
+            buf
+        };
+        (msg, encoded.into())
+    }
+
+    /// Returns `Extended` and its expected encoded variant.
+    fn make_extended() -> (Message, Bytes) {
+        let id = 3;
+        let payload = b"d8:msg_typei0e5:piecei0ee".to_vec();
+        let encoded = {
+            // 1 byte message id, 1 byte extended message id, and n byte
+            // payload
+            let msg_len = 1 + 1 + payload.len();
+            // 4 byte message length prefix and message length
+            let buf_len = 4 + msg_len;
+            let mut buf = BytesMut::with_capacity(buf_len);
+            buf.put_u32(msg_len as u32);
+            buf.put_u8(MessageId::Extended as u8);
+            buf.put_u8(id);
+            buf.extend_from_slice(&payload);
+            buf
+        };
+        (Message::Extended { id, payload }, encoded.into())
+    }
+
+    /// Helper used to create 'request', 'cancel', and 'reject request' encoded
+    /// messages that have the same format.
     fn make_block_info_encoded_msg_payload(
         id: MessageId,
         piece_index: usize,
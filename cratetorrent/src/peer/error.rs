@@ -30,6 +30,18 @@ pub enum PeerError {
     InvalidPieceIndex,
     /// Peer's torrent info hash did not match ours.
     InvalidInfoHash,
+    /// The peer doesn't support exchanging the torrent's metadata, or doesn't
+    /// have it.
+    MetadataNotSupported,
+    /// The peer rejected our request for a piece of the torrent's metadata.
+    MetadataRejected,
+    /// The metadata the peer sent is invalid or doesn't match the info hash.
+    InvalidMetadata,
+    /// The peer's extended handshake is not a valid bencoded dictionary.
+    InvalidExtendedHandshake,
+    /// The peer sent a message of the Fast extension without advertising
+    /// support for it in its handshake.
+    FastNotSupported,
     /// An IO error ocurred.
     Io(std::io::Error),
 }
@@ -49,6 +61,15 @@ impl fmt::Display for PeerError {
             InvalidBlockInfo => write!(fmt, "invalid block info"),
             InvalidPieceIndex => write!(fmt, "invalid piece index"),
             InvalidInfoHash => write!(fmt, "invalid info hash"),
+            MetadataNotSupported => write!(fmt, "metadata not supported"),
+            MetadataRejected => write!(fmt, "metadata request rejected"),
+            InvalidMetadata => write!(fmt, "invalid metadata"),
+            InvalidExtendedHandshake => {
+                write!(fmt, "invalid extended handshake")
+            }
+            FastNotSupported => {
+                write!(fmt, "fast extension message without support")
+            }
             Io(e) => write!(fmt, "{}", e),
         }
     }
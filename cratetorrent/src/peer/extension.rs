@@ -0,0 +1,382 @@
+//! Messages of the extension protocol (BEP 10) and of the extensions built on
+//! top of it, as well as the trait through which users of the library may add
+//! their own extensions.
+//!
+//! The extension protocol is negotiated by setting a bit in the handshake's
+//! reserved field, after which both sides may send an extended handshake to
+//! advertise the extensions they support. Each extension is mapped to
+//! a message id of the sender's choosing, which the other side must use when
+//! sending messages of that extension.
+
+use std::{collections::BTreeMap, net::SocketAddr, sync::Arc};
+
+use super::pex::{UT_PEX, UT_PEX_ID};
+use crate::metainfo::BencodeError;
+
+/// The id of the extended handshake message. All other extended message ids
+/// are chosen by the receiving side in its extended handshake.
+pub(crate) const HANDSHAKE_ID: u8 = 0;
+
+/// The name of the metadata exchange extension (BEP 9).
+pub(crate) const UT_METADATA: &str = "ut_metadata";
+
+/// The id with which we want to receive metadata exchange messages.
+pub(crate) const UT_METADATA_ID: u8 = 1;
+
+/// The id with which we receive messages of the first extension registered by
+/// the user. The ids below this are reserved for the extensions implemented by
+/// the engine.
+pub(crate) const FIRST_USER_EXTENSION_ID: u8 = 16;
+
+/// The length of a metadata piece. All but the last piece of the metadata must
+/// be this long.
+pub(crate) const METADATA_PIECE_LEN: usize = 0x4000;
+
+/// The handshake of the extension protocol.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ExtendedHandshake {
+    /// The extensions the sender supports, mapped to the message id with
+    /// which the sender wants to receive messages of the extension. An id of
+    /// 0 means the extension is disabled.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    /// The length of the torrent's info dictionary, if the sender supports
+    /// metadata exchange and has the metadata.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Parses the bencoded handshake.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, BencodeError> {
+        serde_bencode::from_bytes(buf)
+    }
+
+    /// Bencodes the handshake.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BencodeError> {
+        serde_bencode::to_bytes(self)
+    }
+
+    /// Returns the id with which the sender of the handshake wants to receive
+    /// messages of the given extension, if it supports the extension.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != HANDSHAKE_ID)
+    }
+}
+
+/// The handler of a custom extension built on the extension protocol.
+///
+/// Extensions are registered per torrent, via
+/// [`TorrentParams::extensions`](crate::engine::TorrentParams::extensions), and
+/// are shared by all peer sessions of the torrent. Each extension is advertised
+/// to peers under its name in our extended handshake, and its handlers are
+/// only invoked for peers that advertise the same extension.
+///
+/// The handlers are called directly from the peer session tasks, so they
+/// mustn't block.
+pub trait Extension: Send + Sync {
+    /// The name of the extension, e.g. `ut_pex`, which must be the same for all
+    /// clients implementing the extension.
+    fn name(&self) -> &str;
+
+    /// Called when a peer that supports the extension sends its extended
+    /// handshake.
+    ///
+    /// If a payload is returned, it is sent to the peer as a message of this
+    /// extension.
+    fn on_handshake(&self, _addr: SocketAddr) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Called with the payload of each message of this extension received from
+    /// the peer at the given address.
+    ///
+    /// If a payload is returned, it is sent back to the peer as a message of
+    /// this extension.
+    fn on_message(&self, addr: SocketAddr, payload: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Returns the extended handshake that advertises the given user extensions,
+/// as well as peer exchange if enabled.
+///
+/// Since message ids are a single byte, extensions beyond the 240 that fit
+/// after the reserved ids are not advertised.
+pub(crate) fn extended_handshake(
+    extensions: &[Arc<dyn Extension>],
+    is_pex_enabled: bool,
+) -> ExtendedHandshake {
+    let mut m: BTreeMap<_, _> = extensions
+        .iter()
+        .zip(FIRST_USER_EXTENSION_ID..=u8::MAX)
+        .map(|(ext, id)| (ext.name().to_string(), id))
+        .collect();
+    if is_pex_enabled {
+        m.insert(UT_PEX.to_string(), UT_PEX_ID);
+    }
+    ExtendedHandshake {
+        m,
+        metadata_size: None,
+    }
+}
+
+/// Returns the user extension whose messages we receive with the given id, if
+/// any.
+pub(crate) fn user_extension(
+    extensions: &[Arc<dyn Extension>],
+    id: u8,
+) -> Option<&Arc<dyn Extension>> {
+    let index = id.checked_sub(FIRST_USER_EXTENSION_ID)?;
+    extensions.get(index as usize)
+}
+
+/// A message of the metadata exchange extension (BEP 9).
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum MetadataMsg {
+    /// Requests the metadata piece with the given index.
+    Request { piece: usize },
+    /// A piece of the metadata, sent in response to a request.
+    Data {
+        piece: usize,
+        /// The length of the whole metadata.
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    /// The sender doesn't have the requested piece.
+    Reject { piece: usize },
+}
+
+/// The bencoded dictionary at the start of each metadata message. In the case
+/// of the data message, it is followed by the piece's raw bytes.
+#[derive(Debug, Serialize, Deserialize)]
+struct MetadataMsgHeader {
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+impl MetadataMsg {
+    const REQUEST: u8 = 0;
+    const DATA: u8 = 1;
+    const REJECT: u8 = 2;
+
+    /// Parses the payload of an extended message of the metadata extension.
+    ///
+    /// Returns `None` if the message is not valid or of an unknown type.
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        // the data message's payload follows the bencoded header, so we need
+        // to find where the header ends
+        let header_len = bencode_value_len(buf)?;
+        let header: MetadataMsgHeader =
+            serde_bencode::from_bytes(&buf[..header_len]).ok()?;
+        match header.msg_type {
+            Self::REQUEST => Some(Self::Request {
+                piece: header.piece,
+            }),
+            Self::DATA => Some(Self::Data {
+                piece: header.piece,
+                total_size: header.total_size?,
+                data: buf[header_len..].to_vec(),
+            }),
+            Self::REJECT => Some(Self::Reject {
+                piece: header.piece,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes the message as the payload of an extended message.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BencodeError> {
+        let (header, data) = match self {
+            Self::Request { piece } => (
+                MetadataMsgHeader {
+                    msg_type: Self::REQUEST,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => (
+                MetadataMsgHeader {
+                    msg_type: Self::DATA,
+                    piece: *piece,
+                    total_size: Some(*total_size),
+                },
+                Some(data),
+            ),
+            Self::Reject { piece } => (
+                MetadataMsgHeader {
+                    msg_type: Self::REJECT,
+                    piece: *piece,
+                    total_size: None,
+                },
+                None,
+            ),
+        };
+        let mut buf = serde_bencode::to_bytes(&header)?;
+        if let Some(data) = data {
+            buf.extend_from_slice(data);
+        }
+        Ok(buf)
+    }
+}
+
+/// Returns the length of the bencoded value at the start of the buffer, or
+/// `None` if the buffer doesn't start with a complete bencoded value.
+fn bencode_value_len(buf: &[u8]) -> Option<usize> {
+    nested_bencode_value_len(buf, 0)
+}
+
+/// Lists and dictionaries nested deeper than this are rejected so that
+/// a malicious peer can't exhaust the stack.
+const MAX_BENCODE_DEPTH: usize = 32;
+
+fn nested_bencode_value_len(buf: &[u8], depth: usize) -> Option<usize> {
+    match buf.first()? {
+        b'i' => Some(buf.iter().position(|b| *b == b'e')? + 1),
+        b'l' | b'd' => {
+            if depth >= MAX_BENCODE_DEPTH {
+                return None;
+            }
+            let mut len = 1;
+            while *buf.get(len)? != b'e' {
+                len = len.checked_add(nested_bencode_value_len(
+                    &buf[len..],
+                    depth + 1,
+                )?)?;
+            }
+            Some(len + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = buf.iter().position(|b| *b == b':')?;
+            let str_len: usize =
+                std::str::from_utf8(&buf[..colon]).ok()?.parse().ok()?;
+            // the length is sent by the peer, so it may overflow
+            let len = (colon + 1).checked_add(str_len)?;
+            if len > buf.len() {
+                return None;
+            }
+            Some(len)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that an extended handshake from another client is parsed, even if
+    /// it has fields we don't use.
+    #[test]
+    fn should_parse_extended_handshake() {
+        let buf = b"d1:md11:ut_metadatai3e6:ut_pexi0ee\
+            13:metadata_sizei31235e1:pi6881e1:v3:foo6:yourip4:\x7f\x00\x00\x01e";
+        let handshake = ExtendedHandshake::from_bytes(buf).unwrap();
+        assert_eq!(handshake.metadata_size, Some(31235));
+        assert_eq!(handshake.extension_id(UT_METADATA), Some(3));
+        // an id of 0 means the extension is disabled
+        assert_eq!(handshake.extension_id("ut_pex"), None);
+        assert_eq!(handshake.extension_id("lt_donthave"), None);
+
+        let encoded = handshake.to_bytes().unwrap();
+        assert_eq!(ExtendedHandshake::from_bytes(&encoded).unwrap(), handshake);
+    }
+
+    struct Echo(&'static str);
+
+    impl Extension for Echo {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn on_message(
+            &self,
+            _addr: SocketAddr,
+            payload: &[u8],
+        ) -> Option<Vec<u8>> {
+            Some(payload.to_vec())
+        }
+    }
+
+    /// Tests that user extensions are advertised with, and looked up by, the
+    /// ids following the reserved ones.
+    #[test]
+    fn should_map_user_extension_ids() {
+        let extensions: Vec<Arc<dyn Extension>> =
+            vec![Arc::new(Echo("foo")), Arc::new(Echo("bar"))];
+        let handshake = extended_handshake(&extensions, false);
+        assert_eq!(handshake.m.len(), 2);
+        assert_eq!(handshake.extension_id(UT_PEX), None);
+        assert_eq!(
+            handshake.extension_id("foo"),
+            Some(FIRST_USER_EXTENSION_ID)
+        );
+        assert_eq!(
+            handshake.extension_id("bar"),
+            Some(FIRST_USER_EXTENSION_ID + 1)
+        );
+        let handshake = extended_handshake(&extensions, true);
+        assert_eq!(handshake.m.len(), 3);
+        assert_eq!(handshake.extension_id(UT_PEX), Some(UT_PEX_ID));
+
+        assert!(user_extension(&extensions, UT_METADATA_ID).is_none());
+        assert!(user_extension(&extensions, UT_PEX_ID).is_none());
+        assert_eq!(
+            user_extension(&extensions, FIRST_USER_EXTENSION_ID)
+                .unwrap()
+                .name(),
+            "foo"
+        );
+        assert_eq!(
+            user_extension(&extensions, FIRST_USER_EXTENSION_ID + 1)
+                .unwrap()
+                .name(),
+            "bar"
+        );
+        assert!(
+            user_extension(&extensions, FIRST_USER_EXTENSION_ID + 2).is_none()
+        );
+    }
+
+    /// Tests the encoding and subsequent decoding of all metadata messages.
+    #[test]
+    fn should_encode_and_decode_metadata_msgs() {
+        let request = MetadataMsg::Request { piece: 2 };
+        let encoded = request.to_bytes().unwrap();
+        assert_eq!(encoded, b"d8:msg_typei0e5:piecei2ee");
+        assert_eq!(MetadataMsg::from_bytes(&encoded), Some(request));
+
+        let data = MetadataMsg::Data {
+            piece: 1,
+            total_size: 0x4000 + 3,
+            data: b"ee:".to_vec(),
+        };
+        let encoded = data.to_bytes().unwrap();
+        assert_eq!(
+            encoded,
+            b"d8:msg_typei1e5:piecei1e10:total_sizei16387eeee:".as_ref()
+        );
+        assert_eq!(MetadataMsg::from_bytes(&encoded), Some(data));
+
+        let reject = MetadataMsg::Reject { piece: 0 };
+        let encoded = reject.to_bytes().unwrap();
+        assert_eq!(MetadataMsg::from_bytes(&encoded), Some(reject));
+
+        // unknown message type and truncated message
+        assert_eq!(MetadataMsg::from_bytes(b"d8:msg_typei7e5:piecei0ee"), None);
+        assert_eq!(MetadataMsg::from_bytes(b"d8:msg_typei0e5:piecei0e"), None);
+    }
+
+    /// Tests that a string length that doesn't fit in memory is rejected
+    /// rather than overflowing.
+    #[test]
+    fn should_reject_overflowing_string_len() {
+        assert_eq!(bencode_value_len(b"18446744073709551615:"), None);
+        assert_eq!(MetadataMsg::from_bytes(b"d18446744073709551615:e"), None);
+    }
+}
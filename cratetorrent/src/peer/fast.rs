@@ -0,0 +1,101 @@
+//! The Fast extension (BEP 6), which lets peers exchange piece availability in
+//! fewer bytes, reject requests explicitly, and download a few pieces even
+//! while choked.
+//!
+//! The pieces a peer may download while choked are its allowed fast set, which
+//! is derived from the peer's IP and the info hash. Since the set is the same
+//! no matter which peer computes it, a peer can't get a different set by
+//! reconnecting, and the pieces most peers of a subnet get are the same, so
+//! that they may exchange them among themselves.
+
+use std::net::IpAddr;
+
+use sha1::{Digest, Sha1};
+
+use crate::{PieceIndex, Sha1Hash};
+
+/// The number of pieces in the allowed fast set we give to a peer.
+pub(crate) const ALLOWED_FAST_COUNT: usize = 10;
+
+/// Returns the allowed fast set of the peer with the given IP, as defined by
+/// BEP 6.
+///
+/// The algorithm is only defined for IPv4, so IPv6 peers don't get an allowed
+/// fast set. The set is smaller than requested if the torrent has fewer pieces.
+pub(crate) fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &Sha1Hash,
+    piece_count: usize,
+    count: usize,
+) -> Vec<PieceIndex> {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Vec::new(),
+    };
+    let count = count.min(piece_count);
+    let mut set = Vec::with_capacity(count);
+
+    // only the /24 subnet of the peer is used, so that a peer can't get more
+    // pieces by using several addresses of the same subnet
+    let mut hash = Vec::with_capacity(4 + info_hash.len());
+    hash.extend_from_slice(&(u32::from(ip) & 0xffff_ff00).to_be_bytes());
+    hash.extend_from_slice(info_hash);
+
+    while set.len() < count {
+        hash = Sha1::digest(&hash).to_vec();
+        for chunk in hash.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let y =
+                u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y as usize % piece_count;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the allowed fast set generation against the example in BEP 6.
+    #[test]
+    fn should_generate_allowed_fast_set() {
+        let ip: IpAddr = "80.4.4.200".parse().unwrap();
+        let info_hash = [0xaa; 20];
+
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            allowed_fast_set(ip, &info_hash, 1313, 9),
+            vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+    }
+
+    /// Tests that all pieces are in the set if the torrent has fewer pieces
+    /// than the size of the set, and that IPv6 peers get no set.
+    #[test]
+    fn should_limit_allowed_fast_set() {
+        let info_hash = [0xaa; 20];
+
+        let mut set =
+            allowed_fast_set("80.4.4.200".parse().unwrap(), &info_hash, 3, 10);
+        set.sort_unstable();
+        assert_eq!(set, vec![0, 1, 2]);
+
+        assert!(allowed_fast_set(
+            "2001:db8::1".parse().unwrap(),
+            &info_hash,
+            3,
+            10
+        )
+        .is_empty());
+    }
+}
@@ -0,0 +1,430 @@
+//! Downloading a torrent's metadata (its info dictionary) from a peer, via the
+//! metadata exchange extension (BEP 9).
+//!
+//! This is used for torrents that are started from a magnet link, for which
+//! only the info hash is known at first. Until the metadata is downloaded, we
+//! can't create the torrent's storage or piece picker, so these sessions are
+//! separate from the regular [`PeerSession`](super::PeerSession): they only
+//! exchange the handshakes and the metadata, after which they disconnect.
+
+use std::{io, net::SocketAddr};
+
+use futures::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio_util::codec::{Framed, FramedParts};
+
+use super::{codec::*, error::*, extension::*, transport::Connector};
+use crate::{PeerId, Sha1Hash};
+
+/// The maximum length of metadata we accept, so that a malicious peer can't
+/// make us allocate arbitrary amounts of memory.
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+/// Connects to the peer at the given address with the connector and downloads
+/// the torrent's metadata from it.
+///
+/// The returned metadata is verified against the info hash. The function
+/// returns once the metadata is downloaded, or with an error if the peer
+/// doesn't support metadata exchange, rejects our requests, sends invalid
+/// metadata, or if the connection is closed.
+pub(crate) async fn fetch_metadata(
+    connector: &dyn Connector,
+    addr: SocketAddr,
+    info_hash: Sha1Hash,
+    client_id: PeerId,
+) -> Result<Vec<u8>> {
+    let log_target = format!("cratetorrent::peer::metadata [{}]", addr);
+    log::info!(target: &log_target, "Connecting to peer");
+    let socket = connector.connect(addr).await?;
+    let mut socket = Framed::new(socket, HandshakeCodec);
+
+    let handshake = Handshake::new(info_hash, client_id);
+    log::info!(target: &log_target, "Sending handshake");
+    socket.send(handshake).await?;
+
+    let peer_handshake = match socket.next().await {
+        Some(peer_handshake) => peer_handshake?,
+        None => {
+            log::warn!(target: &log_target, "No handshake received");
+            return Err(connection_closed());
+        }
+    };
+    log::info!(target: &log_target, "Peer sent handshake");
+    if peer_handshake.info_hash != info_hash {
+        log::info!(target: &log_target, "Peer handshake invalid info hash");
+        return Err(PeerError::InvalidInfoHash);
+    }
+    if !peer_handshake.supports_extension_protocol() {
+        log::info!(target: &log_target, "Peer doesn't support extensions");
+        return Err(PeerError::MetadataNotSupported);
+    }
+
+    // switch to the peer message codec, keeping any bytes the peer may have
+    // sent after the handshake
+    let old_parts = socket.into_parts();
+    let mut new_parts = FramedParts::new(old_parts.io, PeerCodec);
+    new_parts.read_buf = old_parts.read_buf;
+    new_parts.write_buf = old_parts.write_buf;
+    let mut socket = Framed::from_parts(new_parts);
+
+    // advertise that we want to receive metadata messages
+    let mut ext_handshake = ExtendedHandshake::default();
+    ext_handshake.m.insert(UT_METADATA.into(), UT_METADATA_ID);
+    socket
+        .send(Message::Extended {
+            id: HANDSHAKE_ID,
+            payload: ext_handshake.to_bytes().map_err(bencode_error)?,
+        })
+        .await?;
+
+    let mut download: Option<MetadataDownload> = None;
+    while let Some(msg) = socket.next().await {
+        let (id, payload) = match msg? {
+            Message::Extended { id, payload } => (id, payload),
+            // we don't download any pieces in this session
+            msg => {
+                log::trace!(target: &log_target, "Ignoring message {:?}", msg);
+                continue;
+            }
+        };
+
+        if id == HANDSHAKE_ID {
+            if download.is_some() {
+                log::debug!(target: &log_target, "Ignoring extended handshake");
+                continue;
+            }
+            let peer_ext_handshake = ExtendedHandshake::from_bytes(&payload)
+                .map_err(bencode_error)?;
+            log::info!(
+                target: &log_target,
+                "Peer sent extended handshake: {:?}",
+                peer_ext_handshake
+            );
+            let (peer_ut_metadata_id, metadata_size) = match (
+                peer_ext_handshake.extension_id(UT_METADATA),
+                peer_ext_handshake.metadata_size,
+            ) {
+                (Some(id), Some(size)) => (id, size),
+                _ => return Err(PeerError::MetadataNotSupported),
+            };
+            if metadata_size == 0 || metadata_size > MAX_METADATA_SIZE {
+                log::warn!(
+                    target: &log_target,
+                    "Invalid metadata size {}",
+                    metadata_size
+                );
+                return Err(PeerError::InvalidMetadata);
+            }
+
+            // request all metadata pieces at once, they are few and small
+            let metadata = MetadataDownload::new(metadata_size);
+            log::info!(
+                target: &log_target,
+                "Requesting {} metadata piece(s)",
+                metadata.piece_count()
+            );
+            for piece in 0..metadata.piece_count() {
+                let request = MetadataMsg::Request { piece };
+                socket
+                    .send(Message::Extended {
+                        id: peer_ut_metadata_id,
+                        payload: request.to_bytes().map_err(bencode_error)?,
+                    })
+                    .await?;
+            }
+            download = Some(metadata);
+        } else if id == UT_METADATA_ID {
+            let download = match &mut download {
+                Some(download) => download,
+                None => {
+                    log::warn!(
+                        target: &log_target,
+                        "Metadata message before extended handshake"
+                    );
+                    return Err(PeerError::InvalidMetadata);
+                }
+            };
+            match MetadataMsg::from_bytes(&payload) {
+                Some(MetadataMsg::Data {
+                    piece,
+                    total_size,
+                    data,
+                }) => {
+                    log::debug!(
+                        target: &log_target,
+                        "Received metadata piece {}",
+                        piece
+                    );
+                    if total_size != download.len() {
+                        return Err(PeerError::InvalidMetadata);
+                    }
+                    download.add_piece(piece, &data)?;
+                    if download.is_complete() {
+                        let metadata = download.finish(&info_hash)?;
+                        log::info!(
+                            target: &log_target,
+                            "Downloaded {} bytes of metadata",
+                            metadata.len()
+                        );
+                        return Ok(metadata);
+                    }
+                }
+                Some(MetadataMsg::Reject { piece }) => {
+                    log::info!(
+                        target: &log_target,
+                        "Peer rejected metadata piece {}",
+                        piece
+                    );
+                    return Err(PeerError::MetadataRejected);
+                }
+                Some(MetadataMsg::Request { .. }) => {
+                    // we don't have the metadata ourselves (the peer shouldn't
+                    // ask since we didn't advertise its size), so we can't
+                    // serve it
+                    log::debug!(target: &log_target, "Ignoring metadata request");
+                }
+                None => {
+                    log::warn!(target: &log_target, "Invalid metadata message");
+                    return Err(PeerError::InvalidMetadata);
+                }
+            }
+        } else {
+            log::debug!(
+                target: &log_target,
+                "Ignoring unknown extended message {}",
+                id
+            );
+        }
+    }
+
+    log::info!(target: &log_target, "Peer closed connection");
+    Err(connection_closed())
+}
+
+/// The metadata pieces downloaded so far.
+struct MetadataDownload {
+    /// The buffer of the whole metadata, into which pieces are copied.
+    buf: Vec<u8>,
+    /// Which pieces have been received.
+    received: Vec<bool>,
+}
+
+impl MetadataDownload {
+    fn new(len: usize) -> Self {
+        let piece_count = len.div_ceil(METADATA_PIECE_LEN);
+        Self {
+            buf: vec![0; len],
+            received: vec![false; piece_count],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn piece_count(&self) -> usize {
+        self.received.len()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|received| *received)
+    }
+
+    /// Copies the piece into the metadata buffer, if it's valid.
+    fn add_piece(&mut self, index: usize, data: &[u8]) -> Result<()> {
+        if index >= self.piece_count() {
+            return Err(PeerError::InvalidMetadata);
+        }
+        let start = index * METADATA_PIECE_LEN;
+        let end = (start + METADATA_PIECE_LEN).min(self.len());
+        if data.len() != end - start {
+            return Err(PeerError::InvalidMetadata);
+        }
+        self.buf[start..end].copy_from_slice(data);
+        self.received[index] = true;
+        Ok(())
+    }
+
+    /// Verifies the complete metadata against the info hash and returns it.
+    fn finish(&mut self, info_hash: &Sha1Hash) -> Result<Vec<u8>> {
+        debug_assert!(self.is_complete());
+        let hash = Sha1::digest(&self.buf);
+        if hash[..] != info_hash[..] {
+            return Err(PeerError::InvalidMetadata);
+        }
+        Ok(std::mem::take(&mut self.buf))
+    }
+}
+
+fn connection_closed() -> PeerError {
+    PeerError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed",
+    ))
+}
+
+fn bencode_error(e: crate::metainfo::BencodeError) -> PeerError {
+    PeerError::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use futures::future::{BoxFuture, FutureExt};
+    use tokio::{io::DuplexStream, net::TcpListener};
+
+    use super::*;
+    use crate::peer::transport::{BoxTransport, DefaultConnector, Transport};
+
+    /// Spawns a peer that serves the given metadata to a single connection,
+    /// returning its address.
+    async fn spawn_peer(info_hash: Sha1Hash, metadata: Vec<u8>) -> SocketAddr {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            serve_metadata(socket, info_hash, metadata).await;
+        });
+        addr
+    }
+
+    /// Serves the given metadata to the peer on the other end of the socket.
+    async fn serve_metadata<S: Transport>(
+        socket: S,
+        info_hash: Sha1Hash,
+        metadata: Vec<u8>,
+    ) {
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = socket.next().await.unwrap().unwrap();
+        assert!(handshake.supports_extension_protocol());
+        let handshake = Handshake::new(info_hash, [1; 20]);
+        socket.send(handshake).await.unwrap();
+
+        let parts = socket.into_parts();
+        let mut new_parts = FramedParts::new(parts.io, PeerCodec);
+        new_parts.read_buf = parts.read_buf;
+        let mut socket = Framed::from_parts(new_parts);
+
+        // use a different id than ours to test that ids are mapped
+        let peer_ut_metadata_id = UT_METADATA_ID + 1;
+        let mut ext_handshake = ExtendedHandshake {
+            metadata_size: Some(metadata.len()),
+            ..Default::default()
+        };
+        ext_handshake
+            .m
+            .insert(UT_METADATA.into(), peer_ut_metadata_id);
+        socket
+            .send(Message::Extended {
+                id: HANDSHAKE_ID,
+                payload: ext_handshake.to_bytes().unwrap(),
+            })
+            .await
+            .unwrap();
+
+        let mut client_ut_metadata_id = None;
+        while let Some(Ok(msg)) = socket.next().await {
+            let (id, payload) = match msg {
+                Message::Extended { id, payload } => (id, payload),
+                _ => continue,
+            };
+            if id == HANDSHAKE_ID {
+                let handshake =
+                    ExtendedHandshake::from_bytes(&payload).unwrap();
+                client_ut_metadata_id = handshake.extension_id(UT_METADATA);
+                continue;
+            }
+            assert_eq!(id, peer_ut_metadata_id);
+            let piece = match MetadataMsg::from_bytes(&payload) {
+                Some(MetadataMsg::Request { piece }) => piece,
+                msg => panic!("unexpected metadata message {:?}", msg),
+            };
+            let start = piece * METADATA_PIECE_LEN;
+            let end = (start + METADATA_PIECE_LEN).min(metadata.len());
+            let data = MetadataMsg::Data {
+                piece,
+                total_size: metadata.len(),
+                data: metadata[start..end].to_vec(),
+            };
+            socket
+                .send(Message::Extended {
+                    id: client_ut_metadata_id.unwrap(),
+                    payload: data.to_bytes().unwrap(),
+                })
+                .await
+                .unwrap();
+        }
+    }
+
+    /// A connector that hands out one end of an in-memory pipe.
+    struct PipeConnector(Mutex<Option<DuplexStream>>);
+
+    impl Connector for PipeConnector {
+        fn connect(
+            &self,
+            _: SocketAddr,
+        ) -> BoxFuture<'static, io::Result<BoxTransport>> {
+            let socket = self.0.lock().unwrap().take().unwrap();
+            async move { Ok(Box::new(socket) as BoxTransport) }.boxed()
+        }
+    }
+
+    /// Returns metadata spanning multiple metadata pieces and its hash.
+    fn make_metadata() -> (Sha1Hash, Vec<u8>) {
+        let metadata: Vec<u8> = (0..2 * METADATA_PIECE_LEN + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&Sha1::digest(&metadata));
+        (info_hash, metadata)
+    }
+
+    /// Tests downloading metadata that spans multiple pieces from a peer.
+    #[tokio::test]
+    async fn should_fetch_metadata() {
+        let (info_hash, metadata) = make_metadata();
+        let addr = spawn_peer(info_hash, metadata.clone()).await;
+        let fetched = fetch_metadata(
+            &DefaultConnector::new(None),
+            addr,
+            info_hash,
+            [2; 20],
+        )
+        .await
+        .unwrap();
+        assert_eq!(fetched, metadata);
+    }
+
+    /// Tests downloading metadata over a transport made by a custom
+    /// connector.
+    #[tokio::test]
+    async fn should_fetch_metadata_over_custom_transport() {
+        let (info_hash, metadata) = make_metadata();
+        let (socket, peer_socket) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_metadata(peer_socket, info_hash, metadata.clone()));
+        let connector = PipeConnector(Mutex::new(Some(socket)));
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let fetched = fetch_metadata(&connector, addr, info_hash, [2; 20])
+            .await
+            .unwrap();
+        assert_eq!(fetched, metadata);
+    }
+
+    /// Tests that metadata that doesn't match the info hash is rejected.
+    #[tokio::test]
+    async fn should_reject_metadata_not_matching_info_hash() {
+        let (info_hash, mut metadata) = make_metadata();
+        metadata[42] ^= 0xff;
+        let addr = spawn_peer(info_hash, metadata).await;
+        let result = fetch_metadata(
+            &DefaultConnector::new(None),
+            addr,
+            info_hash,
+            [2; 20],
+        )
+        .await;
+        assert!(matches!(result, Err(PeerError::InvalidMetadata)));
+    }
+}
@@ -0,0 +1,622 @@
+//! Message stream encryption (MSE), also known as protocol encryption (PE).
+//!
+//! Some ISPs throttle BitTorrent traffic, which they recognize by the plain
+//! handshake and messages. MSE obfuscates a connection with a handshake that
+//! precedes the BitTorrent handshake: the sides agree on a secret via
+//! a Diffie-Hellman key exchange, from which they derive RC4 keys. The
+//! handshake itself is always encrypted, while the rest of the connection is
+//! encrypted only if the sides chose RC4, and is plaintext otherwise ("header
+//! only" encryption).
+//!
+//! The side that initiates the connection (A) sends:
+//!
+//! 1. its public key and random padding,
+//! 2. after receiving the other side's (B) public key and padding,
+//!    `HASH("req1", S)`, `HASH("req2", SKEY) xor HASH("req3", S)`, and the
+//!    encrypted `VC, crypto_provide, len(PadC), PadC, len(IA), IA`.
+//!
+//! B then replies with the encrypted `VC, crypto_select, len(PadD), PadD`,
+//! after which the BitTorrent handshake follows. S is the shared secret, SKEY
+//! is the torrent's info hash, and VC is a verification constant of 8 zero
+//! bytes.
+//!
+//! This module implements both sides of the handshake and the stream that
+//! encrypts the rest of the connection, which is the layer under the
+//! handshake and peer message codecs.
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::ready;
+use rand::Rng;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::transport::Transport;
+use crate::{conf::EncryptionPolicy, Sha1Hash};
+use dh::{KeyPair, KEY_LEN};
+use rc4::Rc4;
+
+mod dh;
+mod rc4;
+
+/// The verification constant, with which the sides find the beginning of the
+/// encrypted part of each other's handshake.
+const VC: [u8; 8] = [0; 8];
+
+/// The maximum length of each padding in the handshake.
+const MAX_PAD_LEN: usize = 512;
+
+/// The number of bytes of each keystream that are discarded before use.
+const KEYSTREAM_DISCARD_LEN: usize = 1024;
+
+/// The way the connection is encrypted after the handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CryptoMethod {
+    /// Only the handshake is encrypted.
+    Plaintext,
+    /// The whole connection is encrypted with RC4.
+    Rc4,
+}
+
+impl CryptoMethod {
+    /// Returns the bit of the method in `crypto_provide` and `crypto_select`.
+    fn bit(self) -> u32 {
+        match self {
+            Self::Plaintext => 0x01,
+            Self::Rc4 => 0x02,
+        }
+    }
+}
+
+/// Returns whether a connection is allowed by the policy, where `crypto` is
+/// the connection's crypto method, or `None` if it's not obfuscated at all.
+pub(crate) fn is_allowed(
+    policy: EncryptionPolicy,
+    crypto: Option<CryptoMethod>,
+) -> bool {
+    match policy {
+        EncryptionPolicy::Disabled => crypto.is_none(),
+        EncryptionPolicy::Enabled => true,
+        EncryptionPolicy::Forced => crypto == Some(CryptoMethod::Rc4),
+    }
+}
+
+/// Performs the handshake as the side that initiated the connection, for the
+/// torrent with the info hash.
+///
+/// With the forced policy only RC4 is offered, otherwise both methods are,
+/// and the other side chooses one.
+pub(crate) async fn handshake_outbound<S: Transport>(
+    mut socket: S,
+    info_hash: &Sha1Hash,
+    policy: EncryptionPolicy,
+) -> io::Result<(PeerStream<S>, CryptoMethod)> {
+    debug_assert_ne!(policy, EncryptionPolicy::Disabled);
+    let keys = KeyPair::generate();
+    socket.write_all(&keys.public).await?;
+    socket.write_all(&random_pad()).await?;
+
+    let mut reader = HandshakeReader::new(Vec::new());
+    reader.fill(&mut socket, KEY_LEN).await?;
+    let mut remote_public = [0; KEY_LEN];
+    remote_public.copy_from_slice(&reader.take(KEY_LEN));
+    let secret = keys
+        .shared_secret(&remote_public)
+        .ok_or_else(|| invalid_data("invalid public key"))?;
+
+    let (mut encryptor, mut decryptor) = ciphers(&secret, info_hash, true);
+    let crypto_provide = match policy {
+        EncryptionPolicy::Forced => CryptoMethod::Rc4.bit(),
+        _ => CryptoMethod::Rc4.bit() | CryptoMethod::Plaintext.bit(),
+    };
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&hash(&[b"req1", &secret]));
+    msg.extend_from_slice(&xor(
+        &hash(&[b"req2", info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut encrypted = Vec::new();
+    encrypted.extend_from_slice(&VC);
+    encrypted.extend_from_slice(&crypto_provide.to_be_bytes());
+    // no PadC and no initial payload, the BitTorrent handshake is sent
+    // separately
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut encrypted);
+    msg.extend_from_slice(&encrypted);
+    socket.write_all(&msg).await?;
+
+    // the encrypted VC marks the end of B's padding
+    let mut vc = VC;
+    decryptor.apply(&mut vc);
+    reader.sync(&mut socket, &vc, MAX_PAD_LEN).await?;
+
+    reader.fill(&mut socket, 4 + 2).await?;
+    let mut buf = reader.take(4 + 2);
+    decryptor.apply(&mut buf);
+    let crypto_select = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let pad_len = u16::from_be_bytes([buf[4], buf[5]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(invalid_data("PadD too long"));
+    }
+    reader.fill(&mut socket, pad_len).await?;
+    decryptor.apply(&mut reader.take(pad_len));
+
+    let crypto = if crypto_select == CryptoMethod::Rc4.bit()
+        && crypto_provide & crypto_select != 0
+    {
+        CryptoMethod::Rc4
+    } else if crypto_select == CryptoMethod::Plaintext.bit()
+        && crypto_provide & crypto_select != 0
+    {
+        CryptoMethod::Plaintext
+    } else {
+        return Err(invalid_data("invalid crypto_select"));
+    };
+
+    let leftover = reader.into_inner();
+    Ok((
+        PeerStream::encrypted(socket, leftover, encryptor, decryptor, crypto),
+        crypto,
+    ))
+}
+
+/// Performs the handshake as the side that accepted the connection, whose
+/// first bytes, which turned out not to be a BitTorrent handshake, were
+/// already read.
+///
+/// Since the other side only sends a hash of the info hash, the torrent is
+/// looked up by `find_info_hash`, which is given `HASH("req2", SKEY)` and
+/// returns the info hash of the torrent with that hash, if there is one.
+///
+/// RC4 is chosen if the other side offers it, as the connection may not be
+/// accepted by the torrent otherwise.
+pub(crate) async fn handshake_inbound<S, F, Fut>(
+    mut socket: S,
+    prefix: Vec<u8>,
+    find_info_hash: F,
+) -> io::Result<(PeerStream<S>, CryptoMethod, Sha1Hash)>
+where
+    S: Transport,
+    F: FnOnce(Sha1Hash) -> Fut,
+    Fut: Future<Output = Option<Sha1Hash>>,
+{
+    let mut reader = HandshakeReader::new(prefix);
+    reader.fill(&mut socket, KEY_LEN).await?;
+    let mut remote_public = [0; KEY_LEN];
+    remote_public.copy_from_slice(&reader.take(KEY_LEN));
+
+    let keys = KeyPair::generate();
+    let secret = keys
+        .shared_secret(&remote_public)
+        .ok_or_else(|| invalid_data("invalid public key"))?;
+    socket.write_all(&keys.public).await?;
+    socket.write_all(&random_pad()).await?;
+
+    // the hash marks the end of A's padding
+    reader
+        .sync(&mut socket, &hash(&[b"req1", &secret]), MAX_PAD_LEN)
+        .await?;
+    reader.fill(&mut socket, 20).await?;
+    let mut skey_hash = [0; 20];
+    skey_hash.copy_from_slice(&reader.take(20));
+    let skey_hash = xor(&skey_hash, &hash(&[b"req3", &secret]));
+    let info_hash = match find_info_hash(skey_hash).await {
+        Some(info_hash) => info_hash,
+        None => return Err(invalid_data("unknown torrent")),
+    };
+
+    let (mut encryptor, mut decryptor) = ciphers(&secret, &info_hash, false);
+    reader.fill(&mut socket, 8 + 4 + 2).await?;
+    let mut buf = reader.take(8 + 4 + 2);
+    decryptor.apply(&mut buf);
+    if buf[..8] != VC {
+        return Err(invalid_data("invalid VC"));
+    }
+    let crypto_provide = u32::from_be_bytes([buf[8], buf[9], buf[10], buf[11]]);
+    let pad_len = u16::from_be_bytes([buf[12], buf[13]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(invalid_data("PadC too long"));
+    }
+    reader.fill(&mut socket, pad_len + 2).await?;
+    let mut buf = reader.take(pad_len + 2);
+    decryptor.apply(&mut buf);
+    let ia_len = u16::from_be_bytes([buf[pad_len], buf[pad_len + 1]]) as usize;
+    // the initial payload, usually the BitTorrent handshake, is always
+    // encrypted
+    reader.fill(&mut socket, ia_len).await?;
+    let mut ia = reader.take(ia_len);
+    decryptor.apply(&mut ia);
+
+    let crypto = if crypto_provide & CryptoMethod::Rc4.bit() != 0 {
+        CryptoMethod::Rc4
+    } else if crypto_provide & CryptoMethod::Plaintext.bit() != 0 {
+        CryptoMethod::Plaintext
+    } else {
+        return Err(invalid_data("no supported crypto method"));
+    };
+
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&VC);
+    msg.extend_from_slice(&crypto.bit().to_be_bytes());
+    msg.extend_from_slice(&0u16.to_be_bytes());
+    encryptor.apply(&mut msg);
+    socket.write_all(&msg).await?;
+
+    // the initial payload is already decrypted, but the bytes after it are
+    // only decrypted if RC4 was chosen
+    let mut stream = PeerStream::encrypted(
+        socket,
+        reader.into_inner(),
+        encryptor,
+        decryptor,
+        crypto,
+    );
+    ia.append(&mut stream.read_buf);
+    stream.read_buf = ia;
+    Ok((stream, crypto, info_hash))
+}
+
+/// The connection with a peer over the transport `S`, which is encrypted if
+/// RC4 was chosen in the encrypted handshake.
+pub(crate) struct PeerStream<S> {
+    socket: S,
+    /// The bytes that were read from the socket during the handshake but not
+    /// consumed by it, already decrypted. These are returned before reading
+    /// from the socket again.
+    read_buf: Vec<u8>,
+    /// The ciphers with which the bytes written to and read from the socket
+    /// are encrypted and decrypted.
+    ciphers: Option<(Rc4, Rc4)>,
+    /// The encrypted bytes that are yet to be written to the socket.
+    write_buf: Vec<u8>,
+}
+
+impl<S> fmt::Debug for PeerStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerStream")
+            .field("is_encrypted", &self.ciphers.is_some())
+            .finish()
+    }
+}
+
+impl<S: Transport> PeerStream<S> {
+    /// Creates a plaintext stream, with the bytes already read from the
+    /// socket.
+    pub fn new(socket: S, read_buf: Vec<u8>) -> Self {
+        Self {
+            socket,
+            read_buf,
+            ciphers: None,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Creates the stream after the encrypted handshake, with the bytes read
+    /// from the socket after it.
+    fn encrypted(
+        socket: S,
+        mut read_buf: Vec<u8>,
+        encryptor: Rc4,
+        mut decryptor: Rc4,
+        crypto: CryptoMethod,
+    ) -> Self {
+        let ciphers = match crypto {
+            CryptoMethod::Plaintext => None,
+            CryptoMethod::Rc4 => {
+                decryptor.apply(&mut read_buf);
+                Some((encryptor, decryptor))
+            }
+        };
+        Self {
+            socket,
+            read_buf,
+            ciphers,
+            write_buf: Vec::new(),
+        }
+    }
+
+    /// Writes the encrypted bytes that are yet to be written to the socket.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(
+                Pin::new(&mut self.socket).poll_write(cx, &self.write_buf)
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: Transport> AsyncRead for PeerStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.read_buf.is_empty() {
+            let n = buf.len().min(this.read_buf.len());
+            buf[..n].copy_from_slice(&this.read_buf[..n]);
+            this.read_buf.drain(..n);
+            return Poll::Ready(Ok(n));
+        }
+        let n = ready!(Pin::new(&mut this.socket).poll_read(cx, buf))?;
+        if let Some((_, decryptor)) = &mut this.ciphers {
+            decryptor.apply(&mut buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<S: Transport> AsyncWrite for PeerStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.socket).poll_write(cx, buf);
+        }
+        // The bytes are encrypted as they are accepted, which advances the
+        // keystream, so they must be buffered until written. Only accept new
+        // bytes once the previous ones are written, so that the buffer
+        // doesn't grow indefinitely.
+        ready!(this.poll_write_buf(cx))?;
+        this.write_buf.extend_from_slice(buf);
+        if let Some((encryptor, _)) = &mut this.ciphers {
+            encryptor.apply(&mut this.write_buf);
+        }
+        // the bytes are accepted even if the socket isn't ready, in which
+        // case they are written on the next flush
+        if let Poll::Ready(Err(e)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.socket).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.socket).poll_shutdown(cx)
+    }
+}
+
+/// Buffers the bytes read during the handshake, as the padding of unknown
+/// length means that more bytes may be read than the handshake consists of.
+struct HandshakeReader {
+    buf: Vec<u8>,
+}
+
+impl HandshakeReader {
+    fn new(buf: Vec<u8>) -> Self {
+        Self { buf }
+    }
+
+    /// Reads from the socket until at least `len` bytes are buffered.
+    async fn fill<S: Transport>(
+        &mut self,
+        socket: &mut S,
+        len: usize,
+    ) -> io::Result<()> {
+        let mut chunk = [0; 1024];
+        while self.buf.len() < len {
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(())
+    }
+
+    /// Reads from the socket until the pattern is found within `max_skip`
+    /// bytes, and consumes the bytes up to and including the pattern.
+    async fn sync<S: Transport>(
+        &mut self,
+        socket: &mut S,
+        pattern: &[u8],
+        max_skip: usize,
+    ) -> io::Result<()> {
+        loop {
+            if let Some(pos) =
+                self.buf.windows(pattern.len()).position(|w| w == pattern)
+            {
+                self.buf.drain(..pos + pattern.len());
+                return Ok(());
+            }
+            if self.buf.len() >= max_skip + pattern.len() {
+                return Err(invalid_data("handshake out of sync"));
+            }
+            let len = self.buf.len() + 1;
+            self.fill(socket, len).await?;
+        }
+    }
+
+    /// Consumes the first `len` buffered bytes, which must be available.
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        self.buf.drain(..len).collect()
+    }
+
+    fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Returns the ciphers with which we encrypt and decrypt the connection.
+///
+/// A encrypts with the key derived from "keyA" and B with the one derived
+/// from "keyB".
+fn ciphers(
+    secret: &[u8; KEY_LEN],
+    info_hash: &Sha1Hash,
+    is_outbound: bool,
+) -> (Rc4, Rc4) {
+    let mut key_a = Rc4::new(&hash(&[b"keyA", secret, info_hash]));
+    let mut key_b = Rc4::new(&hash(&[b"keyB", secret, info_hash]));
+    key_a.discard(KEYSTREAM_DISCARD_LEN);
+    key_b.discard(KEYSTREAM_DISCARD_LEN);
+    if is_outbound {
+        (key_a, key_b)
+    } else {
+        (key_b, key_a)
+    }
+}
+
+/// Returns the SHA-1 hash of the concatenated parts.
+fn hash(parts: &[&[u8]]) -> Sha1Hash {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Returns `HASH("req2", SKEY)` of the info hash, which the side that
+/// initiated the connection sends to identify the torrent.
+pub(crate) fn info_hash_hash(info_hash: &Sha1Hash) -> Sha1Hash {
+    hash(&[b"req2", info_hash])
+}
+
+fn xor(a: &Sha1Hash, b: &Sha1Hash) -> Sha1Hash {
+    let mut result = [0; 20];
+    for (r, (a, b)) in result.iter_mut().zip(a.iter().zip(b.iter())) {
+        *r = a ^ b;
+    }
+    result
+}
+
+/// Returns a random padding of random length.
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0..=MAX_PAD_LEN);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    /// Connects a pair of sockets over localhost.
+    async fn socket_pair() -> (TcpStream, TcpStream) {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (outbound, inbound) =
+            tokio::join!(TcpStream::connect(addr), listener.accept());
+        (outbound.unwrap(), inbound.unwrap().0)
+    }
+
+    /// Runs both sides of the handshake and checks that they agree on the
+    /// torrent and crypto method, and that the streams can exchange data
+    /// afterwards.
+    async fn assert_handshake(policy: EncryptionPolicy) -> CryptoMethod {
+        let info_hash = [0xab; 20];
+        let (outbound, mut inbound) = socket_pair().await;
+
+        let outbound = handshake_outbound(outbound, &info_hash, policy);
+        let inbound = async {
+            // the listener reads the first bytes to tell whether the
+            // connection is plaintext
+            let mut prefix = vec![0; 20];
+            inbound.read_exact(&mut prefix).await.unwrap();
+            handshake_inbound(inbound, prefix, |hash| async move {
+                if hash == info_hash_hash(&info_hash) {
+                    Some(info_hash)
+                } else {
+                    None
+                }
+            })
+            .await
+        };
+        let (outbound, inbound) = tokio::join!(outbound, inbound);
+        let (mut a, a_crypto) = outbound.unwrap();
+        let (mut b, b_crypto, b_info_hash) = inbound.unwrap();
+        assert_eq!(a_crypto, b_crypto);
+        assert_eq!(b_info_hash, info_hash);
+
+        a.write_all(b"hello from A").await.unwrap();
+        a.flush().await.unwrap();
+        b.write_all(b"hello from B").await.unwrap();
+        b.flush().await.unwrap();
+        let mut buf = [0; 12];
+        b.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from A");
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello from B");
+
+        a_crypto
+    }
+
+    #[tokio::test]
+    async fn should_handshake_with_rc4() {
+        assert_eq!(
+            assert_handshake(EncryptionPolicy::Enabled).await,
+            CryptoMethod::Rc4
+        );
+        assert_eq!(
+            assert_handshake(EncryptionPolicy::Forced).await,
+            CryptoMethod::Rc4
+        );
+    }
+
+    /// Tests that the inbound side rejects a connection for a torrent it
+    /// doesn't have.
+    #[tokio::test]
+    async fn should_reject_unknown_torrent() {
+        let (outbound, mut inbound) = socket_pair().await;
+        let outbound =
+            handshake_outbound(outbound, &[1; 20], EncryptionPolicy::Enabled);
+        let inbound = async {
+            let mut prefix = vec![0; 20];
+            inbound.read_exact(&mut prefix).await.unwrap();
+            handshake_inbound(inbound, prefix, |_| async { None }).await
+        };
+        let (outbound, inbound) = tokio::join!(outbound, inbound);
+        assert!(inbound.is_err());
+        // the inbound side closed the connection
+        assert!(outbound.is_err());
+    }
+
+    #[test]
+    fn should_apply_policy() {
+        use EncryptionPolicy::*;
+        assert!(is_allowed(Disabled, None));
+        assert!(!is_allowed(Disabled, Some(CryptoMethod::Plaintext)));
+        assert!(is_allowed(Enabled, None));
+        assert!(is_allowed(Enabled, Some(CryptoMethod::Plaintext)));
+        assert!(!is_allowed(Forced, None));
+        assert!(!is_allowed(Forced, Some(CryptoMethod::Plaintext)));
+        assert!(is_allowed(Forced, Some(CryptoMethod::Rc4)));
+    }
+}
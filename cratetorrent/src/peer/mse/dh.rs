@@ -0,0 +1,268 @@
+//! The Diffie-Hellman key exchange of the encrypted handshake.
+//!
+//! Both sides use the same 768 bit prime and the generator 2. As this is the
+//! only modular arithmetic we need, it's implemented here on fixed size
+//! numbers rather than pulling in a big integer library. Exponentiation uses
+//! Montgomery multiplication, since the modulus is odd.
+
+use rand::Rng;
+
+/// The length of a public key and of the shared secret, in bytes.
+pub(crate) const KEY_LEN: usize = 96;
+
+/// The prime modulus of the key exchange, in big-endian order.
+const P: [u8; KEY_LEN] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xc9, 0x0f, 0xda, 0xa2,
+    0x21, 0x68, 0xc2, 0x34, 0xc4, 0xc6, 0x62, 0x8b, 0x80, 0xdc, 0x1c, 0xd1,
+    0x29, 0x02, 0x4e, 0x08, 0x8a, 0x67, 0xcc, 0x74, 0x02, 0x0b, 0xbe, 0xa6,
+    0x3b, 0x13, 0x9b, 0x22, 0x51, 0x4a, 0x08, 0x79, 0x8e, 0x34, 0x04, 0xdd,
+    0xef, 0x95, 0x19, 0xb3, 0xcd, 0x3a, 0x43, 0x1b, 0x30, 0x2b, 0x0a, 0x6d,
+    0xf2, 0x5f, 0x14, 0x37, 0x4f, 0xe1, 0x35, 0x6d, 0x6d, 0x51, 0xc2, 0x45,
+    0xe4, 0x85, 0xb5, 0x76, 0x62, 0x5e, 0x7e, 0xc6, 0xf4, 0x4c, 0x42, 0xe9,
+    0xa6, 0x3a, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+/// The generator of the key exchange.
+const G: u32 = 2;
+
+/// The length of the private key, in bytes.
+const PRIVATE_KEY_LEN: usize = 20;
+
+/// The number of 32 bit limbs in a key.
+const LIMB_COUNT: usize = KEY_LEN / 4;
+
+/// A number modulo `P`, stored as little-endian 32 bit limbs.
+type Num = [u32; LIMB_COUNT];
+
+/// Our side of the key exchange.
+pub(crate) struct KeyPair {
+    private: [u8; PRIVATE_KEY_LEN],
+    /// The public key, which is sent to the other side.
+    pub public: [u8; KEY_LEN],
+}
+
+impl KeyPair {
+    /// Generates a random private key and its public key.
+    pub fn generate() -> Self {
+        let private: [u8; PRIVATE_KEY_LEN] = rand::thread_rng().gen();
+        let mut generator = [0; LIMB_COUNT];
+        generator[0] = G;
+        let public = to_bytes(&mod_pow(&generator, &private));
+        Self { private, public }
+    }
+
+    /// Returns the secret shared with the other side, derived from its public
+    /// key, or None if the key is invalid.
+    ///
+    /// A valid public key is in the range `[2, P - 2]`. The others would make
+    /// the shared secret 0, 1 or `P - 1`, which anyone can guess, so they're
+    /// rejected rather than reduced.
+    pub fn shared_secret(
+        &self,
+        remote_public: &[u8; KEY_LEN],
+    ) -> Option<[u8; KEY_LEN]> {
+        let base = from_bytes(remote_public);
+        let mut two = [0; LIMB_COUNT];
+        two[0] = 2;
+        let mut max = from_bytes(&P);
+        sub_assign(&mut max, &two);
+        if is_less(&base, &two) || is_less(&max, &base) {
+            return None;
+        }
+        Some(to_bytes(&mod_pow(&base, &self.private)))
+    }
+}
+
+/// Returns `base^exp mod P`, with the exponent in big-endian order.
+fn mod_pow(base: &Num, exp: &[u8]) -> Num {
+    let m = Montgomery::new(&from_bytes(&P));
+    let mut one = [0; LIMB_COUNT];
+    one[0] = 1;
+
+    // convert to Montgomery form, where x is represented by xR mod P
+    let base = m.mul(base, &m.r2);
+    let mut acc = m.mul(&one, &m.r2);
+    for byte in exp {
+        for bit in (0..8).rev() {
+            acc = m.mul(&acc, &acc);
+            if byte >> bit & 1 == 1 {
+                acc = m.mul(&acc, &base);
+            }
+        }
+    }
+    // and back
+    m.mul(&acc, &one)
+}
+
+/// Multiplication modulo an odd number, in Montgomery form.
+struct Montgomery {
+    n: Num,
+    /// `-n^-1 mod 2^32`.
+    n_inv: u32,
+    /// `R^2 mod n`, where `R = 2^768`, used to convert to Montgomery form.
+    r2: Num,
+}
+
+impl Montgomery {
+    fn new(n: &Num) -> Self {
+        // Newton's method doubles the number of correct low bits of the
+        // inverse with each iteration
+        let mut inv: u32 = 1;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(n[0].wrapping_mul(inv)));
+        }
+
+        // R^2 mod n by doubling 1 modulo n 2 * 768 times
+        let mut r2 = [0; LIMB_COUNT];
+        r2[0] = 1;
+        for _ in 0..2 * 32 * LIMB_COUNT {
+            let carry = shl1(&mut r2);
+            if carry || !is_less(&r2, n) {
+                sub_assign(&mut r2, n);
+            }
+        }
+
+        Self {
+            n: *n,
+            n_inv: inv.wrapping_neg(),
+            r2,
+        }
+    }
+
+    /// Returns `a * b * R^-1 mod n`, for `a` and `b` less than `n`.
+    fn mul(&self, a: &Num, b: &Num) -> Num {
+        let n = &self.n;
+        let mut t = [0u32; LIMB_COUNT + 2];
+        for &b_i in b.iter() {
+            // t += a * b_i
+            let mut carry = 0u64;
+            for j in 0..LIMB_COUNT {
+                let s = t[j] as u64 + a[j] as u64 * b_i as u64 + carry;
+                t[j] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[LIMB_COUNT] as u64 + carry;
+            t[LIMB_COUNT] = s as u32;
+            t[LIMB_COUNT + 1] = (s >> 32) as u32;
+
+            // t = (t + m * n) / 2^32, where m is chosen so that the division
+            // is exact
+            let m = t[0].wrapping_mul(self.n_inv);
+            let s = t[0] as u64 + m as u64 * n[0] as u64;
+            let mut carry = s >> 32;
+            for j in 1..LIMB_COUNT {
+                let s = t[j] as u64 + m as u64 * n[j] as u64 + carry;
+                t[j - 1] = s as u32;
+                carry = s >> 32;
+            }
+            let s = t[LIMB_COUNT] as u64 + carry;
+            t[LIMB_COUNT - 1] = s as u32;
+            t[LIMB_COUNT] = t[LIMB_COUNT + 1] + (s >> 32) as u32;
+        }
+
+        // the result is less than 2n, so at most one subtraction is needed
+        let mut result = [0; LIMB_COUNT];
+        result.copy_from_slice(&t[..LIMB_COUNT]);
+        if t[LIMB_COUNT] != 0 || !is_less(&result, n) {
+            sub_assign(&mut result, n);
+        }
+        result
+    }
+}
+
+fn from_bytes(bytes: &[u8; KEY_LEN]) -> Num {
+    let mut num = [0; LIMB_COUNT];
+    for (limb, chunk) in num.iter_mut().zip(bytes.rchunks_exact(4)) {
+        *limb = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    num
+}
+
+fn to_bytes(num: &Num) -> [u8; KEY_LEN] {
+    let mut bytes = [0; KEY_LEN];
+    for (chunk, limb) in bytes.rchunks_exact_mut(4).zip(num.iter()) {
+        chunk.copy_from_slice(&limb.to_be_bytes());
+    }
+    bytes
+}
+
+fn is_less(a: &Num, b: &Num) -> bool {
+    a.iter().rev().cmp(b.iter().rev()) == std::cmp::Ordering::Less
+}
+
+/// Subtracts `b` from `a`, wrapping around on underflow.
+fn sub_assign(a: &mut Num, b: &Num) {
+    let mut borrow = false;
+    for (a, b) in a.iter_mut().zip(b.iter()) {
+        let (d, b1) = a.overflowing_sub(*b);
+        let (d, b2) = d.overflowing_sub(borrow as u32);
+        *a = d;
+        borrow = b1 || b2;
+    }
+}
+
+/// Shifts the number left by one bit, returning the bit shifted out.
+fn shl1(a: &mut Num) -> bool {
+    let mut carry = 0;
+    for limb in a.iter_mut() {
+        let next = *limb >> 31;
+        *limb = *limb << 1 | carry;
+        carry = next;
+    }
+    carry == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_mod_pow() {
+        let mut generator = [0; LIMB_COUNT];
+        generator[0] = G;
+        let exp =
+            hex::decode("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let expected = hex::decode(
+            "6fd4bc7aa649593205ec30348a3ccc737b61fa01e9e1762c2c53eb69033afecb\
+            df7c13b8ac3643af78d0760b0f42db009f2b96c970f009d060faf617f117d0f1\
+            c221cea0561b9a86e852fc70a6f09ad0f82378603aa5e56b811deb3f534bf276",
+        )
+        .unwrap();
+        assert_eq!(to_bytes(&mod_pow(&generator, &exp)).to_vec(), expected);
+
+        // small results aren't reduced
+        assert_eq!(mod_pow(&generator, &[10])[0], 1024);
+    }
+
+    #[test]
+    fn should_agree_on_shared_secret() {
+        let a = KeyPair::generate();
+        let b = KeyPair::generate();
+        assert_eq!(a.shared_secret(&b.public), b.shared_secret(&a.public));
+        assert!(a.shared_secret(&b.public).is_some());
+        assert_ne!(a.public, b.public);
+    }
+
+    #[test]
+    fn should_reject_invalid_public_key() {
+        let keys = KeyPair::generate();
+        let mut one = [0; LIMB_COUNT];
+        one[0] = 1;
+
+        let mut num = [0; LIMB_COUNT];
+        assert!(keys.shared_secret(&to_bytes(&num)).is_none());
+        num[0] = 1;
+        assert!(keys.shared_secret(&to_bytes(&num)).is_none());
+        num[0] = 2;
+        assert!(keys.shared_secret(&to_bytes(&num)).is_some());
+
+        // P - 1 and above are invalid, while P - 2 is the largest valid key
+        assert!(keys.shared_secret(&[0xff; KEY_LEN]).is_none());
+        assert!(keys.shared_secret(&P).is_none());
+        let mut num = from_bytes(&P);
+        sub_assign(&mut num, &one);
+        assert!(keys.shared_secret(&to_bytes(&num)).is_none());
+        sub_assign(&mut num, &one);
+        assert!(keys.shared_secret(&to_bytes(&num)).is_some());
+    }
+}
@@ -0,0 +1,62 @@
+//! The RC4 stream cipher, with which the encrypted handshake and, if
+//! negotiated, the rest of the connection is encrypted.
+
+/// The state of an RC4 keystream.
+pub(crate) struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// Creates the keystream for the key, which must not be empty.
+    pub fn new(key: &[u8]) -> Self {
+        debug_assert!(!key.is_empty());
+        let mut s = [0; 256];
+        for (i, x) in s.iter_mut().enumerate() {
+            *x = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+        Self { s, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts the buffer in place, which are the same
+    /// operation.
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        for byte in buf.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k =
+                self.s[self.i as usize].wrapping_add(self.s[self.j as usize]);
+            *byte ^= self.s[k as usize];
+        }
+    }
+
+    /// Advances the keystream by the given number of bytes.
+    pub fn discard(&mut self, count: usize) {
+        let mut buf = vec![0; count];
+        self.apply(&mut buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests the cipher against well known test vectors.
+    #[test]
+    fn should_encrypt() {
+        let mut buf = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut buf);
+        assert_eq!(hex::encode(&buf), "bbf316e8d940af0ad3");
+
+        let mut buf = b"Attack at dawn".to_vec();
+        Rc4::new(b"Secret").apply(&mut buf);
+        assert_eq!(hex::encode(&buf), "45a01f645fc35b383552544b9bf5");
+    }
+}
@@ -0,0 +1,178 @@
+//! The peer exchange extension (BEP 11), with which connected peers tell each
+//! other about the peers they are connected to.
+//!
+//! Each message contains the peers that the sender connected to and
+//! disconnected from since its previous message, along with some flags that
+//! describe the added peers.
+
+use std::{net::SocketAddr, time::Duration};
+
+use crate::{
+    compact::{decode_v4_addrs, decode_v6_addrs, encode_addr},
+    metainfo::BencodeError,
+};
+
+/// The name of the peer exchange extension.
+pub(crate) const UT_PEX: &str = "ut_pex";
+
+/// The id with which we want to receive peer exchange messages.
+pub(crate) const UT_PEX_ID: u8 = 2;
+
+/// Peer exchange messages must not be sent more often than this.
+pub(crate) const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The maximum number of added and of dropped peers in a single message.
+pub(crate) const MAX_PEX_PEERS: usize = 50;
+
+/// The flags of a peer in a peer exchange message.
+///
+/// The raw byte is kept so that flags we don't use, such as whether the peer
+/// prefers encryption (0x01) or supports uTP (0x04), are passed on as is when we
+/// advertise the peer to others.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct PexFlags(pub u8);
+
+impl PexFlags {
+    /// The peer is a seed, or only uploads.
+    pub const SEED: u8 = 0x02;
+    /// An outgoing connection to the peer was successful, so it's reachable.
+    pub const REACHABLE: u8 = 0x10;
+
+    /// Returns whether the given flag is set.
+    pub fn contains(self, flag: u8) -> bool {
+        self.0 & flag != 0
+    }
+
+    /// Sets or clears the given flag.
+    pub fn set(&mut self, flag: u8, value: bool) {
+        if value {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
+        }
+    }
+}
+
+/// A peer exchange message.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct PexMsg {
+    /// The peers the sender connected to since its last message.
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    /// The peers the sender disconnected from since its last message.
+    pub dropped: Vec<SocketAddr>,
+}
+
+/// The bencoded form of the peer exchange message, in which the addresses are
+/// in the compact format and the flags are a string of one byte per added peer.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct RawPexMsg {
+    #[serde(default, with = "serde_bytes")]
+    added: Vec<u8>,
+    #[serde(default, rename = "added.f", with = "serde_bytes")]
+    added_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    added6: Vec<u8>,
+    #[serde(default, rename = "added6.f", with = "serde_bytes")]
+    added6_flags: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    dropped: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    dropped6: Vec<u8>,
+}
+
+impl PexMsg {
+    /// Parses the payload of a peer exchange message.
+    ///
+    /// Peers whose flags are missing are given no flags.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, BencodeError> {
+        let raw: RawPexMsg = serde_bencode::from_bytes(buf)?;
+        let with_flags = |addrs: Vec<SocketAddr>, flags: &[u8]| {
+            addrs
+                .into_iter()
+                .enumerate()
+                .map(|(i, addr)| {
+                    (addr, PexFlags(flags.get(i).copied().unwrap_or_default()))
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut added =
+            with_flags(decode_v4_addrs(&raw.added), &raw.added_flags);
+        added.extend(with_flags(
+            decode_v6_addrs(&raw.added6),
+            &raw.added6_flags,
+        ));
+        let mut dropped = decode_v4_addrs(&raw.dropped);
+        dropped.extend(decode_v6_addrs(&raw.dropped6));
+        Ok(Self { added, dropped })
+    }
+
+    /// Encodes the message as the payload of an extended message.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BencodeError> {
+        let mut raw = RawPexMsg::default();
+        for (addr, flags) in self.added.iter() {
+            if addr.is_ipv4() {
+                encode_addr(addr, &mut raw.added);
+                raw.added_flags.push(flags.0);
+            } else {
+                encode_addr(addr, &mut raw.added6);
+                raw.added6_flags.push(flags.0);
+            }
+        }
+        for addr in self.dropped.iter() {
+            if addr.is_ipv4() {
+                encode_addr(addr, &mut raw.dropped);
+            } else {
+                encode_addr(addr, &mut raw.dropped6);
+            }
+        }
+        serde_bencode::to_bytes(&raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that peer exchange messages are encoded and decoded with the
+    /// flags of the added peers.
+    #[test]
+    fn should_encode_and_decode_pex_msg() {
+        let msg = PexMsg {
+            added: vec![
+                (
+                    "10.0.0.1:6881".parse().unwrap(),
+                    PexFlags(PexFlags::SEED | PexFlags::REACHABLE),
+                ),
+                (
+                    "[2001:db8::1]:6882".parse().unwrap(),
+                    // prefers encryption and supports uTP
+                    PexFlags(0x01 | 0x04),
+                ),
+            ],
+            dropped: vec!["10.0.0.2:6881".parse().unwrap()],
+        };
+        let encoded = msg.to_bytes().unwrap();
+        assert_eq!(PexMsg::from_bytes(&encoded).unwrap(), msg);
+    }
+
+    /// Tests parsing a message with only IPv4 peers and without flags for
+    /// some of them.
+    #[test]
+    fn should_parse_pex_msg_with_missing_flags() {
+        let buf =
+            b"d5:added12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe1\
+            7:added.f1:\x127:dropped0:e";
+        let msg = PexMsg::from_bytes(buf).unwrap();
+        assert_eq!(
+            msg.added,
+            vec![
+                ("10.0.0.1:6881".parse().unwrap(), PexFlags(0x12)),
+                ("10.0.0.2:6881".parse().unwrap(), PexFlags::default()),
+            ]
+        );
+        assert!(msg.added[0].1.contains(PexFlags::SEED));
+        assert!(msg.added[0].1.contains(PexFlags::REACHABLE));
+        assert!(!msg.added[1].1.contains(PexFlags::SEED));
+        assert!(msg.dropped.is_empty());
+    }
+}
@@ -97,6 +97,10 @@ pub(super) struct SessionContext {
     /// the hot path.
     pub in_endgame: bool,
 
+    /// Whether the peer is one of the torrent's fastest peers, which download
+    /// the pieces with a deadline.
+    pub is_fast: bool,
+
     /// The target request queue size is the number of block requests we keep
     /// outstanding to fully saturate the link.
     ///
@@ -195,6 +199,19 @@ impl SessionContext {
         self.target_request_queue_len = Some(Self::START_REQUEST_QUEUE_LEN);
     }
 
+    /// Prepares for requesting the pieces that the peer allows us to download
+    /// while choked.
+    ///
+    /// The target request queue size is left as is if we were already
+    /// downloading from the peer.
+    pub fn prepare_for_allowed_fast_download(&mut self) {
+        debug_assert!(self.state.is_choked);
+
+        if self.target_request_queue_len.is_none() {
+            self.target_request_queue_len = Some(Self::START_REQUEST_QUEUE_LEN);
+        }
+    }
+
     /// Convenience method to set any field in state and to set the [`Self::changed`]
     /// flag.
     #[inline(always)]
@@ -0,0 +1,155 @@
+//! The transports over which peer connections run.
+//!
+//! A peer session runs over any stream that implements [`Transport`]. By
+//! default, the engine connects to peers over uTP, falling back to TCP, but
+//! the library user may supply their own [`Connector`] to make the
+//! connections some other way.
+
+use std::{
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use crate::utp::UtpSocket;
+
+/// A stream over which a peer session runs, such as a TCP or uTP connection.
+///
+/// This is implemented for all types that are `AsyncRead + AsyncWrite + Unpin
+/// + Send`, so it never needs to be implemented manually.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send + ?Sized {}
+
+/// A transport of any type, as returned by a [`Connector`].
+pub type BoxTransport = Box<dyn Transport>;
+
+/// Makes the outbound connections to peers, for both the peer sessions of
+/// torrents and the metadata downloads of magnet links.
+///
+/// The engine's connector is set in
+/// [`EngineConf::connector`](crate::conf::EngineConf::connector). A custom
+/// connector may connect peers over a proxy or a Unix socket, or over
+/// in-memory pipes such as `tokio::io::duplex` in tests, in which case the
+/// other end is handed to the other engine with
+/// [`EngineHandle::add_incoming_peer`](crate::engine::EngineHandle::add_incoming_peer).
+///
+/// The connection is encrypted afterwards, according to the torrent's
+/// [`EncryptionPolicy`](crate::conf::EncryptionPolicy), so the connector
+/// only needs to establish the stream.
+pub trait Connector: Send + Sync {
+    /// Connects to the peer at the address.
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'static, io::Result<BoxTransport>>;
+}
+
+impl std::fmt::Debug for dyn Connector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Connector")
+    }
+}
+
+/// We don't remember more peers that don't answer over uTP than this, after
+/// which they are all tried over uTP again.
+const MAX_TCP_PEER_COUNT: usize = 4096;
+
+/// The engine's connector, unless the user supplied one, which connects over
+/// uTP if enabled and falls back to TCP if the peer doesn't answer over uTP.
+///
+/// The peers that didn't answer over uTP are remembered and connected to over
+/// TCP right away the next time, so that e.g. reconnecting in plaintext after
+/// a failed encrypted handshake uses the transport that worked, without
+/// waiting for uTP to time out again.
+pub(crate) struct DefaultConnector {
+    /// The engine's uTP socket, if uTP is enabled.
+    utp: Option<UtpSocket>,
+    /// The peers that didn't answer over uTP the last time.
+    tcp_peers: Arc<Mutex<HashSet<SocketAddr>>>,
+}
+
+impl DefaultConnector {
+    pub fn new(utp: Option<UtpSocket>) -> Self {
+        Self {
+            utp,
+            tcp_peers: Default::default(),
+        }
+    }
+}
+
+impl Connector for DefaultConnector {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+    ) -> BoxFuture<'static, io::Result<BoxTransport>> {
+        let utp = self.utp.clone();
+        let tcp_peers = Arc::clone(&self.tcp_peers);
+        async move {
+            let is_tcp_peer = tcp_peers.lock().unwrap().contains(&addr);
+            if let Some(utp) = utp.filter(|_| !is_tcp_peer) {
+                match utp.connect(addr).await {
+                    Ok(socket) => {
+                        log::debug!("Connected to {} over uTP", addr);
+                        return Ok(Box::new(socket) as BoxTransport);
+                    }
+                    Err(e) => {
+                        log::debug!(
+                            "Cannot connect to {} over uTP, using TCP: {}",
+                            addr,
+                            e
+                        );
+                        let mut tcp_peers = tcp_peers.lock().unwrap();
+                        if tcp_peers.len() >= MAX_TCP_PEER_COUNT {
+                            tcp_peers.clear();
+                        }
+                        tcp_peers.insert(addr);
+                    }
+                }
+            }
+            let socket = TcpStream::connect(addr).await?;
+            log::debug!("Connected to {} over TCP", addr);
+            Ok(Box::new(socket) as BoxTransport)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket as StdUdpSocket, time::Duration};
+
+    use tokio::{net::TcpListener, time};
+
+    use super::*;
+    use crate::utp;
+
+    /// Tests that a peer that doesn't answer over uTP is connected to over
+    /// TCP, and right away the next time.
+    #[tokio::test]
+    async fn should_remember_tcp_peers() {
+        let (utp, _) =
+            utp::spawn(StdUdpSocket::bind("127.0.0.1:0").unwrap()).unwrap();
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        // the connections are established without being accepted
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connector = DefaultConnector::new(Some(utp));
+
+        connector.connect(addr).await.unwrap();
+        assert!(connector.tcp_peers.lock().unwrap().contains(&addr));
+
+        // uTP would take seconds to time out again
+        time::timeout(Duration::from_millis(500), connector.connect(addr))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
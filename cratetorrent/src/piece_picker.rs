@@ -1,4 +1,17 @@
-use crate::{Bitfield, PieceIndex};
+use std::{collections::BTreeSet, time::Instant};
+
+use rand::seq::IteratorRandom;
+
+use crate::{Bitfield, PieceIndex, Priority, DEFAULT_PRIORITY};
+
+/// Until we have this many pieces, pieces are picked at random rather than
+/// rarest first.
+///
+/// The rarest pieces are available from the fewest peers, so they tend to be
+/// the slowest to download. Until we have a few complete pieces we have nothing
+/// to offer to other peers, so at first it's more important to complete pieces
+/// quickly than to complete rare ones.
+const RANDOM_PIECE_COUNT: usize = 4;
 
 pub(crate) struct PiecePicker {
     /// Represents the pieces that we have downloaded.
@@ -6,15 +19,33 @@ pub(crate) struct PiecePicker {
     /// The bitfield is pre-allocated to the number of pieces in the torrent and
     /// each field that we have is set to true.
     own_pieces: Bitfield,
+    /// A cache for the number of pieces we have.
+    own_count: usize,
     /// We collect metadata about pieces in the torrent swarm in this vector.
     ///
     /// The vector is pre-allocated to the number of pieces in the torrent.
     pieces: Vec<Piece>,
-    /// A cache for the number of pieces we haven't received yet (but may have
-    /// picked).
+    /// The pieces that can be picked, that is, the pieces we want but don't
+    /// have and aren't downloading, in a queue per priority, indexed by the
+    /// priority.
+    ///
+    /// Pieces with priority 0 are not downloaded, so they are not in any
+    /// queue.
+    queues: Vec<Queue>,
+    /// A cache for the number of pieces we want but haven't received yet (but
+    /// may have picked).
     missing_count: usize,
     /// A cache for the number of pieces that can be picked.
     free_count: usize,
+    /// Whether pieces are picked in order of their index, rather than rarest
+    /// first.
+    sequential: bool,
+    /// The pieces that need to be downloaded by a deadline, ordered by their
+    /// deadline.
+    ///
+    /// The deadline of each piece is also stored in the piece itself, so that
+    /// it can be found when the deadline is reset.
+    deadlines: BTreeSet<(Instant, PieceIndex)>,
 }
 
 /// Metadata about a piece relevant for the piece picker.
@@ -32,20 +63,61 @@ pub(crate) struct Piece {
     /// wouldn't be able to download multiple pieces simultaneously (an
     /// important optimizaiton step).
     pub is_pending: bool,
+    /// The download priority of the piece, which is the highest priority of
+    /// the files the piece overlaps with.
+    pub priority: Priority,
+    /// The position of the piece in the queue of its priority, if it can be
+    /// picked.
+    position: Option<usize>,
+    /// The time by which the piece should be downloaded, if any.
+    deadline: Option<Instant>,
+}
+
+/// The pieces of one priority that can be picked, ordered by their frequency
+/// in the swarm.
+///
+/// Pieces with the same frequency form a contiguous bucket. Since the frequency
+/// of a piece only ever changes by one, the piece can be moved to the
+/// neighboring bucket by swapping it with the piece at the edge of its own
+/// bucket, so that the order is kept in constant time and picking the rarest
+/// piece doesn't need to go through all pieces.
+#[derive(Default)]
+struct Queue {
+    pieces: Vec<PieceIndex>,
+    /// The position at which the bucket of each frequency starts.
+    ///
+    /// The buckets of frequencies past the end of this vector are empty.
+    bucket_starts: Vec<usize>,
+    /// The same pieces ordered by their index, for sequential picking.
+    ordered: BTreeSet<PieceIndex>,
 }
 
 impl PiecePicker {
     /// Creates a new piece picker with the given own_pieces we already have.
+    ///
+    /// All pieces have the default priority.
     pub fn new(own_pieces: Bitfield) -> Self {
-        let mut pieces = Vec::new();
-        pieces.resize_with(own_pieces.len(), Piece::default);
-        let missing_count = own_pieces.count_zeros();
-        Self {
+        let piece = Piece {
+            priority: DEFAULT_PRIORITY,
+            ..Default::default()
+        };
+        let own_count = own_pieces.count_ones();
+        let mut piece_picker = Self {
+            pieces: vec![piece; own_pieces.len()],
+            missing_count: own_pieces.len() - own_count,
             own_pieces,
-            pieces,
-            missing_count,
-            free_count: missing_count,
+            own_count,
+            queues: Vec::new(),
+            free_count: 0,
+            sequential: false,
+            deadlines: BTreeSet::new(),
+        };
+        for index in 0..piece_picker.pieces.len() {
+            if !piece_picker.own_pieces[index] {
+                piece_picker.add_to_queue(index);
+            }
         }
+        piece_picker
     }
 
     /// Returns an immutable reference to a bitfield of the pieces we own.
@@ -54,43 +126,191 @@ impl PiecePicker {
     }
 
     /// Returns the number of missing pieces that are needed to complete the
-    /// download.
+    /// download, which only includes the pieces we want.
     pub fn missing_piece_count(&self) -> usize {
         self.missing_count
     }
 
-    /// Returns true if all pieces have been picked (whether pending or
+    /// Returns the indices of the missing pieces that are needed to complete
+    /// the download, which only includes the pieces we want.
+    pub fn missing_pieces(&self) -> impl Iterator<Item = PieceIndex> + '_ {
+        (0..self.pieces.len()).filter(move |&index| self.wants(index))
+    }
+
+    /// Returns true if all pieces we want have been picked (whether pending or
     /// recieved).
     pub fn all_pieces_picked(&self) -> bool {
         self.free_count == 0
     }
 
-    /// Returns the first piece that we don't yet have and isn't already being
-    /// downloaded, or None, if no piece can be picked at this time.
-    pub fn pick_piece(&mut self) -> Option<PieceIndex> {
+    /// Returns whether the peer has any piece we want.
+    pub fn is_interested(&self, peer_pieces: &Bitfield) -> bool {
+        peer_pieces
+            .iter()
+            .enumerate()
+            .any(|(index, peer_has_piece)| *peer_has_piece && self.wants(index))
+    }
+
+    /// Picks one of the rarest pieces in the swarm that the peer has, that we
+    /// want but don't yet have and that isn't already being downloaded, or
+    /// returns None, if no piece can be picked at this time.
+    ///
+    /// Pieces with a higher priority are picked first. Ties between the rarest
+    /// pieces are broken randomly, so that peers downloading from the same
+    /// swarm don't all go for the same pieces. Until we have a few pieces,
+    /// a random piece is picked instead (see [`RANDOM_PIECE_COUNT`]).
+    ///
+    /// In sequential mode the piece with the lowest index is picked instead,
+    /// still in order of priority.
+    pub fn pick_piece(&mut self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
         log::trace!("Picking next piece");
 
-        for index in 0..self.own_pieces.len() {
-            // only consider this piece if we don't have it and if we are not
-            // already downloading it (whether it's not pending)
-            debug_assert!(index < self.pieces.len());
-            let piece = &mut self.pieces[index];
-            if !self.own_pieces[index]
-                && piece.frequency > 0
-                && !piece.is_pending
-            {
-                // set pending flag on piece so that this piece is not picked
-                // again (see note on field)
-                piece.is_pending = true;
-                self.free_count -= 1;
-                log::trace!("Picked piece {}", index);
-                return Some(index);
+        let is_random = self.own_count < RANDOM_PIECE_COUNT;
+        let pick = self.queues.iter().rev().find_map(|queue| {
+            if self.sequential {
+                queue.pick_first(peer_pieces)
+            } else {
+                queue.pick(&self.pieces, peer_pieces, is_random)
             }
+        });
+
+        if let Some(index) = pick {
+            // set pending flag on piece so that this piece is not picked
+            // again (see note on field)
+            self.remove_from_queue(index);
+            self.pieces[index].is_pending = true;
+            log::trace!("Picked piece {}", index);
+        } else {
+            log::trace!("Could not pick piece");
         }
+        pick
+    }
 
-        // no piece could be picked
-        log::trace!("Could not pick piece");
-        None
+    /// Picks the piece with the earliest deadline that the peer has, that we
+    /// want but don't yet have and that isn't already being downloaded, or
+    /// returns None, if there is no such piece.
+    pub fn pick_deadline_piece(
+        &mut self,
+        peer_pieces: &Bitfield,
+    ) -> Option<PieceIndex> {
+        log::trace!("Picking next deadline piece");
+
+        let pieces = &self.pieces;
+        let pick =
+            self.deadlines
+                .iter()
+                .map(|(_, index)| *index)
+                .find(|index| {
+                    pieces[*index].position.is_some() && peer_pieces[*index]
+                });
+
+        if let Some(index) = pick {
+            self.remove_from_queue(index);
+            self.pieces[index].is_pending = true;
+            log::trace!("Picked deadline piece {}", index);
+        }
+        pick
+    }
+
+    /// Sets whether pieces are picked in order of their index, rather than
+    /// rarest first.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        log::trace!("Setting sequential picking: {}", sequential);
+        self.sequential = sequential;
+    }
+
+    /// Sets the time by which the piece should be downloaded, replacing its
+    /// previous deadline, if any.
+    ///
+    /// Pieces with a deadline are picked before all other pieces by
+    /// [`Self::pick_deadline_piece`], in order of their deadline. The deadline
+    /// is kept until it's reset, even after the piece is received.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the piece index is out of range.
+    pub fn set_piece_deadline(&mut self, index: PieceIndex, deadline: Instant) {
+        log::trace!("Setting piece {} deadline", index);
+        assert!(index < self.pieces.len(), "invalid piece index");
+        self.reset_piece_deadline(index);
+        self.pieces[index].deadline = Some(deadline);
+        self.deadlines.insert((deadline, index));
+    }
+
+    /// Removes the deadline of the piece, returning it, if it had one.
+    pub fn reset_piece_deadline(
+        &mut self,
+        index: PieceIndex,
+    ) -> Option<Instant> {
+        let deadline = self.pieces[index].deadline.take()?;
+        self.deadlines.remove(&(deadline, index));
+        Some(deadline)
+    }
+
+    /// Returns the pieces with a deadline, ordered by their deadline.
+    pub fn deadlines(
+        &self,
+    ) -> impl Iterator<Item = (Instant, PieceIndex)> + '_ {
+        self.deadlines.iter().copied()
+    }
+
+    /// Marks the piece as pending without picking it, so that it is not picked
+    /// again.
+    ///
+    /// This is used when a piece download is continued that was not started via
+    /// [`Self::pick_piece`] (e.g. when restored from resume data).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the piece index is out of range or if we already have the
+    /// piece.
+    pub fn mark_pending(&mut self, index: PieceIndex) {
+        log::trace!("Marking piece {} as pending", index);
+        assert!(
+            !self.own_pieces.get(index).expect("invalid piece index"),
+            "piece must be missing"
+        );
+        self.remove_from_queue(index);
+        self.pieces[index].is_pending = true;
+    }
+
+    /// Sets the priority of each piece, see [`Priority`].
+    ///
+    /// Pieces that are already being downloaded are not affected until they
+    /// complete, even if their priority is set to 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of priorities is different from the number of
+    /// pieces.
+    pub fn set_piece_priorities(&mut self, priorities: &[Priority]) {
+        log::trace!("Setting piece priorities");
+
+        assert_eq!(
+            priorities.len(),
+            self.pieces.len(),
+            "there must be a priority for each piece"
+        );
+
+        for (index, priority) in priorities.iter().copied().enumerate() {
+            let old_priority = self.pieces[index].priority;
+            if priority == old_priority {
+                continue;
+            }
+
+            self.remove_from_queue(index);
+            if !self.own_pieces[index] {
+                if old_priority == 0 {
+                    self.missing_count += 1;
+                } else if priority == 0 {
+                    self.missing_count -= 1;
+                }
+            }
+            self.pieces[index].priority = priority;
+            if self.wants(index) && !self.pieces[index].is_pending {
+                self.add_to_queue(index);
+            }
+        }
     }
 
     /// Registers the avilability of a peer's pieces and returns whether we're
@@ -111,15 +331,13 @@ impl PiecePicker {
         );
 
         let mut interested = false;
-        for (index, (have_piece, peer_has_piece)) in
-            self.own_pieces.iter().zip(pieces.iter()).enumerate()
-        {
+        for (index, peer_has_piece) in pieces.iter().enumerate() {
             // increase frequency count for this piece if peer has it
             if *peer_has_piece {
-                self.pieces[index].frequency += 1;
-                // if we don't have at least one piece peer has, we're
-                // interested
-                if !have_piece {
+                self.increment_frequency(index);
+                // if we don't have at least one piece peer has that we want,
+                // we're interested
+                if self.wants(index) {
                     interested = true;
                 }
             }
@@ -128,7 +346,8 @@ impl PiecePicker {
         interested
     }
 
-    /// Increments the availability of a piece.
+    /// Increments the availability of a piece, and returns whether we want
+    /// the piece.
     ///
     /// This should be called when a peer sends us a `have` message of a new
     /// piece.
@@ -139,10 +358,30 @@ impl PiecePicker {
     /// ensured at the protocol level (in [`crate::peer::PeerSession`]).
     pub fn register_peer_piece(&mut self, index: PieceIndex) -> bool {
         log::trace!("Registering newly available piece {}", index);
-        let is_interested =
-            self.own_pieces.get(index).expect("invalid piece index");
-        self.pieces[index].frequency += 1;
-        *is_interested
+        assert!(index < self.pieces.len(), "invalid piece index");
+        self.increment_frequency(index);
+        self.wants(index)
+    }
+
+    /// Decrements the availability of a disconnected peer's pieces.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the peer's pieces have a different count than ours.
+    pub fn unregister_peer_pieces(&mut self, pieces: &Bitfield) {
+        log::trace!("Unregistering piece availability: {}", pieces);
+
+        assert_eq!(
+            pieces.len(),
+            self.own_pieces.len(),
+            "peer's bitfield must be the same length as ours"
+        );
+
+        for (index, peer_has_piece) in pieces.iter().enumerate() {
+            if *peer_has_piece {
+                self.decrement_frequency(index);
+            }
+        }
     }
 
     /// Tells the piece picker that we have downloaded the piece at the given
@@ -157,36 +396,225 @@ impl PiecePicker {
         // we assert here as this method is only called by internal methods on
         // piece completion, meaning the piece must exist (we can't download an
         // invalid piece)
-        let mut have_piece =
-            self.own_pieces.get_mut(index).expect("invalid piece index");
-        // we must not already have this piece as otherwise the free/missing
-        // count logic is thrown off
-        assert!(!*have_piece);
+        {
+            let mut have_piece =
+                self.own_pieces.get_mut(index).expect("invalid piece index");
+            // we must not already have this piece as otherwise the
+            // free/missing count logic is thrown off
+            assert!(!*have_piece);
 
-        // register owned piece
-        *have_piece = true;
-        self.missing_count -= 1;
+            // register owned piece
+            *have_piece = true;
+        }
+        self.own_count += 1;
+        // we may have downloaded a piece we don't want if it was already being
+        // downloaded when its priority was set to 0
+        if self.pieces[index].priority > 0 {
+            self.missing_count -= 1;
+        }
 
         // This is an edge-case and shouldn't normally happen, but we guard
         // against it anyway in case there are changes in other parts of the
         // code.
         // If the piece was received without it having previously been picked,
-        // we need to decrease the free piece count here, as it is normally done
-        // in the `pick_piece` method.
-        let piece = &mut self.pieces[index];
-        if !piece.is_pending {
-            self.free_count -= 1;
-            // also set that this piece is no longer pending (even though we
-            // won't be downloading it anymore, later we may re-download a piece
-            // in which case not resetting the flag would cause us to never pick
-            // the piece again)
-            piece.is_pending = false;
-        }
+        // it is still in its queue and thus counted as a free piece, so we
+        // need to remove it here, as it is normally done in the `pick_piece`
+        // method.
+        self.remove_from_queue(index);
+        // also set that this piece is no longer pending (later we may
+        // re-download a piece in which case not resetting the flag would cause
+        // us to never pick the piece again)
+        self.pieces[index].is_pending = false;
     }
 
     pub fn pieces(&self) -> &[Piece] {
         &self.pieces
     }
+
+    /// Returns whether we want the piece, i.e. whether we don't have it and
+    /// its priority is not 0.
+    fn wants(&self, index: PieceIndex) -> bool {
+        !self.own_pieces[index] && self.pieces[index].priority > 0
+    }
+
+    /// Adds the piece to the queue of its priority, so that it can be picked.
+    fn add_to_queue(&mut self, index: PieceIndex) {
+        let priority = self.pieces[index].priority as usize;
+        debug_assert!(priority > 0);
+        if self.queues.len() <= priority {
+            self.queues.resize_with(priority + 1, Queue::default);
+        }
+        self.queues[priority].insert(&mut self.pieces, index);
+        self.free_count += 1;
+    }
+
+    /// Removes the piece from its queue, if it's in it.
+    fn remove_from_queue(&mut self, index: PieceIndex) {
+        if self.pieces[index].position.is_some() {
+            let priority = self.pieces[index].priority as usize;
+            self.queues[priority].remove(&mut self.pieces, index);
+            self.free_count -= 1;
+        }
+    }
+
+    /// Increments the frequency of the piece, moving it to the next bucket if
+    /// it's in a queue.
+    fn increment_frequency(&mut self, index: PieceIndex) {
+        if self.pieces[index].position.is_some() {
+            let priority = self.pieces[index].priority as usize;
+            self.queues[priority].move_to_next_bucket(&mut self.pieces, index);
+        }
+        self.pieces[index].frequency += 1;
+    }
+
+    /// Decrements the frequency of the piece, moving it to the previous bucket
+    /// if it's in a queue.
+    fn decrement_frequency(&mut self, index: PieceIndex) {
+        // a peer may disconnect after the availability was reset, in which
+        // case its pieces were never counted
+        if self.pieces[index].frequency == 0 {
+            return;
+        }
+        if self.pieces[index].position.is_some() {
+            let priority = self.pieces[index].priority as usize;
+            self.queues[priority]
+                .move_to_previous_bucket(&mut self.pieces, index);
+        }
+        self.pieces[index].frequency -= 1;
+    }
+}
+
+impl Queue {
+    /// Picks the piece with the lowest index that the peer has.
+    fn pick_first(&self, peer_pieces: &Bitfield) -> Option<PieceIndex> {
+        self.ordered
+            .iter()
+            .copied()
+            .find(|index| peer_pieces[*index])
+    }
+
+    /// Picks a random piece that the peer has from the bucket of the rarest
+    /// pieces that has any, or from all buckets if `is_random` is set.
+    ///
+    /// The pieces that no peer has are never picked.
+    fn pick(
+        &self,
+        pieces: &[Piece],
+        peer_pieces: &Bitfield,
+        is_random: bool,
+    ) -> Option<PieceIndex> {
+        let mut rng = rand::thread_rng();
+        // the pieces that no peer has are at the front of the queue
+        let mut start = self.bucket_end(0);
+        if is_random {
+            return self.pieces[start..]
+                .iter()
+                .copied()
+                .filter(|index| peer_pieces[*index])
+                .choose(&mut rng);
+        }
+
+        while start < self.pieces.len() {
+            let frequency = pieces[self.pieces[start]].frequency;
+            let end = self.bucket_end(frequency);
+            let pick = self.pieces[start..end]
+                .iter()
+                .copied()
+                .filter(|index| peer_pieces[*index])
+                .choose(&mut rng);
+            if pick.is_some() {
+                return pick;
+            }
+            start = end;
+        }
+        None
+    }
+
+    /// Inserts the piece into the bucket of its frequency.
+    fn insert(&mut self, pieces: &mut [Piece], index: PieceIndex) {
+        let frequency = pieces[index].frequency;
+        while self.bucket_starts.len() <= frequency {
+            self.bucket_starts.push(self.pieces.len());
+        }
+
+        // insert the piece at the end of the queue and move it to its bucket
+        // by swapping it with the first piece of each bucket above it, which
+        // makes it the last piece of the bucket below
+        let mut position = self.pieces.len();
+        self.pieces.push(index);
+        self.ordered.insert(index);
+        pieces[index].position = Some(position);
+        for frequency in (frequency + 1..self.bucket_starts.len()).rev() {
+            let first = self.bucket_starts[frequency];
+            self.swap(pieces, position, first);
+            self.bucket_starts[frequency] = first + 1;
+            position = first;
+        }
+    }
+
+    /// Removes the piece from the queue.
+    fn remove(&mut self, pieces: &mut [Piece], index: PieceIndex) {
+        let piece = pieces[index];
+        let mut position = piece.position.expect("piece not in queue");
+        // move the piece to the end of the queue by swapping it with the last
+        // piece of its bucket, which makes it the first piece of the next
+        // bucket, and so on
+        for frequency in piece.frequency..self.bucket_starts.len() {
+            let last = self.bucket_end(frequency) - 1;
+            self.swap(pieces, position, last);
+            if let Some(start) = self.bucket_starts.get_mut(frequency + 1) {
+                *start = last;
+            }
+            position = last;
+        }
+        self.pieces.pop();
+        self.ordered.remove(&index);
+        pieces[index].position = None;
+    }
+
+    /// Moves the piece to the bucket of the next frequency.
+    fn move_to_next_bucket(&mut self, pieces: &mut [Piece], index: PieceIndex) {
+        let piece = pieces[index];
+        let position = piece.position.expect("piece not in queue");
+        let next = piece.frequency + 1;
+        if self.bucket_starts.len() == next {
+            self.bucket_starts.push(self.pieces.len());
+        }
+        // the last piece of the bucket becomes the first of the next one
+        let last = self.bucket_starts[next] - 1;
+        self.swap(pieces, position, last);
+        self.bucket_starts[next] = last;
+    }
+
+    /// Moves the piece to the bucket of the previous frequency.
+    fn move_to_previous_bucket(
+        &mut self,
+        pieces: &mut [Piece],
+        index: PieceIndex,
+    ) {
+        let piece = pieces[index];
+        let position = piece.position.expect("piece not in queue");
+        // the first piece of the bucket becomes the last of the previous one
+        let first = self.bucket_starts[piece.frequency];
+        self.swap(pieces, position, first);
+        self.bucket_starts[piece.frequency] = first + 1;
+    }
+
+    /// Returns the position in the queue one past the last piece with the
+    /// given frequency.
+    fn bucket_end(&self, frequency: usize) -> usize {
+        self.bucket_starts
+            .get(frequency + 1)
+            .copied()
+            .unwrap_or(self.pieces.len())
+    }
+
+    /// Swaps the pieces at the two positions in the queue.
+    fn swap(&mut self, pieces: &mut [Piece], a: usize, b: usize) {
+        self.pieces.swap(a, b);
+        pieces[self.pieces[a]].position = Some(a);
+        pieces[self.pieces[b]].position = Some(b);
+    }
 }
 
 #[cfg(test)]
@@ -208,12 +636,8 @@ mod tests {
         let mut picked = HashSet::with_capacity(piece_count);
 
         // pick all pieces one by one
-        for index in 0..piece_count {
-            let pick = piece_picker.pick_piece();
-            // for now we assert that we pick pieces in sequential order, but
-            // later, when we add different algorithms, this line has to change
-            assert_eq!(pick, Some(index));
-            let pick = pick.unwrap();
+        for _ in 0..piece_count {
+            let pick = piece_picker.pick_piece(&available_pieces).unwrap();
             // assert that this piece hasn't been picked before
             assert!(!picked.contains(&pick));
             // mark piece as picked
@@ -222,6 +646,100 @@ mod tests {
 
         // assert that we picked all pieces
         assert_eq!(picked.len(), piece_count);
+        assert_eq!(piece_picker.pick_piece(&available_pieces), None);
+        piece_picker.assert_consistent();
+    }
+
+    /// Tests that, once we have a few pieces, the rarest pieces are picked
+    /// first.
+    #[test]
+    fn should_pick_rarest_pieces_first() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        for index in 0..RANDOM_PIECE_COUNT {
+            piece_picker.received_piece(index);
+        }
+
+        // every peer has all pieces but one: the rarest pieces are the ones
+        // that were missing
+        let seed = Bitfield::repeat(true, piece_count);
+        let rare = [13, 7, 9];
+        for index in rare.iter() {
+            let mut pieces = seed.clone();
+            pieces.set(*index, false);
+            piece_picker.register_peer_pieces(&pieces);
+        }
+        piece_picker.assert_consistent();
+
+        let picked: HashSet<_> = (0..rare.len())
+            .map(|_| piece_picker.pick_piece(&seed).unwrap())
+            .collect();
+        assert_eq!(picked, rare.iter().copied().collect());
+        piece_picker.assert_consistent();
+    }
+
+    /// Tests that only pieces the peer has are picked, even if rarer pieces
+    /// are available from other peers.
+    #[test]
+    fn should_only_pick_pieces_peer_has() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        for index in 0..RANDOM_PIECE_COUNT {
+            piece_picker.received_piece(index);
+        }
+
+        // piece 5 is the rarest, but only the other peer has it
+        let mut rare_pieces = Bitfield::repeat(false, piece_count);
+        rare_pieces.set(5, true);
+        piece_picker.register_peer_pieces(&rare_pieces);
+        let mut peer_pieces = Bitfield::repeat(false, piece_count);
+        peer_pieces.set(10, true);
+        peer_pieces.set(11, true);
+        piece_picker.register_peer_pieces(&peer_pieces);
+        piece_picker.register_peer_pieces(&peer_pieces);
+
+        let pick = piece_picker.pick_piece(&peer_pieces).unwrap();
+        assert!(pick == 10 || pick == 11);
+        let pick = piece_picker.pick_piece(&peer_pieces).unwrap();
+        assert!(pick == 10 || pick == 11);
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), None);
+        assert_eq!(piece_picker.pick_piece(&rare_pieces), Some(5));
+        piece_picker.assert_consistent();
+    }
+
+    /// Tests that the pieces of a disconnected peer are no longer counted
+    /// towards the piece frequencies.
+    #[test]
+    fn should_unregister_peer_pieces() {
+        let piece_count = 15;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        for index in 0..RANDOM_PIECE_COUNT {
+            piece_picker.received_piece(index);
+        }
+
+        let seed = Bitfield::repeat(true, piece_count);
+        let mut pieces = Bitfield::repeat(false, piece_count);
+        pieces.set(8, true);
+        piece_picker.register_peer_pieces(&seed);
+        piece_picker.register_peer_pieces(&seed);
+        piece_picker.register_peer_pieces(&pieces);
+        piece_picker.unregister_peer_pieces(&seed);
+        piece_picker.assert_consistent();
+
+        assert_eq!(piece_picker.pieces()[8].frequency, 2);
+        assert_eq!(piece_picker.pieces()[9].frequency, 1);
+        assert!(piece_picker.pick_piece(&seed) != Some(8));
+
+        // frequencies of pieces that are not in the queue are updated too
+        piece_picker.unregister_peer_pieces(&seed);
+        assert_eq!(piece_picker.pieces()[0].frequency, 0);
+        assert_eq!(piece_picker.pieces()[8].frequency, 1);
+        // unregistering more pieces than were registered is ignored
+        piece_picker.unregister_peer_pieces(&seed);
+        assert_eq!(piece_picker.pieces()[8].frequency, 0);
+        assert_eq!(piece_picker.pieces()[9].frequency, 0);
+        assert_eq!(piece_picker.pick_piece(&seed), None);
+        piece_picker.assert_consistent();
     }
 
     /// Tests registering a received piece causes the piece picker to not pick
@@ -245,7 +763,7 @@ mod tests {
         // request pieces to pick next and make sure the ones we already have
         // are not picked
         for _ in 0..piece_count - owned_pieces.len() {
-            let pick = piece_picker.pick_piece().unwrap();
+            let pick = piece_picker.pick_piece(&available_pieces).unwrap();
             // assert that it's not a piece we already have
             assert!(owned_pieces.iter().all(|owned| *owned != pick));
         }
@@ -284,34 +802,168 @@ mod tests {
         let piece_count = 15;
         let mut piece_picker = PiecePicker::empty(piece_count);
         // NOTE: need to register frequency before we pick any pieces
-        piece_picker.register_peer_pieces(&Bitfield::repeat(true, piece_count));
+        let available_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&available_pieces);
 
         assert_eq!(piece_picker.free_count, piece_count);
 
         // picked and received 2 pieces
-        for i in 0..2 {
-            assert!(piece_picker.pick_piece().is_some());
-            piece_picker.received_piece(i);
+        for _ in 0..2 {
+            let pick = piece_picker.pick_piece(&available_pieces).unwrap();
+            piece_picker.received_piece(pick);
         }
         assert_eq!(piece_picker.free_count, 13);
 
         // pick 3 pieces
-        for _ in 0..3 {
-            assert!(piece_picker.pick_piece().is_some());
-        }
+        let picked: Vec<_> = (0..3)
+            .map(|_| piece_picker.pick_piece(&available_pieces).unwrap())
+            .collect();
         assert_eq!(piece_picker.free_count, 10);
 
         // received 1 of the above picked pieces: shouldn't change outcome
-        piece_picker.received_piece(2);
+        piece_picker.received_piece(picked[0]);
         assert_eq!(piece_picker.free_count, 10);
 
         // pick rest of the pieces
         for _ in 0..10 {
-            assert!(piece_picker.pick_piece().is_some());
+            assert!(piece_picker.pick_piece(&available_pieces).is_some());
         }
         assert!(piece_picker.all_pieces_picked());
     }
 
+    /// Tests that a piece marked as pending is not picked, and that it is
+    /// only counted once towards the picked pieces.
+    #[test]
+    fn should_not_pick_pending_piece() {
+        let piece_count = 3;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let available_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&available_pieces);
+
+        piece_picker.mark_pending(1);
+        piece_picker.mark_pending(1);
+        assert_eq!(piece_picker.free_count, 2);
+
+        let picked: HashSet<_> = (0..2)
+            .map(|_| piece_picker.pick_piece(&available_pieces).unwrap())
+            .collect();
+        assert_eq!(picked, [0, 2].iter().copied().collect());
+        assert_eq!(piece_picker.pick_piece(&available_pieces), None);
+        assert!(piece_picker.all_pieces_picked());
+    }
+
+    /// Tests that pieces with a higher priority are picked first and that
+    /// pieces with priority 0 are not picked at all.
+    #[test]
+    fn should_pick_pieces_by_priority() {
+        let piece_count = 6;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let available_pieces = Bitfield::repeat(true, piece_count);
+        piece_picker.register_peer_pieces(&available_pieces);
+
+        piece_picker.set_piece_priorities(&[0, 1, 7, 4, 0, 7]);
+        piece_picker.assert_consistent();
+        assert_eq!(piece_picker.missing_piece_count(), 4);
+        assert_eq!(piece_picker.free_count, 4);
+
+        let picked: HashSet<_> = (0..2)
+            .map(|_| piece_picker.pick_piece(&available_pieces).unwrap())
+            .collect();
+        assert_eq!(picked, [2, 5].iter().copied().collect());
+        assert_eq!(piece_picker.pick_piece(&available_pieces), Some(3));
+        assert_eq!(piece_picker.pick_piece(&available_pieces), Some(1));
+        assert_eq!(piece_picker.pick_piece(&available_pieces), None);
+        assert!(piece_picker.all_pieces_picked());
+        piece_picker.assert_consistent();
+    }
+
+    /// Tests that in sequential mode pieces are picked in order of their index,
+    /// still respecting their priority.
+    #[test]
+    fn should_pick_pieces_sequentially() {
+        let piece_count = 6;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut peer_pieces = Bitfield::repeat(true, piece_count);
+        peer_pieces.set(1, false);
+        piece_picker.register_peer_pieces(&peer_pieces);
+        piece_picker.set_sequential(true);
+        piece_picker.set_piece_priorities(&[4, 4, 4, 4, 7, 4]);
+
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(4));
+        for index in [0, 2, 3, 5].iter() {
+            assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(*index));
+        }
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), None);
+        piece_picker.assert_consistent();
+    }
+
+    /// Tests that pieces with a deadline are picked in order of their
+    /// deadline, and only if they can be picked.
+    #[test]
+    fn should_pick_deadline_pieces_by_deadline() {
+        let piece_count = 6;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut peer_pieces = Bitfield::repeat(true, piece_count);
+        peer_pieces.set(3, false);
+        piece_picker.register_peer_pieces(&peer_pieces);
+
+        let now = Instant::now();
+        let second = std::time::Duration::from_secs(1);
+        piece_picker.set_piece_deadline(2, now + 3 * second);
+        piece_picker.set_piece_deadline(3, now);
+        piece_picker.set_piece_deadline(5, now + 2 * second);
+        piece_picker.set_piece_deadline(0, now + 4 * second);
+        // the new deadline replaces the old one
+        piece_picker.set_piece_deadline(0, now + second);
+        assert_eq!(piece_picker.deadlines().count(), 4);
+
+        // piece 3 is not available and piece 0 is already being downloaded
+        piece_picker.mark_pending(0);
+        assert_eq!(piece_picker.pick_deadline_piece(&peer_pieces), Some(5));
+        assert_eq!(piece_picker.pick_deadline_piece(&peer_pieces), Some(2));
+        assert_eq!(piece_picker.pick_deadline_piece(&peer_pieces), None);
+        piece_picker.assert_consistent();
+
+        assert_eq!(piece_picker.reset_piece_deadline(0), Some(now + second));
+        assert_eq!(piece_picker.reset_piece_deadline(0), None);
+        assert_eq!(piece_picker.deadlines().count(), 3);
+    }
+
+    /// Tests that changing priorities updates which pieces are missing and
+    /// whether we are interested in a peer.
+    #[test]
+    fn should_update_missing_pieces_and_interest_on_priority_change() {
+        let piece_count = 4;
+        let mut piece_picker = PiecePicker::empty(piece_count);
+        let mut peer_pieces = Bitfield::repeat(false, piece_count);
+        peer_pieces.set(3, true);
+        assert!(piece_picker.register_peer_pieces(&peer_pieces));
+
+        piece_picker.received_piece(0);
+        piece_picker.mark_pending(1);
+        piece_picker.set_piece_priorities(&[0, 0, 4, 0]);
+        piece_picker.assert_consistent();
+        // the pending piece is no longer counted, but the piece we have
+        // doesn't affect the count
+        assert_eq!(piece_picker.missing_piece_count(), 1);
+        assert_eq!(piece_picker.missing_pieces().collect::<Vec<_>>(), [2]);
+        assert!(!piece_picker.is_interested(&peer_pieces));
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), None);
+
+        // receiving a piece that was already downloading doesn't count either
+        piece_picker.received_piece(1);
+        assert_eq!(piece_picker.missing_piece_count(), 1);
+
+        piece_picker.set_piece_priorities(&[1, 1, 1, 1]);
+        piece_picker.assert_consistent();
+        assert_eq!(piece_picker.missing_piece_count(), 2);
+        assert_eq!(piece_picker.missing_pieces().collect::<Vec<_>>(), [2, 3]);
+        assert!(piece_picker.is_interested(&peer_pieces));
+        assert!(piece_picker.register_peer_piece(2));
+        assert_eq!(piece_picker.pick_piece(&peer_pieces), Some(3));
+        piece_picker.assert_consistent();
+    }
+
     /// Tests that the piece picker correctly determines whether we are
     /// interested in a variety of piece sets.
     // TODO: break this up into smaller tests
@@ -366,5 +1018,27 @@ mod tests {
         fn empty(piece_count: usize) -> Self {
             Self::new(Bitfield::repeat(false, piece_count))
         }
+
+        /// Asserts that the queues hold exactly the free pieces, ordered by
+        /// frequency, and that the bucket starts and positions match them.
+        fn assert_consistent(&self) {
+            let mut queued_count = 0;
+            for (priority, queue) in self.queues.iter().enumerate() {
+                for (position, index) in queue.pieces.iter().enumerate() {
+                    let piece = &self.pieces[*index];
+                    assert_eq!(piece.position, Some(position));
+                    assert_eq!(piece.priority as usize, priority);
+                    assert!(self.wants(*index) && !piece.is_pending);
+                    assert!(queue.bucket_starts[piece.frequency] <= position);
+                    assert!(position < queue.bucket_end(piece.frequency));
+                }
+                queued_count += queue.pieces.len();
+            }
+            assert_eq!(queued_count, self.free_count);
+            assert_eq!(
+                self.pieces.iter().filter(|p| p.position.is_some()).count(),
+                queued_count
+            );
+        }
     }
 }
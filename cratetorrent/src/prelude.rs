@@ -6,7 +6,7 @@ pub use crate::{
     engine::{self, EngineHandle, Mode, TorrentParams},
     error::Error,
     metainfo::Metainfo,
-    TorrentId,
+    Priority, TorrentId,
 };
 // this is needed for `AlertReceiver::next`
 pub use futures::stream::StreamExt;
@@ -0,0 +1,275 @@
+//! This module contains the resume data of a torrent, which allows restarting
+//! a torrent from where it left off in a previous run of the engine.
+//!
+//! Resume data is requested from a torrent via
+//! [`EngineHandle::save_resume_data`](crate::engine::EngineHandle::save_resume_data)
+//! and is returned asynchronously in an
+//! [`Alert::ResumeData`](crate::alert::Alert::ResumeData). It can be serialized
+//! with [`ResumeData::to_bytes`] and persisted by the application in whatever
+//! way it sees fit. On the next start, it may be parsed with
+//! [`ResumeData::from_bytes`] and passed to the engine in
+//! [`TorrentParams::resume_data`](crate::engine::TorrentParams::resume_data).
+//!
+//! When a torrent is created with resume data, the engine checks that the
+//! torrent's files on disk match the sizes and modification times recorded in
+//! the resume data. If they don't, the files were changed since the resume
+//! data was saved, so it is not used.
+
+use std::{fs, path::Path, time::UNIX_EPOCH};
+
+use crate::{
+    block_len, storage_info::StorageInfo, Bitfield, PieceIndex, Priority,
+    Sha1Hash, BLOCK_LEN,
+};
+
+pub use serde_bencode::Error as BencodeError;
+
+/// The state of a torrent needed to continue it after an engine restart.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResumeData {
+    /// The info hash of the torrent to which the resume data belongs.
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Sha1Hash,
+    /// The number of pieces in the torrent.
+    pub piece_count: usize,
+    /// The pieces that we have downloaded and verified, encoded the same way
+    /// as in the peer protocol's bitfield message: the highest bit of the first
+    /// byte is the first piece, and spare bits at the end are zero.
+    #[serde(with = "serde_bytes")]
+    pub own_pieces: Vec<u8>,
+    /// The pieces whose download was in progress when the resume data was
+    /// saved.
+    ///
+    /// Blocks are only written to disk once their piece is complete and
+    /// verified, so the downloaded blocks of these pieces are included here.
+    pub partial_pieces: Vec<PartialPiece>,
+    /// The state of the torrent's files on disk, in the same order as in the
+    /// metainfo.
+    pub files: Vec<FileResumeData>,
+    /// The download priority of each of the torrent's files, in the same order
+    /// as in the metainfo.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub file_priorities: Vec<Priority>,
+    /// The trackers of the torrent and the tracker id they sent us, if any.
+    pub trackers: Vec<TrackerResumeData>,
+    /// The total number of payload bytes uploaded to peers.
+    pub uploaded: u64,
+    /// The total number of payload bytes downloaded from peers.
+    pub downloaded: u64,
+}
+
+/// A piece that was partially downloaded.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    /// The piece's index.
+    pub index: PieceIndex,
+    /// The blocks of the piece that were downloaded, ordered by their offset.
+    pub blocks: Vec<PartialBlock>,
+}
+
+/// A block downloaded as part of a partial piece.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PartialBlock {
+    /// The block's byte offset in piece.
+    pub offset: u32,
+    /// The block's data.
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// The state of a file on disk at the time the resume data was saved.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileResumeData {
+    /// The length of the file on disk. This is not necessarily the file's
+    /// length in the torrent, as files are allocated lazily.
+    pub len: u64,
+    /// The last modification time of the file, in seconds since the UNIX
+    /// epoch, if available.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+}
+
+/// Tracker related resume data.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerResumeData {
+    /// The tracker's URL.
+    pub url: String,
+    /// The tracker id that the tracker sent in a previous announce, which
+    /// needs to be included in further announces.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+}
+
+impl ResumeData {
+    /// Parses the bencoded resume data from a byte buffer.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, BencodeError> {
+        serde_bencode::from_bytes(buf)
+    }
+
+    /// Serializes the resume data as bencode.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, BencodeError> {
+        serde_bencode::to_bytes(self)
+    }
+
+    /// Returns the pieces we have as a bitfield, or none if the encoded
+    /// bitfield doesn't match the piece count.
+    pub(crate) fn own_pieces(&self) -> Option<Bitfield> {
+        if self.own_pieces.len() != self.piece_count.div_ceil(8) {
+            return None;
+        }
+        let mut own_pieces = Bitfield::from_vec(self.own_pieces.clone());
+        own_pieces.resize(self.piece_count, false);
+        Some(own_pieces)
+    }
+
+    /// Checks that the resume data belongs to the torrent with the given info
+    /// hash and storage, and that the torrent's files on disk have not changed
+    /// since the resume data was saved.
+    pub(crate) fn is_valid(
+        &self,
+        info_hash: &Sha1Hash,
+        storage: &StorageInfo,
+    ) -> bool {
+        if &self.info_hash != info_hash {
+            log::warn!("Resume data info hash mismatch");
+            return false;
+        }
+        if self.piece_count != storage.piece_count
+            || self.own_pieces().is_none()
+        {
+            log::warn!("Resume data piece count mismatch");
+            return false;
+        }
+        if self.files.len() != storage.files.len() {
+            log::warn!("Resume data file count mismatch");
+            return false;
+        }
+
+        for (file, info) in self.files.iter().zip(storage.files.iter()) {
+            let path = storage.download_dir.join(&info.path);
+            let actual = FileResumeData::new(&path);
+            if actual.len != file.len
+                || (file.mtime.is_some() && actual.mtime != file.mtime)
+            {
+                log::warn!(
+                    "Torrent file {:?} changed since resume data was saved",
+                    path
+                );
+                return false;
+            }
+        }
+
+        // the blocks of partial pieces must be valid as they are written to
+        // disk as is
+        for piece in self.partial_pieces.iter() {
+            if piece.index >= self.piece_count {
+                log::warn!("Resume data partial piece {} invalid", piece.index);
+                return false;
+            }
+            let piece_len = storage.piece_len(piece.index);
+            for block in piece.blocks.iter() {
+                let index_in_piece = (block.offset / BLOCK_LEN) as usize;
+                if block.offset % BLOCK_LEN != 0
+                    || block.offset >= piece_len
+                    || block.data.len() as u32
+                        != block_len(piece_len, index_in_piece)
+                {
+                    log::warn!(
+                        "Resume data piece {} block at offset {} invalid",
+                        piece.index,
+                        block.offset
+                    );
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+impl FileResumeData {
+    /// Reads the current state of the file at the given path.
+    ///
+    /// If the file does not exist, its length is reported as 0.
+    pub(crate) fn new(path: &Path) -> Self {
+        match fs::metadata(path) {
+            Ok(metadata) => Self {
+                len: metadata.len(),
+                mtime: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+            },
+            Err(e) => {
+                log::debug!("Cannot read file {:?} metadata: {}", path, e);
+                Self {
+                    len: 0,
+                    mtime: None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that resume data survives a serialization roundtrip.
+    #[test]
+    fn should_serialize_and_parse_resume_data() {
+        let resume_data = ResumeData {
+            info_hash: [7; 20],
+            piece_count: 10,
+            own_pieces: vec![0b1010_0000, 0b0100_0000],
+            partial_pieces: vec![PartialPiece {
+                index: 3,
+                blocks: vec![PartialBlock {
+                    offset: 0x4000,
+                    data: vec![1, 2, 3],
+                }],
+            }],
+            files: vec![
+                FileResumeData {
+                    len: 1234,
+                    mtime: Some(1_600_000_000),
+                },
+                FileResumeData {
+                    len: 0,
+                    mtime: None,
+                },
+            ],
+            file_priorities: vec![0, 4],
+            trackers: vec![TrackerResumeData {
+                url: "http://tracker.example.com/announce".into(),
+                tracker_id: Some("abc".into()),
+            }],
+            uploaded: 42,
+            downloaded: 4242,
+        };
+
+        let buf = resume_data.to_bytes().unwrap();
+        let parsed = ResumeData::from_bytes(&buf).unwrap();
+        assert_eq!(parsed, resume_data);
+
+        let own_pieces = parsed.own_pieces().unwrap();
+        assert_eq!(own_pieces.len(), 10);
+        assert_eq!(own_pieces.count_ones(), 3);
+        assert!(own_pieces[0]);
+        assert!(own_pieces[2]);
+        assert!(own_pieces[9]);
+    }
+
+    /// Tests that a bitfield that doesn't match the piece count is rejected.
+    #[test]
+    fn should_reject_invalid_own_pieces() {
+        let resume_data = ResumeData {
+            piece_count: 17,
+            own_pieces: vec![0xff, 0xff],
+            ..Default::default()
+        };
+        assert!(resume_data.own_pieces().is_none());
+    }
+}
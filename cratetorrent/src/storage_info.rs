@@ -1,6 +1,6 @@
 use std::{ops::Range, path::PathBuf};
 
-use crate::{metainfo::Metainfo, FileIndex, PieceIndex};
+use crate::{metainfo::Metainfo, FileIndex, PieceIndex, Priority};
 
 /// Information about a torrent's file.
 #[derive(Clone, Debug)]
@@ -127,6 +127,13 @@ impl StorageInfo {
         }
     }
 
+    /// Returns true if the torrent is an archive, in which case its files are
+    /// downloaded into the torrent's own directory (see
+    /// [`Self::download_dir`]).
+    pub fn is_archive(&self) -> bool {
+        self.files.len() > 1
+    }
+
     /// Returns the zero-based indices of the files of torrent that intersect
     /// with the piece.
     ///
@@ -206,6 +213,40 @@ impl StorageInfo {
         }
     }
 
+    /// Returns the priority of each piece given the priority of each file,
+    /// which is the highest priority of the files the piece overlaps with.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of priorities is different from the number of
+    /// files.
+    pub fn piece_priorities(
+        &self,
+        file_priorities: &[Priority],
+    ) -> Vec<Priority> {
+        assert_eq!(
+            file_priorities.len(),
+            self.files.len(),
+            "there must be a priority for each file"
+        );
+        let mut priorities = vec![0; self.piece_count];
+        for (file, priority) in self.files.iter().zip(file_priorities) {
+            // empty files don't overlap with any piece
+            if file.len == 0 {
+                continue;
+            }
+            let first_piece = file.torrent_offset / self.piece_len as u64;
+            let last_piece =
+                (file.torrent_end_offset() - 1) / self.piece_len as u64;
+            for piece_priority in
+                &mut priorities[first_piece as usize..=last_piece as usize]
+            {
+                *piece_priority = (*piece_priority).max(*priority);
+            }
+        }
+        priorities
+    }
+
     /// Returns the piece's absolute offset in the torrent.
     pub fn torrent_piece_offset(&self, index: PieceIndex) -> u64 {
         index as u64 * self.piece_len as u64
@@ -414,6 +455,47 @@ mod tests {
         assert_eq!(info.files_intersecting_piece(4), 6..7);
     }
 
+    #[test]
+    fn test_piece_priorities() {
+        // pieces: (index:first byte offset)
+        // ----------------------------------
+        // |0:0     |1:8     |2:16    |3:24 |
+        // ----------------------------------
+        // files: (index:first byte offset,last byte offset)
+        // ----------------------------------
+        // |0:0,9     |2:10,21       |3:22,29|
+        // ----------------------------------
+        // file 1 is empty
+        let lens = [10, 0, 12, 8];
+        let mut torrent_offset = 0;
+        let files = lens
+            .iter()
+            .enumerate()
+            .map(|(index, len)| {
+                let file = FileInfo {
+                    path: PathBuf::from(format!("/{}", index)),
+                    torrent_offset,
+                    len: *len,
+                };
+                torrent_offset += len;
+                file
+            })
+            .collect();
+        let info = StorageInfo {
+            piece_count: 4,
+            piece_len: 8,
+            last_piece_len: 6,
+            download_len: 30,
+            download_dir: PathBuf::from("/"),
+            files,
+        };
+
+        assert_eq!(info.piece_priorities(&[0, 7, 0, 0]), vec![0, 0, 0, 0]);
+        assert_eq!(info.piece_priorities(&[1, 0, 0, 0]), vec![1, 1, 0, 0]);
+        assert_eq!(info.piece_priorities(&[0, 0, 4, 0]), vec![0, 4, 4, 0]);
+        assert_eq!(info.piece_priorities(&[1, 0, 4, 7]), vec![1, 4, 7, 7]);
+    }
+
     #[test]
     fn test_files_intersecting_bytes() {
         let download_len = 12341234;
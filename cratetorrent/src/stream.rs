@@ -0,0 +1,485 @@
+//! Reading a torrent's files while they are downloaded.
+//!
+//! A [`FileStream`], opened with
+//! [`EngineHandle::open_file`](crate::engine::EngineHandle::open_file),
+//! implements [`AsyncRead`] and [`AsyncSeek`] over one of a torrent's files, so
+//! that e.g. a media player can play the file, or an HTTP server can serve it,
+//! before the download is complete.
+//!
+//! A read waits until the piece that contains the read position is downloaded
+//! and verified. The pieces after it are given deadlines so that they are
+//! downloaded in time for the next reads, see
+//! [`EngineHandle::set_piece_deadline`](crate::engine::EngineHandle::set_piece_deadline).
+//! The bytes themselves are read via the disk task, and thus usually from its
+//! read cache. The deadlines are removed again when the stream seeks elsewhere
+//! or is dropped, so that pieces no one reads anymore aren't rushed.
+
+use std::{
+    fmt,
+    io::{self, SeekFrom},
+    ops::Range,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    ready,
+};
+use tokio::{
+    io::{AsyncRead, AsyncSeek},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    block_len, disk, peer, storage_info::StorageInfo, torrent, BlockData,
+    BlockInfo, FileInfo, PieceIndex, TorrentId, BLOCK_LEN,
+};
+
+/// The number of pieces, including the one at the read position, that are
+/// given a deadline with each read of a new piece.
+const READ_AHEAD_PIECE_COUNT: usize = 4;
+
+/// A handle to one of a torrent's files that reads the file as its pieces are
+/// downloaded.
+///
+/// Reading past the pieces we have waits for the pieces to be downloaded.
+/// A read fails if the torrent is stopped in the meantime, or if the file
+/// could not be read from disk.
+pub struct FileStream {
+    id: TorrentId,
+    torrent_tx: torrent::Sender,
+    disk_tx: disk::Sender,
+    storage: StorageInfo,
+    /// The file that is read.
+    file: FileInfo,
+    /// The position of the next read, relative to the start of the file.
+    pos: u64,
+    /// The piece of the last block that was read, which we know we have, so
+    /// that its other blocks can be read without waiting for it again.
+    available_piece: Option<PieceIndex>,
+    /// The last block that was read, with its offset in the torrent.
+    block: Option<(u64, BlockData)>,
+    /// The pieces that were last given a deadline for this stream, which are
+    /// reset when the stream seeks or is dropped.
+    read_ahead: Option<Range<PieceIndex>>,
+    /// The in-progress read of the block at the read position, which returns
+    /// the block's offset in the torrent and its data.
+    read: Option<BoxFuture<'static, io::Result<(u64, BlockData)>>>,
+}
+
+impl FileStream {
+    /// Creates a stream of the file with the given index in the torrent.
+    pub(crate) fn new(
+        id: TorrentId,
+        torrent_tx: torrent::Sender,
+        disk_tx: disk::Sender,
+        storage: StorageInfo,
+        file_index: usize,
+    ) -> Self {
+        let file = storage.files[file_index].clone();
+        Self {
+            id,
+            torrent_tx,
+            disk_tx,
+            storage,
+            file,
+            pos: 0,
+            available_piece: None,
+            block: None,
+            read_ahead: None,
+            read: None,
+        }
+    }
+
+    /// Returns the length of the file.
+    pub fn len(&self) -> u64 {
+        self.file.len
+    }
+
+    /// Returns whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.file.len == 0
+    }
+
+    /// Returns the bytes of the last block read that are at the read position,
+    /// if the block contains the read position, up to the end of the file.
+    fn buffered(&self) -> Option<&[u8]> {
+        let torrent_offset = self.file.torrent_offset + self.pos;
+        let (block_offset, data) = self.block.as_ref()?;
+        if torrent_offset < *block_offset
+            || torrent_offset >= block_offset + data.len() as u64
+        {
+            return None;
+        }
+        let start = (torrent_offset - block_offset) as usize;
+        let end = (self.file.torrent_end_offset() - block_offset)
+            .min(data.len() as u64) as usize;
+        Some(&data[start..end])
+    }
+
+    /// Returns the future that reads the block at the read position, which
+    /// first waits for the block's piece, unless we know we have it.
+    fn read_block(
+        &mut self,
+    ) -> BoxFuture<'static, io::Result<(u64, BlockData)>> {
+        let torrent_offset = self.file.torrent_offset + self.pos;
+        let piece_len = self.storage.piece_len as u64;
+        let piece_index = (torrent_offset / piece_len) as PieceIndex;
+        let piece_offset = (torrent_offset % piece_len) as u32;
+        let block_index = (piece_offset / BLOCK_LEN) as usize;
+        let block_info = BlockInfo {
+            piece_index,
+            offset: block_index as u32 * BLOCK_LEN,
+            len: block_len(self.storage.piece_len(piece_index), block_index),
+        };
+        let block_offset = self.storage.torrent_piece_offset(piece_index)
+            + block_info.offset as u64;
+
+        // the read ahead doesn't go past the file
+        let last_piece =
+            ((self.file.torrent_end_offset() - 1) / piece_len) as PieceIndex;
+        let pieces = piece_index
+            ..(piece_index + READ_AHEAD_PIECE_COUNT).min(last_piece + 1);
+        let wait = self.available_piece != Some(piece_index);
+        if wait {
+            self.read_ahead = Some(pieces.clone());
+        }
+
+        let id = self.id;
+        let torrent_tx = self.torrent_tx.clone();
+        let disk_tx = self.disk_tx.clone();
+        async move {
+            if wait {
+                log::debug!("Stream waiting for pieces {:?}", pieces);
+                let (piece_tx, piece_rx) = oneshot::channel();
+                torrent_tx
+                    .send(torrent::Command::StreamPieces { pieces, piece_tx })
+                    .map_err(|_| torrent_stopped())?;
+                piece_rx.await.map_err(|_| torrent_stopped())?;
+            }
+
+            // the disk task returns the block via the same channel as it does
+            // to peer sessions, and drops the sender if the read fails
+            let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+            disk_tx
+                .send(disk::Command::ReadBlock {
+                    id,
+                    block_info,
+                    result_tx,
+                })
+                .map_err(|_| torrent_stopped())?;
+            match result_rx.recv().await {
+                Some(peer::Command::Block(block)) => {
+                    Ok((block_offset, block.data))
+                }
+                _ => Err(io::Error::other(format!(
+                    "could not read block {}",
+                    block_info
+                ))),
+            }
+        }
+        .boxed()
+    }
+
+    /// Removes the deadlines of the pieces that were last given one for this
+    /// stream, if any.
+    fn reset_read_ahead(&mut self) {
+        if let Some(pieces) = self.read_ahead.take() {
+            log::debug!("Stream resetting deadlines of pieces {:?}", pieces);
+            // the torrent may have stopped in the meantime
+            self.torrent_tx
+                .send(torrent::Command::ResetStreamPieces(pieces))
+                .ok();
+        }
+    }
+}
+
+impl AsyncRead for FileStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() || self.pos >= self.file.len {
+            return Poll::Ready(Ok(0));
+        }
+
+        loop {
+            if let Some(data) = self.buffered() {
+                let count = data.len().min(buf.len());
+                buf[..count].copy_from_slice(&data[..count]);
+                self.pos += count as u64;
+                return Poll::Ready(Ok(count));
+            }
+
+            if self.read.is_none() {
+                self.read = Some(self.read_block());
+            }
+            let result = ready!(self.read.as_mut().unwrap().poll_unpin(cx));
+            self.read = None;
+            let (block_offset, data) = result?;
+            self.available_piece = Some(
+                (block_offset / self.storage.piece_len as u64) as PieceIndex,
+            );
+            self.block = Some((block_offset, data));
+        }
+    }
+}
+
+impl AsyncSeek for FileStream {
+    fn start_seek(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<io::Result<()>> {
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => checked_add(self.file.len, offset),
+            SeekFrom::Current(offset) => checked_add(self.pos, offset),
+        };
+        match pos {
+            Some(pos) => {
+                // a read in progress is for the old position, and so are the
+                // deadlines of the pieces after it, which are set again by the
+                // next read
+                self.read = None;
+                self.reset_read_ahead();
+                self.available_piece = None;
+                self.pos = pos;
+                Poll::Ready(Ok(()))
+            }
+            None => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+
+    fn poll_complete(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.reset_read_ahead();
+    }
+}
+
+impl fmt::Debug for FileStream {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("FileStream")
+            .field("id", &self.id)
+            .field("path", &self.file.path)
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+fn checked_add(pos: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        pos.checked_add(offset as u64)
+    } else {
+        pos.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+fn torrent_stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "torrent stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::*;
+    use crate::Block;
+
+    /// The byte at the offset in the torrent of the test streams.
+    fn byte(torrent_offset: u64) -> u8 {
+        (torrent_offset % 251) as u8
+    }
+
+    /// Returns a stream of the file with the given index, and spawns tasks
+    /// that serve its pieces and blocks, returning the pieces the stream
+    /// waited for and the pieces whose deadlines it reset.
+    fn spawn_stream(
+        file_index: usize,
+    ) -> (
+        FileStream,
+        mpsc::UnboundedReceiver<PieceIndex>,
+        mpsc::UnboundedReceiver<Range<PieceIndex>>,
+    ) {
+        // 3 files over 5 pieces, with the middle one spanning the pieces 1-3
+        let piece_len = 2 * BLOCK_LEN;
+        let file_lens = [3 * BLOCK_LEN as u64, 0, 6 * BLOCK_LEN as u64 + 100];
+        let mut torrent_offset = 0;
+        let files: Vec<_> = file_lens
+            .iter()
+            .copied()
+            .enumerate()
+            .map(|(i, len)| {
+                let file = FileInfo {
+                    path: PathBuf::from(i.to_string()),
+                    len,
+                    torrent_offset,
+                };
+                torrent_offset += len;
+                file
+            })
+            .collect();
+        let download_len = torrent_offset;
+        let piece_count = download_len.div_ceil(piece_len as u64) as usize;
+        let storage = StorageInfo {
+            piece_count,
+            piece_len,
+            last_piece_len: (download_len
+                - (piece_count as u64 - 1) * piece_len as u64)
+                as u32,
+            download_len,
+            download_dir: PathBuf::from("/tmp"),
+            files,
+        };
+
+        let (torrent_tx, mut torrent_rx) = mpsc::unbounded_channel();
+        let (waits_tx, waits_rx) = mpsc::unbounded_channel();
+        let (resets_tx, resets_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(cmd) = torrent_rx.recv().await {
+                match cmd {
+                    torrent::Command::StreamPieces { pieces, piece_tx } => {
+                        waits_tx.send(pieces.start).unwrap();
+                        piece_tx.send(()).unwrap();
+                    }
+                    torrent::Command::ResetStreamPieces(pieces) => {
+                        resets_tx.send(pieces).unwrap();
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        let (disk_tx, mut disk_rx) = mpsc::unbounded_channel();
+        let piece_len = storage.piece_len;
+        tokio::spawn(async move {
+            while let Some(cmd) = disk_rx.recv().await {
+                if let disk::Command::ReadBlock {
+                    block_info,
+                    result_tx,
+                    ..
+                } = cmd
+                {
+                    let start = block_info.piece_index as u64
+                        * piece_len as u64
+                        + block_info.offset as u64;
+                    let data: Vec<_> = (start..start + block_info.len as u64)
+                        .map(byte)
+                        .collect();
+                    result_tx
+                        .send(peer::Command::Block(Block::new(
+                            block_info,
+                            Arc::new(data),
+                        )))
+                        .ok();
+                }
+            }
+        });
+
+        let stream = FileStream::new(
+            TorrentId::new(),
+            torrent_tx,
+            disk_tx,
+            storage,
+            file_index,
+        );
+        (stream, waits_rx, resets_rx)
+    }
+
+    /// Tests that a file spanning multiple pieces is read in full, waiting for
+    /// each piece once.
+    #[tokio::test]
+    async fn should_read_file() {
+        let (mut stream, mut waits_rx, _resets_rx) = spawn_stream(2);
+        let torrent_offset = stream.file.torrent_offset;
+
+        let mut data = Vec::new();
+        stream.read_to_end(&mut data).await.unwrap();
+        let expected: Vec<_> = (torrent_offset..torrent_offset + stream.len())
+            .map(byte)
+            .collect();
+        assert_eq!(data, expected);
+
+        drop(stream);
+        let mut waits = Vec::new();
+        while let Some(index) = waits_rx.recv().await {
+            waits.push(index);
+        }
+        assert_eq!(waits, vec![1, 2, 3, 4]);
+    }
+
+    /// Tests that reads continue from the position sought to.
+    #[tokio::test]
+    async fn should_seek() {
+        let (mut stream, _waits_rx, _resets_rx) = spawn_stream(2);
+        let torrent_offset = stream.file.torrent_offset;
+        let mut buf = [0; 10];
+
+        let pos = stream.seek(SeekFrom::End(-5)).await.unwrap();
+        assert_eq!(pos, stream.len() - 5);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 5);
+        let start = torrent_offset + pos;
+        let expected: Vec<_> = (start..start + 5).map(byte).collect();
+        assert_eq!(&buf[..5], &expected[..]);
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+
+        let pos = stream
+            .seek(SeekFrom::Start(BLOCK_LEN as u64 - 2))
+            .await
+            .unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        let start = torrent_offset + pos;
+        let expected: Vec<_> = (start..start + 10).map(byte).collect();
+        assert_eq!(&buf[..], &expected[..]);
+
+        assert!(stream.seek(SeekFrom::Current(-100_000)).await.is_err());
+    }
+
+    /// Tests that an empty file is read without waiting for any pieces.
+    #[tokio::test]
+    async fn should_read_empty_file() {
+        let (mut stream, _waits_rx, _resets_rx) = spawn_stream(1);
+        let mut data = Vec::new();
+        assert_eq!(stream.read_to_end(&mut data).await.unwrap(), 0);
+    }
+
+    /// Tests that the deadlines of the pieces read ahead are reset when the
+    /// stream seeks elsewhere and when it's dropped.
+    #[tokio::test]
+    async fn should_reset_read_ahead_on_seek_and_drop() {
+        let (mut stream, mut waits_rx, mut resets_rx) = spawn_stream(2);
+        let mut buf = [0; 10];
+
+        // seeking before any read has nothing to reset
+        stream.seek(SeekFrom::Start(0)).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(waits_rx.recv().await, Some(1));
+
+        // the seek resets the read ahead from the first piece, and the next
+        // read waits for the piece at the new position even though it was
+        // read ahead
+        stream.seek(SeekFrom::End(-5)).await.unwrap();
+        assert_eq!(resets_rx.recv().await, Some(1..5));
+        stream.read_exact(&mut buf[..5]).await.unwrap();
+        assert_eq!(waits_rx.recv().await, Some(4));
+
+        drop(stream);
+        assert_eq!(resets_rx.recv().await, Some(4..5));
+        assert_eq!(resets_rx.recv().await, None);
+    }
+}
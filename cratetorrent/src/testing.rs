@@ -0,0 +1,1092 @@
+//! Support for testing the engine in a swarm of peers, all in one process.
+//!
+//! This module is compiled with the `test-support` feature, and for the
+//! crate's own tests. It starts
+//! several engines listening on loopback, along with a [mock HTTP
+//! tracker](MockTracker) through which they find each other, so that scenarios
+//! like downloading from multiple seeds can be tested with `cargo test`,
+//! without Docker or other torrent clients.
+//!
+//! The torrents' contents are random [payloads](Payload), from which the
+//! matching metainfo is created, and which are compared to the downloaded
+//! files at the end of the test:
+//!
+//! ```ignore
+//! let mut swarm = Swarm::new("single_seed").await?;
+//! let payload = Payload::random_file("file.bin", 1024 * 1024);
+//! let metainfo = payload.metainfo(32 * 1024, &[swarm.tracker().url()]);
+//!
+//! let seed = swarm.spawn_peer()?;
+//! seed.seed(&payload, &metainfo)?;
+//! let mut leech = swarm.spawn_peer()?;
+//! let id = leech.download(&metainfo)?;
+//! assert!(leech.wait_for_completion(id, Duration::from_secs(60)).await);
+//! payload.assert_downloaded(leech.download_dir());
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{
+    future::{AbortHandle, Abortable},
+    StreamExt,
+};
+use percent_encoding::percent_decode_str;
+use rand::Rng;
+use reqwest::Url;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+
+use crate::{
+    alert::{Alert, AlertReceiver},
+    compact,
+    conf::Conf,
+    engine::{self, EngineHandle, Mode, TorrentParams},
+    error::Result,
+    metainfo::Metainfo,
+    torrent::stats::TorrentStats,
+    Sha1Hash, TorrentId,
+};
+
+/// The announce interval the mock tracker sends, which is short so that
+/// peers that start later are found quickly.
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The contents of a torrent, made up of random bytes.
+#[derive(Clone)]
+pub struct Payload {
+    /// The name of the torrent.
+    name: String,
+    /// Whether the torrent is a directory, in which case its files are
+    /// listed separately in the metainfo.
+    is_dir: bool,
+    /// The path of each file in the torrent and its contents.
+    files: Vec<(PathBuf, Vec<u8>)>,
+}
+
+impl Payload {
+    /// Creates a single file torrent with random contents.
+    pub fn random_file(name: &str, len: usize) -> Self {
+        Self {
+            name: name.into(),
+            is_dir: false,
+            files: vec![(name.into(), random_bytes(len))],
+        }
+    }
+
+    /// Creates a directory torrent with random contents, made up of files of
+    /// the given paths and lengths. The paths are relative to the torrent's
+    /// directory and may contain subdirectories.
+    pub fn random_dir(name: &str, files: &[(&str, usize)]) -> Self {
+        Self {
+            name: name.into(),
+            is_dir: true,
+            files: files
+                .iter()
+                .map(|(path, len)| (path.into(), random_bytes(*len)))
+                .collect(),
+        }
+    }
+
+    /// Returns the name of the torrent.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the length of all files in the torrent.
+    pub fn download_len(&self) -> u64 {
+        self.files.iter().map(|(_, data)| data.len() as u64).sum()
+    }
+
+    /// Creates the metainfo of the torrent, with the trackers in a single
+    /// tier.
+    pub fn metainfo(&self, piece_len: u32, trackers: &[Url]) -> Metainfo {
+        let data: Vec<u8> = self
+            .files
+            .iter()
+            .flat_map(|(_, data)| data.iter().copied())
+            .collect();
+        let pieces: Vec<u8> = data
+            .chunks(piece_len as usize)
+            .flat_map(Sha1::digest)
+            .collect();
+
+        // the keys of a bencoded dictionary are sorted
+        let mut info = b"d".to_vec();
+        if self.is_dir {
+            encode_str(b"files", &mut info);
+            info.push(b'l');
+            for (path, data) in self.files.iter() {
+                info.push(b'd');
+                encode_str(b"length", &mut info);
+                encode_int(data.len() as u64, &mut info);
+                encode_str(b"path", &mut info);
+                info.push(b'l');
+                for component in path.iter() {
+                    encode_str(
+                        component.to_string_lossy().as_bytes(),
+                        &mut info,
+                    );
+                }
+                info.extend_from_slice(b"ee");
+            }
+            info.push(b'e');
+        } else {
+            encode_str(b"length", &mut info);
+            encode_int(self.download_len(), &mut info);
+        }
+        encode_str(b"name", &mut info);
+        encode_str(self.name.as_bytes(), &mut info);
+        encode_str(b"piece length", &mut info);
+        encode_int(piece_len as u64, &mut info);
+        encode_str(b"pieces", &mut info);
+        encode_str(&pieces, &mut info);
+        info.push(b'e');
+
+        // the keys of a bencoded dictionary are sorted
+        let mut buf = b"d".to_vec();
+        if let Some(tracker) = trackers.first() {
+            encode_str(b"announce", &mut buf);
+            encode_str(tracker.as_str().as_bytes(), &mut buf);
+            encode_str(b"announce-list", &mut buf);
+            buf.extend_from_slice(b"ll");
+            for tracker in trackers {
+                encode_str(tracker.as_str().as_bytes(), &mut buf);
+            }
+            buf.extend_from_slice(b"ee");
+        }
+        encode_str(b"info", &mut buf);
+        buf.extend_from_slice(&info);
+        buf.push(b'e');
+        Metainfo::from_bytes(&buf).expect("invalid metainfo")
+    }
+
+    /// Writes the files of the torrent to the download directory, as a seed
+    /// expects to find them.
+    pub fn write(&self, download_dir: &Path) -> io::Result<()> {
+        for (path, data) in self.paths(download_dir) {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, data)?;
+        }
+        Ok(())
+    }
+
+    /// Asserts that the files of the torrent in the download directory match
+    /// the payload.
+    ///
+    /// # Panics
+    ///
+    /// This panics if a file is missing, or if its length or contents differ.
+    pub fn assert_downloaded(&self, download_dir: &Path) {
+        for (path, data) in self.paths(download_dir) {
+            let downloaded = fs::read(&path).unwrap_or_else(|e| {
+                panic!("cannot read downloaded file {:?}: {}", path, e)
+            });
+            assert_eq!(
+                downloaded.len(),
+                data.len(),
+                "downloaded file {:?} has wrong length",
+                path
+            );
+            if let Some(offset) =
+                downloaded.iter().zip(data).position(|(a, b)| a != b)
+            {
+                panic!(
+                    "downloaded file {:?} differs from payload at byte {}",
+                    path, offset
+                );
+            }
+        }
+    }
+
+    /// Returns the path of each file in the download directory, along with
+    /// its contents.
+    ///
+    /// Like the engine, only torrents with more than one file are placed in
+    /// their own directory.
+    fn paths<'a>(
+        &'a self,
+        download_dir: &Path,
+    ) -> impl Iterator<Item = (PathBuf, &'a [u8])> {
+        let root = if self.files.len() > 1 {
+            download_dir.join(&self.name)
+        } else {
+            download_dir.to_path_buf()
+        };
+        self.files
+            .iter()
+            .map(move |(path, data)| (root.join(path), data.as_slice()))
+    }
+}
+
+/// Returns a buffer of random bytes.
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    rand::thread_rng().fill(buf.as_mut_slice());
+    buf
+}
+
+/// Appends the bencoded string to the buffer.
+fn encode_str(s: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(s.len().to_string().as_bytes());
+    buf.push(b':');
+    buf.extend_from_slice(s);
+}
+
+/// Appends the bencoded integer to the buffer.
+fn encode_int(n: u64, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(format!("i{}e", n).as_bytes());
+}
+
+/// The peers of each torrent, and whether they are seeds.
+type Swarms = Arc<Mutex<HashMap<Sha1Hash, HashMap<SocketAddr, bool>>>>;
+
+/// An HTTP tracker that hands out the peers of each torrent that announced to
+/// it, in the compact format.
+///
+/// The tracker stops when it's dropped.
+pub struct MockTracker {
+    addr: SocketAddr,
+    swarms: Swarms,
+    abort_handle: AbortHandle,
+}
+
+impl MockTracker {
+    /// Spawns the tracker on a random loopback port.
+    pub async fn spawn() -> io::Result<Self> {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        let swarms = Swarms::default();
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        task::spawn(Abortable::new(
+            serve(listener, Arc::clone(&swarms)),
+            abort_registration,
+        ));
+        log::info!("Mock tracker listening on {}", addr);
+        Ok(Self {
+            addr,
+            swarms,
+            abort_handle,
+        })
+    }
+
+    /// Returns the tracker's announce URL.
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://{}/announce", self.addr)).unwrap()
+    }
+
+    /// Returns the peers that announced the torrent and haven't stopped yet.
+    pub fn peers(&self, info_hash: &Sha1Hash) -> Vec<SocketAddr> {
+        self.swarms
+            .lock()
+            .unwrap()
+            .get(info_hash)
+            .map(|swarm| swarm.keys().copied().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+/// Accepts the connections of the tracker, each of which sends a single
+/// request.
+async fn serve(mut listener: TcpListener, swarms: Swarms) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                log::warn!("Mock tracker accept error: {}", e);
+                continue;
+            }
+        };
+        let swarms = Arc::clone(&swarms);
+        task::spawn(async move {
+            if let Err(e) = handle_request(socket, addr.ip(), &swarms).await {
+                log::warn!("Mock tracker error with {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Reads the HTTP request from the socket and answers it.
+async fn handle_request(
+    mut socket: TcpStream,
+    ip: IpAddr,
+    swarms: &Swarms,
+) -> io::Result<()> {
+    // the request has no body, so it ends with the empty line after the
+    // headers
+    let mut buf = Vec::new();
+    while !buf.ends_with(b"\r\n\r\n") {
+        let mut chunk = [0; 1024];
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    // the query string is percent encoded, so the request line is ASCII
+    let request = String::from_utf8_lossy(&buf);
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    let mut target = target.splitn(2, '?');
+    let path = target.next().unwrap_or_default();
+    let query = target.next().unwrap_or_default();
+    let (status, body) = if path != "/announce" {
+        ("404 Not Found", Vec::new())
+    } else {
+        match announce(query, ip, swarms) {
+            Some(body) => ("200 OK", body),
+            None => ("400 Bad Request", Vec::new()),
+        }
+    };
+
+    let mut resp = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    resp.extend_from_slice(&body);
+    socket.write_all(&resp).await
+}
+
+/// Registers the announcing peer and returns the bencoded response with the
+/// torrent's other peers, or `None` if the query is invalid.
+fn announce(query: &str, mut ip: IpAddr, swarms: &Swarms) -> Option<Vec<u8>> {
+    let mut info_hash = None;
+    let mut port = None;
+    let mut left = None;
+    let mut event = None;
+    for pair in query.split('&') {
+        let mut pair = pair.splitn(2, '=');
+        let key = pair.next()?;
+        let value: Vec<u8> = percent_decode_str(pair.next()?).collect();
+        match key {
+            "info_hash" if value.len() == 20 => {
+                let mut hash = [0; 20];
+                hash.copy_from_slice(&value);
+                info_hash = Some(hash);
+            }
+            "port" => port = str::from_utf8(&value).ok()?.parse().ok(),
+            "left" => left = str::from_utf8(&value).ok()?.parse::<u64>().ok(),
+            "event" => event = Some(String::from_utf8(value).ok()?),
+            "ip" => ip = str::from_utf8(&value).ok()?.parse().ok()?,
+            _ => (),
+        }
+    }
+    let addr = SocketAddr::new(ip, port?);
+    log::debug!("Mock tracker announce from {}, event: {:?}", addr, event);
+
+    let mut swarms = swarms.lock().unwrap();
+    let swarm = swarms.entry(info_hash?).or_default();
+    if event.as_deref() == Some("stopped") {
+        swarm.remove(&addr);
+    } else {
+        swarm.insert(addr, left? == 0);
+    }
+
+    let mut peers = Vec::new();
+    let mut peers6 = Vec::new();
+    for peer in swarm.keys().filter(|peer| **peer != addr) {
+        if peer.is_ipv4() {
+            compact::encode_addr(peer, &mut peers);
+        } else {
+            compact::encode_addr(peer, &mut peers6);
+        }
+    }
+    let seed_count = swarm.values().filter(|is_seed| **is_seed).count();
+    let interval = ANNOUNCE_INTERVAL.as_secs();
+
+    // the keys of a bencoded dictionary are sorted
+    let mut buf = format!(
+        "d8:completei{}e10:incompletei{}e8:intervali{}e12:min intervali{}e",
+        seed_count,
+        swarm.len() - seed_count,
+        interval,
+        interval
+    )
+    .into_bytes();
+    encode_str(b"peers", &mut buf);
+    encode_str(&peers, &mut buf);
+    if !peers6.is_empty() {
+        encode_str(b"peers6", &mut buf);
+        encode_str(&peers6, &mut buf);
+    }
+    buf.push(b'e');
+    Some(buf)
+}
+
+/// A swarm of engines in the same process, which find each other through
+/// a mock tracker.
+///
+/// Each engine gets its own download directory in a temporary directory,
+/// which is removed when the swarm is dropped, unless the test failed.
+pub struct Swarm {
+    tracker: MockTracker,
+    /// The directory in which the peers' download directories are created.
+    dir: PathBuf,
+    /// The number of peers spawned so far, used to give each a unique id and
+    /// download directory.
+    peer_count: usize,
+}
+
+impl Swarm {
+    /// Creates the swarm's directory and spawns its tracker. The name should
+    /// be unique among the tests that run at the same time.
+    pub async fn new(name: &str) -> Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "cratetorrent-swarm-{}-{:08x}",
+            name,
+            rand::random::<u32>()
+        ));
+        fs::create_dir_all(&dir)?;
+        let tracker = MockTracker::spawn().await?;
+        Ok(Self {
+            tracker,
+            dir,
+            peer_count: 0,
+        })
+    }
+
+    /// Returns the swarm's tracker.
+    pub fn tracker(&self) -> &MockTracker {
+        &self.tracker
+    }
+
+    /// Spawns a new engine with the default configuration.
+    pub fn spawn_peer(&mut self) -> Result<Peer> {
+        self.spawn_peer_with_conf(|_| ())
+    }
+
+    /// Spawns a new engine, whose configuration may be changed from the
+    /// default before it's started.
+    ///
+    /// By default the engine listens on a random loopback port and has its
+    /// own client id and download directory.
+    pub fn spawn_peer_with_conf(
+        &mut self,
+        f: impl FnOnce(&mut Conf),
+    ) -> Result<Peer> {
+        let index = self.peer_count;
+        self.peer_count += 1;
+        let download_dir = self.dir.join(format!("peer-{}", index));
+        fs::create_dir_all(&download_dir)?;
+
+        let mut conf = Conf::new(&download_dir);
+        let client_id = format!("cbt-swarm-{:010}", index);
+        conf.engine.client_id.copy_from_slice(client_id.as_bytes());
+        conf.engine.listen_addr = "127.0.0.1:0".parse().unwrap();
+        conf.engine.scrape_interval = None;
+        f(&mut conf);
+
+        let (engine, alert_rx) = engine::spawn(conf)?;
+        log::info!("Spawned swarm peer {} on {}", index, engine.listen_addr());
+        Ok(Peer {
+            engine,
+            alert_rx,
+            download_dir,
+        })
+    }
+}
+
+impl Drop for Swarm {
+    fn drop(&mut self) {
+        // keep the files of a failed test for inspection
+        if !std::thread::panicking() {
+            fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+}
+
+/// An engine in a [`Swarm`].
+pub struct Peer {
+    engine: EngineHandle,
+    alert_rx: AlertReceiver,
+    download_dir: PathBuf,
+}
+
+impl Peer {
+    /// Returns the handle of the peer's engine.
+    pub fn engine(&self) -> &EngineHandle {
+        &self.engine
+    }
+
+    /// Returns the channel of the engine's alerts.
+    pub fn alert_rx(&mut self) -> &mut AlertReceiver {
+        &mut self.alert_rx
+    }
+
+    /// Returns the engine's download directory.
+    pub fn download_dir(&self) -> &Path {
+        &self.download_dir
+    }
+
+    /// Returns the address on which the engine accepts connections.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.engine.listen_addr()
+    }
+
+    /// Starts downloading the torrent, with peers from its trackers.
+    pub fn download(&self, metainfo: &Metainfo) -> Result<TorrentId> {
+        self.create_torrent(metainfo, Mode::Download { seeds: Vec::new() })
+    }
+
+    /// Writes the payload to the download directory and starts seeding the
+    /// torrent.
+    pub fn seed(
+        &self,
+        payload: &Payload,
+        metainfo: &Metainfo,
+    ) -> Result<TorrentId> {
+        payload.write(&self.download_dir)?;
+        self.create_torrent(metainfo, Mode::Seed)
+    }
+
+    fn create_torrent(
+        &self,
+        metainfo: &Metainfo,
+        mode: Mode,
+    ) -> Result<TorrentId> {
+        self.engine.create_torrent(TorrentParams {
+            metainfo: metainfo.clone(),
+            conf: None,
+            mode,
+            resume_data: None,
+            file_priorities: None,
+            extensions: Vec::new(),
+        })
+    }
+
+    /// Waits for the torrent to complete its download, returning false if it
+    /// doesn't within the timeout.
+    ///
+    /// The engine's other alerts are discarded, and its errors logged.
+    pub async fn wait_for_completion(
+        &mut self,
+        id: TorrentId,
+        timeout: Duration,
+    ) -> bool {
+        let alert_rx = &mut self.alert_rx;
+        let completion = async {
+            while let Some(alert) = alert_rx.next().await {
+                match alert {
+                    Alert::TorrentComplete(torrent_id) if torrent_id == id => {
+                        return true;
+                    }
+                    Alert::Error(e) => log::warn!("Swarm peer error: {}", e),
+                    _ => (),
+                }
+            }
+            false
+        };
+        time::timeout(timeout, completion).await.unwrap_or(false)
+    }
+
+    /// Waits for a stats update of the torrent that is sent after this call,
+    /// returning None if none arrives within the timeout.
+    ///
+    /// The alerts already received are discarded, so that the stats are up to
+    /// date, and the engine's errors logged.
+    pub async fn wait_for_stats(
+        &mut self,
+        id: TorrentId,
+        timeout: Duration,
+    ) -> Option<TorrentStats> {
+        while let Ok(alert) = self.alert_rx.try_recv() {
+            if let Alert::Error(e) = alert {
+                log::warn!("Swarm peer error: {}", e);
+            }
+        }
+        let alert_rx = &mut self.alert_rx;
+        let stats = async {
+            while let Some(alert) = alert_rx.next().await {
+                match alert {
+                    Alert::TorrentStats {
+                        id: torrent_id,
+                        stats,
+                    } if torrent_id == id => {
+                        return Some(*stats);
+                    }
+                    Alert::Error(e) => log::warn!("Swarm peer error: {}", e),
+                    _ => (),
+                }
+            }
+            None
+        };
+        time::timeout(timeout, stats).await.unwrap_or(None)
+    }
+
+    /// Gracefully shuts down the engine.
+    pub async fn shutdown(self) -> Result<()> {
+        self.engine.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use futures::SinkExt;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use tokio_util::codec::FramedParts;
+
+    use crate::{
+        conf::EncryptionPolicy,
+        engine::MagnetParams,
+        error::Error,
+        magnet::MagnetLink,
+        peer::codec::{Handshake, HandshakeCodec, Message, PeerCodec},
+        Bitfield, BlockData, BlockInfo, BLOCK_LEN,
+    };
+
+    /// How long a download in the tests may take.
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    const PIECE_LEN: u32 = 32 * 1024;
+
+    /// Tests that the metainfo created from a payload describes its files.
+    #[test]
+    fn should_create_metainfo_from_payload() {
+        let tracker: Url = "http://127.0.0.1:6969/announce".parse().unwrap();
+        let payload = Payload::random_file("file.bin", 100_000);
+        let metainfo =
+            payload.metainfo(PIECE_LEN, std::slice::from_ref(&tracker));
+        assert_eq!(metainfo.name, "file.bin");
+        assert!(!metainfo.is_archive());
+        assert_eq!(metainfo.download_len(), 100_000);
+        assert_eq!(metainfo.piece_count(), 4);
+        assert_eq!(metainfo.trackers, vec![vec![tracker]]);
+
+        let payload = Payload::random_dir(
+            "dir",
+            &[("a.bin", 40_000), ("sub/b.bin", 30_000)],
+        );
+        let metainfo = payload.metainfo(PIECE_LEN, &[]);
+        assert!(metainfo.is_archive());
+        assert_eq!(metainfo.files[1].path, Path::new("sub/b.bin"));
+        assert_eq!(metainfo.files[1].torrent_offset, 40_000);
+        assert_eq!(metainfo.piece_count(), 3);
+        assert!(metainfo.trackers.is_empty());
+    }
+
+    /// Tests that a leech downloads a torrent from several seeds.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_download_from_multiple_seeds() {
+        let mut swarm = Swarm::new("multi_seed").await.unwrap();
+        let payload = Payload::random_file("file.bin", 2 * 1024 * 1024 + 100);
+        let metainfo = payload.metainfo(PIECE_LEN, &[swarm.tracker().url()]);
+
+        let mut seeds = Vec::new();
+        for _ in 0..3 {
+            let seed = swarm.spawn_peer().unwrap();
+            let id = seed.seed(&payload, &metainfo).unwrap();
+            seeds.push((seed, id));
+        }
+        // the leech may otherwise finish downloading from the first seed it
+        // finds before the tracker learns about the others
+        time::timeout(TIMEOUT, async {
+            while swarm.tracker().peers(&metainfo.info_hash).len() < 3 {
+                time::delay_for(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut leech = swarm.spawn_peer().unwrap();
+        let id = leech.download(&metainfo).unwrap();
+
+        assert!(leech.wait_for_completion(id, TIMEOUT).await);
+        payload.assert_downloaded(leech.download_dir());
+        assert_eq!(swarm.tracker().peers(&metainfo.info_hash).len(), 4);
+
+        // the pieces were downloaded from more than one of the seeds
+        let mut uploading_seed_count = 0;
+        for (seed, id) in seeds.iter_mut() {
+            let stats = seed
+                .wait_for_stats(*id, Duration::from_secs(5))
+                .await
+                .expect("no seed stats");
+            if stats.thruput.payload.up.total > 0 {
+                uploading_seed_count += 1;
+            }
+        }
+        assert!(
+            uploading_seed_count > 1,
+            "only {} seed uploaded",
+            uploading_seed_count
+        );
+    }
+
+    /// Tests that a seed uploads a torrent to several leeches at once.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_seed_to_multiple_leeches() {
+        let mut swarm = Swarm::new("multi_leech").await.unwrap();
+        let payload = Payload::random_file("file.bin", 1024 * 1024);
+        let metainfo = payload.metainfo(PIECE_LEN, &[swarm.tracker().url()]);
+
+        let seed = swarm.spawn_peer().unwrap();
+        seed.seed(&payload, &metainfo).unwrap();
+        let mut leeches = Vec::new();
+        for _ in 0..3 {
+            let leech = swarm.spawn_peer().unwrap();
+            let id = leech.download(&metainfo).unwrap();
+            leeches.push((leech, id));
+        }
+
+        for (leech, id) in leeches.iter_mut() {
+            assert!(leech.wait_for_completion(*id, TIMEOUT).await);
+            payload.assert_downloaded(leech.download_dir());
+        }
+    }
+
+    /// Tests downloading a torrent with multiple files in subdirectories.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_download_directory() {
+        let mut swarm = Swarm::new("directory").await.unwrap();
+        let payload = Payload::random_dir(
+            "dir",
+            &[
+                ("a.bin", 300_000),
+                ("sub/b.bin", 10),
+                ("sub/deeper/c.bin", 500_000),
+            ],
+        );
+        let metainfo = payload.metainfo(PIECE_LEN, &[swarm.tracker().url()]);
+
+        let seed = swarm.spawn_peer().unwrap();
+        seed.seed(&payload, &metainfo).unwrap();
+        let mut leech = swarm.spawn_peer().unwrap();
+        let id = leech.download(&metainfo).unwrap();
+
+        assert!(leech.wait_for_completion(id, TIMEOUT).await);
+        payload.assert_downloaded(leech.download_dir());
+        leech.shutdown().await.unwrap();
+        seed.shutdown().await.unwrap();
+    }
+
+    /// Tests that a paused torrent refuses connections without answering the
+    /// handshake, and that it uploads again once resumed.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_refuse_peers_while_paused() {
+        let mut swarm = Swarm::new("pause").await.unwrap();
+        let payload = Payload::random_file("file.bin", 256 * 1024);
+        let metainfo = payload.metainfo(PIECE_LEN, &[swarm.tracker().url()]);
+
+        let seed = swarm.spawn_peer().unwrap();
+        let seed_id = seed.seed(&payload, &metainfo).unwrap();
+        seed.engine().pause_torrent(seed_id).unwrap();
+
+        let socket = TcpStream::connect(seed.listen_addr()).await.unwrap();
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = Handshake::new(metainfo.info_hash, [1; 20]);
+        socket.send(handshake).await.unwrap();
+        assert!(!matches!(socket.next().await, Some(Ok(_))));
+
+        // the leech is given the seed directly, as the paused seed doesn't
+        // announce itself to the tracker
+        let mut leech = swarm.spawn_peer().unwrap();
+        let id = leech
+            .engine()
+            .create_torrent(TorrentParams {
+                metainfo: metainfo.clone(),
+                conf: None,
+                mode: Mode::Download {
+                    seeds: vec![seed.listen_addr()],
+                },
+                resume_data: None,
+                file_priorities: None,
+                extensions: Vec::new(),
+            })
+            .unwrap();
+        assert!(!leech.wait_for_completion(id, Duration::from_secs(3)).await);
+
+        seed.engine().resume_torrent(seed_id).unwrap();
+        assert!(leech.wait_for_completion(id, TIMEOUT).await);
+        payload.assert_downloaded(leech.download_dir());
+    }
+
+    /// Tests that a paused seed tells the tracker it stopped, and that it
+    /// announces itself again right away once resumed, rather than only after
+    /// the tracker's announce interval.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_reannounce_seed_on_resume() {
+        let mut swarm = Swarm::new("reannounce").await.unwrap();
+        let payload = Payload::random_file("file.bin", 64 * 1024);
+        let metainfo = payload.metainfo(PIECE_LEN, &[swarm.tracker().url()]);
+        let seed = swarm.spawn_peer().unwrap();
+        let id = seed.seed(&payload, &metainfo).unwrap();
+        let seed_addr = seed.listen_addr();
+        let is_announced = || {
+            swarm
+                .tracker()
+                .peers(&metainfo.info_hash)
+                .contains(&seed_addr)
+        };
+        let wait_until = |announced: bool, timeout| async move {
+            time::timeout(timeout, async {
+                while is_announced() != announced {
+                    time::delay_for(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .is_ok()
+        };
+
+        assert!(wait_until(true, TIMEOUT).await);
+        seed.engine().pause_torrent(id).unwrap();
+        assert!(wait_until(false, TIMEOUT).await);
+        // the stop was announced just now, so the announce interval hasn't
+        // elapsed yet
+        seed.engine().resume_torrent(id).unwrap();
+        assert!(wait_until(true, ANNOUNCE_INTERVAL / 2).await);
+        seed.shutdown().await.unwrap();
+    }
+
+    /// Tests that the engine keeps handling commands while a removed torrent
+    /// waits for its exit announce, and that the torrent is removed once the
+    /// announce times out.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_handle_commands_while_removing_torrent() {
+        // the torrent's exit announce only completes when it times out
+        let (tracker_url, tracker) = spawn_unresponsive_tracker().await;
+
+        let mut swarm = Swarm::new("remove").await.unwrap();
+        let payload = Payload::random_file("file.bin", 64 * 1024);
+        let metainfo = payload.metainfo(PIECE_LEN, &[tracker_url]);
+        let mut seed = swarm
+            .spawn_peer_with_conf(|conf| {
+                conf.torrent.stop_announce_timeout = Duration::from_secs(2);
+            })
+            .unwrap();
+        let id = seed.seed(&payload, &metainfo).unwrap();
+        while let Some(alert) = seed.alert_rx().next().await {
+            if let Alert::TorrentChecked { .. } = alert {
+                break;
+            }
+        }
+
+        seed.engine().remove_torrent(id, false).unwrap();
+        // the torrent is gone, so this is rejected right away, even though
+        // the torrent is still waiting for its tracker
+        seed.engine().pause_torrent(id).unwrap();
+        let alert =
+            time::timeout(Duration::from_secs(1), seed.alert_rx().next())
+                .await
+                .unwrap();
+        assert!(matches!(alert, Some(Alert::Error(Error::InvalidTorrentId))));
+
+        let removal = async {
+            while let Some(alert) = seed.alert_rx().next().await {
+                if let Alert::TorrentRemoved(torrent_id) = alert {
+                    return torrent_id;
+                }
+            }
+            panic!("engine stopped");
+        };
+        assert_eq!(time::timeout(TIMEOUT, removal).await.unwrap(), id);
+        tracker.abort();
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn should_fail_over_to_next_tracker_tier() {
+        let (unresponsive_url, unresponsive) =
+            spawn_unresponsive_tracker().await;
+        let mut swarm = Swarm::new("failover").await.unwrap();
+        let payload = Payload::random_file("file.bin", 64 * 1024);
+        let mut metainfo = payload.metainfo(PIECE_LEN, &[]);
+        metainfo.trackers =
+            vec![vec![unresponsive_url], vec![swarm.tracker().url()]];
+        let set_timeouts = |conf: &mut Conf| {
+            conf.torrent.announce_timeout = Duration::from_secs(1);
+            conf.torrent.stop_announce_timeout = Duration::from_millis(500);
+        };
+
+        // both peers only find each other through the second tier
+        let seed = swarm.spawn_peer_with_conf(set_timeouts).unwrap();
+        seed.seed(&payload, &metainfo).unwrap();
+        let mut leech = swarm.spawn_peer_with_conf(set_timeouts).unwrap();
+        let id = leech.download(&metainfo).unwrap();
+        assert!(leech.wait_for_completion(id, TIMEOUT).await);
+        payload.assert_downloaded(leech.download_dir());
+
+        // the stop is sent to both tiers at once, so shutting down takes as
+        // long as the stop announce to the unresponsive tracker, rather than
+        // the regular announce timeout and then some
+        let seed_addr = seed.listen_addr();
+        assert!(swarm
+            .tracker()
+            .peers(&metainfo.info_hash)
+            .contains(&seed_addr));
+        let start = Instant::now();
+        seed.shutdown().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(!swarm
+            .tracker()
+            .peers(&metainfo.info_hash)
+            .contains(&seed_addr));
+
+        leech.shutdown().await.unwrap();
+        unresponsive.abort();
+    }
+
+    /// Tests that a magnet link's metadata download that waits for an
+    /// unresponsive tracker doesn't hold up the engine's shutdown.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_shut_down_while_fetching_metadata() {
+        let (tracker_url, tracker) = spawn_unresponsive_tracker().await;
+        let mut swarm = Swarm::new("magnet_shutdown").await.unwrap();
+        let peer = swarm.spawn_peer().unwrap();
+        peer.engine()
+            .create_magnet_torrent(MagnetParams {
+                magnet: MagnetLink {
+                    info_hash: [1; 20],
+                    name: None,
+                    trackers: vec![tracker_url],
+                    peers: Vec::new(),
+                },
+                conf: None,
+                extensions: Vec::new(),
+            })
+            .unwrap();
+
+        // give the download time to announce to the tracker
+        time::delay_for(Duration::from_secs(2)).await;
+        time::timeout(Duration::from_secs(5), peer.shutdown())
+            .await
+            .expect("shutdown waited for the announce")
+            .unwrap();
+        tracker.abort();
+    }
+
+    /// Tests that skipping the file of a piece that's being downloaded, which
+    /// completes the download, doesn't complete it again when the piece
+    /// arrives.
+    #[tokio::test(threaded_scheduler)]
+    async fn should_complete_once_when_skipping_downloading_piece() {
+        let mut swarm = Swarm::new("skip").await.unwrap();
+        let file_len = PIECE_LEN as usize;
+        let payload =
+            Payload::random_dir("dir", &[("a", file_len), ("b", file_len)]);
+        let metainfo = payload.metainfo(PIECE_LEN, &[]);
+        let info_hash = metainfo.info_hash;
+
+        // a seed driven by the test, so that the second piece is held back
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut seed = TcpListener::bind(addr).await.unwrap();
+        let mut leech = swarm
+            .spawn_peer_with_conf(|conf| {
+                conf.engine.utp = false;
+                conf.torrent.encryption = EncryptionPolicy::Disabled;
+            })
+            .unwrap();
+        let id = leech
+            .engine()
+            .create_torrent(TorrentParams {
+                metainfo,
+                conf: None,
+                mode: Mode::Download {
+                    seeds: vec![seed.local_addr().unwrap()],
+                },
+                resume_data: None,
+                file_priorities: None,
+                extensions: Vec::new(),
+            })
+            .unwrap();
+
+        let (socket, _) = seed.accept().await.unwrap();
+        let mut socket = Framed::new(socket, HandshakeCodec);
+        let handshake = socket.next().await.unwrap().unwrap();
+        assert_eq!(handshake.info_hash, info_hash);
+        socket
+            .send(Handshake::new(info_hash, [2; 20]))
+            .await
+            .unwrap();
+        let old_parts = socket.into_parts();
+        let mut parts = FramedParts::new(old_parts.io, PeerCodec);
+        parts.read_buf = old_parts.read_buf;
+        let mut socket = Framed::from_parts(parts);
+        socket
+            .send(Message::Bitfield(Bitfield::repeat(true, 2)))
+            .await
+            .unwrap();
+        socket.send(Message::Unchoke).await.unwrap();
+
+        let block = |block: BlockInfo| {
+            let (_, data) = &payload.files[block.piece_index];
+            let start = block.offset as usize;
+            Message::Block {
+                piece_index: block.piece_index,
+                offset: block.offset,
+                data: BlockData::Owned(
+                    data[start..start + block.len as usize].to_vec(),
+                ),
+            }
+        };
+        let mut held_back = Vec::new();
+        let mut served_count = 0;
+        let block_count = file_len / BLOCK_LEN as usize;
+        while served_count < block_count || held_back.len() < block_count {
+            match socket.next().await.unwrap().unwrap() {
+                Message::Request(info) if info.piece_index == 0 => {
+                    socket.send(block(info)).await.unwrap();
+                    served_count += 1;
+                }
+                Message::Request(info) => held_back.push(info),
+                _ => (),
+            }
+        }
+
+        // the second piece is no longer wanted, so the download is complete
+        // once the first one is written, if it wasn't already
+        leech.engine().set_file_priorities(id, vec![1, 0]).unwrap();
+        assert!(leech.wait_for_completion(id, TIMEOUT).await);
+
+        for info in held_back {
+            socket.send(block(info)).await.unwrap();
+        }
+        assert!(!leech.wait_for_completion(id, Duration::from_secs(2)).await);
+    }
+
+    /// Spawns a tracker that accepts connections but never responds to them,
+    /// and returns its announce URL along with the handle to stop it.
+    async fn spawn_unresponsive_tracker() -> (Url, AbortHandle) {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(addr).await.unwrap();
+        let url = Url::parse(&format!(
+            "http://{}/announce",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        task::spawn(Abortable::new(
+            async move {
+                let mut conns = Vec::new();
+                while let Ok((socket, _)) = listener.accept().await {
+                    conns.push(socket);
+                }
+            },
+            abort_registration,
+        ));
+        (url, abort_handle)
+    }
+}
@@ -1,6 +1,8 @@
 use std::{
-    collections::HashMap,
-    net::SocketAddr,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    net::{Ipv6Addr, SocketAddr},
+    ops::Range,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -9,34 +11,55 @@ use futures::{
     select,
     stream::{Fuse, StreamExt},
 };
+use rand::seq::SliceRandom;
+use reqwest::Url;
 use tokio::{
-    net::{TcpListener, TcpStream},
     sync::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        RwLock,
+        oneshot, RwLock,
     },
     task, time,
 };
+use tokio_util::codec::Framed;
 
 use crate::{
     alert::{Alert, AlertSender},
-    conf::TorrentConf,
-    counter::ThruputCounters,
+    conf::{EncryptionPolicy, TorrentConf},
+    counter::{Counter, ThruputCounters},
+    dht::{self, DhtHandle},
     disk::{
         self,
         error::{ReadError, WriteError},
     },
     download::PieceDownload,
     error::Error,
-    peer::{self, ConnectionState, PeerSession, SessionState, SessionTick},
+    listener::IncomingPeer,
+    peer::{
+        self,
+        codec::{Handshake, HandshakeCodec},
+        mse::{self, PeerStream},
+        pex::{PexFlags, PEX_INTERVAL},
+        BoxTransport, ConnectionState, Connector, Direction, Extension,
+        PeerSession, SessionState, SessionTick,
+    },
     piece_picker::PiecePicker,
+    resume::{ResumeData, TrackerResumeData},
     storage_info::StorageInfo,
-    tracker::{Announce, Event, Tracker},
-    Bitfield, BlockInfo, PeerId, PieceIndex, Sha1Hash, TorrentId,
+    stream::FileStream,
+    tracker::{
+        self, Announce, Event, Response, ScrapeStats, Tracker, TrackerError,
+    },
+    Bitfield, BlockInfo, PeerId, PieceIndex, Priority, Sha1Hash, TorrentId,
+    DEFAULT_PRIORITY,
 };
+use choker::{Candidate, Choker, CHOKE_INTERVAL};
 use error::*;
-use stats::{Peers, PieceStats, ThruputStats, TorrentStats};
+use stats::{
+    Peers, PieceStats, SwarmStats, ThruputStats, TorrentState, TorrentStats,
+    TrackerState, TrackerStats,
+};
 
+mod choker;
 pub mod error;
 pub mod stats;
 
@@ -59,11 +82,78 @@ pub(crate) enum Command {
         block_info: BlockInfo,
         error: ReadError,
     },
+    /// A connection of a peer that wants this torrent, accepted by the
+    /// engine's listener.
+    IncomingPeer(Box<IncomingPeer>),
     /// A message sent only once, after the peer has been connected.
     PeerConnected { addr: SocketAddr, id: PeerId },
     /// Peer sessions periodically send this message when they have a state
     /// change.
     PeerState { addr: SocketAddr, info: SessionTick },
+    /// The peers a peer told us about via peer exchange.
+    PexPeers {
+        /// The address of the peer that sent the peers.
+        addr: SocketAddr,
+        peers: Vec<(SocketAddr, PexFlags)>,
+    },
+    /// Pause the torrent.
+    ///
+    /// All peer sessions are shut down and trackers are notified that we
+    /// stopped, but the torrent's state is kept so that it can be resumed.
+    Pause,
+    /// Resume a paused torrent.
+    Resume,
+    /// Collect the torrent's resume data and post it to the user.
+    ///
+    /// The torrent fills in what it knows and passes the resume data to the
+    /// disk task, which adds the state of the files and the in-progress pieces
+    /// and returns it via [`Command::ResumeData`].
+    SaveResumeData,
+    /// The resume data, completed by the disk task.
+    ResumeData(Box<ResumeData>),
+    /// Disconnect all peers and verify the torrent's pieces on disk.
+    ForceRecheck,
+    /// Set the download priority of each of the torrent's files.
+    SetFilePriorities(Vec<Priority>),
+    /// Set whether the torrent's pieces are downloaded in order.
+    SetSequentialDownload(bool),
+    /// Set the time by which the piece should be downloaded.
+    SetPieceDeadline {
+        index: PieceIndex,
+        deadline: Instant,
+    },
+    /// Remove the deadline of the piece.
+    ResetPieceDeadline(PieceIndex),
+    /// Open a stream of the file with the index and return it via the sender.
+    OpenFile {
+        file_index: usize,
+        result_tx: oneshot::Sender<std::result::Result<FileStream, Error>>,
+    },
+    /// Sent by a file stream that reads the first of the pieces. Set deadlines
+    /// for the pieces and notify the stream via the sender once we have the
+    /// first one.
+    StreamPieces {
+        pieces: Range<PieceIndex>,
+        piece_tx: oneshot::Sender<()>,
+    },
+    /// Sent by a file stream that seeks elsewhere or is dropped. Remove the
+    /// deadlines of the pieces it last read ahead.
+    ResetStreamPieces(Range<PieceIndex>),
+    /// The result of an announce to the tracker with the URL, sent by the
+    /// task that ran the announce.
+    AnnounceResult {
+        url: Url,
+        event: Option<Event>,
+        result: tracker::Result<Response>,
+    },
+    /// The torrent's swarm statistics from a scrape of the tracker with the
+    /// URL, sent by the engine.
+    ScrapeResult { url: Url, stats: ScrapeStats },
+    /// Sent by the disk task after each checked percent of the pieces.
+    CheckProgress { checked_count: usize },
+    /// Sent by the disk task when all pieces have been checked, with the
+    /// pieces that are valid.
+    CheckComplete(Bitfield),
     /// Gracefully shut down the torrent.
     ///
     /// This command tells all active peer sessions of torrent to do the same,
@@ -124,6 +214,19 @@ pub(crate) struct TorrentContext {
     pub disk_tx: disk::Sender,
     /// Info about the torrent's storage (piece length, download length, etc).
     pub storage: StorageInfo,
+
+    /// The user's extension protocol message handlers, shared by all peer
+    /// sessions.
+    pub extensions: Vec<Arc<dyn Extension>>,
+    /// Whether the torrent is private, in which case peer exchange is
+    /// disabled.
+    pub is_private: bool,
+    /// The engine's DHT node, if enabled and if the torrent is not private.
+    pub dht: Option<DhtHandle>,
+    /// Whether the connections with peers are encrypted.
+    pub encryption: EncryptionPolicy,
+    /// Makes the connections to peers.
+    pub connector: Arc<dyn Connector>,
 }
 
 /// Parameters for the torrent constructor.
@@ -133,11 +236,24 @@ pub(crate) struct Params {
     pub info_hash: Sha1Hash,
     pub storage_info: StorageInfo,
     pub own_pieces: Bitfield,
-    pub trackers: Vec<Tracker>,
+    /// The priority of each file in the torrent.
+    pub file_priorities: Vec<Priority>,
+    /// The tiers of trackers, see
+    /// [`Metainfo::trackers`](crate::metainfo::Metainfo::trackers).
+    pub trackers: Vec<Vec<Tracker>>,
     pub client_id: PeerId,
+    /// The address of the engine's listener.
     pub listen_addr: SocketAddr,
+    /// Our IPv6 address, if the engine accepts IPv6 connections.
+    pub ipv6_addr: Option<Ipv6Addr>,
     pub conf: TorrentConf,
     pub alert_tx: AlertSender,
+    pub resume_data: Option<ResumeData>,
+    pub needs_check: bool,
+    pub extensions: Vec<Arc<dyn Extension>>,
+    pub is_private: bool,
+    pub dht: Option<DhtHandle>,
+    pub connector: Arc<dyn Connector>,
 }
 
 /// Represents a torrent upload or download.
@@ -149,8 +265,9 @@ pub(crate) struct Params {
 pub(crate) struct Torrent {
     /// The peers in this torrent.
     peers: HashMap<SocketAddr, PeerSessionEntry>,
-    /// The peers returned by tracker to which we can connect.
-    available_peers: Vec<SocketAddr>,
+    /// The peers returned by tracker or by other peers via peer exchange to
+    /// which we can connect, with their peer exchange flags, if known.
+    available_peers: Vec<(SocketAddr, PexFlags)>,
     /// Information that is shared with peer sessions.
     ctx: Arc<TorrentContext>,
     /// The port on which other entities in the engine send this torrent
@@ -159,12 +276,42 @@ pub(crate) struct Torrent {
     /// The channel has to be wrapped in a `stream::Fuse` so that we can
     /// `select!` on it in the torrent event loop.
     cmd_rx: Fuse<Receiver>,
-    /// The trackers we can announce to.
-    trackers: Vec<TrackerEntry>,
+    /// The trackers we can announce to, in tiers.
+    trackers: Vec<Vec<TrackerEntry>>,
 
-    /// The address on which torrent should listen for new peers.
+    /// The address on which the engine listens for new peers, whose port is
+    /// announced to trackers and peers.
     listen_addr: SocketAddr,
-
+    /// Our IPv6 address, announced to trackers if we accept IPv6 connections.
+    ipv6_addr: Option<Ipv6Addr>,
+
+    /// The last time we sent our peers to the peer sessions for peer exchange.
+    last_pex_time: Option<Instant>,
+
+    /// Decides which peers we upload to.
+    choker: Choker,
+    /// The last time we recalculated which peers to upload to.
+    last_choke_time: Option<Instant>,
+
+    /// The DHT sends the peers it finds on this channel, a copy of whose
+    /// sender is passed along with each query.
+    dht_peers_tx: dht::PeersSender,
+    dht_peers_rx: Fuse<UnboundedReceiver<Vec<SocketAddr>>>,
+    /// The last time we asked the DHT for peers.
+    last_dht_query_time: Option<Instant>,
+
+    /// The priority of each file in the torrent, from which the priorities of
+    /// the pieces are derived.
+    file_priorities: Vec<Priority>,
+
+    /// Whether the torrent is checking its pieces, active, or paused.
+    state: TorrentState,
+    /// Whether the torrent's pieces on disk need to be checked before it can
+    /// be started.
+    needs_check: bool,
+    /// Whether the torrent should become active or paused once the current
+    /// check of its pieces completes.
+    resume_after_check: bool,
     /// The time the torrent was first started.
     start_time: Option<Instant>,
     /// The total time the torrent has been running.
@@ -172,8 +319,6 @@ pub(crate) struct Torrent {
     /// This is a separate field as `Instant::now() - start_time` cannot be
     /// relied upon due to the fact that it is possible to pause a torrent, in
     /// which case we don't want to record the run time.
-    // TODO: pausing a torrent is not actually at this point, but this is done
-    // in expectation of that feature
     run_duration: Duration,
 
     /// In the last part of the download the torrent is in what's called the
@@ -186,12 +331,22 @@ pub(crate) struct Torrent {
     /// the slower peers.
     in_endgame: bool,
 
+    /// Whether we have all the pieces we want, which is only reported once.
+    ///
+    /// If we want more pieces again after file priorities are raised, this is
+    /// reset, so that the new completion is reported too.
+    is_complete: bool,
+
     /// Measures various transfer statistics.
     counters: ThruputCounters,
 
     /// The configuration of this particular torrent.
     conf: TorrentConf,
 
+    /// The resume data from a previous run, if any, which is restored when the
+    /// torrent is started.
+    resume_data: Option<ResumeData>,
+
     /// If `TorrentAlertConf::latest_completed_pieces` alert type is set, each
     /// round the torrent collects the pieces that were downloaded, sends them
     /// to peer as an alert, and resets the list.
@@ -199,6 +354,9 @@ pub(crate) struct Torrent {
     /// This is set to some if the configuration is enabled, and set to none if
     /// disabled.
     completed_pieces: Option<Vec<PieceIndex>>,
+
+    /// The file streams waiting for pieces, by the piece they are waiting for.
+    piece_waiters: HashMap<PieceIndex, Vec<oneshot::Sender<()>>>,
 }
 
 impl Torrent {
@@ -215,17 +373,41 @@ impl Torrent {
             info_hash,
             storage_info,
             own_pieces,
+            file_priorities,
             trackers,
             client_id,
             listen_addr,
+            ipv6_addr,
             conf,
             alert_tx,
+            resume_data,
+            needs_check,
+            extensions,
+            is_private,
+            dht,
+            connector,
         } = params;
 
         let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
-        let piece_picker = PiecePicker::new(own_pieces);
+        let (dht_peers_tx, dht_peers_rx) = mpsc::unbounded_channel();
+        let mut piece_picker = PiecePicker::new(own_pieces);
+        piece_picker.set_piece_priorities(
+            &storage_info.piece_priorities(&file_priorities),
+        );
+        piece_picker.set_sequential(conf.sequential_download);
+        let is_complete = piece_picker.missing_piece_count() == 0;
         let cmd_rx = cmd_rx.fuse();
-        let trackers = trackers.into_iter().map(TrackerEntry::new).collect();
+        // the trackers within a tier are tried in a random order, see BEP 12
+        let mut rng = rand::thread_rng();
+        let trackers = trackers
+            .into_iter()
+            .map(|tier| {
+                let mut tier: Vec<_> =
+                    tier.into_iter().map(TrackerEntry::new).collect();
+                tier.shuffle(&mut rng);
+                tier
+            })
+            .collect();
         let completed_pieces = if conf.alerts.completed_pieces {
             Some(Vec::new())
         } else {
@@ -246,16 +428,35 @@ impl Torrent {
                     alert_tx,
                     disk_tx,
                     storage: storage_info,
+                    extensions,
+                    is_private,
+                    dht,
+                    encryption: conf.encryption,
+                    connector,
                 }),
+                file_priorities,
+                state: TorrentState::Active,
+                needs_check,
+                resume_after_check: true,
                 start_time: None,
                 run_duration: Duration::default(),
                 cmd_rx,
                 trackers,
                 in_endgame: false,
+                is_complete,
                 counters: Default::default(),
                 listen_addr,
+                ipv6_addr,
+                last_pex_time: None,
+                choker: Choker::default(),
+                last_choke_time: None,
+                dht_peers_tx,
+                dht_peers_rx: dht_peers_rx.fuse(),
+                last_dht_query_time: None,
                 conf,
+                resume_data,
                 completed_pieces,
+                piece_waiters: HashMap::new(),
             },
             cmd_tx,
         )
@@ -265,23 +466,25 @@ impl Torrent {
     pub async fn start(&mut self, peers: &[SocketAddr]) -> Result<()> {
         log::info!("Starting torrent");
 
-        self.available_peers.extend_from_slice(peers);
+        self.available_peers
+            .extend(peers.iter().map(|addr| (*addr, PexFlags::default())));
+
+        if let Some(resume_data) = self.resume_data.take() {
+            self.restore(resume_data).await?;
+        }
 
         // record the torrent starttime
         self.start_time = Some(Instant::now());
 
-        // if the torrent is a seed, don't send the started event, just an
-        // empty announce
-        let tracker_event =
-            if self.ctx.piece_picker.read().await.missing_piece_count() == 0 {
-                None
-            } else {
-                Some(Event::Started)
-            };
-        if let Err(e) = self
-            .announce_to_trackers(Instant::now(), tracker_event)
-            .await
-        {
+        // if the torrent has existing files, we need to verify them before we
+        // can announce ourselves, which is done once the check is complete
+        let start_result = if self.needs_check {
+            self.needs_check = false;
+            self.start_check(true)
+        } else {
+            self.announce_start().await
+        };
+        if let Err(e) = start_result {
             // this is a torrent error, not a tracker error, as that is handled
             // inside the function
             self.ctx
@@ -312,12 +515,6 @@ impl Torrent {
         let mut tick_timer = time::interval(Duration::from_secs(1)).fuse();
         let mut last_tick_time = None;
 
-        let mut listener = TcpListener::bind(&self.listen_addr).await?;
-        // the bind port may have been 0, so we need to get the actual port in
-        // use
-        self.listen_addr = listener.local_addr()?;
-        let mut incoming = listener.incoming().fuse();
-
         // the torrent loop is triggered every second by the loop timer and by
         // disk IO events
         loop {
@@ -325,32 +522,22 @@ impl Torrent {
                 tick_time = tick_timer.select_next_some() => {
                     self.tick(&mut last_tick_time, tick_time.into_std()).await?;
                 }
-                peer_conn_result = incoming.select_next_some() => {
-                    let socket = match peer_conn_result {
-                        Ok(socket) => socket,
-                        Err(e) => {
-                            log::info!("Error accepting peer connection: {}", e);
-                            continue;
-                        }
-                    };
-                    let addr = match socket.peer_addr() {
-                        Ok(addr) => addr,
-                        Err(e) => {
-                            log::info!("Error getting socket address of peer: {}", e);
-                            continue;
-                        }
-                    };
-                    log::info!("New connection {:?}", addr);
-
-                    // start inbound session
-                    let (session, tx) = PeerSession::new(
-                        Arc::clone(&self.ctx),
-                        addr,
+                peers = self.dht_peers_rx.select_next_some() => {
+                    let peers = peers
+                        .into_iter()
+                        .map(|addr| (addr, PexFlags::default()));
+                    let added_count = self.add_available_peers(peers).await;
+                    log::debug!(
+                        "DHT returned {} new peer(s), available: {}",
+                        added_count,
+                        self.available_peers.len()
                     );
-                    self.peers.insert(addr, PeerSessionEntry::start_inbound(socket, session, tx));
                 }
                 cmd = self.cmd_rx.select_next_some() => {
                     match cmd {
+                        Command::IncomingPeer(peer) => {
+                            self.handle_incoming_peer(*peer);
+                        }
                         Command::PeerConnected { addr, id } => {
                             if let Some(peer) = self.peers.get_mut(&addr) {
                                 log::debug!(
@@ -359,14 +546,94 @@ impl Torrent {
                                     addr, String::from_utf8_lossy(&id)
                                 );
                                 peer.id = Some(id);
+                                // we managed to connect to the peer, so it's
+                                // reachable by others too
+                                if peer.direction == Direction::Outbound {
+                                    peer.pex_flags.set(PexFlags::REACHABLE, true);
+                                }
                             }
                         }
                         Command::PeerState { addr, info } => {
                             self.handle_peer_state_change(addr, info);
                         }
+                        Command::PexPeers { addr, peers } => {
+                            self.handle_pex_peers(addr, peers).await;
+                        }
+                        Command::Pause => {
+                            self.pause().await?;
+                        }
+                        Command::Resume => {
+                            self.resume().await?;
+                        }
+                        Command::SaveResumeData => {
+                            self.save_resume_data().await?;
+                        }
+                        Command::ForceRecheck => {
+                            self.force_recheck().await?;
+                        }
+                        Command::SetFilePriorities(priorities) => {
+                            self.set_file_priorities(priorities).await?;
+                        }
+                        Command::SetSequentialDownload(sequential) => {
+                            log::info!("Setting sequential download: {}", sequential);
+                            self.conf.sequential_download = sequential;
+                            self.ctx
+                                .piece_picker
+                                .write()
+                                .await
+                                .set_sequential(sequential);
+                        }
+                        Command::SetPieceDeadline { index, deadline } => {
+                            self.set_piece_deadline(index, deadline).await?;
+                        }
+                        Command::ResetPieceDeadline(index) => {
+                            self.reset_piece_deadline(index).await;
+                        }
+                        Command::OpenFile { file_index, result_tx } => {
+                            self.open_file(file_index, result_tx).await?;
+                        }
+                        Command::StreamPieces { pieces, piece_tx } => {
+                            self.stream_pieces(pieces, piece_tx).await;
+                        }
+                        Command::ResetStreamPieces(pieces) => {
+                            self.reset_stream_pieces(pieces).await;
+                        }
+                        Command::CheckProgress { checked_count } => {
+                            if let TorrentState::Checking { .. } = self.state {
+                                self.state =
+                                    TorrentState::Checking { checked_count };
+                                self.ctx.alert_tx.send(
+                                    Alert::TorrentCheckProgress {
+                                        id: self.ctx.id,
+                                        checked_count,
+                                        piece_count: self.ctx.storage.piece_count,
+                                    },
+                                )?;
+                            }
+                        }
+                        Command::CheckComplete(own_pieces) => {
+                            self.handle_check_complete(own_pieces).await?;
+                        }
+                        Command::ResumeData(data) => {
+                            log::info!("Resume data saved");
+                            self.ctx.alert_tx.send(Alert::ResumeData {
+                                id: self.ctx.id,
+                                data,
+                            })?;
+                        }
                         Command::PieceCompletion(write_result) => {
                             log::debug!("Disk write result {:?}", write_result);
                             match write_result {
+                                // the pieces we have are determined by the
+                                // check, and this write may have been in
+                                // flight when it started
+                                Ok(_) if matches!(
+                                    self.state,
+                                    TorrentState::Checking { .. }
+                                ) =>
+                                {
+                                    log::debug!("Discarding write during check");
+                                }
                                 Ok(piece) => {
                                     self.handle_piece_completion(piece).await?;
                                 }
@@ -390,6 +657,12 @@ impl Torrent {
                             // the torrent was still seeding. In this case we'd need to stop
                             // torrent and send an alert to the API consumer.
                         }
+                        Command::AnnounceResult { url, event, result } => {
+                            self.handle_announce_result(url, event, result).await?;
+                        }
+                        Command::ScrapeResult { url, stats } => {
+                            self.handle_scrape_result(url, stats);
+                        }
                         Command::Shutdown => {
                             self.shutdown().await?;
                             break;
@@ -402,6 +675,140 @@ impl Torrent {
         Ok(())
     }
 
+    /// Starts an inbound session with the peer whose connection the engine
+    /// accepted.
+    fn handle_incoming_peer(&mut self, peer: IncomingPeer) {
+        let IncomingPeer {
+            addr,
+            socket,
+            handshake,
+            crypto,
+        } = peer;
+        if self.state != TorrentState::Active {
+            log::info!("Torrent not active, rejecting connection {}", addr);
+            return;
+        }
+        if !mse::is_allowed(self.ctx.encryption, crypto) {
+            log::info!(
+                "Connection {} ({:?}) not allowed by encryption policy {:?}",
+                addr,
+                crypto,
+                self.ctx.encryption
+            );
+            return;
+        }
+        if self.peers.contains_key(&addr) {
+            log::info!("Already connected to {}, rejecting connection", addr);
+            return;
+        }
+        log::info!("New connection {}", addr);
+
+        let (session, tx) = PeerSession::new(Arc::clone(&self.ctx), addr);
+        self.peers.insert(
+            addr,
+            PeerSessionEntry::start_inbound(socket, handshake, session, tx),
+        );
+    }
+
+    /// Restores the torrent's state from resume data, which must have been
+    /// validated beforehand.
+    ///
+    /// The pieces we have are already set up in the piece picker by this
+    /// point, but the in-progress piece downloads are restored here, with
+    /// their blocks sent to the disk task's write buffer.
+    async fn restore(&mut self, resume_data: ResumeData) -> Result<()> {
+        log::info!("Restoring torrent from resume data");
+
+        self.counters.payload.up = Counter::with_total(resume_data.uploaded);
+        self.counters.payload.down =
+            Counter::with_total(resume_data.downloaded);
+
+        for tracker in resume_data.trackers.into_iter() {
+            if let Some(entry) = self
+                .trackers
+                .iter_mut()
+                .flatten()
+                .find(|t| t.client.url().as_str() == tracker.url)
+            {
+                entry.id = tracker.tracker_id;
+            }
+        }
+
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        let mut downloads = self.ctx.downloads.write().await;
+        for piece in resume_data.partial_pieces.into_iter() {
+            if piece.blocks.is_empty() || piece_picker.own_pieces()[piece.index]
+            {
+                continue;
+            }
+            log::debug!(
+                "Restoring piece {} download with {} block(s)",
+                piece.index,
+                piece.blocks.len()
+            );
+
+            let mut download = PieceDownload::new(
+                piece.index,
+                self.ctx.storage.piece_len(piece.index),
+            );
+            for block in piece.blocks.into_iter() {
+                let block_info = BlockInfo {
+                    piece_index: piece.index,
+                    offset: block.offset,
+                    len: block.data.len() as u32,
+                };
+                download.restore_block(&block_info);
+                self.ctx.disk_tx.send(disk::Command::WriteBlock {
+                    id: self.ctx.id,
+                    block_info,
+                    data: block.data,
+                })?;
+            }
+            piece_picker.mark_pending(piece.index);
+            downloads.insert(piece.index, RwLock::new(download));
+        }
+
+        Ok(())
+    }
+
+    /// Collects the torrent level resume data and sends it to disk to
+    /// complete it.
+    async fn save_resume_data(&self) -> Result<()> {
+        let own_pieces = self
+            .ctx
+            .piece_picker
+            .read()
+            .await
+            .own_pieces()
+            .as_slice()
+            .to_vec();
+        let trackers = self
+            .trackers
+            .iter()
+            .flatten()
+            .map(|t| TrackerResumeData {
+                url: t.client.url().to_string(),
+                tracker_id: t.id.clone(),
+            })
+            .collect();
+        let resume_data = ResumeData {
+            info_hash: self.ctx.info_hash,
+            piece_count: self.ctx.storage.piece_count,
+            own_pieces,
+            partial_pieces: Vec::new(),
+            files: Vec::new(),
+            file_priorities: self.file_priorities.clone(),
+            trackers,
+            uploaded: self.counters.payload.up.total(),
+            downloaded: self.counters.payload.down.total(),
+        };
+        self.ctx.disk_tx.send(disk::Command::SaveResumeData {
+            id: self.ctx.id,
+            resume_data: Box::new(resume_data),
+        })?;
+        Ok(())
+    }
+
     /// The torrent tick, as in "the tick of a clock", which runs every second
     /// to perform periodic updates.
     ///
@@ -412,23 +819,38 @@ impl Torrent {
         last_tick_time: &mut Option<Instant>,
         now: Instant,
     ) -> Result<()> {
-        // calculate how long torrent has been running
-
+        // calculate how long torrent has been running, not counting the time
+        // it was paused
         let elapsed_since_last_tick = last_tick_time
             .or(self.start_time)
             .map(|t| now.saturating_duration_since(t))
             .unwrap_or_default();
-        self.run_duration += elapsed_since_last_tick;
         *last_tick_time = Some(now);
 
-        // check if we can connect some peers
-        // NOTE: do this before announcing as we don't want to block new
-        // connections with the potentially long running announce requests
-        self.connect_peers();
+        if self.state == TorrentState::Active {
+            self.run_duration += elapsed_since_last_tick;
+
+            // check if we can connect some peers
+            // NOTE: do this before announcing as we don't want to block new
+            // connections with the potentially long running announce requests
+            self.connect_peers();
+
+            // decide which peers we upload to
+            self.choke_peers(now).await;
 
-        // check if we need to announce to some trackers
-        let event = None;
-        self.announce_to_trackers(now, event).await?;
+            // make sure the pieces with a deadline are downloaded in time
+            self.update_deadline_downloads(now).await;
+
+            // tell our peers about the peers we're connected to
+            self.send_pex_peers(now);
+
+            // get peers from and announce ourselves to the DHT
+            self.query_dht(now);
+
+            // check if we need to announce to some trackers
+            let event = None;
+            self.announce_to_trackers(now, event).await;
+        }
 
         log::debug!(
             "Stats: \
@@ -493,163 +915,603 @@ impl Torrent {
         }
 
         log::debug!("Connecting {} peer(s)", connect_count);
-        for addr in self.available_peers.drain(0..connect_count) {
+        for (addr, pex_flags) in self.available_peers.drain(0..connect_count) {
             log::info!("Connecting to peer {}", addr);
             let (session, tx) = PeerSession::new(Arc::clone(&self.ctx), addr);
+            let mut entry = PeerSessionEntry::start_outbound(session, tx);
+            entry.pex_flags = pex_flags;
+            self.peers.insert(addr, entry);
+        }
+    }
+
+    /// Adds the peers received via peer exchange to the peers we can connect
+    /// to.
+    async fn handle_pex_peers(
+        &mut self,
+        source: SocketAddr,
+        peers: Vec<(SocketAddr, PexFlags)>,
+    ) {
+        if self.ctx.is_private {
+            return;
+        }
+        let added_count = self.add_available_peers(peers).await;
+        log::debug!(
+            "Peer {} sent {} new peer(s), available: {}",
+            source,
+            added_count,
+            self.available_peers.len()
+        );
+    }
+
+    /// Adds the peers to the peers we can connect to, unless we already know
+    /// them, and returns the number of added peers.
+    ///
+    /// If we're a seed, other seeds are of no use to us, so they're skipped.
+    async fn add_available_peers(
+        &mut self,
+        peers: impl IntoIterator<Item = (SocketAddr, PexFlags)>,
+    ) -> usize {
+        let is_seed =
+            self.ctx.piece_picker.read().await.missing_piece_count() == 0;
+        let mut added_count = 0;
+        for (addr, flags) in peers.into_iter() {
+            if self.available_peers.len() >= MAX_AVAILABLE_PEER_COUNT {
+                break;
+            }
+            if (is_seed && flags.contains(PexFlags::SEED))
+                || addr == self.listen_addr
+                || self.peers.contains_key(&addr)
+                || self.available_peers.iter().any(|(a, _)| *a == addr)
+            {
+                continue;
+            }
+            self.available_peers.push((addr, flags));
+            added_count += 1;
+        }
+        added_count
+    }
+
+    /// Asks the DHT for peers, which also announces the torrent to it.
+    ///
+    /// This is done more often while we're short of peers.
+    fn query_dht(&mut self, now: Instant) {
+        let dht = match &self.ctx.dht {
+            Some(dht) => dht,
+            None => return,
+        };
+        let interval = if self.peers.len() + self.available_peers.len()
+            < self.conf.min_requested_peer_count
+        {
+            dht::MIN_QUERY_INTERVAL
+        } else {
+            dht::ANNOUNCE_INTERVAL
+        };
+        if let Some(last_dht_query_time) = self.last_dht_query_time {
+            if now.saturating_duration_since(last_dht_query_time) < interval {
+                return;
+            }
+        }
+        self.last_dht_query_time = Some(now);
+
+        log::debug!("Querying DHT for peers");
+        let cmd = dht::Command::GetPeers {
+            info_hash: self.ctx.info_hash,
+            announce_port: Some(self.listen_addr.port()),
+            peers_tx: self.dht_peers_tx.clone(),
+        };
+        if dht.tx.send(cmd).is_err() {
+            log::warn!("DHT task is not running");
+        }
+    }
+
+    /// Recalculates which of the interested peers we upload to, choking the
+    /// rest, see [`Choker`].
+    ///
+    /// While downloading, peers are ranked by how fast they upload to us, and
+    /// while seeding, by how fast we upload to them.
+    async fn choke_peers(&mut self, now: Instant) {
+        if let Some(last_choke_time) = self.last_choke_time {
+            if now.saturating_duration_since(last_choke_time) < CHOKE_INTERVAL {
+                return;
+            }
+        }
+        self.last_choke_time = Some(now);
+
+        let is_seed =
+            self.ctx.piece_picker.read().await.missing_piece_count() == 0;
+        let candidates = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.state.connection == ConnectionState::Connected
+                    && peer.state.is_peer_interested
+            })
+            .map(|(addr, peer)| Candidate {
+                addr: *addr,
+                rate: if is_seed {
+                    peer.thruput.payload.up.rate
+                } else {
+                    peer.thruput.payload.down.rate
+                },
+            })
+            .collect();
+        let unchoked =
+            self.choker
+                .unchoke(now, candidates, self.conf.upload_slot_count);
+
+        // only tell the sessions whose state needs to change
+        for (addr, peer) in self.peers.iter() {
+            let should_unchoke = unchoked.contains(addr);
+            if should_unchoke != peer.state.is_peer_choked {
+                continue;
+            }
+            if let Some(tx) = &peer.tx {
+                let cmd = if should_unchoke {
+                    peer::Command::Unchoke
+                } else {
+                    peer::Command::Choke
+                };
+                // the session may have stopped in the meantime
+                tx.send(cmd).ok();
+            }
+        }
+    }
+
+    /// Marks the downloads of the pieces at risk of missing their deadline as
+    /// urgent, and tells the fastest peers that they are, as they are the ones
+    /// that download the pieces with a deadline.
+    ///
+    /// The fastest peers are the ones that upload to us the fastest among the
+    /// peers that unchoked us.
+    async fn update_deadline_downloads(&mut self, now: Instant) {
+        let deadlines = self
+            .ctx
+            .piece_picker
+            .read()
+            .await
+            .deadlines()
+            .collect::<Vec<_>>();
+
+        let downloads = self.ctx.downloads.read().await;
+        for (deadline, index) in deadlines.iter() {
+            if let Some(download) = downloads.get(index) {
+                let is_urgent = is_deadline_at_risk(*deadline, now);
+                let mut download = download.write().await;
+                if is_urgent && !download.is_urgent() {
+                    log::info!(
+                        "Piece {} is at risk of missing its deadline",
+                        index
+                    );
+                }
+                download.set_urgent(is_urgent);
+            }
+        }
+        drop(downloads);
+
+        // no peer needs to be fast if there are no deadlines
+        let candidates = if deadlines.is_empty() {
+            Vec::new()
+        } else {
             self.peers
-                .insert(addr, PeerSessionEntry::start_outbound(session, tx));
+                .iter()
+                .filter(|(_, peer)| {
+                    peer.state.connection == ConnectionState::Connected
+                        && !peer.state.is_choked
+                })
+                .map(|(addr, peer)| (*addr, peer.thruput.payload.down.rate))
+                .collect()
+        };
+        let fast_peers = pick_fast_peers(candidates);
+
+        // only tell the sessions whose state needs to change
+        for (addr, peer) in self.peers.iter_mut() {
+            let is_fast = fast_peers.contains(addr);
+            if is_fast == peer.is_fast {
+                continue;
+            }
+            peer.is_fast = is_fast;
+            if let Some(tx) = &peer.tx {
+                // the session may have stopped in the meantime
+                tx.send(peer::Command::SetFast(is_fast)).ok();
+            }
+        }
+    }
+
+    /// Sends the peers we're connected to to the peer sessions, which then
+    /// advertise them to their peers via peer exchange.
+    ///
+    /// Only the peers we connected to are advertised, as the addresses of
+    /// inbound peers are not the ones they're listening on.
+    fn send_pex_peers(&mut self, now: Instant) {
+        if self.ctx.is_private {
+            return;
+        }
+        if let Some(last_pex_time) = self.last_pex_time {
+            if now.saturating_duration_since(last_pex_time) < PEX_INTERVAL {
+                return;
+            }
+        }
+        self.last_pex_time = Some(now);
+
+        let pex_peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.direction == Direction::Outbound
+                    && peer.state.connection == ConnectionState::Connected
+            })
+            .map(|(addr, peer)| (*addr, peer.pex_flags))
+            .collect();
+        log::debug!("Sending {} peer(s) for peer exchange", pex_peers.len());
+        let pex_peers = Arc::new(pex_peers);
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                tx.send(peer::Command::PexPeers(Arc::clone(&pex_peers)))
+                    .ok();
+            }
         }
     }
 
     /// Chacks whether we need to announce to any trackers of if we need to request
     /// peers.
+    ///
+    /// Unless configured to announce to all trackers, the tracker tiers are
+    /// used as described in BEP 12: we only announce to the first tracker
+    /// that responds, trying the trackers in order, and the tracker that
+    /// responds is moved to the front of its tier, so that it's tried first
+    /// next time.
+    ///
+    /// The announces are run on separate tasks, so that slow trackers don't
+    /// block the torrent, and their results are returned via
+    /// [`Command::AnnounceResult`].
+    ///
+    /// The stopped event is not announced this way, see
+    /// [`Self::announce_stop`].
     async fn announce_to_trackers(
         &mut self,
         now: Instant,
         event: Option<Event>,
-    ) -> Result<()> {
-        // calculate transfer statistics in advance
+    ) {
+        let announce_to_all = self.conf.announce_to_all_trackers;
+        // only computed if we do announce, as it goes through all pieces
+        let mut left = None;
+        for tier in 0..self.trackers.len() {
+            for index in 0..self.trackers[tier].len() {
+                let tracker = &self.trackers[tier][index];
+                // events are always announced, but otherwise we wait for the
+                // tracker's previous response
+                if tracker.state == TrackerState::Updating && event.is_none() {
+                    if announce_to_all {
+                        continue;
+                    }
+                    return;
+                }
+
+                let needed_peer_count = self.needed_peer_count();
+                // a failing tracker is only retried once its retry delay has
+                // elapsed, but otherwise we can override the normal announce
+                // interval if we need peers or if we have an event to announce
+                let needs_announce = match tracker.retry_time {
+                    Some(retry_time) => now >= retry_time,
+                    None => {
+                        event.is_some()
+                            || (needed_peer_count > Some(0)
+                                && tracker.can_announce(
+                                    now,
+                                    self.conf.announce_interval,
+                                ))
+                            || tracker.should_announce(
+                                now,
+                                self.conf.announce_interval,
+                            )
+                    }
+                };
+                if !needs_announce {
+                    // the first tracker that isn't failing is the one we're
+                    // announcing to, so we don't need to try the others
+                    if announce_to_all || tracker.retry_time.is_some() {
+                        continue;
+                    }
+                    return;
+                }
+
+                let left = match left {
+                    Some(left) => left,
+                    None => *left.get_or_insert(self.missing_len().await),
+                };
+                self.spawn_announce(
+                    tier,
+                    index,
+                    now,
+                    event,
+                    needed_peer_count,
+                    left,
+                );
+                // if the announce fails, the next tracker is tried once we
+                // get the result
+                if !announce_to_all {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Tells all trackers we announced to that we're stopping, and returns the
+    /// number of announces sent.
+    ///
+    /// Unlike other events, the stop is announced to the trackers of all tiers
+    /// at once, without failing over between them, as the torrent may wait
+    /// for the responses before it stops. For the same reason, these
+    /// announces have their own, shorter timeout.
+    async fn announce_stop(&mut self, now: Instant) -> usize {
+        let left = self.missing_len().await;
+        let mut count = 0;
+        for tier in 0..self.trackers.len() {
+            for index in 0..self.trackers[tier].len() {
+                // trackers we never announced to don't know about us
+                if self.trackers[tier][index].last_announce_time.is_some() {
+                    self.spawn_announce(
+                        tier,
+                        index,
+                        now,
+                        Some(Event::Stopped),
+                        None,
+                        left,
+                    );
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    /// Returns the number of peers to request from trackers, if any.
+    ///
+    /// Peers are only requested if the torrent's peer count has fallen below
+    /// the minimum.
+    fn needed_peer_count(&self) -> Option<usize> {
+        let peer_count = self.peers.len() + self.available_peers.len();
+        if peer_count >= self.conf.min_requested_peer_count {
+            None
+        } else {
+            debug_assert!(self.conf.max_connected_peer_count >= peer_count);
+            let needed = self.conf.max_connected_peer_count - peer_count;
+            // Download at least this numbe of peers, even if we don't need
+            // as many. This is because later we may be able to connect to
+            // more peers and in that case we don't want to wait till the
+            // next tracker request.
+            Some(self.conf.min_requested_peer_count.max(needed))
+        }
+    }
+
+    /// Returns the number of bytes left to download, which is the length of
+    /// the missing pieces we want.
+    ///
+    /// This can't be derived from the downloaded total, as that includes
+    /// pieces that were downloaded more than once and doesn't include the
+    /// pieces we had on startup.
+    async fn missing_len(&self) -> u64 {
+        let piece_picker = self.ctx.piece_picker.read().await;
+        piece_picker
+            .missing_pieces()
+            .map(|index| self.ctx.storage.piece_len(index) as u64)
+            .sum()
+    }
+
+    /// Spawns a task that announces to the tracker at the index of the tier
+    /// and sends the result back to the torrent.
+    fn spawn_announce(
+        &mut self,
+        tier: usize,
+        index: usize,
+        now: Instant,
+        event: Option<Event>,
+        peer_count: Option<usize>,
+        left: u64,
+    ) {
+        // calculate transfer statistics
         let uploaded = self.counters.payload.up.total();
         let downloaded = self.counters.payload.down.total();
-        let left = self.ctx.storage.download_len - downloaded;
 
-        // skip trackers that errored too often
-        // TODO: introduce a retry timeout
-        let tracker_error_threshold = self.conf.tracker_error_threshold;
-        for tracker in self
-            .trackers
-            .iter_mut()
-            .filter(|t| t.error_count < tracker_error_threshold)
-        {
-            // Check if the torrent's peer count has fallen below the minimum.
-            // But don't request new peers otherwise or if we're about to stop
-            // torrent.
-            let peer_count = self.peers.len() + self.available_peers.len();
-            let needed_peer_count = if peer_count
-                >= self.conf.min_requested_peer_count
-                || event == Some(Event::Stopped)
-            {
-                None
-            } else {
-                debug_assert!(self.conf.max_connected_peer_count >= peer_count);
-                let needed = self.conf.max_connected_peer_count - peer_count;
-                // Download at least this numbe of peers, even if we don't need
-                // as many. This is because later we may be able to connect to
-                // more peers and in that case we don't want to wait till the
-                // next tracker request.
-                Some(self.conf.min_requested_peer_count.max(needed))
-            };
+        let tracker = &mut self.trackers[tier][index];
+        let params = Announce {
+            tracker_id: tracker.id.clone(),
+            info_hash: self.ctx.info_hash,
+            peer_id: self.ctx.client_id,
+            port: self.listen_addr.port(),
+            peer_count,
+            uploaded,
+            downloaded,
+            left,
+            ip: None,
+            ipv6: self.ipv6_addr,
+            event,
+        };
+        tracker.last_announce_time = Some(now);
+        tracker.state = TrackerState::Updating;
 
-            // we can override the normal annoucne interval if we need peers or
-            // if we have an event to announce
-            if event.is_some()
-                || (needed_peer_count > Some(0)
-                    && tracker.can_announce(now, self.conf.announce_interval))
-                || tracker.should_announce(now, self.conf.announce_interval)
-            {
-                let params = Announce {
-                    tracker_id: tracker.id.clone(),
-                    info_hash: self.ctx.info_hash,
-                    peer_id: self.ctx.client_id,
-                    port: self.listen_addr.port(),
-                    peer_count: needed_peer_count,
-                    uploaded,
-                    downloaded,
-                    left,
-                    ip: None,
-                    event,
+        let client = Arc::clone(&tracker.client);
+        let cmd_tx = self.ctx.cmd_tx.clone();
+        let timeout = if event == Some(Event::Stopped) {
+            self.conf.stop_announce_timeout
+        } else {
+            self.conf.announce_timeout
+        };
+        task::spawn(async move {
+            let result =
+                match time::timeout(timeout, client.announce(params)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(TrackerError::Timeout),
                 };
-                // TODO: We probably don't want to block the torrent event loop
-                // here waiting on the tracker response. Instead, poll the
-                // future in the event loop select call, or spawn the tracker
-                // announce on a separate task and return the result as
-                // an mpsc message.
-                match tracker.client.announce(params).await {
-                    Ok(resp) => {
-                        log::info!(
-                            "Announced to tracker {}, response: {:?}",
-                            tracker.client,
-                            resp
-                        );
-                        if let Some(tracker_id) = resp.tracker_id {
-                            tracker.id = Some(tracker_id);
-                        }
-                        if let Some(failure_reason) = resp.failure_reason {
-                            log::warn!(
-                                "Error contacting tracker {}: {}",
-                                tracker.client,
-                                failure_reason
-                            );
-                        }
-                        if let Some(warning_message) = resp.warning_message {
-                            log::warn!(
-                                "Warning from tracker {}: {}",
-                                tracker.client,
-                                warning_message
-                            );
-                        }
-                        if let Some(interval) = resp.interval {
-                            log::info!(
-                                "Tracker {} interval: {} s",
-                                tracker.client,
-                                interval.as_secs()
-                            );
-                            tracker.interval = Some(interval);
-                        }
-                        if let Some(min_interval) = resp.min_interval {
-                            log::info!(
-                                "Tracker {} min min_interval: {} s",
-                                tracker.client,
-                                min_interval.as_secs()
-                            );
-                            tracker.min_interval = Some(min_interval);
-                        }
+            // the torrent may have stopped in the meantime
+            cmd_tx
+                .send(Command::AnnounceResult {
+                    url: client.url().clone(),
+                    event,
+                    result,
+                })
+                .ok();
+        });
+    }
 
-                        if let (Some(seeder_count), Some(leecher_count)) =
-                            (resp.seeder_count, resp.leecher_count)
-                        {
-                            log::debug!(
-                                "Torrent seeds: {} and leeches: {}",
-                                seeder_count,
-                                leecher_count
-                            );
-                        }
+    /// Updates the tracker's state with the result of an announce to it, and
+    /// if it failed, tries the next tracker.
+    async fn handle_announce_result(
+        &mut self,
+        url: Url,
+        event: Option<Event>,
+        result: tracker::Result<Response>,
+    ) -> Result<()> {
+        let (tier, index) = match self.trackers.iter().enumerate().find_map(
+            |(tier, trackers)| {
+                trackers
+                    .iter()
+                    .position(|t| t.client.url() == &url)
+                    .map(|index| (tier, index))
+            },
+        ) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
 
-                        if !resp.peers.is_empty() {
-                            log::debug!(
-                                "Received peers from tracker {}: {:?}",
-                                tracker.client,
-                                resp.peers
-                            );
-                            self.available_peers.extend(resp.peers.into_iter());
-                        }
-                    }
-                    Err(e) => {
-                        log::warn!(
-                            "Error announcing to tracker {}: {}",
-                            tracker.client,
-                            e
-                        );
-                        tracker.error_count += 1;
-                        self.ctx.alert_tx.send(Alert::Error(
-                            Error::Tracker {
-                                id: self.ctx.id,
-                                error: e,
-                            },
-                        ))?;
-                    }
+        let tracker = &mut self.trackers[tier][index];
+        match result {
+            Ok(resp) => {
+                log::info!(
+                    "Announced to tracker {}, response: {:?}",
+                    tracker.client,
+                    resp
+                );
+                tracker.state = TrackerState::Working;
+                tracker.error_count = 0;
+                tracker.retry_time = None;
+                if let Some(tracker_id) = resp.tracker_id {
+                    tracker.id = Some(tracker_id);
+                }
+                if let Some(warning_message) = resp.warning_message {
+                    log::warn!(
+                        "Warning from tracker {}: {}",
+                        tracker.client,
+                        warning_message
+                    );
+                }
+                if let Some(interval) = resp.interval {
+                    log::info!(
+                        "Tracker {} interval: {} s",
+                        tracker.client,
+                        interval.as_secs()
+                    );
+                    tracker.interval = Some(interval);
+                }
+                if let Some(min_interval) = resp.min_interval {
+                    log::info!(
+                        "Tracker {} min min_interval: {} s",
+                        tracker.client,
+                        min_interval.as_secs()
+                    );
+                    tracker.min_interval = Some(min_interval);
+                }
+
+                if let (Some(seeder_count), Some(leecher_count)) =
+                    (resp.seeder_count, resp.leecher_count)
+                {
+                    log::debug!(
+                        "Torrent seeds: {} and leeches: {}",
+                        seeder_count,
+                        leecher_count
+                    );
+                }
+                if resp.seeder_count.is_some() {
+                    tracker.swarm.seeder_count = resp.seeder_count;
+                }
+                if resp.leecher_count.is_some() {
+                    tracker.swarm.leecher_count = resp.leecher_count;
+                }
+
+                if !resp.peers.is_empty() {
+                    log::debug!(
+                        "Received peers from tracker {}: {:?}",
+                        tracker.client,
+                        resp.peers
+                    );
+                }
+
+                if !self.conf.announce_to_all_trackers {
+                    let tier = &mut self.trackers[tier];
+                    let tracker = tier.remove(index);
+                    tier.insert(0, tracker);
+                }
+
+                let added_count = self
+                    .add_available_peers(
+                        resp.peers
+                            .into_iter()
+                            .map(|addr| (addr, PexFlags::default())),
+                    )
+                    .await;
+                log::debug!(
+                    "Added {} new peer(s) from tracker, available: {}",
+                    added_count,
+                    self.available_peers.len()
+                );
+            }
+            Err(e) => {
+                tracker.error_count += 1;
+                let retry_delay = tracker::retry_delay(
+                    tracker.error_count,
+                    self.conf.tracker_retry_interval,
+                    self.conf.max_tracker_retry_interval,
+                );
+                log::warn!(
+                    "Error announcing to tracker {}: {}, retrying in {} s",
+                    tracker.client,
+                    e,
+                    retry_delay.as_secs()
+                );
+                tracker.retry_time = Some(Instant::now() + retry_delay);
+                tracker.state = TrackerState::Failed(e.to_string());
+                self.ctx.alert_tx.send(Alert::Error(Error::Tracker {
+                    id: self.ctx.id,
+                    error: e,
+                }))?;
+
+                // fail over to the next tracker with the same event, which
+                // skips the tracker that just failed, except for the stop,
+                // which was sent to all trackers already
+                if !self.conf.announce_to_all_trackers
+                    && event != Some(Event::Stopped)
+                {
+                    self.announce_to_trackers(Instant::now(), event).await;
                 }
-                tracker.last_announce_time = Some(now);
             }
         }
 
         Ok(())
     }
 
+    /// Updates the swarm statistics of the tracker from its scrape response.
+    fn handle_scrape_result(&mut self, url: Url, stats: ScrapeStats) {
+        // the tracker may no longer exist if the torrent changed in the
+        // meantime
+        if let Some(tracker) = self
+            .trackers
+            .iter_mut()
+            .flatten()
+            .find(|t| *t.client.url() == url)
+        {
+            log::debug!("Scraped tracker {}: {:?}", tracker.client, stats);
+            tracker.swarm = SwarmStats {
+                seeder_count: Some(stats.seeder_count),
+                leecher_count: Some(stats.leecher_count),
+                download_count: Some(stats.download_count),
+            };
+        }
+    }
+
     /// Returns high-level statistics about the torrent for sending to the user.
     async fn build_stats(&mut self) -> TorrentStats {
-        let missing_piece_count =
-            self.ctx.piece_picker.read().await.missing_piece_count();
+        let own_piece_count =
+            self.ctx.piece_picker.read().await.own_pieces().count_ones();
         let piece_count = self.ctx.storage.piece_count;
         let completed_pieces = self
             .completed_pieces
@@ -671,38 +1533,79 @@ impl Torrent {
         } else {
             Peers::Count(self.peers.len())
         };
+        let trackers = self
+            .trackers
+            .iter()
+            .enumerate()
+            .flat_map(|(tier, trackers)| {
+                trackers.iter().map(move |t| (tier, t))
+            })
+            .map(|(tier, tracker)| TrackerStats {
+                url: tracker.client.url().clone(),
+                tier,
+                state: tracker.state.clone(),
+                next_announce_time: tracker
+                    .next_announce_time(self.conf.announce_interval),
+                error_count: tracker.error_count,
+                swarm: tracker.swarm,
+            })
+            .collect::<Vec<_>>();
+        let mut swarm = SwarmStats::default();
+        for tracker in trackers.iter() {
+            swarm.merge(&tracker.swarm);
+        }
 
         TorrentStats {
+            state: self.state,
             start_time: self.start_time,
             run_duration: self.run_duration,
             pieces: PieceStats {
                 total: piece_count,
-                complete: piece_count - missing_piece_count,
+                complete: own_piece_count,
                 pending: self.ctx.downloads.read().await.len(),
                 latest_completed: completed_pieces,
             },
             thruput: ThruputStats::from(&self.counters),
             peers,
+            trackers,
+            swarm,
         }
     }
 
     /// Handles the message that peer sessions send to torrent when their state
     /// changed.
     ///
-    /// It simply updates the minimum copy of the peer's state that is kept in
-    /// torrent in order to perform various pieces of logic (the choke
-    /// algorithm and detailed reporting to user, neither of which is done at
-    /// the moment).
+    /// It updates the minimum copy of the peer's state that is kept in torrent
+    /// for the choke algorithm (see [`Self::choke_peers`]) and the per-peer
+    /// stats reported to the user, and removes the peer once it disconnected.
     fn handle_peer_state_change(
         &mut self,
         addr: SocketAddr,
         info: SessionTick,
     ) {
+        let unchoked_count = self
+            .peers
+            .values()
+            .filter(|p| !p.state.is_peer_choked)
+            .count();
         if let Some(peer) = self.peers.get_mut(&addr) {
             log::debug!("Updating peer {} state", addr);
 
+            // if a peer became interested while there is a free upload slot,
+            // don't make it wait for the next choke round
+            if info.state.is_peer_interested
+                && !peer.state.is_peer_interested
+                && unchoked_count <= self.conf.upload_slot_count
+            {
+                self.last_choke_time = None;
+            }
+
             peer.state = info.state;
             peer.piece_count = info.piece_count;
+            peer.pex_flags.set(
+                PexFlags::SEED,
+                peer.piece_count == self.ctx.storage.piece_count,
+            );
             peer.thruput = ThruputStats::from(&info.counters);
 
             // update torrent thruput stats
@@ -723,6 +1626,16 @@ impl Torrent {
         &mut self,
         piece: PieceCompletion,
     ) -> Result<()> {
+        // a piece whose write was in flight when a recheck started is picked
+        // up by the check itself
+        if let TorrentState::Checking { .. } = self.state {
+            log::debug!(
+                "Ignoring piece {} completion during check",
+                piece.index
+            );
+            return Ok(());
+        }
+
         // if this write completed a piece, check torrent
         // completion
         if piece.is_valid {
@@ -736,6 +1649,8 @@ impl Torrent {
             piece_picker_write_guard.received_piece(piece.index);
             let missing_piece_count =
                 piece_picker_write_guard.missing_piece_count();
+            let deadline =
+                piece_picker_write_guard.reset_piece_deadline(piece.index);
 
             // Even if we don't have all pieces, they may all have already
             // been picked. In this case we need to enter endgame mode, if not
@@ -761,6 +1676,16 @@ impl Torrent {
                 latest_completed_pieces.push(piece.index);
             }
 
+            if let Some(deadline) = deadline {
+                self.complete_deadline_piece(piece.index, deadline);
+            }
+            // the streams may have been dropped in the meantime
+            for tx in
+                self.piece_waiters.remove(&piece.index).unwrap_or_default()
+            {
+                tx.send(()).ok();
+            }
+
             // tell all sessions that we got a new piece so that they can send
             // a "have(piece)" message to their peers or cancel potential
             // duplicate requests for the same piece
@@ -779,25 +1704,7 @@ impl Torrent {
 
             // if the torrent is fully downloaded, stop the download loop
             if missing_piece_count == 0 {
-                log::info!(
-                    "Finished torrent download, exiting. \
-                    Peak download rate: {} b/s, wasted: {} b",
-                    self.counters.payload.down.peak(),
-                    self.counters.waste.total(),
-                );
-
-                // notify user of torrent completion
-                self.ctx
-                    .alert_tx
-                    .send(Alert::TorrentComplete(self.ctx.id))
-                    .ok();
-
-                // tell trackers we've finished
-                self.announce_to_trackers(
-                    Instant::now(),
-                    Some(Event::Completed),
-                )
-                .await?;
+                self.complete_download().await;
             }
         } else {
             // TODO(https://github.com/mandreyel/cratetorrent/issues/61):
@@ -814,9 +1721,480 @@ impl Torrent {
         Ok(())
     }
 
+    /// Notifies the user that we have the piece that had a deadline.
+    fn complete_deadline_piece(&self, index: PieceIndex, deadline: Instant) {
+        let is_late = Instant::now() > deadline;
+        if is_late {
+            log::warn!("Piece {} missed its deadline", index);
+        } else {
+            log::info!("Piece {} made its deadline", index);
+        }
+        self.ctx
+            .alert_tx
+            .send(Alert::DeadlinePieceComplete {
+                id: self.ctx.id,
+                index,
+                is_late,
+            })
+            .ok();
+    }
+
+    /// Sets the time by which the piece should be downloaded.
+    ///
+    /// If we already have the piece, the user is notified right away.
+    async fn set_piece_deadline(
+        &mut self,
+        index: PieceIndex,
+        deadline: Instant,
+    ) -> Result<()> {
+        if index >= self.ctx.storage.piece_count {
+            log::warn!("Invalid piece {} deadline", index);
+            self.ctx.alert_tx.send(Alert::Error(Error::Torrent {
+                id: self.ctx.id,
+                error: TorrentError::InvalidPieceIndex,
+            }))?;
+            return Ok(());
+        }
+
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        // during a check we don't know yet whether we have the piece, in which
+        // case the user is notified once the check completes
+        let is_checking = matches!(self.state, TorrentState::Checking { .. });
+        if !is_checking && piece_picker.own_pieces()[index] {
+            drop(piece_picker);
+            self.complete_deadline_piece(index, deadline);
+        } else {
+            log::info!("Setting piece {} deadline", index);
+            piece_picker.set_piece_deadline(index, deadline);
+        }
+
+        Ok(())
+    }
+
+    /// Removes the deadline of the piece, so that it's downloaded like any
+    /// other piece.
+    async fn reset_piece_deadline(&mut self, index: PieceIndex) {
+        log::info!("Resetting piece {} deadline", index);
+        self.ctx
+            .piece_picker
+            .write()
+            .await
+            .reset_piece_deadline(index);
+        if let Some(download) = self.ctx.downloads.read().await.get(&index) {
+            download.write().await.set_urgent(false);
+        }
+    }
+
+    /// Opens a stream of the file with the index and returns it via the
+    /// sender.
+    ///
+    /// If the file was not being downloaded, its priority is raised to the
+    /// default priority.
+    async fn open_file(
+        &mut self,
+        file_index: usize,
+        result_tx: oneshot::Sender<std::result::Result<FileStream, Error>>,
+    ) -> Result<()> {
+        if file_index >= self.ctx.storage.files.len() {
+            log::warn!("Cannot open invalid file {}", file_index);
+            result_tx
+                .send(Err(Error::Torrent {
+                    id: self.ctx.id,
+                    error: TorrentError::InvalidFileIndex,
+                }))
+                .ok();
+            return Ok(());
+        }
+
+        if self.file_priorities[file_index] == 0 {
+            let mut priorities = self.file_priorities.clone();
+            priorities[file_index] = DEFAULT_PRIORITY;
+            self.set_file_priorities(priorities).await?;
+        }
+
+        log::info!("Opening stream of file {}", file_index);
+        let stream = FileStream::new(
+            self.ctx.id,
+            self.ctx.cmd_tx.clone(),
+            self.ctx.disk_tx.clone(),
+            self.ctx.storage.clone(),
+            file_index,
+        );
+        // the user may have stopped waiting for the stream
+        result_tx.send(Ok(stream)).ok();
+        Ok(())
+    }
+
+    /// Sets deadlines for the pieces a file stream is about to read, and
+    /// notifies the stream via the sender once we have the first one.
+    ///
+    /// The first piece is needed right away, and the deadlines of the rest are
+    /// staggered so that they are downloaded in order.
+    async fn stream_pieces(
+        &mut self,
+        pieces: Range<PieceIndex>,
+        piece_tx: oneshot::Sender<()>,
+    ) {
+        let now = Instant::now();
+        // during a check we don't know yet whether we have the pieces, so we
+        // wait for the check to complete
+        let is_checking = matches!(self.state, TorrentState::Checking { .. });
+        let first = pieces.start;
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        for (i, index) in pieces.enumerate() {
+            if is_checking || !piece_picker.own_pieces()[index] {
+                piece_picker.set_piece_deadline(
+                    index,
+                    now + i as u32 * STREAM_DEADLINE_INTERVAL,
+                );
+            }
+        }
+
+        if !is_checking && piece_picker.own_pieces()[first] {
+            piece_tx.send(()).ok();
+        } else {
+            self.piece_waiters.entry(first).or_default().push(piece_tx);
+        }
+    }
+
+    /// Removes the deadlines of the pieces a file stream no longer reads ahead.
+    ///
+    /// The pieces we already have had their deadlines removed when they were
+    /// downloaded, so this only affects the pieces still missing.
+    async fn reset_stream_pieces(&mut self, pieces: Range<PieceIndex>) {
+        log::debug!("Resetting stream piece deadlines {:?}", pieces);
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        let reset: Vec<_> = pieces
+            .filter(|index| piece_picker.reset_piece_deadline(*index).is_some())
+            .collect();
+        drop(piece_picker);
+
+        let downloads = self.ctx.downloads.read().await;
+        for index in reset {
+            if let Some(download) = downloads.get(&index) {
+                download.write().await.set_urgent(false);
+            }
+        }
+    }
+
+    /// Notifies the user and the trackers that we downloaded all the pieces we
+    /// want, unless they were already notified.
+    ///
+    /// The download may be found complete more than once, e.g. when a piece
+    /// that was in flight when its file was skipped arrives.
+    async fn complete_download(&mut self) {
+        if self.is_complete {
+            return;
+        }
+        self.is_complete = true;
+
+        log::info!(
+            "Finished torrent download, exiting. \
+            Peak download rate: {} b/s, wasted: {} b",
+            self.counters.payload.down.peak(),
+            self.counters.waste.total(),
+        );
+
+        // notify user of torrent completion
+        self.ctx
+            .alert_tx
+            .send(Alert::TorrentComplete(self.ctx.id))
+            .ok();
+
+        // tell trackers we've finished
+        if self.state == TorrentState::Active {
+            self.announce_to_trackers(Instant::now(), Some(Event::Completed))
+                .await;
+        }
+    }
+
+    /// Sets the priority of each file and updates the priorities of the pieces
+    /// accordingly.
+    ///
+    /// If this means that we have all the pieces we want, the download is
+    /// complete. The peer sessions are told to update their interest, as we
+    /// may no longer want, or may now want, the pieces of their peers.
+    async fn set_file_priorities(
+        &mut self,
+        priorities: Vec<Priority>,
+    ) -> Result<()> {
+        if priorities.len() != self.ctx.storage.files.len() {
+            log::warn!(
+                "Got {} file priorities for {} files",
+                priorities.len(),
+                self.ctx.storage.files.len()
+            );
+            self.ctx.alert_tx.send(Alert::Error(Error::Torrent {
+                id: self.ctx.id,
+                error: TorrentError::InvalidFilePriorities,
+            }))?;
+            return Ok(());
+        }
+        log::info!("Setting file priorities: {:?}", priorities);
+        self.file_priorities = priorities;
+
+        // the piece picker is reset with the current priorities once the
+        // check completes
+        if let TorrentState::Checking { .. } = self.state {
+            return Ok(());
+        }
+
+        let piece_priorities =
+            self.ctx.storage.piece_priorities(&self.file_priorities);
+        let mut piece_picker = self.ctx.piece_picker.write().await;
+        piece_picker.set_piece_priorities(&piece_priorities);
+        let missing_piece_count = piece_picker.missing_piece_count();
+        self.in_endgame =
+            missing_piece_count > 0 && piece_picker.all_pieces_picked();
+        drop(piece_picker);
+
+        for peer in self.peers.values() {
+            if let Some(tx) = &peer.tx {
+                tx.send(peer::Command::PrioritiesChanged {
+                    in_endgame: self.in_endgame,
+                })
+                .ok();
+            }
+        }
+
+        if missing_piece_count == 0 {
+            self.complete_download().await;
+        } else {
+            self.is_complete = false;
+        }
+
+        Ok(())
+    }
+
+    /// Pauses the torrent by disconnecting all peers and announcing our exit
+    /// to trackers.
+    ///
+    /// The piece picker, the in-progress piece downloads and the torrent's disk
+    /// state are kept so that the torrent can continue where it left off once
+    /// resumed.
+    async fn pause(&mut self) -> Result<()> {
+        match self.state {
+            TorrentState::Paused => {
+                log::debug!("Torrent already paused");
+                return Ok(());
+            }
+            TorrentState::Checking { .. } => {
+                log::info!("Pausing torrent once check completes");
+                self.resume_after_check = false;
+                return Ok(());
+            }
+            TorrentState::Active => {}
+        }
+        log::info!("Pausing torrent");
+
+        self.disconnect_peers().await;
+        // The peers we connected to ourselves (e.g. the ones given by the user
+        // or a tracker) are listening for connections, so we can try them again
+        // on resume. Inbound peers' addresses are not their listen addresses,
+        // so we don't keep them.
+        let outbound_peers: Vec<_> = self
+            .peers
+            .drain()
+            .filter(|(_, peer)| peer.direction == Direction::Outbound)
+            .map(|(addr, peer)| (addr, peer.pex_flags))
+            .collect();
+        self.add_available_peers(outbound_peers).await;
+
+        self.state = TorrentState::Paused;
+
+        // tell trackers we're leaving
+        self.announce_stop(Instant::now()).await;
+        Ok(())
+    }
+
+    /// Resumes a paused torrent, announcing to trackers that we're back.
+    ///
+    /// New peers are connected with the next tick.
+    async fn resume(&mut self) -> Result<()> {
+        match self.state {
+            TorrentState::Active => {
+                log::debug!("Torrent already active");
+                return Ok(());
+            }
+            TorrentState::Checking { .. } => {
+                log::info!("Resuming torrent once check completes");
+                self.resume_after_check = true;
+                return Ok(());
+            }
+            TorrentState::Paused => {}
+        }
+        log::info!("Resuming torrent");
+
+        self.state = TorrentState::Active;
+        self.announce_start().await
+    }
+
+    /// Announces to trackers that the torrent has started.
+    async fn announce_start(&mut self) -> Result<()> {
+        // if the torrent is a seed, don't send the started event, just an
+        // empty announce, unless we already announced before: in that case
+        // the torrent was paused and told trackers it stopped, so they need
+        // to learn that we're back regardless of the announce interval
+        let is_seed =
+            self.ctx.piece_picker.read().await.missing_piece_count() == 0;
+        let has_announced = self
+            .trackers
+            .iter()
+            .flatten()
+            .any(|tracker| tracker.last_announce_time.is_some());
+        let tracker_event = if is_seed && !has_announced {
+            None
+        } else {
+            Some(Event::Started)
+        };
+        self.announce_to_trackers(Instant::now(), tracker_event)
+            .await;
+        Ok(())
+    }
+
+    /// Tells the disk task to verify the torrent's pieces.
+    ///
+    /// The torrent must not have any peers at this point. Once the check
+    /// completes, the torrent becomes active if `resume_after_check` is set,
+    /// or stays paused otherwise.
+    fn start_check(&mut self, resume_after_check: bool) -> Result<()> {
+        log::info!("Checking torrent pieces on disk");
+        debug_assert!(self.peers.is_empty());
+        self.state = TorrentState::Checking { checked_count: 0 };
+        self.resume_after_check = resume_after_check;
+        self.ctx
+            .disk_tx
+            .send(disk::Command::CheckTorrent(self.ctx.id))?;
+        Ok(())
+    }
+
+    /// Disconnects all peers, drops the in-progress downloads, and verifies
+    /// the torrent's pieces on disk.
+    ///
+    /// The torrent returns to its current state once the check is complete.
+    async fn force_recheck(&mut self) -> Result<()> {
+        let resume_after_check = match self.state {
+            TorrentState::Checking { .. } => {
+                log::debug!("Torrent already being checked");
+                return Ok(());
+            }
+            TorrentState::Active => {
+                self.pause().await?;
+                true
+            }
+            TorrentState::Paused => false,
+        };
+        log::info!("Rechecking torrent");
+
+        // the in-progress downloads are discarded by the disk task as well
+        self.ctx.downloads.write().await.clear();
+        self.in_endgame = false;
+
+        self.start_check(resume_after_check)
+    }
+
+    /// Sets the pieces we have to the result of the check and puts the
+    /// torrent in the state it should be in after the check.
+    async fn handle_check_complete(
+        &mut self,
+        own_pieces: Bitfield,
+    ) -> Result<()> {
+        if !matches!(self.state, TorrentState::Checking { .. }) {
+            log::warn!("Received check result while not checking");
+            return Ok(());
+        }
+        log::info!(
+            "Torrent check complete, have {}/{} pieces",
+            own_pieces.count_ones(),
+            own_pieces.len()
+        );
+
+        // no peers are connected during a check, so it's fine to reset the
+        // piece availability as well
+        let mut piece_picker = PiecePicker::new(own_pieces.clone());
+        piece_picker.set_piece_priorities(
+            &self.ctx.storage.piece_priorities(&self.file_priorities),
+        );
+        piece_picker.set_sequential(self.conf.sequential_download);
+        self.is_complete = piece_picker.missing_piece_count() == 0;
+        let deadlines = self
+            .ctx
+            .piece_picker
+            .read()
+            .await
+            .deadlines()
+            .collect::<Vec<_>>();
+        for (deadline, index) in deadlines {
+            if own_pieces[index] {
+                self.complete_deadline_piece(index, deadline);
+            } else {
+                piece_picker.set_piece_deadline(index, deadline);
+            }
+        }
+        let waited_pieces: Vec<_> = self
+            .piece_waiters
+            .keys()
+            .copied()
+            .filter(|index| own_pieces[*index])
+            .collect();
+        for index in waited_pieces {
+            for tx in self.piece_waiters.remove(&index).unwrap_or_default() {
+                tx.send(()).ok();
+            }
+        }
+        *self.ctx.piece_picker.write().await = piece_picker;
+        self.ctx.alert_tx.send(Alert::TorrentChecked {
+            id: self.ctx.id,
+            pieces: own_pieces,
+        })?;
+
+        if self.resume_after_check {
+            self.state = TorrentState::Active;
+            self.announce_start().await
+        } else {
+            self.state = TorrentState::Paused;
+            Ok(())
+        }
+    }
+
     /// Shuts down torrent and all peer sessions, and also announces torrent's
     /// exit to tracker.
     async fn shutdown(&mut self) -> Result<()> {
+        // a paused torrent has already disconnected its peers and told
+        // trackers that it stopped, while a checking torrent has no peers and
+        // trackers were told it stopped, or were never told it started
+        if self.state != TorrentState::Active {
+            return Ok(());
+        }
+
+        self.disconnect_peers().await;
+
+        // tell trackers we're leaving, and wait for their responses, as the
+        // announces would otherwise be cut short if the engine shuts down too,
+        // but don't handle other commands anymore
+        let mut pending_count = self.announce_stop(Instant::now()).await;
+        while pending_count > 0 {
+            match self.cmd_rx.next().await {
+                Some(Command::AnnounceResult { url, event, result }) => {
+                    // other announces may still be in flight, but we only
+                    // wait for the stops
+                    if event == Some(Event::Stopped) {
+                        pending_count -= 1;
+                    }
+                    self.handle_announce_result(url, event, result).await?;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tells all peer sessions to shut down and waits for them to finish.
+    ///
+    /// The peer entries themselves are not removed.
+    async fn disconnect_peers(&mut self) {
         // send shutdown command to all connected peers
         for peer in self.peers.values() {
             if let Some(tx) = &peer.tx {
@@ -827,20 +2205,12 @@ impl Torrent {
         }
 
         for peer in self.peers.values_mut() {
-            if let Err(e) = peer
-                .join_handle
-                .take()
-                .expect("peer join handle missing")
-                .await
-                .expect("task error")
-            {
-                log::error!("Peer session error: {}", e);
+            if let Some(join_handle) = peer.join_handle.take() {
+                if let Err(e) = join_handle.await.expect("task error") {
+                    log::error!("Peer session error: {}", e);
+                }
             }
         }
-
-        // tell trackers we're leaving
-        self.announce_to_trackers(Instant::now(), Some(Event::Stopped))
-            .await
     }
 }
 
@@ -851,6 +2221,8 @@ struct PeerSessionEntry {
     ///
     /// This is set when the session is started.
     tx: Option<peer::Sender>,
+    /// Whether we or the peer initiated the connection.
+    direction: Direction,
 
     /// Peer's 20 byte BitTorrent id. Updated when the peer sends us its peer
     /// id, in the handshake.
@@ -860,9 +2232,17 @@ struct PeerSessionEntry {
     state: SessionState,
     /// The number of pieces that the peer has available.
     piece_count: usize,
+    /// The flags with which the peer is advertised to other peers via peer
+    /// exchange. These are initially the flags with which the peer was
+    /// advertised to us, if any, and are updated as we learn more about the
+    /// peer.
+    pex_flags: PexFlags,
 
     /// Most recent throughput statistics of this peer.
     thruput: ThruputStats,
+    /// Whether the peer is one of the fastest peers, which download the
+    /// pieces with a deadline.
+    is_fast: bool,
 
     /// The peer session task's join handle, used during shutdown.
     join_handle: Option<task::JoinHandle<peer::error::Result<()>>>,
@@ -872,41 +2252,82 @@ impl PeerSessionEntry {
     fn start_outbound(mut session: PeerSession, tx: peer::Sender) -> Self {
         let join_handle =
             task::spawn(async move { session.start_outbound().await });
-        Self::new(tx, join_handle)
+        Self::new(tx, Direction::Outbound, join_handle)
     }
 
     fn start_inbound(
-        socket: TcpStream,
+        socket: Framed<PeerStream<BoxTransport>, HandshakeCodec>,
+        handshake: Handshake,
         mut session: PeerSession,
         tx: peer::Sender,
     ) -> Self {
-        let join_handle =
-            task::spawn(async move { session.start_inbound(socket).await });
-        Self::new(tx, join_handle)
+        let join_handle = task::spawn(async move {
+            session.start_inbound(socket, handshake).await
+        });
+        Self::new(tx, Direction::Inbound, join_handle)
     }
 
     fn new(
         tx: peer::Sender,
+        direction: Direction,
         join_handle: task::JoinHandle<peer::error::Result<()>>,
     ) -> Self {
         Self {
             tx: Some(tx),
+            direction,
             id: None,
             state: SessionState {
                 connection: ConnectionState::Connecting,
                 ..Default::default()
             },
             piece_count: 0,
+            pex_flags: PexFlags::default(),
             thruput: Default::default(),
+            is_fast: false,
             join_handle: Some(join_handle),
         }
     }
 }
 
+/// We don't keep more peers to connect to than this, so that peers can't make
+/// us use unbounded memory via peer exchange.
+const MAX_AVAILABLE_PEER_COUNT: usize = 1000;
+
+/// The number of fastest peers that download the pieces with a deadline.
+const FAST_PEER_COUNT: usize = 4;
+
+/// A piece being downloaded is at risk of missing its deadline if the deadline
+/// is closer than this, in which case the fastest peers request its blocks in
+/// parallel.
+const DEADLINE_RISK_MARGIN: Duration = Duration::from_secs(2);
+
+/// Returns whether a piece with the given deadline is at risk of missing it.
+fn is_deadline_at_risk(deadline: Instant, now: Instant) -> bool {
+    deadline.saturating_duration_since(now) < DEADLINE_RISK_MARGIN
+}
+
+/// Returns the addresses of at most [`FAST_PEER_COUNT`] peers with the highest
+/// download rates among the given peers and their rates.
+fn pick_fast_peers(
+    mut candidates: Vec<(SocketAddr, u64)>,
+) -> HashSet<SocketAddr> {
+    candidates.sort_by_key(|(_, rate)| Reverse(*rate));
+    candidates
+        .into_iter()
+        .take(FAST_PEER_COUNT)
+        .map(|(addr, _)| addr)
+        .collect()
+}
+
+/// The deadlines of the pieces a file stream is about to read are this far
+/// apart, so that the pieces are downloaded in the order they're read.
+const STREAM_DEADLINE_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Contains the tracker client as well as additional metadata about the
 /// tracker.
 struct TrackerEntry {
-    client: Tracker,
+    /// The tracker client, shared with the task of the in-progress announce.
+    client: Arc<Tracker>,
     /// If a previous announce contained a tracker_id, it should be included in
     /// next announces. Therefore it is cached here.
     id: Option<String>,
@@ -918,21 +2339,46 @@ struct TrackerEntry {
     /// The absolute minimum interval at which we can contact tracker.
     /// This is set after the first announce request.
     min_interval: Option<Duration>,
-    /// Each time we fail to requet from tracker, this counter is incremented.
-    /// If it fails too often, we stop requesting from tracker.
+    /// Each time we fail to requet from tracker, this counter is incremented,
+    /// and it's reset when we succeed. The more it fails in a row, the longer
+    /// we wait before retrying it.
     error_count: usize,
+    /// If the last announce failed, the tracker is not contacted again before
+    /// this time.
+    retry_time: Option<Instant>,
+    /// Whether the tracker is working, reported to the user in the stats.
+    state: TrackerState,
+    /// The size of the swarm as last reported by the tracker.
+    swarm: SwarmStats,
 }
 
 impl TrackerEntry {
     fn new(client: Tracker) -> Self {
         Self {
-            client,
+            client: Arc::new(client),
             id: None,
             last_announce_time: None,
             interval: None,
             min_interval: None,
             error_count: 0,
+            retry_time: None,
+            state: TrackerState::NotContacted,
+            swarm: SwarmStats::default(),
+        }
+    }
+
+    /// Returns when we next announce to the tracker, if known.
+    fn next_announce_time(
+        &self,
+        default_announce_interval: Duration,
+    ) -> Option<Instant> {
+        if self.state == TrackerState::Updating {
+            return None;
         }
+        self.retry_time.or_else(|| {
+            self.last_announce_time
+                .map(|t| t + self.interval.unwrap_or(default_announce_interval))
+        })
     }
 
     /// Determines whether we should announce to the tracker at the given time,
@@ -976,3 +2422,53 @@ impl TrackerEntry {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that a piece is at risk of missing its deadline only when the
+    /// deadline is closer than the risk margin, including when it has already
+    /// passed.
+    #[test]
+    fn should_mark_deadline_at_risk_within_margin() {
+        let now = Instant::now();
+        assert!(!is_deadline_at_risk(
+            now + DEADLINE_RISK_MARGIN + Duration::from_millis(1),
+            now
+        ));
+        assert!(!is_deadline_at_risk(now + DEADLINE_RISK_MARGIN, now));
+        assert!(is_deadline_at_risk(
+            now + DEADLINE_RISK_MARGIN - Duration::from_millis(1),
+            now
+        ));
+        assert!(is_deadline_at_risk(now, now));
+        // a missed deadline is still at risk
+        assert!(is_deadline_at_risk(now, now + Duration::from_secs(1)));
+    }
+
+    /// Tests that the peers with the highest download rates are picked as the
+    /// fast peers, and no more than the fast peer count.
+    #[test]
+    fn should_pick_fastest_peers() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let candidates: Vec<_> = (0..FAST_PEER_COUNT as u16 + 2)
+            .map(|port| (addr(port), port as u64 * 100))
+            .collect();
+        let fast_peers = pick_fast_peers(candidates);
+
+        assert_eq!(fast_peers.len(), FAST_PEER_COUNT);
+        // the two slowest peers are left out
+        assert!(!fast_peers.contains(&addr(0)));
+        assert!(!fast_peers.contains(&addr(1)));
+        for port in 2..FAST_PEER_COUNT as u16 + 2 {
+            assert!(fast_peers.contains(&addr(port)));
+        }
+
+        // all peers are fast if there are fewer than the fast peer count
+        let fast_peers = pick_fast_peers(vec![(addr(0), 0), (addr(1), 10)]);
+        assert_eq!(fast_peers.len(), 2);
+
+        assert!(pick_fast_peers(Vec::new()).is_empty());
+    }
+}
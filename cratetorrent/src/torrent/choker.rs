@@ -0,0 +1,178 @@
+//! The choking algorithm, which decides which peers we upload to.
+//!
+//! Uploading to all interested peers would split our upload capacity so thin
+//! that no peer would have a reason to reciprocate. Instead, we only upload to
+//! a few peers at a time, one per upload slot: while downloading, to the peers
+//! that upload the fastest to us (tit-for-tat), and while seeding, to the peers
+//! we can upload the fastest to.
+//!
+//! On top of these, one more peer is unchoked regardless of its rate. This
+//! optimistic unchoke gives new peers, which haven't had a chance to upload to
+//! us yet, something to reciprocate, and it lets us discover peers that are
+//! faster than the current ones. It's rotated less often than the regular
+//! unchokes are recalculated, so that the peer has time to prove itself.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::seq::SliceRandom;
+
+/// The regular unchokes are recalculated this often.
+pub(super) const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistically unchoked peer is rotated this often.
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// An interested peer that may be unchoked.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Candidate {
+    pub addr: SocketAddr,
+    /// The rate by which the peer is ranked, in bytes per second: the rate at
+    /// which the peer uploads to us while we're downloading, and at which we
+    /// upload to it while we're seeding.
+    pub rate: u64,
+}
+
+/// Keeps track of the optimistic unchoke between choke rounds.
+#[derive(Debug, Default)]
+pub(super) struct Choker {
+    /// The optimistically unchoked peer.
+    optimistic_unchoke: Option<SocketAddr>,
+    /// When the optimistic unchoke was last rotated.
+    last_optimistic_unchoke_time: Option<Instant>,
+}
+
+impl Choker {
+    /// Returns the peers to unchoke among the candidates, which are the peers
+    /// interested in us. All other peers should be choked.
+    ///
+    /// The fastest candidates get the upload slots, and one more candidate is
+    /// optimistically unchoked.
+    pub fn unchoke(
+        &mut self,
+        now: Instant,
+        mut candidates: Vec<Candidate>,
+        slot_count: usize,
+    ) -> HashSet<SocketAddr> {
+        candidates.sort_by_key(|c| Reverse(c.rate));
+        let mut unchoked: HashSet<_> =
+            candidates.iter().take(slot_count).map(|c| c.addr).collect();
+        let choked: Vec<_> = candidates
+            .iter()
+            .map(|c| c.addr)
+            .filter(|addr| !unchoked.contains(addr))
+            .collect();
+
+        // the optimistic unchoke is kept until it's time to rotate it, unless
+        // the peer left, is no longer interested, or earned a regular slot
+        let current =
+            self.optimistic_unchoke.filter(|addr| choked.contains(addr));
+        let is_due = self
+            .last_optimistic_unchoke_time
+            .map(|t| {
+                now.saturating_duration_since(t) >= OPTIMISTIC_UNCHOKE_INTERVAL
+            })
+            .unwrap_or(true);
+        if current.is_none() || is_due {
+            // prefer someone other than the current peer, but keep it if
+            // there is no one else
+            let others: Vec<_> = choked
+                .iter()
+                .copied()
+                .filter(|addr| Some(*addr) != current)
+                .collect();
+            self.optimistic_unchoke =
+                others.choose(&mut rand::thread_rng()).copied().or(current);
+            self.last_optimistic_unchoke_time = Some(now);
+        } else {
+            self.optimistic_unchoke = current;
+        }
+
+        if let Some(addr) = self.optimistic_unchoke {
+            log::debug!("Optimistically unchoking peer {}", addr);
+            unchoked.insert(addr);
+        }
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(port: u16, rate: u64) -> Candidate {
+        Candidate {
+            addr: SocketAddr::new([127, 0, 0, 1].into(), port),
+            rate,
+        }
+    }
+
+    /// Tests that the fastest peers get the upload slots and that one more
+    /// peer is unchoked optimistically.
+    #[test]
+    fn should_unchoke_fastest_peers_and_one_optimistically() {
+        let mut choker = Choker::default();
+        let candidates: Vec<_> =
+            (0..10).map(|i| candidate(i, i as u64 * 100)).collect();
+        let unchoked = choker.unchoke(Instant::now(), candidates.clone(), 3);
+
+        assert_eq!(unchoked.len(), 4);
+        for c in &candidates[7..] {
+            assert!(unchoked.contains(&c.addr));
+        }
+        let optimistic = choker.optimistic_unchoke.unwrap();
+        assert!(candidates[..7].iter().any(|c| c.addr == optimistic));
+    }
+
+    /// Tests that the optimistic unchoke is kept until it's rotated.
+    #[test]
+    fn should_rotate_optimistic_unchoke() {
+        let mut choker = Choker::default();
+        let candidates: Vec<_> =
+            (0..10).map(|i| candidate(i, i as u64 * 100)).collect();
+        let start = Instant::now();
+
+        choker.unchoke(start, candidates.clone(), 3);
+        let optimistic = choker.optimistic_unchoke.unwrap();
+
+        // the regular unchokes are recalculated, but the optimistic unchoke
+        // stays
+        choker.unchoke(start + CHOKE_INTERVAL, candidates.clone(), 3);
+        assert_eq!(choker.optimistic_unchoke, Some(optimistic));
+
+        // it's time to rotate it, and since there are other choked peers,
+        // another one is picked
+        choker.unchoke(start + OPTIMISTIC_UNCHOKE_INTERVAL, candidates, 3);
+        assert_ne!(choker.optimistic_unchoke, Some(optimistic));
+    }
+
+    /// Tests that the optimistic unchoke is replaced right away if the peer
+    /// is no longer a candidate.
+    #[test]
+    fn should_replace_optimistic_unchoke_that_left() {
+        let mut choker = Choker::default();
+        let start = Instant::now();
+        let candidates = vec![candidate(0, 100), candidate(1, 0)];
+        choker.unchoke(start, candidates, 1);
+        assert_eq!(choker.optimistic_unchoke, Some(candidate(1, 0).addr));
+
+        let candidates = vec![candidate(0, 100), candidate(2, 0)];
+        let unchoked = choker.unchoke(start + CHOKE_INTERVAL, candidates, 1);
+        assert_eq!(choker.optimistic_unchoke, Some(candidate(2, 0).addr));
+        assert_eq!(unchoked.len(), 2);
+    }
+
+    /// Tests that all candidates are unchoked if there are enough slots.
+    #[test]
+    fn should_unchoke_all_if_enough_slots() {
+        let mut choker = Choker::default();
+        let candidates = vec![candidate(0, 100), candidate(1, 0)];
+        let unchoked = choker.unchoke(Instant::now(), candidates, 4);
+        assert_eq!(unchoked.len(), 2);
+        assert_eq!(choker.optimistic_unchoke, None);
+    }
+}
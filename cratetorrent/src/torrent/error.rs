@@ -1,5 +1,7 @@
 use std::fmt;
 
+use crate::metainfo::MetainfoError;
+
 pub use tokio::{io::Error as IoError, sync::mpsc::error::SendError};
 
 pub(crate) type Result<T, E = TorrentError> = std::result::Result<T, E>;
@@ -16,6 +18,19 @@ pub enum TorrentError {
     Channel,
     /// An IO error ocurred.
     Io(std::io::Error),
+    /// The resume data passed to torrent does not belong to it or the
+    /// torrent's files were changed since it was saved, so it was not used.
+    InvalidResumeData,
+    /// The metadata downloaded from peers for a torrent started from a magnet
+    /// link matched the info hash, but is not a valid info dictionary.
+    InvalidMetadata(MetainfoError),
+    /// The number of file priorities is different from the number of files in
+    /// the torrent.
+    InvalidFilePriorities,
+    /// The piece index is not in the torrent.
+    InvalidPieceIndex,
+    /// The file index is not in the torrent.
+    InvalidFileIndex,
 }
 
 impl fmt::Display for TorrentError {
@@ -24,6 +39,11 @@ impl fmt::Display for TorrentError {
         match self {
             Channel => write!(fmt, "channel error"),
             Io(e) => write!(fmt, "{}", e),
+            InvalidResumeData => write!(fmt, "invalid resume data"),
+            InvalidMetadata(e) => write!(fmt, "invalid metadata: {}", e),
+            InvalidFilePriorities => write!(fmt, "invalid file priorities"),
+            InvalidPieceIndex => write!(fmt, "invalid piece index"),
+            InvalidFileIndex => write!(fmt, "invalid file index"),
         }
     }
 }
@@ -3,6 +3,8 @@ use std::{
     time::{Duration, Instant},
 };
 
+use reqwest::Url;
+
 use crate::{
     counter::{ChannelCounter, Counter, ThruputCounters},
     PeerId, PieceIndex,
@@ -13,6 +15,9 @@ pub use crate::peer::{ConnectionState, SessionState};
 /// Aggregated statistics of a torrent.
 #[derive(Clone, Debug, Default)]
 pub struct TorrentStats {
+    /// Whether the torrent is running or paused.
+    pub state: TorrentState,
+
     /// When the torrent was _first_ started.
     pub start_time: Option<Instant>,
 
@@ -33,6 +38,40 @@ pub struct TorrentStats {
 
     /// Various thruput statistics of the torrent.
     pub thruput: ThruputStats,
+
+    /// The state of each of the torrent's trackers.
+    pub trackers: Vec<TrackerStats>,
+
+    /// The size of the torrent's swarm, as the largest counts reported by any
+    /// of its trackers.
+    ///
+    /// These are known without the torrent announcing to its trackers, if the
+    /// engine periodically scrapes them, see
+    /// [`EngineConf::scrape_interval`](crate::conf::EngineConf::scrape_interval).
+    pub swarm: SwarmStats,
+}
+
+/// The state of a torrent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TorrentState {
+    /// The torrent's pieces on disk are being verified, either because the
+    /// torrent was started with existing files or because a recheck was
+    /// requested. The torrent has no peer connections in this state.
+    Checking {
+        /// The number of pieces checked so far.
+        checked_count: usize,
+    },
+    /// The torrent is connecting to peers and announcing to trackers.
+    Active,
+    /// The torrent was paused by the user. It has no peer connections and does
+    /// not announce to trackers, but it keeps its progress.
+    Paused,
+}
+
+impl Default for TorrentState {
+    fn default() -> Self {
+        Self::Active
+    }
 }
 
 /// Statistics of a torrent's pieces.
@@ -65,6 +104,73 @@ impl PieceStats {
     }
 }
 
+/// The state of one of the torrent's trackers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrackerStats {
+    /// The URL of the tracker.
+    pub url: Url,
+    /// The index of the tracker's tier, see
+    /// [`Metainfo::trackers`](crate::metainfo::Metainfo::trackers).
+    pub tier: usize,
+    /// Whether the tracker is working.
+    pub state: TrackerState,
+    /// When the torrent next announces to the tracker, if known.
+    ///
+    /// This is not set while an announce is in progress, or if the tracker
+    /// has not been announced to yet.
+    pub next_announce_time: Option<Instant>,
+    /// The number of announces in a row that failed.
+    pub error_count: usize,
+    /// The size of the torrent's swarm as last reported by the tracker, either
+    /// in an announce or a scrape response.
+    pub swarm: SwarmStats,
+}
+
+/// The size of a torrent's swarm, as reported by trackers.
+///
+/// Each count is `None` if no tracker has reported it yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SwarmStats {
+    /// The number of peers that have the complete torrent.
+    pub seeder_count: Option<usize>,
+    /// The number of peers that are still downloading the torrent.
+    pub leecher_count: Option<usize>,
+    /// The number of times the torrent has been fully downloaded. This is only
+    /// known from scrapes.
+    pub download_count: Option<usize>,
+}
+
+impl SwarmStats {
+    /// Merges the counts of another tracker into these, keeping the largest
+    /// of each.
+    pub(crate) fn merge(&mut self, other: &Self) {
+        fn max(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            }
+        }
+        self.seeder_count = max(self.seeder_count, other.seeder_count);
+        self.leecher_count = max(self.leecher_count, other.leecher_count);
+        self.download_count = max(self.download_count, other.download_count);
+    }
+}
+
+/// The state of a tracker.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TrackerState {
+    /// The tracker has not been announced to yet, e.g. because it's a backup
+    /// tracker in a lower tier.
+    NotContacted,
+    /// An announce to the tracker is in progress.
+    Updating,
+    /// The tracker responded to the last announce.
+    Working,
+    /// The last announce failed with the given error message. The tracker is
+    /// retried at its next announce time.
+    Failed(String),
+}
+
 /// Limited or full information of a torrent's peer sessions.
 #[derive(Clone, Debug)]
 pub enum Peers {
@@ -1,20 +1,33 @@
 use std::{
     fmt,
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     time::Duration,
 };
 
 use bytes::Buf;
-use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
-use reqwest::{Client, Url};
+use reqwest::Url;
 use serde::de;
 
-use crate::{metainfo::BencodeError, PeerId, Sha1Hash};
+use crate::{
+    compact::decode_v6_addrs, metainfo::BencodeError, PeerId, Sha1Hash,
+};
+
+use http::HttpTracker;
+use udp::UdpTracker;
+
+mod http;
+mod udp;
 
 pub use reqwest::Error as HttpError;
 
 pub(crate) type Result<T, E = TrackerError> = crate::error::Result<T, E>;
 
+/// The maximum number of info hashes that may be scraped in a single request.
+///
+/// This is how many fit in a UDP scrape request, and it also keeps the query
+/// strings of HTTP scrape requests to a reasonable length.
+pub(crate) const MAX_SCRAPE_INFO_HASH_COUNT: usize = 74;
+
 /// The possible errors that may occur when contating the tracker.
 #[derive(Debug)]
 #[non_exhaustive]
@@ -23,6 +36,15 @@ pub enum TrackerError {
     Bencode(BencodeError),
     /// HTTP related errors when contacting the tracker.
     Http(HttpError),
+    /// Socket related errors when contacting a UDP tracker, or if the
+    /// tracker's host name can't be resolved.
+    Io(std::io::Error),
+    /// The tracker didn't respond to any of our retransmitted requests.
+    Timeout,
+    /// The tracker's response is malformed.
+    InvalidResponse,
+    /// The tracker rejected the request, with the given reason.
+    Failure(String),
 }
 
 impl From<BencodeError> for TrackerError {
@@ -37,11 +59,21 @@ impl From<HttpError> for TrackerError {
     }
 }
 
+impl From<std::io::Error> for TrackerError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
 impl fmt::Display for TrackerError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Bencode(e) => e.fmt(f),
             Self::Http(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::Timeout => write!(f, "tracker timed out"),
+            Self::InvalidResponse => write!(f, "invalid tracker response"),
+            Self::Failure(reason) => write!(f, "tracker failure: {}", reason),
         }
     }
 }
@@ -59,6 +91,12 @@ pub(crate) struct Announce {
     /// proxy, or when the tracker is on the same NAT'd subnet as peer (in which case it
     /// is necessary that tracker not give out an unroutable address to peer).
     pub ip: Option<IpAddr>,
+    /// The IPv6 address of the client, if it has one (BEP 7).
+    ///
+    /// This lets a tracker that is contacted over IPv4 give out our IPv6
+    /// address to other peers too. It's only sent to HTTP trackers, as the UDP
+    /// tracker protocol has no field for it.
+    pub ipv6: Option<Ipv6Addr>,
 
     /// Number up bytes downloaded so far.
     pub downloaded: u64,
@@ -104,6 +142,9 @@ pub(crate) struct Response {
 
     /// If this is not empty, no other fields in response are valid. It contains
     /// a human-readable error message as to why the request was invalid.
+    ///
+    /// Such a response is turned into a [`TrackerError::Failure`], so this is
+    /// never set in the responses returned by [`Tracker::announce`].
     #[serde(rename = "failure reason")]
     pub failure_reason: Option<String>,
 
@@ -131,22 +172,62 @@ pub(crate) struct Response {
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_peers")]
     pub peers: Vec<SocketAddr>,
+
+    /// The IPv6 peers, sent in a separate compact string (BEP 7).
+    ///
+    /// These are moved to the other peers, so this is always empty in the
+    /// responses returned by [`Tracker::announce`].
+    #[serde(default)]
+    #[serde(deserialize_with = "deserialize_peers6")]
+    pub peers6: Vec<SocketAddr>,
 }
 
-/// The HTTP tracker for a torrent for which we can request peers as well as to
-/// announce transfer progress.
-pub(crate) struct Tracker {
-    /// The HTTP client.
-    client: Client,
-    /// The URL of the tracker.
-    url: Url,
+/// The statistics of a torrent in a tracker's swarm, as returned by a scrape
+/// request.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ScrapeStats {
+    /// The number of peers that have the complete torrent.
+    pub seeder_count: usize,
+    /// The number of peers that are still downloading the torrent.
+    pub leecher_count: usize,
+    /// The number of times the torrent has been fully downloaded.
+    pub download_count: usize,
+}
+
+/// A tracker of a torrent, from which we can request peers, and to which we
+/// announce our transfer progress.
+///
+/// Both HTTP and UDP (BEP 15) trackers are supported, and they are used the
+/// same way.
+pub(crate) enum Tracker {
+    Http(HttpTracker),
+    Udp(UdpTracker),
 }
 
 impl Tracker {
+    /// Creates a new tracker, whose protocol is chosen based on the URL's
+    /// scheme.
+    ///
+    /// The URL's scheme should be checked with [`Tracker::is_supported`]
+    /// beforehand, as all URLs that are not UDP are assumed to be HTTP.
     pub fn new(url: Url) -> Self {
-        Self {
-            client: Client::new(),
-            url,
+        if url.scheme() == "udp" {
+            Self::Udp(UdpTracker::new(url))
+        } else {
+            Self::Http(HttpTracker::new(url))
+        }
+    }
+
+    /// Returns whether the tracker's protocol is supported.
+    pub fn is_supported(url: &Url) -> bool {
+        matches!(url.scheme(), "http" | "https" | "udp")
+    }
+
+    /// Returns the tracker's URL.
+    pub fn url(&self) -> &Url {
+        match self {
+            Self::Http(tracker) => tracker.url(),
+            Self::Udp(tracker) => tracker.url(),
         }
     }
 
@@ -160,82 +241,65 @@ impl Tracker {
     /// The tracker may not be contacted more often than the minimum interval
     /// returned in the first announce response.
     pub async fn announce(&self, params: Announce) -> Result<Response> {
-        // announce parameters are built up in the query string, see:
-        // https://www.bittorrent.org/beps/bep_0003.html trackers section
-        let mut query = vec![
-            ("port", params.port.to_string()),
-            ("downloaded", params.downloaded.to_string()),
-            ("uploaded", params.uploaded.to_string()),
-            ("left", params.left.to_string()),
-            // Indicates that client accepts a compact response (each peer takes
-            // up only 6 bytes where the first four bytes constitute the IP
-            // address and the last 2 the port number, in Network Byte Order).
-            // The is always true to save network traffic (many trackers don't
-            // consider this and send compact lists anyway).
-            ("compact", "1".to_string()),
-        ];
-        if let Some(peer_count) = params.peer_count {
-            query.push(("numwant", peer_count.to_string()));
+        match self {
+            Self::Http(tracker) => tracker.announce(params).await,
+            Self::Udp(tracker) => tracker.announce(params).await,
         }
-        if let Some(ip) = &params.ip {
-            query.push(("ip", ip.to_string()));
+    }
+
+    /// Returns whether the tracker can be scraped.
+    ///
+    /// UDP trackers always support scraping, while an HTTP tracker only does
+    /// if its scrape URL can be derived from its announce URL.
+    pub fn supports_scrape(&self) -> bool {
+        match self {
+            Self::Http(tracker) => tracker.scrape_url().is_some(),
+            Self::Udp(_) => true,
         }
+    }
 
-        // hack:
-        // reqwest uses serde_urlencoded which doesn't support encoding a raw
-        // byte array into a percent encoded string. However, the tracker
-        // expects the url encoded form of the raw info hash, so we need to be
-        // able to map the raw bytes to its url encoded form. The peer id is
-        // also stored as a raw byte array. Using `String::from_utf8_lossy`
-        // would cause information loss.
-        //
-        // We do this using the separate percent_encoding crate, and by
-        // "hard-coding" the info hash and the peer id into the url string. This
-        // is the only way in which reqwest doesn't url encode again the custom
-        // url encoded info hash. All other methods, such as mutating the query
-        // parameters on the `Url` object, or by serializing the info hash with
-        // `serde_bytes` do not work: they throw an error due to expecting valid
-        // utf8.
-        //
-        // However, this is decidedly _not_ great: we're relying on an
-        // undocumented edge case of a third party library (reqwest) that may
-        // very well break in a future update.
-        let url = format!(
-            "{url}\
-            ?info_hash={info_hash}\
-            &peer_id={peer_id}",
-            url = self.url,
-            info_hash = percent_encoding::percent_encode(
-                &params.info_hash,
-                URL_ENCODE_RESERVED
-            ),
-            peer_id = percent_encoding::percent_encode(
-                &params.peer_id,
-                URL_ENCODE_RESERVED
-            ),
-        );
-
-        // send request
-        let resp = self
-            .client
-            .get(&url)
-            .query(&query)
-            .send()
-            .await?
-            .error_for_status()?
-            .bytes()
-            .await?;
-        let resp = serde_bencode::from_bytes(&resp)?;
-        Ok(resp)
+    /// Requests the swarm statistics of the torrents with the given info
+    /// hashes from the tracker, of which there may be at most
+    /// [`MAX_SCRAPE_INFO_HASH_COUNT`].
+    ///
+    /// The statistics are returned along with the info hash of the torrent
+    /// they belong to. Torrents the tracker doesn't know about may be missing
+    /// from the result.
+    pub async fn scrape(
+        &self,
+        info_hashes: &[Sha1Hash],
+    ) -> Result<Vec<(Sha1Hash, ScrapeStats)>> {
+        match self {
+            Self::Http(tracker) => tracker.scrape(info_hashes).await,
+            Self::Udp(tracker) => {
+                let stats = tracker.scrape(info_hashes).await?;
+                Ok(info_hashes.iter().copied().zip(stats).collect())
+            }
+        }
     }
 }
 
 impl fmt::Display for Tracker {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "'{}'", self.url)
+        write!(f, "'{}'", self.url())
     }
 }
 
+/// Returns how long to wait before retrying a tracker that failed the given
+/// number of times in a row.
+///
+/// The delay starts at the base delay and doubles with each failure, up to the
+/// maximum.
+pub(crate) fn retry_delay(
+    error_count: usize,
+    base: Duration,
+    max: Duration,
+) -> Duration {
+    // cap the exponent so that the multiplication can't overflow
+    let exp = error_count.saturating_sub(1).min(16) as u32;
+    base.checked_mul(1 << exp).unwrap_or(max).min(max)
+}
+
 /// Peers can be sent in two ways: as a bencoded list of dicts including full
 /// peer metadata, or as a single bencoded string that contains only the peer IP
 /// and port (compact representation). This helper method deserializes both into
@@ -338,18 +402,22 @@ where
     Ok(s.map(Duration::from_secs))
 }
 
-/// Contains the characters that need to be URL encoded according to:
-/// https://en.wikipedia.org/wiki/Percent-encoding#Types_of_URI_characters
-const URL_ENCODE_RESERVED: &AsciiSet = &NON_ALPHANUMERIC
-    .remove(b'-')
-    .remove(b'_')
-    .remove(b'~')
-    .remove(b'.');
+/// Deserializes the compact string of IPv6 peers, in which each entry is the
+/// 16 byte IPv6 address followed by the 2 byte port, in network byte order.
+///
+/// A trailing partial entry is ignored.
+fn deserialize_peers6<'de, D>(
+    deserializer: D,
+) -> Result<Vec<SocketAddr>, D::Error>
+where
+    D: de::Deserializer<'de>,
+{
+    let buf: serde_bytes::ByteBuf = de::Deserialize::deserialize(deserializer)?;
+    Ok(decode_v6_addrs(&buf))
+}
 
 #[cfg(test)]
 mod tests {
-    use mockito::{mock, Matcher};
-
     use super::*;
 
     #[derive(Deserialize)]
@@ -375,6 +443,19 @@ mod tests {
         assert_eq!(decoded.peers, vec![addr]);
     }
 
+    #[test]
+    fn should_parse_compact_ipv6_peer_list() {
+        let addr: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let mut encoded = b"d8:intervali60e6:peers618:".to_vec();
+        crate::compact::encode_addr(&addr, &mut encoded);
+        encoded.push(b'e');
+
+        let decoded: Response = serde_bencode::from_bytes(&encoded)
+            .expect("cannot decode bencode string of peers");
+        assert!(decoded.peers.is_empty());
+        assert_eq!(decoded.peers6, vec![addr]);
+    }
+
     #[test]
     fn should_parse_full_peer_list() {
         #[derive(Debug, Serialize)]
@@ -417,90 +498,22 @@ mod tests {
         assert_eq!(decoded.peers, expected);
     }
 
-    #[tokio::test]
-    async fn should_return_peers_on_announce() {
-        let addr = mockito::server_url();
-        let tracker = Tracker::new(addr.parse().unwrap());
-
-        let info_hash_str = "abcdefghij1234567890";
-        let mut info_hash = [0; 20];
-        info_hash.copy_from_slice(info_hash_str.as_bytes());
-
-        let peer_id_str = "cbt-2020-03-03-00000";
-        let mut peer_id = [0; 20];
-        peer_id.copy_from_slice(peer_id_str.as_bytes());
-
-        let announce = Announce {
-            info_hash,
-            peer_id,
-            port: 16,
-            downloaded: 1234,
-            uploaded: 1234,
-            left: 1234,
-            peer_count: Some(2),
-            ip: None,
-            event: None,
-            tracker_id: None,
-        };
-        let peer_ip = Ipv4Addr::new(2, 156, 201, 254);
-        let peer_port = 49123;
-        let expected_resp = Response {
-            tracker_id: None,
-            failure_reason: None,
-            warning_message: None,
-            interval: Some(Duration::from_secs(15)),
-            min_interval: Some(Duration::from_secs(10)),
-            seeder_count: Some(5),
-            leecher_count: Some(3),
-            peers: vec![SocketAddr::new(peer_ip.into(), peer_port)],
-        };
-
-        let mut encoded_resp = Vec::new();
-        // unterminated dict
-        encoded_resp.extend_from_slice(
-            b"d\
-            8:completei5e\
-            10:incompletei3e\
-            8:intervali15e\
-            12:min intervali10e",
-        );
-        // insert peers field into dict
-        encoded_resp.extend_from_slice(b"5:peers");
-        encoded_resp.extend_from_slice(&encode_compact_peers_list(&[(
-            peer_ip, peer_port,
-        )]));
-        // terminate dict
-        encoded_resp.push(b'e');
-
-        let _m = mock("GET", "/")
-            .match_query(Matcher::AllOf(vec![
-                Matcher::UrlEncoded("compact".into(), "1".into()),
-                Matcher::UrlEncoded("info_hash".into(), info_hash_str.into()),
-                Matcher::UrlEncoded("peer_id".into(), peer_id_str.into()),
-                Matcher::UrlEncoded("port".into(), announce.port.to_string()),
-                Matcher::UrlEncoded(
-                    "downloaded".into(),
-                    announce.downloaded.to_string(),
-                ),
-                Matcher::UrlEncoded(
-                    "uploaded".into(),
-                    announce.uploaded.to_string(),
-                ),
-                Matcher::UrlEncoded("left".into(), announce.left.to_string()),
-                Matcher::UrlEncoded(
-                    "numwant".into(),
-                    announce.peer_count.unwrap().to_string(),
-                ),
-            ]))
-            .with_status(200)
-            .with_body(encoded_resp)
-            .create();
-
-        let resp = tracker.announce(announce).await.unwrap();
-        assert_eq!(resp, expected_resp);
+    /// Tests that the retry delay doubles with each failure, up to the
+    /// maximum.
+    #[test]
+    fn should_back_off_exponentially() {
+        let base = Duration::from_secs(60);
+        let max = Duration::from_secs(60 * 60);
+        assert_eq!(retry_delay(1, base, max), base);
+        assert_eq!(retry_delay(2, base, max), base * 2);
+        assert_eq!(retry_delay(4, base, max), base * 8);
+        assert_eq!(retry_delay(7, base, max), max);
+        assert_eq!(retry_delay(usize::MAX, base, max), max);
     }
 
-    fn encode_compact_peers_list(peers: &[(Ipv4Addr, u16)]) -> Vec<u8> {
+    pub(super) fn encode_compact_peers_list(
+        peers: &[(Ipv4Addr, u16)],
+    ) -> Vec<u8> {
         let encoded_peers: Vec<_> = peers
             .into_iter()
             .map(|(ip, port)| {
@@ -0,0 +1,452 @@
+//! The HTTP tracker protocol, see the trackers section of
+//! [BEP 3](https://www.bittorrent.org/beps/bep_0003.html), and the scrape
+//! convention, see [BEP 48](https://www.bittorrent.org/beps/bep_0048.html).
+
+use std::collections::HashMap;
+
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Client, Url};
+use serde_bytes::ByteBuf;
+
+use super::{
+    Announce, Event, Response, Result, ScrapeStats, TrackerError,
+    MAX_SCRAPE_INFO_HASH_COUNT,
+};
+use crate::Sha1Hash;
+
+/// An HTTP tracker, which is announced to by sending a GET request with the
+/// announce parameters in the query string.
+pub(crate) struct HttpTracker {
+    /// The HTTP client.
+    client: Client,
+    /// The URL of the tracker.
+    url: Url,
+    /// The URL to which scrape requests are sent, if the tracker supports
+    /// scraping.
+    scrape_url: Option<Url>,
+}
+
+impl HttpTracker {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: Client::new(),
+            scrape_url: scrape_url(&url),
+            url,
+        }
+    }
+
+    /// Returns the tracker's URL.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Returns the tracker's scrape URL, if it supports scraping.
+    pub fn scrape_url(&self) -> Option<&Url> {
+        self.scrape_url.as_ref()
+    }
+
+    /// Sends an announce request to the tracker with the specified parameters.
+    pub async fn announce(&self, params: Announce) -> Result<Response> {
+        // announce parameters are built up in the query string, see:
+        // https://www.bittorrent.org/beps/bep_0003.html trackers section
+        let mut query = vec![
+            ("port", params.port.to_string()),
+            ("downloaded", params.downloaded.to_string()),
+            ("uploaded", params.uploaded.to_string()),
+            ("left", params.left.to_string()),
+            // Indicates that client accepts a compact response (each peer takes
+            // up only 6 bytes where the first four bytes constitute the IP
+            // address and the last 2 the port number, in Network Byte Order).
+            // The is always true to save network traffic (many trackers don't
+            // consider this and send compact lists anyway).
+            ("compact", "1".to_string()),
+        ];
+        if let Some(peer_count) = params.peer_count {
+            query.push(("numwant", peer_count.to_string()));
+        }
+        if let Some(ip) = &params.ip {
+            query.push(("ip", ip.to_string()));
+        }
+        if let Some(ipv6) = &params.ipv6 {
+            query.push(("ipv6", ipv6.to_string()));
+        }
+        if let Some(event) = params.event {
+            let event = match event {
+                Event::Started => "started",
+                Event::Completed => "completed",
+                Event::Stopped => "stopped",
+            };
+            query.push(("event", event.to_string()));
+        }
+        if let Some(tracker_id) = params.tracker_id {
+            query.push(("trackerid", tracker_id));
+        }
+
+        // hack:
+        // reqwest uses serde_urlencoded which doesn't support encoding a raw
+        // byte array into a percent encoded string. However, the tracker
+        // expects the url encoded form of the raw info hash, so we need to be
+        // able to map the raw bytes to its url encoded form. The peer id is
+        // also stored as a raw byte array. Using `String::from_utf8_lossy`
+        // would cause information loss.
+        //
+        // We do this using the separate percent_encoding crate, and by
+        // "hard-coding" the info hash and the peer id into the url string. This
+        // is the only way in which reqwest doesn't url encode again the custom
+        // url encoded info hash. All other methods, such as mutating the query
+        // parameters on the `Url` object, or by serializing the info hash with
+        // `serde_bytes` do not work: they throw an error due to expecting valid
+        // utf8.
+        //
+        // However, this is decidedly _not_ great: we're relying on an
+        // undocumented edge case of a third party library (reqwest) that may
+        // very well break in a future update.
+        let url = format!(
+            "{url}\
+            ?info_hash={info_hash}\
+            &peer_id={peer_id}",
+            url = self.url,
+            info_hash = percent_encoding::percent_encode(
+                &params.info_hash,
+                URL_ENCODE_RESERVED
+            ),
+            peer_id = percent_encoding::percent_encode(
+                &params.peer_id,
+                URL_ENCODE_RESERVED
+            ),
+        );
+
+        // send request
+        let resp = self
+            .client
+            .get(&url)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let mut resp: Response = serde_bencode::from_bytes(&resp)?;
+        if let Some(reason) = resp.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        resp.peers.append(&mut resp.peers6);
+        Ok(resp)
+    }
+
+    /// Requests the swarm statistics of the torrents from the tracker's scrape
+    /// URL.
+    ///
+    /// If the tracker doesn't support scraping, an invalid response error is
+    /// returned.
+    pub async fn scrape(
+        &self,
+        info_hashes: &[Sha1Hash],
+    ) -> Result<Vec<(Sha1Hash, ScrapeStats)>> {
+        debug_assert!(info_hashes.len() <= MAX_SCRAPE_INFO_HASH_COUNT);
+        let scrape_url = self
+            .scrape_url
+            .as_ref()
+            .ok_or(TrackerError::InvalidResponse)?;
+
+        // as with announces, the raw info hashes have to be hard-coded into
+        // the url string, see the comment in `announce`
+        let mut url = scrape_url.to_string();
+        let mut separator = if scrape_url.query().is_some() {
+            '&'
+        } else {
+            '?'
+        };
+        for info_hash in info_hashes {
+            url.push(separator);
+            url.push_str("info_hash=");
+            url.extend(percent_encoding::percent_encode(
+                info_hash,
+                URL_ENCODE_RESERVED,
+            ));
+            separator = '&';
+        }
+
+        let resp = self
+            .client
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let resp: ScrapeResponse = serde_bencode::from_bytes(&resp)?;
+        if let Some(reason) = resp.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+
+        let mut stats = Vec::with_capacity(resp.files.len());
+        for (info_hash, file) in resp.files {
+            // ignore the torrents we didn't ask for
+            if let Some(info_hash) =
+                info_hashes.iter().find(|hash| hash[..] == info_hash[..])
+            {
+                stats.push((
+                    *info_hash,
+                    ScrapeStats {
+                        seeder_count: file.complete,
+                        leecher_count: file.incomplete,
+                        download_count: file.downloaded,
+                    },
+                ));
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// The scrape response, which maps the raw info hashes of the scraped torrents
+/// to their statistics.
+#[derive(Debug, Deserialize)]
+struct ScrapeResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(default)]
+    files: HashMap<ByteBuf, ScrapeFile>,
+}
+
+/// The statistics of a torrent in a scrape response.
+#[derive(Debug, Deserialize)]
+struct ScrapeFile {
+    #[serde(default)]
+    complete: usize,
+    #[serde(default)]
+    downloaded: usize,
+    #[serde(default)]
+    incomplete: usize,
+}
+
+/// Derives the scrape URL from the tracker's announce URL.
+///
+/// By convention, if the last component of the announce URL's path starts with
+/// "announce", the scrape URL is the same URL with "announce" replaced by
+/// "scrape". Otherwise the tracker doesn't support scraping, and `None` is
+/// returned.
+fn scrape_url(announce_url: &Url) -> Option<Url> {
+    let path = announce_url.path();
+    let last_slash = path.rfind('/')?;
+    let file = path[last_slash + 1..].strip_prefix("announce")?;
+    let mut url = announce_url.clone();
+    url.set_path(&format!("{}scrape{}", &path[..=last_slash], file));
+    Some(url)
+}
+
+/// Contains the characters that need to be URL encoded according to:
+/// https://en.wikipedia.org/wiki/Percent-encoding#Types_of_URI_characters
+const URL_ENCODE_RESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~')
+    .remove(b'.');
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use mockito::{mock, Matcher};
+
+    use super::*;
+    use crate::{
+        compact::encode_addr, tracker::tests::encode_compact_peers_list,
+    };
+
+    #[test]
+    fn should_derive_scrape_url() {
+        let scrape_url = |url: &str| {
+            scrape_url(&url.parse().unwrap()).map(|url| url.to_string())
+        };
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?passkey=abc")
+                .as_deref(),
+            Some("http://example.com/x/scrape.php?passkey=abc")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("http://example.com/announce/x"), None);
+    }
+
+    #[tokio::test]
+    async fn should_return_peers_on_announce() {
+        let addr = mockito::server_url();
+        let tracker = HttpTracker::new(addr.parse().unwrap());
+
+        let info_hash_str = "abcdefghij1234567890";
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(info_hash_str.as_bytes());
+
+        let peer_id_str = "cbt-2020-03-03-00000";
+        let mut peer_id = [0; 20];
+        peer_id.copy_from_slice(peer_id_str.as_bytes());
+
+        let announce = Announce {
+            info_hash,
+            peer_id,
+            port: 16,
+            downloaded: 1234,
+            uploaded: 1234,
+            left: 1234,
+            peer_count: Some(2),
+            ip: None,
+            ipv6: Some("2001:db8::2".parse().unwrap()),
+            event: Some(Event::Started),
+            tracker_id: Some("tracker-1".into()),
+        };
+        let peer_ip = Ipv4Addr::new(2, 156, 201, 254);
+        let peer_port = 49123;
+        let peer6_addr: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let expected_resp = Response {
+            tracker_id: None,
+            failure_reason: None,
+            warning_message: None,
+            interval: Some(Duration::from_secs(15)),
+            min_interval: Some(Duration::from_secs(10)),
+            seeder_count: Some(5),
+            leecher_count: Some(3),
+            peers: vec![SocketAddr::new(peer_ip.into(), peer_port), peer6_addr],
+            peers6: Vec::new(),
+        };
+
+        let mut encoded_resp = Vec::new();
+        // unterminated dict
+        encoded_resp.extend_from_slice(
+            b"d\
+            8:completei5e\
+            10:incompletei3e\
+            8:intervali15e\
+            12:min intervali10e",
+        );
+        // insert peers field into dict
+        encoded_resp.extend_from_slice(b"5:peers");
+        encoded_resp.extend_from_slice(&encode_compact_peers_list(&[(
+            peer_ip, peer_port,
+        )]));
+        // insert IPv6 peers
+        encoded_resp.extend_from_slice(b"6:peers618:");
+        encode_addr(&peer6_addr, &mut encoded_resp);
+        // terminate dict
+        encoded_resp.push(b'e');
+
+        let _m = mock("GET", "/")
+            .match_query(Matcher::AllOf(vec![
+                Matcher::UrlEncoded("compact".into(), "1".into()),
+                Matcher::UrlEncoded("info_hash".into(), info_hash_str.into()),
+                Matcher::UrlEncoded("peer_id".into(), peer_id_str.into()),
+                Matcher::UrlEncoded("port".into(), announce.port.to_string()),
+                Matcher::UrlEncoded(
+                    "downloaded".into(),
+                    announce.downloaded.to_string(),
+                ),
+                Matcher::UrlEncoded(
+                    "uploaded".into(),
+                    announce.uploaded.to_string(),
+                ),
+                Matcher::UrlEncoded("left".into(), announce.left.to_string()),
+                Matcher::UrlEncoded("ipv6".into(), "2001:db8::2".into()),
+                Matcher::UrlEncoded("event".into(), "started".into()),
+                Matcher::UrlEncoded("trackerid".into(), "tracker-1".into()),
+                Matcher::UrlEncoded(
+                    "numwant".into(),
+                    announce.peer_count.unwrap().to_string(),
+                ),
+            ]))
+            .with_status(200)
+            .with_body(encoded_resp)
+            .create();
+
+        let resp = tracker.announce(announce).await.unwrap();
+        assert_eq!(resp, expected_resp);
+    }
+
+    /// Tests that a response with a failure reason is turned into an error.
+    #[tokio::test]
+    async fn should_return_failure_reason_as_error() {
+        let url = format!("{}/failure", mockito::server_url());
+        let tracker = HttpTracker::new(url.parse().unwrap());
+        let _m = mock("GET", "/failure")
+            .match_query(Matcher::Any)
+            .with_status(200)
+            .with_body("d14:failure reason17:torrent not founde")
+            .create();
+
+        let announce = Announce {
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+            port: 16,
+            downloaded: 0,
+            uploaded: 0,
+            left: 0,
+            peer_count: None,
+            ip: None,
+            ipv6: None,
+            event: None,
+            tracker_id: None,
+        };
+        match tracker.announce(announce).await {
+            Err(TrackerError::Failure(reason)) => {
+                assert_eq!(reason, "torrent not found")
+            }
+            _ => panic!("expected tracker failure"),
+        }
+    }
+
+    /// Tests that the statistics of several torrents are scraped with a single
+    /// request.
+    #[tokio::test]
+    async fn should_scrape() {
+        let url = format!("{}/x/announce", mockito::server_url());
+        let tracker = HttpTracker::new(url.parse().unwrap());
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[1; 20]);
+        body.extend_from_slice(
+            b"d8:completei5e10:downloadedi50e10:incompletei10ee",
+        );
+        body.extend_from_slice(b"20:");
+        body.extend_from_slice(&[2; 20]);
+        body.extend_from_slice(b"d8:completei1ee");
+        body.extend_from_slice(b"ee");
+        let _m = mock("GET", "/x/scrape")
+            .match_query(Matcher::Exact(format!(
+                "info_hash={}&info_hash={}",
+                "%01".repeat(20),
+                "%02".repeat(20)
+            )))
+            .with_status(200)
+            .with_body(body)
+            .create();
+
+        let mut stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        stats.sort_by_key(|(info_hash, _)| *info_hash);
+        assert_eq!(
+            stats,
+            vec![
+                (
+                    [1; 20],
+                    ScrapeStats {
+                        seeder_count: 5,
+                        leecher_count: 10,
+                        download_count: 50,
+                    }
+                ),
+                (
+                    [2; 20],
+                    ScrapeStats {
+                        seeder_count: 1,
+                        leecher_count: 0,
+                        download_count: 0,
+                    }
+                ),
+            ]
+        );
+    }
+}
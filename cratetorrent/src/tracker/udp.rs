@@ -0,0 +1,598 @@
+//! The UDP tracker protocol, see
+//! [BEP 15](http://bittorrent.org/beps/bep_0015.html).
+//!
+//! Each request and response is a single datagram. Before announcing or
+//! scraping, the client must obtain a connection id from the tracker and send
+//! it with its subsequent requests, which lets the tracker verify that the
+//! client's address is not spoofed. As datagrams may be lost, a request is
+//! resent if no response arrives in time, and the timeout doubles with each
+//! retransmission.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Buf;
+use tokio::{net::UdpSocket, task, time};
+use url::{Host, Url};
+
+use super::{
+    Announce, Event, Response, Result, ScrapeStats, TrackerError,
+    MAX_SCRAPE_INFO_HASH_COUNT,
+};
+use crate::{
+    compact::{decode_v4_addrs, decode_v6_addrs},
+    Sha1Hash,
+};
+
+/// The magic constant that identifies the protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// The length of the action and transaction id that start every response.
+const HEADER_LEN: usize = 8;
+
+/// A connection id may be used for this long after it was received.
+const CONNECTION_ID_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The time to wait for a response to the first transmission of a request.
+/// After the `n`th retransmission, it's this times `2^n`.
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The number of times a request is retransmitted before the tracker is
+/// considered unreachable.
+const MAX_RETRANSMIT_COUNT: u32 = 8;
+
+/// Responses larger than this are truncated.
+const MAX_DATAGRAM_LEN: usize = 8192;
+
+/// A UDP tracker.
+pub(crate) struct UdpTracker {
+    /// The URL of the tracker, which must contain its host and port.
+    url: Url,
+    /// A random value sent with announces, which lets the tracker identify us
+    /// even if our IP address changes.
+    key: u32,
+    /// The last connection id we received from the tracker and when we
+    /// received it.
+    connection: Mutex<Option<(u64, Instant)>>,
+    /// The timeout of the first transmission of a request.
+    base_timeout: Duration,
+}
+
+impl UdpTracker {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            key: rand::random(),
+            connection: Mutex::new(None),
+            base_timeout: BASE_TIMEOUT,
+        }
+    }
+
+    /// Returns the tracker's URL.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Sends an announce request to the tracker with the specified parameters.
+    ///
+    /// If we don't have a valid connection id, a new one is requested first.
+    pub async fn announce(&self, params: Announce) -> Result<Response> {
+        let (mut socket, addr) = self.bind().await?;
+        let mut attempt = 0;
+        loop {
+            let connection_id =
+                match self.connect(&mut socket, addr, &mut attempt).await? {
+                    Some(id) => id,
+                    None => continue,
+                };
+            let tid: u32 = rand::random();
+            let req = encode_announce(connection_id, tid, self.key, &params);
+            if let Some(resp) = self
+                .send_request(
+                    &mut socket,
+                    addr,
+                    &req,
+                    ACTION_ANNOUNCE,
+                    tid,
+                    &mut attempt,
+                )
+                .await?
+            {
+                return parse_announce(&resp, addr.is_ipv6());
+            }
+        }
+    }
+
+    /// Requests the swarm statistics of the torrents from the tracker.
+    ///
+    /// The statistics are returned in the order of the info hashes, of which
+    /// there may be at most [`MAX_SCRAPE_INFO_HASH_COUNT`].
+    pub async fn scrape(
+        &self,
+        info_hashes: &[Sha1Hash],
+    ) -> Result<Vec<ScrapeStats>> {
+        debug_assert!(info_hashes.len() <= MAX_SCRAPE_INFO_HASH_COUNT);
+        let (mut socket, addr) = self.bind().await?;
+        let mut attempt = 0;
+        loop {
+            let connection_id =
+                match self.connect(&mut socket, addr, &mut attempt).await? {
+                    Some(id) => id,
+                    None => continue,
+                };
+            let tid: u32 = rand::random();
+            let mut req = Vec::with_capacity(16 + info_hashes.len() * 20);
+            req.extend_from_slice(&connection_id.to_be_bytes());
+            req.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+            req.extend_from_slice(&tid.to_be_bytes());
+            for info_hash in info_hashes {
+                req.extend_from_slice(info_hash);
+            }
+            if let Some(resp) = self
+                .send_request(
+                    &mut socket,
+                    addr,
+                    &req,
+                    ACTION_SCRAPE,
+                    tid,
+                    &mut attempt,
+                )
+                .await?
+            {
+                if resp.len() < info_hashes.len() * 12 {
+                    return Err(TrackerError::InvalidResponse);
+                }
+                let mut resp = &resp[..];
+                let stats = info_hashes
+                    .iter()
+                    .map(|_| ScrapeStats {
+                        seeder_count: resp.get_u32() as usize,
+                        download_count: resp.get_u32() as usize,
+                        leecher_count: resp.get_u32() as usize,
+                    })
+                    .collect();
+                return Ok(stats);
+            }
+        }
+    }
+
+    /// Resolves the tracker's address and binds a socket of the same address
+    /// family on which to contact it.
+    async fn bind(&self) -> Result<(UdpSocket, SocketAddr)> {
+        let addr = resolve(&self.url).await?;
+        let local_addr = if addr.is_ipv4() {
+            SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+        } else {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+        };
+        let socket = UdpSocket::bind(local_addr).await?;
+        Ok((socket, addr))
+    }
+
+    /// Returns our connection id if it's still valid, or requests a new one
+    /// from the tracker.
+    ///
+    /// Returns `None` if the connect request timed out and should be
+    /// retransmitted.
+    async fn connect(
+        &self,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        attempt: &mut u32,
+    ) -> Result<Option<u64>> {
+        let connection = *self.connection.lock().unwrap();
+        if let Some((id, time)) = connection {
+            if time.elapsed() < CONNECTION_ID_TIMEOUT {
+                return Ok(Some(id));
+            }
+        }
+
+        let tid: u32 = rand::random();
+        let mut req = Vec::with_capacity(16);
+        req.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+        req.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+        req.extend_from_slice(&tid.to_be_bytes());
+        let resp = match self
+            .send_request(socket, addr, &req, ACTION_CONNECT, tid, attempt)
+            .await?
+        {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+        if resp.len() < 8 {
+            return Err(TrackerError::InvalidResponse);
+        }
+        let id = (&resp[..]).get_u64();
+        *self.connection.lock().unwrap() = Some((id, Instant::now()));
+        Ok(Some(id))
+    }
+
+    /// Sends the request and waits for the response with the same transaction
+    /// id, which is returned without its header.
+    ///
+    /// If no response arrives before the timeout of the current attempt,
+    /// `None` is returned and the attempt count is incremented, so that the
+    /// caller may retransmit the request, possibly after obtaining a new
+    /// connection id if the old one has since expired. Once all attempts are
+    /// used up, a timeout error is returned.
+    async fn send_request(
+        &self,
+        socket: &mut UdpSocket,
+        addr: SocketAddr,
+        req: &[u8],
+        action: u32,
+        tid: u32,
+        attempt: &mut u32,
+    ) -> Result<Option<Vec<u8>>> {
+        if *attempt > MAX_RETRANSMIT_COUNT {
+            return Err(TrackerError::Timeout);
+        }
+        log::trace!(
+            "Sending UDP tracker {} request (attempt {})",
+            action,
+            attempt
+        );
+        socket.send_to(req, &addr).await?;
+
+        let deadline =
+            time::Instant::now() + self.base_timeout * 2u32.pow(*attempt);
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            let (len, from) =
+                match time::timeout_at(deadline, socket.recv_from(&mut buf))
+                    .await
+                {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        *attempt += 1;
+                        return Ok(None);
+                    }
+                };
+            // ignore stray datagrams, such as late responses to previous
+            // attempts
+            if from != addr || len < HEADER_LEN {
+                continue;
+            }
+            let mut header = &buf[..HEADER_LEN];
+            let resp_action = header.get_u32();
+            if header.get_u32() != tid {
+                continue;
+            }
+
+            let body = &buf[HEADER_LEN..len];
+            if resp_action == ACTION_ERROR {
+                return Err(TrackerError::Failure(
+                    String::from_utf8_lossy(body).into_owned(),
+                ));
+            } else if resp_action != action {
+                return Err(TrackerError::InvalidResponse);
+            }
+            return Ok(Some(body.to_vec()));
+        }
+    }
+}
+
+/// Resolves the tracker's address from its URL.
+async fn resolve(url: &Url) -> Result<SocketAddr> {
+    let port = url.port().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "tracker URL has no port")
+    })?;
+    let ip = match url.host() {
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+        Some(Host::Domain(domain)) => {
+            let domain = domain.to_string();
+            let resolve = move || (domain.as_str(), port).to_socket_addrs();
+            let mut addrs = task::spawn_blocking(resolve)
+                .await
+                .map_err(io::Error::other)??;
+            return addrs.next().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "cannot resolve tracker host",
+                )
+                .into()
+            });
+        }
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tracker URL has no host",
+            )
+            .into())
+        }
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Encodes an announce request.
+fn encode_announce(
+    connection_id: u64,
+    tid: u32,
+    key: u32,
+    params: &Announce,
+) -> Vec<u8> {
+    let event: u32 = match params.event {
+        None => 0,
+        Some(Event::Completed) => 1,
+        Some(Event::Started) => 2,
+        Some(Event::Stopped) => 3,
+    };
+    // only an IPv4 address may be sent, otherwise the tracker uses the
+    // address the request came from
+    let ip = match params.ip {
+        Some(IpAddr::V4(ip)) => ip.into(),
+        _ => 0u32,
+    };
+    // -1 lets the tracker decide how many peers to return
+    let peer_count = params.peer_count.map(|c| c as i32).unwrap_or(-1);
+
+    let mut req = Vec::with_capacity(98);
+    req.extend_from_slice(&connection_id.to_be_bytes());
+    req.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    req.extend_from_slice(&tid.to_be_bytes());
+    req.extend_from_slice(&params.info_hash);
+    req.extend_from_slice(&params.peer_id);
+    req.extend_from_slice(&params.downloaded.to_be_bytes());
+    req.extend_from_slice(&params.left.to_be_bytes());
+    req.extend_from_slice(&params.uploaded.to_be_bytes());
+    req.extend_from_slice(&event.to_be_bytes());
+    req.extend_from_slice(&ip.to_be_bytes());
+    req.extend_from_slice(&key.to_be_bytes());
+    req.extend_from_slice(&peer_count.to_be_bytes());
+    req.extend_from_slice(&params.port.to_be_bytes());
+    req
+}
+
+/// Parses the body of an announce response.
+///
+/// The peers are IPv6 addresses if the tracker was contacted over IPv6.
+fn parse_announce(mut buf: &[u8], is_ipv6: bool) -> Result<Response> {
+    if buf.len() < 12 {
+        return Err(TrackerError::InvalidResponse);
+    }
+    let interval = buf.get_u32();
+    let leecher_count = buf.get_u32();
+    let seeder_count = buf.get_u32();
+    let peers = if is_ipv6 {
+        decode_v6_addrs(buf)
+    } else {
+        decode_v4_addrs(buf)
+    };
+    Ok(Response {
+        tracker_id: None,
+        failure_reason: None,
+        warning_message: None,
+        interval: Some(Duration::from_secs(interval as u64)),
+        min_interval: None,
+        seeder_count: Some(seeder_count as usize),
+        leecher_count: Some(leecher_count as usize),
+        peers,
+        peers6: Vec::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONNECTION_ID: u64 = 0x1234_5678_9abc_def0;
+
+    /// Binds the mock tracker's socket and returns it with the URL of the
+    /// tracker.
+    async fn bind_tracker() -> (UdpSocket, Url) {
+        let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let url =
+            Url::parse(&format!("udp://{}", socket.local_addr().unwrap()))
+                .unwrap();
+        (socket, url)
+    }
+
+    /// Receives a request on the mock tracker's socket, checks its action and
+    /// returns its transaction id, body and sender.
+    async fn recv_request(
+        socket: &mut UdpSocket,
+        action: u32,
+    ) -> (u32, Vec<u8>, SocketAddr) {
+        let mut buf = [0; 1024];
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        let mut req = &buf[..len];
+        if action == ACTION_CONNECT {
+            assert_eq!(req.get_u64(), PROTOCOL_ID);
+        } else {
+            assert_eq!(req.get_u64(), CONNECTION_ID);
+        }
+        assert_eq!(req.get_u32(), action);
+        let tid = req.get_u32();
+        (tid, req.to_vec(), from)
+    }
+
+    /// Sends a response with the given header and body from the mock tracker.
+    async fn send_response(
+        socket: &mut UdpSocket,
+        to: SocketAddr,
+        action: u32,
+        tid: u32,
+        body: &[u8],
+    ) {
+        let mut resp = Vec::new();
+        resp.extend_from_slice(&action.to_be_bytes());
+        resp.extend_from_slice(&tid.to_be_bytes());
+        resp.extend_from_slice(body);
+        socket.send_to(&resp, &to).await.unwrap();
+    }
+
+    fn announce_params() -> Announce {
+        Announce {
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+            port: 6881,
+            ip: None,
+            ipv6: None,
+            downloaded: 100,
+            uploaded: 200,
+            left: 300,
+            peer_count: None,
+            tracker_id: None,
+            event: Some(Event::Started),
+        }
+    }
+
+    /// Tests that we connect to the tracker, announce with the right
+    /// parameters, and reuse the connection id for the next announce.
+    #[tokio::test]
+    async fn should_connect_and_announce() {
+        let (mut socket, url) = bind_tracker().await;
+        let tracker = UdpTracker::new(url);
+        let key = tracker.key;
+
+        let server = tokio::spawn(async move {
+            let (tid, _, from) =
+                recv_request(&mut socket, ACTION_CONNECT).await;
+            send_response(
+                &mut socket,
+                from,
+                ACTION_CONNECT,
+                tid,
+                &CONNECTION_ID.to_be_bytes(),
+            )
+            .await;
+
+            // the second announce must not connect again
+            for _ in 0..2 {
+                let (tid, req, from) =
+                    recv_request(&mut socket, ACTION_ANNOUNCE).await;
+                assert_eq!(req.len(), 98 - 16);
+                assert_eq!(&req[..20], &[1; 20]);
+                assert_eq!(&req[20..40], &[2; 20]);
+                let mut req = &req[40..];
+                assert_eq!(req.get_u64(), 100);
+                assert_eq!(req.get_u64(), 300);
+                assert_eq!(req.get_u64(), 200);
+                // started
+                assert_eq!(req.get_u32(), 2);
+                assert_eq!(req.get_u32(), 0);
+                assert_eq!(req.get_u32(), key);
+                assert_eq!(req.get_i32(), -1);
+                assert_eq!(req.get_u16(), 6881);
+
+                let mut body = Vec::new();
+                // interval, leechers and seeders
+                body.extend_from_slice(&1800u32.to_be_bytes());
+                body.extend_from_slice(&3u32.to_be_bytes());
+                body.extend_from_slice(&5u32.to_be_bytes());
+                body.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                send_response(&mut socket, from, ACTION_ANNOUNCE, tid, &body)
+                    .await;
+            }
+        });
+
+        for _ in 0..2 {
+            let resp = tracker.announce(announce_params()).await.unwrap();
+            assert_eq!(resp.interval, Some(Duration::from_secs(1800)));
+            assert_eq!(resp.leecher_count, Some(3));
+            assert_eq!(resp.seeder_count, Some(5));
+            assert_eq!(resp.peers, vec!["10.0.0.1:6881".parse().unwrap()]);
+        }
+        server.await.unwrap();
+    }
+
+    /// Tests that an unanswered request is retransmitted and that the
+    /// tracker's error message is returned.
+    #[tokio::test]
+    async fn should_retransmit_and_return_error() {
+        let (mut socket, url) = bind_tracker().await;
+        let mut tracker = UdpTracker::new(url);
+        tracker.base_timeout = Duration::from_millis(50);
+
+        let server = tokio::spawn(async move {
+            // drop the first connect request
+            recv_request(&mut socket, ACTION_CONNECT).await;
+            let (tid, _, from) =
+                recv_request(&mut socket, ACTION_CONNECT).await;
+            send_response(
+                &mut socket,
+                from,
+                ACTION_CONNECT,
+                tid,
+                &CONNECTION_ID.to_be_bytes(),
+            )
+            .await;
+            let (tid, _, from) =
+                recv_request(&mut socket, ACTION_ANNOUNCE).await;
+            send_response(
+                &mut socket,
+                from,
+                ACTION_ERROR,
+                tid,
+                b"unregistered torrent",
+            )
+            .await;
+        });
+
+        match tracker.announce(announce_params()).await {
+            Err(TrackerError::Failure(reason)) => {
+                assert_eq!(reason, "unregistered torrent")
+            }
+            _ => panic!("expected tracker failure"),
+        }
+        server.await.unwrap();
+    }
+
+    /// Tests that the statistics of several torrents are scraped at once.
+    #[tokio::test]
+    async fn should_scrape() {
+        let (mut socket, url) = bind_tracker().await;
+        let tracker = UdpTracker::new(url);
+
+        let server = tokio::spawn(async move {
+            let (tid, _, from) =
+                recv_request(&mut socket, ACTION_CONNECT).await;
+            send_response(
+                &mut socket,
+                from,
+                ACTION_CONNECT,
+                tid,
+                &CONNECTION_ID.to_be_bytes(),
+            )
+            .await;
+            let (tid, req, from) =
+                recv_request(&mut socket, ACTION_SCRAPE).await;
+            assert_eq!(req.len(), 40);
+            let mut body = Vec::new();
+            for n in [1u32, 2, 3, 4, 5, 6].iter() {
+                body.extend_from_slice(&n.to_be_bytes());
+            }
+            send_response(&mut socket, from, ACTION_SCRAPE, tid, &body).await;
+        });
+
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    seeder_count: 1,
+                    download_count: 2,
+                    leecher_count: 3,
+                },
+                ScrapeStats {
+                    seeder_count: 4,
+                    download_count: 5,
+                    leecher_count: 6,
+                },
+            ]
+        );
+        server.await.unwrap();
+    }
+}
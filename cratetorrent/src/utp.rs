@@ -0,0 +1,585 @@
+//! The uTorrent transport protocol (uTP), see
+//! [BEP 29](http://bittorrent.org/beps/bep_0029.html).
+//!
+//! uTP is a reliable, ordered stream protocol over UDP, like TCP, but with
+//! [LEDBAT](ledbat) congestion control, which backs off as soon as it sees
+//! queuing delay. Thus seeding in the background doesn't slow down the other
+//! traffic of the user, such as browsing.
+//!
+//! All uTP connections share one UDP socket, which is owned by a task that
+//! dispatches the incoming packets to the connections by the sender's address
+//! and the connection id, and that resends timed out packets. Each connection
+//! is exposed to the rest of the engine as a [`UtpStream`], which implements
+//! `AsyncRead` and `AsyncWrite` so that peer sessions can run over it as they
+//! do over TCP.
+
+mod conn;
+mod ledbat;
+mod packet;
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future, select,
+    stream::{self, Fuse, StreamExt},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task, time,
+};
+
+use conn::{Connection, State};
+use packet::{Packet, PacketType, HEADER_LEN};
+
+/// How often timed out packets are checked for.
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The size of the receive buffer, which is larger than any packet we accept.
+const MAX_PACKET_LEN: usize = HEADER_LEN + 8 * 1024;
+
+/// We don't accept more connections from which we only received the SYN than
+/// this, so that spoofed SYNs can't make us keep unbounded state and spawn
+/// a handshake for each.
+const MAX_HALF_OPEN_COUNT: usize = 32;
+
+/// An accepted connection is reset if no packet but the SYN arrives from the
+/// other side within this time.
+const HALF_OPEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The channel for sending commands to the uTP task.
+type Sender = UnboundedSender<Command>;
+/// The channel the uTP task uses to listen for commands.
+type Receiver = UnboundedReceiver<Command>;
+
+/// The channel on which the uTP task sends the connections it accepted.
+pub(crate) type IncomingReceiver = UnboundedReceiver<UtpStream>;
+
+/// The commands the uTP task can receive.
+enum Command {
+    /// Connects to the address, sending the stream once the connection is
+    /// established.
+    Connect {
+        addr: SocketAddr,
+        result_tx: oneshot::Sender<io::Result<UtpStream>>,
+    },
+}
+
+/// The handle to the uTP task, with which connections are made. The task stops
+/// when all handles are dropped.
+#[derive(Clone, Debug)]
+pub(crate) struct UtpSocket {
+    tx: Sender,
+    /// The address of the UDP socket.
+    pub addr: SocketAddr,
+}
+
+impl UtpSocket {
+    /// Connects to the uTP socket at the address.
+    ///
+    /// If the peer doesn't answer, which is the case if it doesn't support
+    /// uTP, this fails with a timeout after a few seconds.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.tx
+            .send(Command::Connect { addr, result_tx })
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?;
+        result_rx
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::NotConnected))?
+    }
+}
+
+/// Spawns the uTP task on the bound UDP socket, returning its handle and the
+/// channel on which it sends the connections it accepts.
+pub(crate) fn spawn(
+    socket: StdUdpSocket,
+) -> io::Result<(UtpSocket, IncomingReceiver)> {
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+    let addr = socket.local_addr()?;
+    let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+    let (incoming_tx, incoming_rx) = mpsc::unbounded_channel();
+    let mut utp = Utp {
+        socket: Arc::new(socket),
+        socket_addr: addr,
+        conns: HashMap::new(),
+        pending_connects: HashMap::new(),
+        half_open: HashMap::new(),
+        cmd_rx: cmd_rx.fuse(),
+        incoming_tx,
+    };
+    task::spawn(async move { utp.run().await });
+    log::info!("Spawned uTP task on {}", addr);
+
+    Ok((UtpSocket { tx: cmd_tx, addr }, incoming_rx))
+}
+
+/// A connection is identified by the address of the other side and the id
+/// with which its packets arrive.
+type ConnKey = (SocketAddr, u16);
+
+/// The task that owns the UDP socket.
+struct Utp {
+    socket: Arc<UdpSocket>,
+    /// The address on which the socket is bound.
+    socket_addr: SocketAddr,
+    conns: HashMap<ConnKey, Arc<Mutex<Connection>>>,
+    /// The connections we initiated that aren't established yet, with the
+    /// channel on which their stream is returned.
+    pending_connects: HashMap<ConnKey, oneshot::Sender<io::Result<UtpStream>>>,
+    /// The connections we accepted from which no packet but the SYN arrived
+    /// yet, with the time they were accepted.
+    half_open: HashMap<ConnKey, Instant>,
+    cmd_rx: Fuse<Receiver>,
+    /// The channel on which accepted connections are sent.
+    incoming_tx: UnboundedSender<UtpStream>,
+}
+
+impl Utp {
+    /// Runs until all handles are dropped, after which the remaining
+    /// connections are reset.
+    async fn run(&mut self) {
+        let socket = Arc::clone(&self.socket);
+        let mut incoming = stream::unfold(socket, |socket| async move {
+            let mut buf = [0; MAX_PACKET_LEN];
+            let result =
+                future::poll_fn(|cx| socket.poll_recv_from(cx, &mut buf))
+                    .await
+                    .map(|(len, addr)| (buf[..len].to_vec(), addr));
+            Some((result, socket))
+        })
+        .boxed()
+        .fuse();
+        let mut tick_timer = time::interval(TICK_INTERVAL).fuse();
+
+        loop {
+            select! {
+                now = tick_timer.select_next_some() => {
+                    self.tick(now.into_std());
+                }
+                result = incoming.select_next_some() => match result {
+                    Ok((buf, addr)) => {
+                        self.handle_packet(&buf, addr, Instant::now());
+                    }
+                    // e.g. an ICMP port unreachable error of a previous send
+                    Err(e) => log::debug!("uTP socket error: {}", e),
+                },
+                cmd = self.cmd_rx.next() => match cmd {
+                    Some(Command::Connect { addr, result_tx }) => {
+                        self.connect(addr, result_tx, Instant::now());
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        // the connections wouldn't get any more packets, so tell the other
+        // sides that they are gone
+        for conn in self.conns.values() {
+            let mut conn = conn.lock().unwrap();
+            conn.reset();
+            send_packets(&self.socket, &mut conn);
+        }
+        log::info!("uTP task stopped");
+    }
+
+    fn connect(
+        &mut self,
+        addr: SocketAddr,
+        result_tx: oneshot::Sender<io::Result<UtpStream>>,
+        now: Instant,
+    ) {
+        log::debug!("Connecting to {} over uTP", addr);
+        // a dual-stack socket sends to and receives from IPv4 peers with
+        // IPv4-mapped addresses
+        let addr = match addr {
+            SocketAddr::V4(v4) if self.socket_addr.is_ipv6() => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            addr => addr,
+        };
+        let mut recv_id: u16 = rand::random();
+        while self.conns.contains_key(&(addr, recv_id)) {
+            recv_id = rand::random();
+        }
+        let mut conn = Connection::connect(addr, recv_id, now);
+        send_packets(&self.socket, &mut conn);
+        let key = (addr, recv_id);
+        self.conns.insert(key, Arc::new(Mutex::new(conn)));
+        self.pending_connects.insert(key, result_tx);
+    }
+
+    fn handle_packet(&mut self, buf: &[u8], addr: SocketAddr, now: Instant) {
+        let packet = match Packet::decode(buf) {
+            Some(packet) => packet,
+            None => {
+                log::debug!("Invalid uTP packet from {}", addr);
+                return;
+            }
+        };
+        // the SYN carries the id with which the initiator receives, and the
+        // connection receives with the next one
+        let key = if packet.ty == PacketType::Syn {
+            (addr, packet.conn_id.wrapping_add(1))
+        } else {
+            (addr, packet.conn_id)
+        };
+
+        let conn = match self.conns.get(&key) {
+            Some(conn) => Arc::clone(conn),
+            None => {
+                if packet.ty == PacketType::Syn {
+                    self.accept(addr, &packet, now);
+                } else if packet.ty != PacketType::Reset {
+                    // tell the other side that we don't know the connection
+                    self.send_reset(addr, &packet);
+                }
+                return;
+            }
+        };
+
+        // a repeated SYN doesn't show that the other side got our reply
+        if packet.ty != PacketType::Syn {
+            self.half_open.remove(&key);
+        }
+        let mut conn = conn.lock().unwrap();
+        conn.handle_packet(packet, now);
+        send_packets(&self.socket, &mut conn);
+
+        if conn.state != State::SynSent {
+            if let Some(result_tx) = self.pending_connects.remove(&key) {
+                let result = match conn.error() {
+                    Some(e) => Err(e),
+                    None => Ok(self.stream(&key)),
+                };
+                drop(conn);
+                self.send_connect_result(result_tx, result);
+            }
+        }
+    }
+
+    /// Accepts the connection initiated by the SYN packet, and sends its
+    /// stream to the listener, unless there are too many half-open
+    /// connections, in which case the SYN is ignored.
+    fn accept(&mut self, addr: SocketAddr, syn: &Packet, now: Instant) {
+        if self.half_open.len() >= MAX_HALF_OPEN_COUNT {
+            log::debug!(
+                "Too many half-open uTP connections, ignoring {}",
+                addr
+            );
+            return;
+        }
+        let mut conn = Connection::accept(addr, syn, rand::random(), now);
+        send_packets(&self.socket, &mut conn);
+        let key = (addr, conn.recv_id);
+        log::debug!("Accepted uTP connection from {}", addr);
+        self.conns.insert(key, Arc::new(Mutex::new(conn)));
+        self.half_open.insert(key, now);
+        let stream = self.stream(&key);
+        // the listener may have stopped, in which case dropping the stream
+        // closes the connection
+        self.incoming_tx.send(stream).ok();
+    }
+
+    fn stream(&self, key: &ConnKey) -> UtpStream {
+        UtpStream {
+            addr: key.0,
+            conn: Arc::clone(&self.conns[key]),
+            socket: Arc::clone(&self.socket),
+        }
+    }
+
+    fn send_connect_result(
+        &self,
+        result_tx: oneshot::Sender<io::Result<UtpStream>>,
+        result: io::Result<UtpStream>,
+    ) {
+        // the stream is dropped, and thus closed, if the connecting task gave
+        // up in the meantime
+        result_tx.send(result).ok();
+    }
+
+    fn send_reset(&self, addr: SocketAddr, packet: &Packet) {
+        let reset = Packet {
+            ty: PacketType::Reset,
+            conn_id: packet.conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 0,
+            seq_nr: 0,
+            ack_nr: packet.seq_nr,
+            payload: Vec::new(),
+        };
+        self.socket.try_send_to(&reset.encode(), addr).ok();
+    }
+
+    /// Resends timed out packets, resets the half-open connections that timed
+    /// out and forgets the connections that are done.
+    fn tick(&mut self, now: Instant) {
+        let conns = &self.conns;
+        self.half_open.retain(|key, accept_time| {
+            if now.saturating_duration_since(*accept_time) < HALF_OPEN_TIMEOUT {
+                return true;
+            }
+            log::debug!("Half-open uTP connection from {} timed out", key.0);
+            if let Some(conn) = conns.get(key) {
+                conn.lock().unwrap().reset();
+            }
+            false
+        });
+
+        let mut failed_connects = Vec::new();
+        let socket = &self.socket;
+        self.conns.retain(|key, conn| {
+            let mut conn = conn.lock().unwrap();
+            conn.tick(now);
+            send_packets(socket, &mut conn);
+            if conn.state == State::Closed && conn.error().is_some() {
+                failed_connects.push(*key);
+            }
+            !conn.is_done()
+        });
+        let conns = &self.conns;
+        self.half_open.retain(|key, _| conns.contains_key(key));
+        for key in failed_connects {
+            if let Some(result_tx) = self.pending_connects.remove(&key) {
+                let e = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "uTP connection timed out",
+                );
+                self.send_connect_result(result_tx, Err(e));
+            }
+        }
+    }
+}
+
+/// Sends the packets in the connection's outbox.
+///
+/// If the socket's send buffer is full the packets are dropped, which is no
+/// different from them being lost on the way.
+fn send_packets(socket: &UdpSocket, conn: &mut Connection) {
+    for packet in conn.take_outbox() {
+        if let Err(e) = socket.try_send_to(&packet.encode(), conn.addr) {
+            log::debug!("Error sending uTP packet to {}: {}", conn.addr, e);
+        }
+    }
+}
+
+/// A uTP connection, which implements `AsyncRead` and `AsyncWrite`.
+///
+/// Dropping the stream closes the connection, after the written data is
+/// delivered.
+pub(crate) struct UtpStream {
+    addr: SocketAddr,
+    conn: Arc<Mutex<Connection>>,
+    socket: Arc<UdpSocket>,
+}
+
+impl UtpStream {
+    /// Returns the address of the other side.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut conn = self.conn.lock().unwrap();
+        let len = conn.read(buf);
+        if len > 0 {
+            // reading may have opened our window
+            send_packets(&self.socket, &mut conn);
+            return Poll::Ready(Ok(len));
+        }
+        if conn.is_eof() || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if let Some(e) = conn.error() {
+            return Poll::Ready(Err(e));
+        }
+        conn.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut conn = self.conn.lock().unwrap();
+        if let Some(e) = conn.error() {
+            return Poll::Ready(Err(e));
+        }
+        if conn.is_fin_queued() || conn.state == State::Closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if !conn.can_write() {
+            conn.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let len = conn.write(buf, Instant::now());
+        send_packets(&self.socket, &mut conn);
+        Poll::Ready(Ok(len))
+    }
+
+    /// The written data is sent as soon as the window allows, so there is
+    /// nothing to flush.
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.shutdown(Instant::now());
+        send_packets(&self.socket, &mut conn);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        conn.is_stream_dropped = true;
+        conn.shutdown(Instant::now());
+        send_packets(&self.socket, &mut conn);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn spawn_local() -> (UtpSocket, IncomingReceiver) {
+        spawn(StdUdpSocket::bind("127.0.0.1:0").unwrap()).unwrap()
+    }
+
+    /// Tests that data is transferred in both directions over loopback, and
+    /// that shutting down one side is seen as the end of the stream by the
+    /// other.
+    #[tokio::test]
+    async fn should_transfer_data() {
+        let (client, _) = spawn_local();
+        let (server, mut incoming) = spawn_local();
+
+        let (outbound, inbound) =
+            tokio::join!(client.connect(server.addr), incoming.next());
+        let mut outbound = outbound.unwrap();
+        let mut inbound = inbound.unwrap();
+        assert_eq!(inbound.peer_addr(), client.addr);
+        assert_eq!(outbound.peer_addr(), server.addr);
+
+        // more than fits in the send buffer, so that writing has to wait for
+        // acknowledgements
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let writer = task::spawn(async move {
+            outbound.write_all(&data).await.unwrap();
+            outbound.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            outbound.read_to_end(&mut reply).await.unwrap();
+            reply
+        });
+
+        let mut received = Vec::new();
+        inbound.read_to_end(&mut received).await.unwrap();
+        assert!(received == expected);
+        inbound.write_all(b"thanks").await.unwrap();
+        inbound.shutdown().await.unwrap();
+
+        assert_eq!(writer.await.unwrap(), b"thanks");
+    }
+
+    /// Tests that connecting to a socket that doesn't answer fails.
+    #[tokio::test]
+    async fn should_fail_to_connect() {
+        let (client, _) = spawn_local();
+        // nothing is listening on this socket
+        let silent = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let result = time::timeout(
+            Duration::from_secs(30),
+            client.connect(silent.local_addr().unwrap()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    /// Tests that no more than the maximum number of half-open connections are
+    /// accepted, and that they are reset if the other side goes silent.
+    #[tokio::test]
+    async fn should_limit_half_open_connections() {
+        let (server, mut incoming) = spawn_local();
+        let client = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+        let syn = |conn_id| Packet {
+            ty: PacketType::Syn,
+            conn_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            window: 1024 * 1024,
+            seq_nr: 1,
+            ack_nr: 0,
+            payload: Vec::new(),
+        };
+
+        for i in 0..MAX_HALF_OPEN_COUNT as u16 + 1 {
+            client.send_to(&syn(2 * i).encode(), server.addr).unwrap();
+        }
+        let mut streams = Vec::new();
+        for _ in 0..MAX_HALF_OPEN_COUNT {
+            streams.push(incoming.next().await.unwrap());
+        }
+        // the last SYN is ignored
+        assert!(time::timeout(Duration::from_millis(500), incoming.next())
+            .await
+            .is_err());
+
+        // the other side never acknowledged our replies, so the connections
+        // are eventually reset
+        let mut buf = [0; 1];
+        let result = time::timeout(
+            HALF_OPEN_TIMEOUT + Duration::from_secs(5),
+            streams[0].read(&mut buf),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result.unwrap_err().kind(),
+            io::ErrorKind::ConnectionAborted
+        );
+    }
+}
@@ -0,0 +1,748 @@
+//! The state machine of a single uTP connection.
+//!
+//! The connection doesn't do any IO itself: incoming packets and timer ticks
+//! are fed to it, and the packets it wants to send are collected in an outbox
+//! that the owner of the socket drains. This keeps the protocol logic
+//! independent of the socket and the clock, and thus testable.
+//!
+//! Every packet that carries data (and the SYN and FIN packets) consumes
+//! a sequence number, and each is acknowledged by the other side's next
+//! packet, which carries the sequence number of the last packet received in
+//! order. Unacknowledged packets are resent after a timeout, or right away if
+//! the same packet is acknowledged repeatedly, which means that the one after
+//! it was lost. How many bytes may be in flight is decided by
+//! [LEDBAT](super::ledbat).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    task::Waker,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use super::{
+    ledbat::Ledbat,
+    packet::{Packet, PacketType},
+};
+
+/// The maximum payload of a packet, which together with the uTP, UDP and IP
+/// headers fits the common Ethernet MTU of 1500 bytes.
+pub(crate) const MSS: usize = 1400;
+
+/// How many bytes we buffer of the received data that the application hasn't
+/// read yet, which is the window we advertise.
+const RECV_BUF_LEN: usize = 1024 * 1024;
+
+/// How many bytes the application may write before they're sent.
+const SEND_BUF_LEN: usize = 1024 * 1024;
+
+/// How many packets received out of order we buffer.
+const MAX_OUT_OF_ORDER_COUNT: usize = RECV_BUF_LEN / MSS;
+
+/// The retransmission timeout before the round trip time is measured.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The retransmission timeout never falls below this.
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The retransmission timeout stops doubling at this.
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// After a packet is resent this many times without being acknowledged, the
+/// connection is considered lost.
+const MAX_RESEND_COUNT: usize = 5;
+
+/// After the SYN packet is resent this many times, which takes a few seconds,
+/// the peer is considered not to support uTP.
+const MAX_SYN_RESEND_COUNT: usize = 2;
+
+/// When the same packet is acknowledged this many more times, the next one is
+/// considered lost.
+const DUPLICATE_ACK_THRESHOLD: usize = 3;
+
+/// The state of a connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum State {
+    /// We sent a SYN and wait for it to be acknowledged.
+    SynSent,
+    Connected,
+    /// The connection is closed, either gracefully after both sides sent and
+    /// acknowledged a FIN, or because of an error.
+    Closed,
+}
+
+/// A packet we sent that wasn't acknowledged yet.
+#[derive(Debug)]
+struct SentPacket {
+    ty: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_time: Instant,
+    resend_count: usize,
+}
+
+/// A uTP connection.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    /// The address of the other side.
+    pub addr: SocketAddr,
+    pub state: State,
+    /// The id with which the other side's packets arrive.
+    pub recv_id: u16,
+    /// The id with which we send packets.
+    send_id: u16,
+    /// The sequence number of the next packet we send.
+    seq_nr: u16,
+    /// The sequence number of the last packet we received in order.
+    ack_nr: u16,
+    /// The timestamp difference we send back, measured on the last packet we
+    /// received.
+    reply_micros: u32,
+    /// The number of bytes the other side is willing to receive.
+    peer_window: usize,
+
+    /// The data received in order that the application hasn't read yet.
+    recv_buf: VecDeque<u8>,
+    /// The packets received ahead of the next expected one, by sequence
+    /// number.
+    out_of_order: HashMap<u16, Packet>,
+    /// Whether the other side's FIN was received in order, after which there
+    /// is no more data to read.
+    is_eof: bool,
+
+    /// The data written by the application that wasn't yet sent.
+    send_buf: VecDeque<u8>,
+    /// The packets we sent that weren't acknowledged, in order.
+    in_flight: VecDeque<SentPacket>,
+    /// The sum of the payload lengths in flight.
+    flight_len: usize,
+    /// Whether the application closed its side of the connection.
+    is_fin_queued: bool,
+    is_fin_sent: bool,
+    is_fin_acked: bool,
+    /// How many times in a row the packet before the first in flight was
+    /// acknowledged.
+    duplicate_ack_count: usize,
+
+    ledbat: Ledbat,
+    /// The smoothed round trip time and its variance.
+    rtt: Option<(Duration, Duration)>,
+    /// The retransmission timeout.
+    timeout: Duration,
+
+    /// The error that closed the connection.
+    error: Option<io::ErrorKind>,
+    /// Whether the application dropped its stream, after which the connection
+    /// only lives until our data is delivered.
+    pub is_stream_dropped: bool,
+    /// The packets to send.
+    outbox: Vec<Packet>,
+    /// The tasks waiting to read and write, respectively.
+    pub read_waker: Option<Waker>,
+    pub write_waker: Option<Waker>,
+}
+
+impl Connection {
+    /// Starts a connection to the address with the given receive id, queuing
+    /// the SYN packet.
+    pub fn connect(addr: SocketAddr, recv_id: u16, now: Instant) -> Self {
+        let mut conn =
+            Self::new(addr, recv_id, recv_id.wrapping_add(1), 1, 0, now);
+        conn.state = State::SynSent;
+        conn.send_packet(PacketType::Syn, Vec::new(), now);
+        conn
+    }
+
+    /// Accepts the connection that the SYN packet initiated, queuing its
+    /// acknowledgement.
+    pub fn accept(
+        addr: SocketAddr,
+        syn: &Packet,
+        seq_nr: u16,
+        now: Instant,
+    ) -> Self {
+        let mut conn = Self::new(
+            addr,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+            seq_nr,
+            syn.seq_nr,
+            now,
+        );
+        conn.state = State::Connected;
+        conn.reply_micros = timestamp_micros().wrapping_sub(syn.timestamp);
+        conn.peer_window = syn.window as usize;
+        conn.send_ack();
+        conn
+    }
+
+    fn new(
+        addr: SocketAddr,
+        recv_id: u16,
+        send_id: u16,
+        seq_nr: u16,
+        ack_nr: u16,
+        now: Instant,
+    ) -> Self {
+        Self {
+            addr,
+            state: State::Connected,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            reply_micros: 0,
+            peer_window: MSS,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            is_eof: false,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            flight_len: 0,
+            is_fin_queued: false,
+            is_fin_sent: false,
+            is_fin_acked: false,
+            duplicate_ack_count: 0,
+            ledbat: Ledbat::new(MSS, now),
+            rtt: None,
+            timeout: INITIAL_TIMEOUT,
+            error: None,
+            is_stream_dropped: false,
+            outbox: Vec::new(),
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Returns the error that closed the connection, if any.
+    pub fn error(&self) -> Option<io::Error> {
+        self.error.map(io::Error::from)
+    }
+
+    /// Returns whether the connection may be forgotten: it's closed, or the
+    /// application is done with it and all our data was delivered.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Closed
+            || (self.is_stream_dropped
+                && self.is_fin_acked
+                && self.in_flight.is_empty())
+    }
+
+    /// Returns the packets to send.
+    pub fn take_outbox(&mut self) -> Vec<Packet> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// Handles a packet from the other side.
+    pub fn handle_packet(&mut self, packet: Packet, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        if packet.ty == PacketType::Reset {
+            self.close(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        self.reply_micros = timestamp_micros().wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window as usize;
+
+        match packet.ty {
+            // our acknowledgement of the SYN was lost
+            PacketType::Syn => {
+                self.send_ack();
+                return;
+            }
+            PacketType::State if self.state == State::SynSent => {
+                // the other side's first packet will have the sequence
+                // number of its acknowledgement of our SYN
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            // data before the SYN is acknowledged is not valid
+            return;
+        }
+
+        self.handle_ack(&packet, now);
+        if let PacketType::Data | PacketType::Fin = packet.ty {
+            self.handle_data(packet);
+        }
+        self.flush(now);
+        self.wake();
+    }
+
+    /// Removes the packets acknowledged by the packet from the ones in flight,
+    /// or resends the first one in flight if it seems lost.
+    fn handle_ack(&mut self, packet: &Packet, now: Instant) {
+        // ignore acknowledgements of packets we haven't sent
+        let last_sent = self.seq_nr.wrapping_sub(1);
+        if is_before(last_sent, packet.ack_nr) {
+            return;
+        }
+
+        let flight_len = self.flight_len;
+        let mut acked_len = 0;
+        let mut acked_count = 0;
+        let mut rtt = None;
+        while let Some(sent) = self.in_flight.front() {
+            if is_before(packet.ack_nr, sent.seq_nr) {
+                break;
+            }
+            let sent = self.in_flight.pop_front().unwrap();
+            acked_len += sent.payload.len();
+            acked_count += 1;
+            // the round trip time of resent packets is ambiguous
+            if sent.resend_count == 0 {
+                rtt = Some(now.saturating_duration_since(sent.sent_time));
+            }
+            if sent.ty == PacketType::Fin {
+                self.is_fin_acked = true;
+            }
+        }
+
+        if acked_count > 0 {
+            self.flight_len -= acked_len;
+            self.duplicate_ack_count = 0;
+            if let Some(rtt) = rtt {
+                self.update_timeout(rtt);
+            }
+            if packet.timestamp_diff != 0 {
+                self.ledbat.on_ack(
+                    packet.timestamp_diff,
+                    acked_len,
+                    flight_len,
+                    now,
+                );
+            }
+            if self.is_fin_acked && self.is_eof {
+                self.state = State::Closed;
+            }
+        } else if packet.ty == PacketType::State && !self.in_flight.is_empty() {
+            self.duplicate_ack_count += 1;
+            if self.duplicate_ack_count == DUPLICATE_ACK_THRESHOLD {
+                log::debug!("uTP packet to {} lost, resending", self.addr);
+                self.ledbat.on_loss();
+                self.resend_first(now);
+            }
+        }
+    }
+
+    /// Buffers the payload of the packet, in order, and acknowledges it.
+    fn handle_data(&mut self, packet: Packet) {
+        let distance = packet.seq_nr.wrapping_sub(self.ack_nr) as usize;
+        if distance == 1 {
+            self.receive(packet);
+            // the packet may have filled a gap before buffered ones
+            while let Some(packet) =
+                self.out_of_order.remove(&self.ack_nr.wrapping_add(1))
+            {
+                self.receive(packet);
+            }
+        } else if distance > 1
+            && distance <= MAX_OUT_OF_ORDER_COUNT
+            && !self.is_eof
+        {
+            self.out_of_order.insert(packet.seq_nr, packet);
+        }
+        // packets before the next expected one are duplicates, which are
+        // acknowledged again as our acknowledgement may have been lost
+        self.send_ack();
+    }
+
+    fn receive(&mut self, packet: Packet) {
+        self.ack_nr = packet.seq_nr;
+        if packet.ty == PacketType::Fin {
+            self.is_eof = true;
+            self.out_of_order.clear();
+            if self.is_fin_acked {
+                self.state = State::Closed;
+            }
+        } else {
+            self.recv_buf.extend(packet.payload);
+        }
+    }
+
+    /// Copies the received data into the buffer, returning the number of
+    /// bytes copied.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let was_full = self.recv_window() < MSS;
+        let len = buf.len().min(self.recv_buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.recv_buf.drain(..len)) {
+            *dst = src;
+        }
+        // tell the other side that it may send again
+        if was_full && self.recv_window() >= MSS {
+            self.send_ack();
+        }
+        len
+    }
+
+    /// Returns whether there is no more data to read, because the other side
+    /// closed the connection.
+    pub fn is_eof(&self) -> bool {
+        self.is_eof && self.recv_buf.is_empty()
+    }
+
+    /// Buffers the data for sending and sends what the window allows,
+    /// returning the number of bytes buffered.
+    pub fn write(&mut self, buf: &[u8], now: Instant) -> usize {
+        let len = buf.len().min(SEND_BUF_LEN - self.send_buf.len());
+        self.send_buf.extend(&buf[..len]);
+        self.flush(now);
+        len
+    }
+
+    /// Closes our side of the connection, sending a FIN after the buffered
+    /// data.
+    pub fn shutdown(&mut self, now: Instant) {
+        self.is_fin_queued = true;
+        self.flush(now);
+    }
+
+    /// Sends buffered data as long as the window allows it.
+    fn flush(&mut self, now: Instant) {
+        if self.state != State::Connected {
+            return;
+        }
+        while !self.send_buf.is_empty() {
+            let len = self.send_buf.len().min(MSS);
+            let window = self.ledbat.window().min(self.peer_window);
+            // a packet is always allowed if none are in flight, so that the
+            // connection doesn't stall when the other side's window is closed
+            if !self.in_flight.is_empty() && self.flight_len + len > window {
+                break;
+            }
+            let payload = self.send_buf.drain(..len).collect();
+            self.send_packet(PacketType::Data, payload, now);
+        }
+        if self.is_fin_queued && !self.is_fin_sent && self.send_buf.is_empty() {
+            self.is_fin_sent = true;
+            self.send_packet(PacketType::Fin, Vec::new(), now);
+        }
+    }
+
+    /// Returns whether the application may write more data.
+    pub fn can_write(&self) -> bool {
+        self.send_buf.len() < SEND_BUF_LEN
+    }
+
+    /// Returns whether the application closed its side of the connection.
+    pub fn is_fin_queued(&self) -> bool {
+        self.is_fin_queued
+    }
+
+    /// Checks whether the first packet in flight timed out, in which case it's
+    /// resent, or the connection is closed if it was resent too many times.
+    pub fn tick(&mut self, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        let sent = match self.in_flight.front() {
+            Some(sent) => sent,
+            None => return,
+        };
+        if now.saturating_duration_since(sent.sent_time) < self.timeout {
+            return;
+        }
+
+        let is_syn = sent.ty == PacketType::Syn;
+        let max_resend_count = if is_syn {
+            MAX_SYN_RESEND_COUNT
+        } else {
+            MAX_RESEND_COUNT
+        };
+        if sent.resend_count >= max_resend_count {
+            log::debug!("uTP connection to {} timed out", self.addr);
+            self.close(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        log::debug!("uTP packet to {} timed out, resending", self.addr);
+        // the SYN timeout doesn't back off, so that falling back to TCP for
+        // peers that don't support uTP doesn't take long
+        if !is_syn {
+            self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+        }
+        self.ledbat.on_timeout();
+        self.duplicate_ack_count = 0;
+        self.resend_first(now);
+    }
+
+    /// Closes the connection because of the error, sending a reset to the
+    /// other side.
+    pub fn reset(&mut self) {
+        if self.state != State::Closed {
+            self.queue_packet(PacketType::Reset, self.seq_nr, Vec::new());
+            self.close(io::ErrorKind::ConnectionAborted);
+        }
+    }
+
+    fn close(&mut self, error: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(error);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Updates the retransmission timeout with a new round trip time sample,
+    /// as in TCP (RFC 6298).
+    fn update_timeout(&mut self, sample: Duration) {
+        let (rtt, var) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, var)) => {
+                let diff = rtt.abs_diff(sample);
+                (rtt * 7 / 8 + sample / 8, var * 3 / 4 + diff / 4)
+            }
+        };
+        self.rtt = Some((rtt, var));
+        self.timeout = (rtt + var * 4).max(MIN_TIMEOUT);
+    }
+
+    /// Sends a packet that consumes a sequence number, and which is therefore
+    /// kept until acknowledged.
+    fn send_packet(&mut self, ty: PacketType, payload: Vec<u8>, now: Instant) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.flight_len += payload.len();
+        self.queue_packet(ty, seq_nr, payload.clone());
+        self.in_flight.push_back(SentPacket {
+            ty,
+            seq_nr,
+            payload,
+            sent_time: now,
+            resend_count: 0,
+        });
+    }
+
+    fn resend_first(&mut self, now: Instant) {
+        if let Some(sent) = self.in_flight.front_mut() {
+            sent.sent_time = now;
+            sent.resend_count += 1;
+            let (ty, seq_nr, payload) =
+                (sent.ty, sent.seq_nr, sent.payload.clone());
+            self.queue_packet(ty, seq_nr, payload);
+        }
+    }
+
+    /// Acknowledges the packets received so far, and advertises our window.
+    fn send_ack(&mut self) {
+        self.queue_packet(PacketType::State, self.seq_nr, Vec::new());
+    }
+
+    fn queue_packet(&mut self, ty: PacketType, seq_nr: u16, payload: Vec<u8>) {
+        // the SYN is sent with the id on which we receive, from which the
+        // other side derives both ids
+        let conn_id = if ty == PacketType::Syn {
+            self.recv_id
+        } else {
+            self.send_id
+        };
+        self.outbox.push(Packet {
+            ty,
+            conn_id,
+            timestamp: timestamp_micros(),
+            timestamp_diff: self.reply_micros,
+            window: self.recv_window() as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+            payload,
+        });
+    }
+
+    fn recv_window(&self) -> usize {
+        RECV_BUF_LEN.saturating_sub(self.recv_buf.len())
+    }
+}
+
+/// Returns whether the sequence number `a` comes before `b`, taking into
+/// account that sequence numbers wrap around.
+fn is_before(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) < 0
+}
+
+/// Returns the current time in microseconds, wrapped to 32 bits, with which
+/// packets are timestamped.
+fn timestamp_micros() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u32)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delivers the packets in the outbox of `from` to `to`, returning how
+    /// many there were.
+    fn deliver(
+        from: &mut Connection,
+        to: &mut Connection,
+        now: Instant,
+    ) -> usize {
+        let packets = from.take_outbox();
+        let count = packets.len();
+        for packet in packets {
+            to.handle_packet(packet, now);
+        }
+        count
+    }
+
+    /// Connects two connections, returning the initiating and the accepting
+    /// one.
+    fn connect(now: Instant) -> (Connection, Connection) {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut a = Connection::connect(addr, 100, now);
+        let syn = a.take_outbox().pop().unwrap();
+        assert_eq!(syn.ty, PacketType::Syn);
+        assert_eq!(syn.conn_id, 100);
+
+        let mut b = Connection::accept(addr, &syn, 5000, now);
+        assert_eq!(b.recv_id, 101);
+        assert_eq!(deliver(&mut b, &mut a, now), 1);
+        assert_eq!(a.state, State::Connected);
+        assert!(a.in_flight.is_empty());
+        (a, b)
+    }
+
+    fn read_all(conn: &mut Connection) -> Vec<u8> {
+        let mut buf = vec![0; RECV_BUF_LEN];
+        let len = conn.read(&mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn should_transfer_data_both_ways() {
+        let now = Instant::now();
+        let (mut a, mut b) = connect(now);
+
+        let data: Vec<u8> = (0..10 * MSS).map(|i| i as u8).collect();
+        assert_eq!(a.write(&data, now), data.len());
+        assert_eq!(b.write(b"hello", now), 5);
+
+        // deliver until neither side has anything to send
+        while deliver(&mut a, &mut b, now) + deliver(&mut b, &mut a, now) > 0 {}
+        assert_eq!(read_all(&mut b), data);
+        assert_eq!(read_all(&mut a), b"hello");
+        assert!(a.in_flight.is_empty());
+        assert!(b.in_flight.is_empty());
+
+        // closing both sides
+        a.shutdown(now);
+        b.shutdown(now);
+        while deliver(&mut a, &mut b, now) + deliver(&mut b, &mut a, now) > 0 {}
+        assert!(a.is_eof());
+        assert!(b.is_eof());
+        assert_eq!(a.state, State::Closed);
+        assert_eq!(b.state, State::Closed);
+        assert!(a.error().is_none());
+    }
+
+    /// Tests that packets received out of order are buffered until the gap is
+    /// filled, and that the lost packet is resent after duplicate
+    /// acknowledgements.
+    #[test]
+    fn should_resend_lost_packet() {
+        let now = Instant::now();
+        let (mut a, mut b) = connect(now);
+        // let the window grow so that several packets are in flight
+        a.ledbat.set_window(10 * MSS);
+        b.peer_window = RECV_BUF_LEN;
+        a.peer_window = RECV_BUF_LEN;
+
+        let data: Vec<u8> = (0..5 * MSS).map(|i| (i % 251) as u8).collect();
+        a.write(&data, now);
+        let mut packets = a.take_outbox();
+        assert_eq!(packets.len(), 5);
+        // the first packet is lost
+        packets.remove(0);
+        for packet in packets {
+            b.handle_packet(packet, now);
+        }
+        assert!(read_all(&mut b).is_empty());
+        assert_eq!(b.out_of_order.len(), 4);
+
+        // the duplicate acknowledgements make a resend the first packet
+        deliver(&mut b, &mut a, now);
+        let resent = a.take_outbox();
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].payload, &data[..MSS]);
+        for packet in resent {
+            b.handle_packet(packet, now);
+        }
+        assert_eq!(read_all(&mut b), data);
+        deliver(&mut b, &mut a, now);
+        assert!(a.in_flight.is_empty());
+    }
+
+    /// Tests that an unacknowledged packet is resent after the timeout, and
+    /// that the connection is closed once it was resent too many times.
+    #[test]
+    fn should_time_out() {
+        let mut now = Instant::now();
+        let (mut a, _b) = connect(now);
+        a.write(b"lost", now);
+        a.take_outbox();
+
+        a.tick(now);
+        assert!(a.take_outbox().is_empty());
+        for _ in 0..MAX_RESEND_COUNT {
+            now += MAX_TIMEOUT;
+            a.tick(now);
+            let resent = a.take_outbox();
+            assert_eq!(resent.len(), 1);
+            assert_eq!(resent[0].payload, b"lost");
+        }
+        now += MAX_TIMEOUT;
+        a.tick(now);
+        assert_eq!(a.state, State::Closed);
+        assert_eq!(a.error().unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+
+    /// Tests that an unanswered SYN gives up sooner than data packets.
+    #[test]
+    fn should_give_up_connecting() {
+        let mut now = Instant::now();
+        let mut a =
+            Connection::connect("127.0.0.1:1".parse().unwrap(), 100, now);
+        a.take_outbox();
+        for _ in 0..MAX_SYN_RESEND_COUNT {
+            now += MAX_TIMEOUT;
+            a.tick(now);
+            assert_eq!(a.take_outbox()[0].ty, PacketType::Syn);
+        }
+        now += MAX_TIMEOUT;
+        a.tick(now);
+        assert_eq!(a.state, State::Closed);
+    }
+
+    #[test]
+    fn should_handle_reset() {
+        let now = Instant::now();
+        let (mut a, mut b) = connect(now);
+        a.reset();
+        assert_eq!(deliver(&mut a, &mut b, now), 1);
+        assert_eq!(b.state, State::Closed);
+        assert_eq!(b.error().unwrap().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn should_compare_wrapping_seq_nrs() {
+        assert!(is_before(1, 2));
+        assert!(is_before(65535, 0));
+        assert!(!is_before(0, 65535));
+        assert!(!is_before(2, 2));
+    }
+}
@@ -0,0 +1,243 @@
+//! LEDBAT congestion control, see
+//! [RFC 6817](https://tools.ietf.org/html/rfc6817).
+//!
+//! Rather than waiting for packet loss like TCP, LEDBAT measures the one-way
+//! delay of packets and shrinks the congestion window as soon as the delay
+//! grows beyond the lowest one seen, which means that a queue is building up
+//! somewhere on the path. It aims to add at most [`TARGET`] of queuing delay,
+//! and so uses the spare capacity of the link while yielding to interactive
+//! traffic and TCP flows.
+//!
+//! Delays are measured between clocks that aren't synchronized, so they also
+//! contain the offset of the clocks. That is the same for all samples, so it
+//! cancels out when subtracting the base delay.
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// The queuing delay we aim for, in microseconds.
+const TARGET: u32 = 100_000;
+
+/// How fast the window grows or shrinks: with a gain of 1, it changes by at
+/// most one packet per round trip.
+const GAIN: f64 = 1.0;
+
+/// The window in packets when a connection starts.
+const INIT_WINDOW: usize = 2;
+
+/// The window never shrinks below this many packets.
+const MIN_WINDOW: usize = 1;
+
+/// The window may only grow this many packets beyond the bytes in flight, so
+/// that it doesn't grow while the connection is limited by the application.
+const ALLOWED_INCREASE: usize = 1;
+
+/// The base delay is the lowest delay of this many minutes, so that it adapts
+/// to route changes.
+const BASE_HISTORY_LEN: usize = 10;
+
+/// The current delay is the lowest of this many samples, which filters out
+/// noise.
+const CURRENT_FILTER_LEN: usize = 4;
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+/// The congestion controller of a connection.
+#[derive(Debug)]
+pub(crate) struct Ledbat {
+    /// The maximum number of bytes in a packet.
+    mss: usize,
+    /// The congestion window, in bytes.
+    window: f64,
+    /// The lowest delay of each of the last minutes, in microseconds.
+    base_delays: VecDeque<u32>,
+    /// When the last entry of `base_delays` was started.
+    last_rollover: Instant,
+    /// The last few delay samples, in microseconds.
+    current_delays: VecDeque<u32>,
+}
+
+impl Ledbat {
+    pub fn new(mss: usize, now: Instant) -> Self {
+        Self {
+            mss,
+            window: (INIT_WINDOW * mss) as f64,
+            base_delays: VecDeque::with_capacity(BASE_HISTORY_LEN),
+            last_rollover: now,
+            current_delays: VecDeque::with_capacity(CURRENT_FILTER_LEN),
+        }
+    }
+
+    /// Returns the congestion window, in bytes.
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    #[cfg(test)]
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window as f64;
+    }
+
+    /// Returns the queuing delay we're causing, in microseconds, or 0 until
+    /// there is a sample.
+    pub fn queuing_delay(&self) -> u32 {
+        match (self.current_delay(), self.base_delay()) {
+            (Some(current), Some(base)) => current.wrapping_sub(base),
+            _ => 0,
+        }
+    }
+
+    /// Updates the window when `acked_len` bytes were acknowledged, with the
+    /// delay the other side measured for our packets and the number of bytes
+    /// that were in flight before the acknowledgement.
+    pub fn on_ack(
+        &mut self,
+        delay: u32,
+        acked_len: usize,
+        flight_len: usize,
+        now: Instant,
+    ) {
+        self.update_base_delay(delay, now);
+        if self.current_delays.len() == CURRENT_FILTER_LEN {
+            self.current_delays.pop_front();
+        }
+        self.current_delays.push_back(delay);
+
+        // negative if we're beyond the target, in which case the window
+        // shrinks proportionally
+        let queuing_delay = self.queuing_delay().min(2 * TARGET);
+        let off_target = (TARGET as f64 - queuing_delay as f64) / TARGET as f64;
+        self.window += GAIN * off_target * acked_len as f64 * self.mss as f64
+            / self.window;
+
+        let max_window = flight_len + ALLOWED_INCREASE * self.mss;
+        self.window = self
+            .window
+            .min(max_window as f64)
+            .max((MIN_WINDOW * self.mss) as f64);
+    }
+
+    /// Halves the window when a packet was lost.
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max((MIN_WINDOW * self.mss) as f64);
+    }
+
+    /// Resets the window when a packet timed out, as then we don't know how
+    /// much of the window is still in flight.
+    pub fn on_timeout(&mut self) {
+        self.window = (MIN_WINDOW * self.mss) as f64;
+    }
+
+    fn update_base_delay(&mut self, delay: u32, now: Instant) {
+        if self.base_delays.is_empty()
+            || now.saturating_duration_since(self.last_rollover) >= MINUTE
+        {
+            if self.base_delays.len() == BASE_HISTORY_LEN {
+                self.base_delays.pop_front();
+            }
+            self.base_delays.push_back(delay);
+            self.last_rollover = now;
+        } else if let Some(last) = self.base_delays.back_mut() {
+            if is_less(delay, *last) {
+                *last = delay;
+            }
+        }
+    }
+
+    fn base_delay(&self) -> Option<u32> {
+        self.base_delays.iter().copied().fold(None, min)
+    }
+
+    fn current_delay(&self) -> Option<u32> {
+        self.current_delays.iter().copied().fold(None, min)
+    }
+}
+
+/// Compares two delays, which may have wrapped around since they contain the
+/// offset of the clocks.
+fn is_less(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn min(acc: Option<u32>, delay: u32) -> Option<u32> {
+    match acc {
+        Some(acc) if !is_less(delay, acc) => Some(acc),
+        _ => Some(delay),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1000;
+
+    /// Tests that the window grows while the delay is at its base, and
+    /// shrinks once the queuing delay exceeds the target.
+    #[test]
+    fn should_follow_queuing_delay() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(MSS, now);
+        assert_eq!(ledbat.window(), INIT_WINDOW * MSS);
+
+        // the clock offset makes the delays large, but they don't change
+        let base = u32::MAX - 5_000;
+        for _ in 0..20 {
+            let window = ledbat.window();
+            ledbat.on_ack(base, MSS, window, now);
+            assert!(ledbat.window() > window);
+        }
+        assert_eq!(ledbat.queuing_delay(), 0);
+
+        // the delay grows beyond the target, and wraps around
+        let delay = base.wrapping_add(2 * TARGET);
+        for _ in 0..CURRENT_FILTER_LEN {
+            ledbat.on_ack(delay, MSS, ledbat.window(), now);
+        }
+        assert_eq!(ledbat.queuing_delay(), 2 * TARGET);
+        let window = ledbat.window();
+        ledbat.on_ack(delay, MSS, window, now);
+        assert!(ledbat.window() < window);
+    }
+
+    /// Tests that the window doesn't grow beyond what's actually in flight.
+    #[test]
+    fn should_not_grow_beyond_flight() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(MSS, now);
+        for _ in 0..100 {
+            ledbat.on_ack(1_000, MSS, MSS, now);
+        }
+        assert_eq!(ledbat.window(), (1 + ALLOWED_INCREASE) * MSS);
+    }
+
+    #[test]
+    fn should_shrink_on_loss_and_timeout() {
+        let now = Instant::now();
+        let mut ledbat = Ledbat::new(MSS, now);
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), INIT_WINDOW * MSS / 2);
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), MIN_WINDOW * MSS);
+
+        let mut ledbat = Ledbat::new(MSS, now);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MIN_WINDOW * MSS);
+    }
+
+    /// Tests that the base delay is forgotten after the history is full, so
+    /// that the connection adapts to a longer route.
+    #[test]
+    fn should_expire_base_delay() {
+        let mut now = Instant::now();
+        let mut ledbat = Ledbat::new(MSS, now);
+        ledbat.on_ack(1_000, MSS, MSS, now);
+        for _ in 0..BASE_HISTORY_LEN {
+            now += MINUTE;
+            ledbat.on_ack(50_000, MSS, MSS, now);
+        }
+        assert_eq!(ledbat.base_delay(), Some(50_000));
+    }
+}
@@ -0,0 +1,184 @@
+//! The uTP packet format.
+//!
+//! Every packet starts with the same 20 byte header, which may be followed by
+//! a chain of extensions and then the payload. We don't use any extensions,
+//! but skip those of the other side.
+
+use std::convert::TryFrom;
+
+use bytes::{Buf, BufMut};
+
+/// The version of the protocol, sent in the low 4 bits of the first byte.
+const VERSION: u8 = 1;
+
+/// The length of the header, without extensions.
+pub(crate) const HEADER_LEN: usize = 20;
+
+/// The type of a packet, sent in the high 4 bits of the first byte.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PacketType {
+    /// A packet with a payload.
+    Data = 0,
+    /// Closes the connection: the sender won't send packets after this one.
+    Fin = 1,
+    /// Acknowledges packets, without a payload.
+    State = 2,
+    /// Terminates the connection forcefully.
+    Reset = 3,
+    /// Initiates a connection.
+    Syn = 4,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = ();
+
+    fn try_from(ty: u8) -> Result<Self, Self::Error> {
+        match ty {
+            0 => Ok(Self::Data),
+            1 => Ok(Self::Fin),
+            2 => Ok(Self::State),
+            3 => Ok(Self::Reset),
+            4 => Ok(Self::Syn),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A uTP packet.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Packet {
+    pub ty: PacketType,
+    /// The id of the connection on the receiving side.
+    pub conn_id: u16,
+    /// The sender's clock when the packet was sent, in microseconds.
+    pub timestamp: u32,
+    /// The difference between the sender's clock when it received the last
+    /// packet and that packet's timestamp, which is the one-way delay of the
+    /// receiver's packets plus the offset of the two clocks.
+    pub timestamp_diff: u32,
+    /// The number of bytes the sender is willing to receive.
+    pub window: u32,
+    pub seq_nr: u16,
+    /// The sequence number of the last packet the sender received in order.
+    pub ack_nr: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Returns the packet in its wire format.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.put_u8((self.ty as u8) << 4 | VERSION);
+        // no extensions
+        buf.put_u8(0);
+        buf.put_u16(self.conn_id);
+        buf.put_u32(self.timestamp);
+        buf.put_u32(self.timestamp_diff);
+        buf.put_u32(self.window);
+        buf.put_u16(self.seq_nr);
+        buf.put_u16(self.ack_nr);
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+
+    /// Parses a packet, returning `None` if it's not a valid uTP packet.
+    pub fn decode(mut buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN {
+            return None;
+        }
+        let ty_version = buf.get_u8();
+        if ty_version & 0xf != VERSION {
+            return None;
+        }
+        let ty = PacketType::try_from(ty_version >> 4).ok()?;
+        let mut extension = buf.get_u8();
+        let conn_id = buf.get_u16();
+        let timestamp = buf.get_u32();
+        let timestamp_diff = buf.get_u32();
+        let window = buf.get_u32();
+        let seq_nr = buf.get_u16();
+        let ack_nr = buf.get_u16();
+
+        // each extension starts with the type of the next one and its length
+        while extension != 0 {
+            if buf.len() < 2 {
+                return None;
+            }
+            extension = buf.get_u8();
+            let len = buf.get_u8() as usize;
+            if buf.len() < len {
+                return None;
+            }
+            buf.advance(len);
+        }
+
+        Some(Self {
+            ty,
+            conn_id,
+            timestamp,
+            timestamp_diff,
+            window,
+            seq_nr,
+            ack_nr,
+            payload: buf.to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_packet() -> Packet {
+        Packet {
+            ty: PacketType::Data,
+            conn_id: 0x1234,
+            timestamp: 0xdead_beef,
+            timestamp_diff: 42,
+            window: 1 << 20,
+            seq_nr: 7,
+            ack_nr: 65535,
+            payload: b"payload".to_vec(),
+        }
+    }
+
+    #[test]
+    fn should_encode_and_decode_packet() {
+        let packet = make_packet();
+        let buf = packet.encode();
+        assert_eq!(buf.len(), HEADER_LEN + 7);
+        assert_eq!(buf[0], 0x01);
+        assert_eq!(&buf[2..4], &[0x12, 0x34]);
+        assert_eq!(Packet::decode(&buf), Some(packet));
+    }
+
+    /// Tests that the other side's extensions are skipped.
+    #[test]
+    fn should_skip_extensions() {
+        let packet = make_packet();
+        let mut buf = packet.encode();
+        // a selective ack extension with a 4 byte bitmask
+        buf[1] = 1;
+        let payload = buf.split_off(HEADER_LEN);
+        buf.extend_from_slice(&[0, 4, 0xff, 0, 0, 0]);
+        buf.extend_from_slice(&payload);
+        assert_eq!(Packet::decode(&buf), Some(packet));
+
+        // a truncated extension
+        assert_eq!(Packet::decode(&buf[..HEADER_LEN + 4]), None);
+    }
+
+    #[test]
+    fn should_reject_invalid_packet() {
+        let buf = make_packet().encode();
+        assert_eq!(Packet::decode(&buf[..HEADER_LEN - 1]), None);
+
+        let mut wrong_version = buf.clone();
+        wrong_version[0] = 0x02;
+        assert_eq!(Packet::decode(&wrong_version), None);
+
+        let mut wrong_type = buf;
+        wrong_type[0] = 0x51;
+        assert_eq!(Packet::decode(&wrong_type), None);
+    }
+}
@@ -50,7 +50,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
 
     // spawn the torrent engine
-    let conf = Conf::new(args.download_dir);
+    let mut conf = Conf::new(args.download_dir);
+    if let Some(listen_addr) = args.listen {
+        conf.engine.listen_addr = listen_addr;
+    }
     let (handle, mut alert_rx) = cratetorrent::engine::spawn(conf)?;
 
     // read in torrent metainfo
@@ -63,9 +66,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let _torrent_id = handle.create_torrent(TorrentParams {
         metainfo,
-        listen_addr: args.listen,
         mode: args.mode,
         conf: None,
+        resume_data: None,
+        file_priorities: None,
+        extensions: Vec::new(),
     })?;
 
     // listen to alerts from the engine